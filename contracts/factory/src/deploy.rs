@@ -1,3 +1,4 @@
+use near_sdk::json_types::Base64VecU8;
 use near_sdk::serde::{ Serialize, Deserialize};
 use near_sdk::{env, log, near, AccountId, NearToken, Promise, PromiseError, PublicKey};
 
@@ -8,15 +9,33 @@ use crate::{Contract, ContractExt, NEAR_PER_STORAGE, NO_DEPOSIT, TGAS};
 struct IndexInitArgs {
     name: String,
     allocation_targets: Vec<AllocationTarget>,
+    metadata: Option<FundMetadataArgs>,
+    creator: AccountId,
 }
 
 #[near(serializers = [json, borsh])]
 #[derive(Clone, Debug)]
 pub struct AllocationTarget {
     pub address: String,
+    /// In basis points, checked by the index contract to add up to 10000
     pub ratio: u32,
 }
 
+/// Metadata forwarded to the index contract, which validates it.
+#[near(serializers = [json])]
+#[derive(Clone, Debug)]
+pub struct FundMetadataArgs {
+    pub symbol: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub themes: Vec<String>,
+    #[serde(default)]
+    pub risk_score: u8,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
 #[near]
 impl Contract {
     #[payable]
@@ -25,6 +44,7 @@ impl Contract {
         name: String,
         allocation_targets: Vec<AllocationTarget>,
         public_key: Option<PublicKey>,
+        metadata: Option<FundMetadataArgs>,
     ) -> Promise {
         // Assert the sub-account is valid
         let current_account = env::current_account_id().to_string();
@@ -50,6 +70,8 @@ impl Contract {
         let init_args = near_sdk::serde_json::to_vec(&IndexInitArgs {
             name: name.clone(),
             allocation_targets,
+            metadata,
+            creator: env::predecessor_account_id(),
         })
         .unwrap();
 
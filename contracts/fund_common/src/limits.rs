@@ -0,0 +1,137 @@
+use near_sdk::json_types::U128;
+use near_sdk::near;
+
+/// Limits on what a contract accepts. Deposits breaking them are refunded.
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct DepositLimits {
+    /// Largest total of deposits the contract holds
+    pub tvl_cap: Option<U128>,
+    /// Smallest single deposit
+    pub min_deposit: U128,
+    /// Largest position a single account may build up
+    pub max_per_account: Option<U128>,
+    /// Only accounts on the allowlist may deposit
+    pub allowlist_enabled: bool,
+}
+
+impl DepositLimits {
+    /// Checks a deposit of `amount` by an account already holding `account_total`
+    /// into a contract holding `tvl`.
+    pub fn check(
+        &self,
+        allowlisted: bool,
+        amount: u128,
+        account_total: u128,
+        tvl: u128,
+    ) -> Result<(), String> {
+        if self.allowlist_enabled && !allowlisted {
+            return Err("account is not on the allowlist".to_string());
+        }
+        if amount < self.min_deposit.0 {
+            return Err(format!(
+                "deposit is below the minimum of {}",
+                self.min_deposit.0
+            ));
+        }
+        if let Some(max) = self.max_per_account {
+            if account_total.saturating_add(amount) > max.0 {
+                return Err(format!(
+                    "account would exceed its maximum of {}",
+                    max.0
+                ));
+            }
+        }
+        if let Some(cap) = self.tvl_cap {
+            if tvl.saturating_add(amount) > cap.0 {
+                return Err(format!("deposits would exceed the TVL cap of {}", cap.0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects limits no deposit could ever satisfy.
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(max), Some(cap)) = (self.max_per_account, self.tvl_cap) {
+            if max.0 > cap.0 {
+                return Err("Account maximum can't exceed the TVL cap".to_string());
+            }
+        }
+        if let Some(max) = self.max_per_account {
+            if self.min_deposit.0 > max.0 {
+                return Err("Minimum deposit can't exceed the account maximum".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> DepositLimits {
+        DepositLimits {
+            tvl_cap: Some(U128(1_000)),
+            min_deposit: U128(10),
+            max_per_account: Some(U128(300)),
+            allowlist_enabled: false,
+        }
+    }
+
+    #[test]
+    fn test_deposit_within_limits() {
+        assert_eq!(limits().check(false, 100, 200, 900), Ok(()));
+    }
+
+    #[test]
+    fn test_deposit_limits() {
+        assert_eq!(
+            limits().check(false, 5, 0, 0),
+            Err("deposit is below the minimum of 10".to_string())
+        );
+        assert_eq!(
+            limits().check(false, 101, 200, 0),
+            Err("account would exceed its maximum of 300".to_string())
+        );
+        assert_eq!(
+            limits().check(false, 101, 0, 900),
+            Err("deposits would exceed the TVL cap of 1000".to_string())
+        );
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let limits = DepositLimits {
+            allowlist_enabled: true,
+            ..limits()
+        };
+        assert_eq!(
+            limits.check(false, 100, 0, 0),
+            Err("account is not on the allowlist".to_string())
+        );
+        assert_eq!(limits.check(true, 100, 0, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_limits() {
+        let limits = DepositLimits {
+            tvl_cap: Some(U128(100)),
+            max_per_account: Some(U128(200)),
+            ..DepositLimits::default()
+        };
+        assert_eq!(
+            limits.validate(),
+            Err("Account maximum can't exceed the TVL cap".to_string())
+        );
+        let limits = DepositLimits {
+            min_deposit: U128(300),
+            max_per_account: Some(U128(200)),
+            ..DepositLimits::default()
+        };
+        assert_eq!(
+            limits.validate(),
+            Err("Minimum deposit can't exceed the account maximum".to_string())
+        );
+    }
+}
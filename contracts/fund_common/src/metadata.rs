@@ -0,0 +1,222 @@
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::{near, AccountId};
+
+/// Version of the `FundMetadata` layout, bumped whenever a field is added.
+pub const FUND_METADATA_SPEC: &str = "fund-metadata-1.0.0";
+
+pub const MAX_NAME_LEN: usize = 64;
+pub const MAX_SYMBOL_LEN: usize = 12;
+pub const MAX_DESCRIPTION_LEN: usize = 1_000;
+pub const MAX_THEMES: usize = 5;
+pub const MAX_THEME_LEN: usize = 32;
+pub const MAX_RISK_SCORE: u8 = 5;
+pub const MAX_URL_LEN: usize = 256;
+
+/// Everything a frontend shows about the fund besides its holdings.
+#[near(serializers = [json, borsh])]
+#[derive(Clone, PartialEq, Debug)]
+pub struct FundMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub description: Option<String>,
+    /// Theme tags, e.g. "defi" or "layer-1"
+    pub themes: Vec<String>,
+    /// From 1 (lowest) to `MAX_RISK_SCORE`, 0 when not rated
+    pub risk_score: u8,
+    /// Data URL or link of the fund icon
+    pub icon: Option<String>,
+    /// Link to a JSON file with more info about the fund
+    pub reference: Option<String>,
+    /// Sha256 hash of the JSON file at `reference`
+    pub reference_hash: Option<Base64VecU8>,
+    pub creator: AccountId,
+}
+
+/// Metadata the creator supplies, the spec and creator are filled in by the contract.
+#[near(serializers = [json])]
+#[derive(Clone, Default)]
+#[serde(default)]
+pub struct FundMetadataArgs {
+    pub name: String,
+    pub symbol: String,
+    pub description: Option<String>,
+    pub themes: Vec<String>,
+    pub risk_score: u8,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+/// Changes to the metadata, fields left out keep their value.
+#[near(serializers = [json])]
+#[derive(Clone, Default)]
+#[serde(default)]
+pub struct FundMetadataUpdate {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub description: Option<String>,
+    pub themes: Option<Vec<String>>,
+    pub risk_score: Option<u8>,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+fn check_len(field: &str, value: &str, max: usize) -> Result<(), String> {
+    if value.len() > max {
+        return Err(format!("{} is longer than {} bytes", field, max));
+    }
+    Ok(())
+}
+
+impl FundMetadata {
+    pub fn new(args: FundMetadataArgs, creator: AccountId) -> Self {
+        Self {
+            spec: FUND_METADATA_SPEC.to_string(),
+            name: args.name,
+            symbol: args.symbol,
+            description: args.description,
+            themes: args.themes,
+            risk_score: args.risk_score,
+            icon: args.icon,
+            reference: args.reference,
+            reference_hash: args.reference_hash,
+            creator,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        check_len("name", &self.name, MAX_NAME_LEN)?;
+        check_len("symbol", &self.symbol, MAX_SYMBOL_LEN)?;
+        if !self.symbol.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("symbol must be alphanumeric".to_string());
+        }
+        if let Some(description) = &self.description {
+            check_len("description", description, MAX_DESCRIPTION_LEN)?;
+        }
+        if self.themes.len() > MAX_THEMES {
+            return Err(format!("at most {} themes are allowed", MAX_THEMES));
+        }
+        for theme in &self.themes {
+            check_len("theme", theme, MAX_THEME_LEN)?;
+            if theme.is_empty() {
+                return Err("theme can't be empty".to_string());
+            }
+        }
+        if self.risk_score > MAX_RISK_SCORE {
+            return Err(format!("risk score can't exceed {}", MAX_RISK_SCORE));
+        }
+        if let Some(icon) = &self.icon {
+            // Data URLs are kept small, anything bigger belongs behind a link
+            check_len("icon", icon, MAX_DESCRIPTION_LEN)?;
+        }
+        if let Some(reference) = &self.reference {
+            check_len("reference", reference, MAX_URL_LEN)?;
+        }
+        match (&self.reference, &self.reference_hash) {
+            (Some(_), None) => return Err("reference needs a reference_hash".to_string()),
+            (None, Some(_)) => return Err("reference_hash needs a reference".to_string()),
+            (_, Some(hash)) if hash.0.len() != 32 => {
+                return Err("reference_hash must be 32 bytes".to_string())
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Applies `update`. The name and symbol can't change once set, so a fund can't
+    /// be passed off as another one after raising deposits.
+    pub fn apply(&mut self, update: FundMetadataUpdate) -> Result<(), String> {
+        if let Some(name) = update.name {
+            if !self.name.is_empty() && name != self.name {
+                return Err("name can't be changed once set".to_string());
+            }
+            self.name = name;
+        }
+        if let Some(symbol) = update.symbol {
+            if !self.symbol.is_empty() && symbol != self.symbol {
+                return Err("symbol can't be changed once set".to_string());
+            }
+            self.symbol = symbol;
+        }
+        if update.description.is_some() {
+            self.description = update.description;
+        }
+        if let Some(themes) = update.themes {
+            self.themes = themes;
+        }
+        if let Some(risk_score) = update.risk_score {
+            self.risk_score = risk_score;
+        }
+        if update.icon.is_some() {
+            self.icon = update.icon;
+        }
+        if update.reference.is_some() || update.reference_hash.is_some() {
+            self.reference = update.reference;
+            self.reference_hash = update.reference_hash;
+        }
+        self.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> FundMetadata {
+        FundMetadata::new(
+            FundMetadataArgs {
+                name: "Blue Chips".to_string(),
+                symbol: "BLUE".to_string(),
+                themes: vec!["large-cap".to_string()],
+                risk_score: 2,
+                ..Default::default()
+            },
+            "creator.testnet".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_validate_metadata() {
+        assert_eq!(metadata().validate(), Ok(()));
+        assert_eq!(
+            FundMetadata {
+                risk_score: 6,
+                ..metadata()
+            }
+            .validate(),
+            Err("risk score can't exceed 5".to_string())
+        );
+        assert_eq!(
+            FundMetadata {
+                reference: Some("https://example.com/fund.json".to_string()),
+                ..metadata()
+            }
+            .validate(),
+            Err("reference needs a reference_hash".to_string())
+        );
+    }
+
+    #[test]
+    fn test_name_and_symbol_are_fixed() {
+        let mut metadata = metadata();
+        assert_eq!(
+            metadata.apply(FundMetadataUpdate {
+                symbol: Some("BLUE2".to_string()),
+                ..Default::default()
+            }),
+            Err("symbol can't be changed once set".to_string())
+        );
+        assert_eq!(
+            metadata.apply(FundMetadataUpdate {
+                description: Some("Top assets by market cap".to_string()),
+                risk_score: Some(3),
+                ..Default::default()
+            }),
+            Ok(())
+        );
+        assert_eq!(metadata.risk_score, 3);
+        assert_eq!(metadata.themes, vec!["large-cap".to_string()]);
+    }
+}
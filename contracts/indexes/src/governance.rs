@@ -0,0 +1,231 @@
+use near_sdk::serde_json::json;
+use near_sdk::store::Vector;
+use near_sdk::{env, near, require, AccountId};
+
+use crate::{AllocationTarget, Contract, ContractExt, Prefix, MAX_BPS};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const DEFAULT_PAGE_LIMIT: u32 = 50;
+
+pub const DEFAULT_TIMELOCK_SEC: u64 = 2 * 24 * 60 * 60;
+/// Shortest timelock allowed, so a proposal can't be pushed through before holders notice
+pub const MIN_TIMELOCK_SEC: u64 = 24 * 60 * 60;
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProposalStatus {
+    /// Waiting for its timelock to pass
+    Pending,
+    Executed,
+    Cancelled,
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct AllocationProposal {
+    pub id: u32,
+    pub proposer: AccountId,
+    pub allocation_targets: Vec<AllocationTarget>,
+    pub proposed_at: u64,
+    pub executable_at: u64,
+    pub status: ProposalStatus,
+    pub executed_at: Option<u64>,
+}
+
+#[near(serializers = [borsh])]
+pub struct Governance {
+    pub timelock_sec: u64,
+    /// Every proposal ever made, its id is its index
+    pub proposals: Vector<AllocationProposal>,
+}
+
+impl Default for Governance {
+    fn default() -> Self {
+        Self {
+            timelock_sec: DEFAULT_TIMELOCK_SEC,
+            proposals: Vector::new(Prefix::Proposals),
+        }
+    }
+}
+
+/// Checks that `proposed` gives every current target exactly one ratio and the ratios add up to `MAX_BPS`.
+pub fn validate_targets(
+    current: &[AllocationTarget],
+    proposed: &[AllocationTarget],
+) -> Result<(), String> {
+    for (i, target) in proposed.iter().enumerate() {
+        if !current.iter().any(|t| t.address == target.address) {
+            return Err(format!("asset {} is not registered", target.address));
+        }
+        if proposed[..i].iter().any(|t| t.address == target.address) {
+            return Err(format!("asset {} is listed twice", target.address));
+        }
+    }
+    if let Some(target) = current
+        .iter()
+        .find(|t| !proposed.iter().any(|p| p.address == t.address))
+    {
+        return Err(format!("asset {} has no ratio", target.address));
+    }
+    let total: u32 = proposed.iter().map(|t| t.ratio).sum();
+    if total != MAX_BPS {
+        return Err(format!("ratios add up to {} instead of {}", total, MAX_BPS));
+    }
+    Ok(())
+}
+
+impl Contract {
+    fn pending_proposal(&self) -> Option<&AllocationProposal> {
+        self.governance
+            .proposals
+            .iter()
+            .rev()
+            .find(|p| p.status == ProposalStatus::Pending)
+    }
+
+    fn proposal_mut(&mut self, proposal_id: u32) -> &mut AllocationProposal {
+        self.governance
+            .proposals
+            .get_mut(proposal_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Proposal {} not found", proposal_id)))
+    }
+}
+
+#[near]
+impl Contract {
+    /// Proposes new allocation targets, executable once the timelock passed.
+    pub fn propose_allocation(
+        &mut self,
+        allocation_targets: Vec<AllocationTarget>,
+    ) -> AllocationProposal {
+        self.assert_creator();
+        require!(
+            self.pending_proposal().is_none(),
+            "An allocation proposal is already pending"
+        );
+        let current: Vec<AllocationTarget> = self.allocation_targets.iter().cloned().collect();
+        if let Err(err) = validate_targets(&current, &allocation_targets) {
+            env::panic_str(&format!("Invalid allocation: {}", err));
+        }
+
+        let proposed_at = env::block_timestamp();
+        let proposal = AllocationProposal {
+            id: self.governance.proposals.len(),
+            proposer: env::predecessor_account_id(),
+            allocation_targets,
+            proposed_at,
+            executable_at: proposed_at
+                .saturating_add(self.governance.timelock_sec.saturating_mul(NANOS_PER_SEC)),
+            status: ProposalStatus::Pending,
+            executed_at: None,
+        };
+        self.governance.proposals.push(proposal.clone());
+        proposal
+    }
+
+    pub fn cancel_allocation_proposal(&mut self, proposal_id: u32) {
+        self.assert_creator();
+        let proposal = self.proposal_mut(proposal_id);
+        require!(
+            proposal.status == ProposalStatus::Pending,
+            "Proposal is not pending"
+        );
+        proposal.status = ProposalStatus::Cancelled;
+    }
+
+    /// Replaces the allocation targets with a proposal whose timelock passed and asks
+    /// the keeper to rebalance. Anyone may execute it.
+    pub fn execute_allocation_proposal(&mut self, proposal_id: u32) -> AllocationProposal {
+        let now = env::block_timestamp();
+        let proposal = self.proposal_mut(proposal_id);
+        require!(
+            proposal.status == ProposalStatus::Pending,
+            "Proposal is not pending"
+        );
+        require!(
+            now >= proposal.executable_at,
+            format!("Proposal can be executed at {}", proposal.executable_at)
+        );
+        proposal.status = ProposalStatus::Executed;
+        proposal.executed_at = Some(now);
+        let proposal = proposal.clone();
+
+        self.allocation_targets.clear();
+        self.allocation_targets
+            .extend(proposal.allocation_targets.iter().cloned());
+
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            json!({
+                "standard": "nexusfi",
+                "version": "1.0.0",
+                "event": "rebalance",
+                "data": [{
+                    "proposal_id": proposal_id,
+                    "allocation_targets": proposal.allocation_targets,
+                }],
+            })
+        ));
+        proposal
+    }
+
+    pub fn set_governance_timelock_sec(&mut self, timelock_sec: u64) {
+        self.assert_creator();
+        require!(
+            timelock_sec >= MIN_TIMELOCK_SEC,
+            format!("Timelock can't be shorter than {} seconds", MIN_TIMELOCK_SEC)
+        );
+        self.governance.timelock_sec = timelock_sec;
+    }
+
+    pub fn get_pending_allocation_proposal(&self) -> Option<&AllocationProposal> {
+        self.pending_proposal()
+    }
+
+    /// Proposals from oldest to newest, pending, executed and cancelled ones alike.
+    pub fn get_allocation_proposals(
+        &self,
+        from_index: Option<u32>,
+        limit: Option<u32>,
+    ) -> Vec<&AllocationProposal> {
+        self.governance
+            .proposals
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(address: &str, ratio: u32) -> AllocationTarget {
+        AllocationTarget {
+            address: address.to_string(),
+            ratio,
+        }
+    }
+
+    #[test]
+    fn test_validate_targets() {
+        let current = vec![target("addr1", 5_000), target("addr2", 5_000)];
+        assert_eq!(
+            validate_targets(&current, &[target("addr1", 7_000), target("addr2", 3_000)]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_targets(&current, &[target("addr1", 7_000), target("addr3", 3_000)]),
+            Err("asset addr3 is not registered".to_string())
+        );
+        assert_eq!(
+            validate_targets(&current, &[target("addr1", 10_000)]),
+            Err("asset addr2 has no ratio".to_string())
+        );
+        assert_eq!(
+            validate_targets(&current, &[target("addr1", 7_000), target("addr2", 4_000)]),
+            Err("ratios add up to 11000 instead of 10000".to_string())
+        );
+    }
+}
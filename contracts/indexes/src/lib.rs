@@ -1,16 +1,45 @@
-use near_sdk::near;
 use near_sdk::store::Vector;
+use near_sdk::{env, near, AccountId, IntoStorageKey};
+
+pub mod governance;
+pub mod metadata;
+pub mod migrate;
+pub use crate::governance::*;
+pub use crate::metadata::*;
+
+/// Denominator of allocation ratios, they are in basis points
+pub const MAX_BPS: u32 = 10_000;
+
+/// Prefixes of the collections stored apart from the contract's state. Each keeps the
+/// bytes it was first deployed with, so collections already stored stay readable.
+#[derive(Clone, Copy)]
+pub enum Prefix {
+    AllocationTargets,
+    Proposals,
+}
+
+impl IntoStorageKey for Prefix {
+    fn into_storage_key(self) -> Vec<u8> {
+        match self {
+            Prefix::AllocationTargets => b"f".to_vec(),
+            Prefix::Proposals => b"p".to_vec(),
+        }
+    }
+}
 
 #[near(contract_state)]
 pub struct Contract {
     name: String,
     allocation_targets: Vector<AllocationTarget>,
+    metadata: FundMetadata,
+    governance: Governance,
 }
 
 #[near(serializers = [json, borsh])]
 #[derive(Clone, Debug)]
 pub struct AllocationTarget {
     pub address: String,
+    /// In basis points, the ratios of an index add up to `MAX_BPS`
     pub ratio: u32,
 }
 
@@ -18,7 +47,15 @@ impl Default for Contract {
     fn default() -> Self {
         Self {
             name: "Default Index".to_string(),
-            allocation_targets: Vector::new(b"f"),
+            allocation_targets: Vector::new(Prefix::AllocationTargets),
+            metadata: FundMetadata::new(
+                FundMetadataArgs {
+                    name: "Default Index".to_string(),
+                    ..Default::default()
+                },
+                env::current_account_id(),
+            ),
+            governance: Governance::default(),
         }
     }
 }
@@ -27,14 +64,34 @@ impl Default for Contract {
 impl Contract {
     #[init]
     #[private]
-    pub fn init(name: String, allocation_targets: Vec<AllocationTarget>) -> Self {
-        let mut allocation_targets_vector = near_sdk::store::Vector::new(b"f");
+    pub fn init(
+        name: String,
+        allocation_targets: Vec<AllocationTarget>,
+        metadata: Option<FundMetadataArgs>,
+        creator: Option<AccountId>,
+    ) -> Self {
+        if let Err(err) = validate_targets(&allocation_targets, &allocation_targets) {
+            env::panic_str(&format!("Invalid allocation: {}", err));
+        }
+        let mut allocation_targets_vector = Vector::new(Prefix::AllocationTargets);
         for at in allocation_targets {
             allocation_targets_vector.push(at);
         }
+        let metadata = FundMetadata::new(
+            FundMetadataArgs {
+                name: name.clone(),
+                ..metadata.unwrap_or_default()
+            },
+            creator.unwrap_or_else(env::predecessor_account_id),
+        );
+        if let Err(err) = metadata.validate() {
+            env::panic_str(&format!("Invalid fund metadata: {}", err));
+        }
         Self {
             name,
             allocation_targets: allocation_targets_vector,
+            metadata,
+            governance: Governance::default(),
         }
     }
 
@@ -61,20 +118,31 @@ mod tests {
         let allocation_targets = vec![
             AllocationTarget {
                 address: "addr1".to_string(),
-                ratio: 50,
+                ratio: 5_000,
             },
             AllocationTarget {
                 address: "addr2".to_string(),
-                ratio: 50,
+                ratio: 5_000,
             },
         ];
 
-        let contract = Contract::init("Test Contract".to_string(), allocation_targets);
+        let contract =
+            Contract::init("Test Contract".to_string(), allocation_targets, None, None);
         let (name, allocation_targets_vec) = contract.get_info();
 
         assert_eq!(name, "Test Contract");
         assert_eq!(allocation_targets_vec.len(), 2);
         assert_eq!(allocation_targets_vec[0].address, "addr1");
-        assert_eq!(allocation_targets_vec[0].ratio, 50);
+        assert_eq!(allocation_targets_vec[0].ratio, 5_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid allocation: ratios add up to 100 instead of 10000")]
+    fn test_init_rejects_percent_ratios() {
+        let allocation_targets = vec![AllocationTarget {
+            address: "addr1".to_string(),
+            ratio: 100,
+        }];
+        Contract::init("Test Contract".to_string(), allocation_targets, None, None);
     }
 }
@@ -0,0 +1,29 @@
+use near_sdk::{env, near, require};
+
+pub use fund_common::metadata::*;
+
+use crate::{Contract, ContractExt};
+
+impl Contract {
+    pub(crate) fn assert_creator(&self) {
+        require!(
+            env::predecessor_account_id() == self.metadata.creator,
+            "Only the creator can call this method"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_metadata(&self) -> &FundMetadata {
+        &self.metadata
+    }
+
+    pub fn update_metadata(&mut self, update: FundMetadataUpdate) -> &FundMetadata {
+        self.assert_creator();
+        if let Err(err) = self.metadata.apply(update) {
+            env::panic_str(&format!("Invalid fund metadata: {}", err));
+        }
+        &self.metadata
+    }
+}
@@ -0,0 +1,77 @@
+use near_sdk::store::Vector;
+use near_sdk::{env, near, AccountId};
+
+use crate::{
+    AllocationTarget, Contract, ContractExt, FundMetadata, FundMetadataArgs, Governance, MAX_BPS,
+};
+
+/// Ratios were whole percents before they became basis points.
+const BPS_PER_PERCENT: u32 = MAX_BPS / 100;
+
+/// State as deployed before metadata and governance, with ratios in percents.
+#[near(serializers = [borsh])]
+pub struct ContractV0 {
+    name: String,
+    allocation_targets: Vector<AllocationTarget>,
+}
+
+#[near]
+impl Contract {
+    /// Brings an index deployed before metadata and governance to the current layout.
+    /// Ratios adding up to 100 are scaled to basis points, and `creator` becomes the
+    /// account that may update the metadata and propose allocations.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(creator: AccountId) -> Self {
+        let mut old: ContractV0 = env::state_read().expect("No state to migrate");
+        let total: u32 = old.allocation_targets.iter().map(|t| t.ratio).sum();
+        if total == 100 {
+            for target in old.allocation_targets.iter_mut() {
+                target.ratio *= BPS_PER_PERCENT;
+            }
+        }
+        let metadata = FundMetadata::new(
+            FundMetadataArgs {
+                name: old.name.clone(),
+                ..Default::default()
+            },
+            creator,
+        );
+        Self {
+            name: old.name,
+            allocation_targets: old.allocation_targets,
+            metadata,
+            governance: Governance::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Prefix;
+
+    #[test]
+    fn test_migrate_from_v0() {
+        let mut allocation_targets = Vector::new(Prefix::AllocationTargets);
+        for (address, ratio) in [("addr1", 60), ("addr2", 40)] {
+            allocation_targets.push(AllocationTarget {
+                address: address.to_string(),
+                ratio,
+            });
+        }
+        allocation_targets.flush();
+        env::state_write(&ContractV0 {
+            name: "Old Index".to_string(),
+            allocation_targets,
+        });
+
+        let creator: AccountId = "creator.testnet".parse().unwrap();
+        let contract = Contract::migrate(creator.clone());
+        let (name, targets) = contract.get_info();
+        assert_eq!(name, "Old Index");
+        assert_eq!(targets[0].ratio, 6_000);
+        assert_eq!(targets[1].ratio, 4_000);
+        assert_eq!(contract.get_metadata().creator, creator);
+    }
+}
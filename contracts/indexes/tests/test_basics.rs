@@ -18,11 +18,11 @@ async fn test_basics_on(contract_wasm: &[u8]) -> Result<(), Box<dyn std::error::
     let allocation_targets = vec![
         json!({
             "address": "fund1.near",
-            "ratio": 60
+            "ratio": 6_000
         }),
         json!({
             "address": "fund2.near",
-            "ratio": 40
+            "ratio": 4_000
         })
     ];
 
@@ -30,7 +30,12 @@ async fn test_basics_on(contract_wasm: &[u8]) -> Result<(), Box<dyn std::error::
         .call(contract.id(), "init")
         .args_json(json!({
             "name": "Test Index",
-            "allocation_targets": allocation_targets
+            "allocation_targets": allocation_targets,
+            "metadata": {
+                "symbol": "TIDX",
+                "themes": ["defi"],
+                "risk_score": 3
+            }
         }))
         .transact()
         .await?;
@@ -42,5 +47,11 @@ async fn test_basics_on(contract_wasm: &[u8]) -> Result<(), Box<dyn std::error::
     assert_eq!(name, "Test Index");
     assert_eq!(allocation_targets_vector.len(), 2);
 
+    let metadata: serde_json::Value = contract.view("get_metadata").args_json(json!({})).await?.json()?;
+    assert_eq!(metadata["spec"], "fund-metadata-1.0.0");
+    assert_eq!(metadata["name"], "Test Index");
+    assert_eq!(metadata["symbol"], "TIDX");
+    assert_eq!(metadata["risk_score"], 3);
+
     Ok(())
 }
\ No newline at end of file
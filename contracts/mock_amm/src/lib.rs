@@ -0,0 +1,260 @@
+// Minimal stand-in for Ref Finance. Like on Ref, tokens are deposited with
+// `ft_transfer_call` and an empty message, swapped against the sender's deposits with
+// `swap`, and taken out again with `withdraw`. Pools trade both ways at a fixed rate
+// that tests set, so no liquidity math is involved.
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::store::LookupMap;
+use near_sdk::{
+    assert_one_yocto, env, near, require, AccountId, Gas, NearToken, PanicOnDefault, Promise,
+    PromiseOrValue,
+};
+use std::collections::HashMap;
+
+const FT_TRANSFER_GAS: Gas = Gas::from_tgas(10);
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct Pool {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    /// `amount_in * numerator / denominator` of `token_out` is bought with `token_in`,
+    /// and the other way round at the inverse rate
+    pub numerator: U128,
+    pub denominator: U128,
+}
+
+#[near(serializers = [json])]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: Option<U128>,
+    pub min_amount_out: U128,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    pools: Vec<Pool>,
+    /// Tokens every account has deposited, by token contract
+    deposits: LookupMap<AccountId, HashMap<AccountId, U128>>,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            pools: Vec::new(),
+            deposits: LookupMap::new(b"d"),
+        }
+    }
+
+    /// Adds a pool and returns its id.
+    pub fn add_pool(
+        &mut self,
+        token_in: AccountId,
+        token_out: AccountId,
+        numerator: U128,
+        denominator: U128,
+    ) -> u64 {
+        require!(numerator.0 > 0 && denominator.0 > 0, "Rate can't be zero");
+        self.pools.push(Pool {
+            token_in,
+            token_out,
+            numerator,
+            denominator,
+        });
+        self.pools.len() as u64 - 1
+    }
+
+    pub fn set_rate(&mut self, pool_id: u64, numerator: U128, denominator: U128) {
+        require!(numerator.0 > 0 && denominator.0 > 0, "Rate can't be zero");
+        let pool = self.pool_mut(pool_id);
+        pool.numerator = numerator;
+        pool.denominator = denominator;
+    }
+
+    pub fn get_pool(&self, pool_id: u64) -> Pool {
+        self.pools
+            .get(pool_id as usize)
+            .cloned()
+            .unwrap_or_else(|| env::panic_str("Pool not found"))
+    }
+
+    pub fn get_deposits(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.deposits.get(&account_id).cloned().unwrap_or_default()
+    }
+
+    /// Credits the transfer to the sender's deposits. Instant swaps in the message are
+    /// not supported.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        require!(msg.is_empty(), "Only deposits are supported");
+        self.credit(&sender_id, &env::predecessor_account_id(), amount.0);
+        PromiseOrValue::Value(U128(0))
+    }
+
+    /// Runs the single swap action against the caller's deposits and returns what it
+    /// bought, which stays deposited. A swap below `min_amount_out` panics with Ref's
+    /// `E68: slippage error`.
+    #[payable]
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128 {
+        assert_one_yocto();
+        let _ = referral_id;
+        require!(actions.len() == 1, "Exactly one swap action is supported");
+        let action = &actions[0];
+        let pool = self.get_pool(action.pool_id);
+        let amount_in = action
+            .amount_in
+            .unwrap_or_else(|| env::panic_str("Amount in is required"))
+            .0;
+
+        let amount_out = if action.token_in == pool.token_in && action.token_out == pool.token_out
+        {
+            amount_in * pool.numerator.0 / pool.denominator.0
+        } else if action.token_in == pool.token_out && action.token_out == pool.token_in {
+            amount_in * pool.denominator.0 / pool.numerator.0
+        } else {
+            env::panic_str("Tokens don't match the pool")
+        };
+        require!(amount_out >= action.min_amount_out.0, "E68: slippage error");
+
+        let account_id = env::predecessor_account_id();
+        self.debit(&account_id, &action.token_in, amount_in);
+        self.credit(&account_id, &action.token_out, amount_out);
+        U128(amount_out)
+    }
+
+    /// Sends `amount` of the caller's deposit of `token_id` back to them.
+    #[payable]
+    pub fn withdraw(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        unregister: Option<bool>,
+    ) -> Promise {
+        assert_one_yocto();
+        let _ = unregister;
+        let account_id = env::predecessor_account_id();
+        self.debit(&account_id, &token_id, amount.0);
+        Promise::new(token_id).function_call(
+            "ft_transfer".to_string(),
+            json!({ "receiver_id": account_id, "amount": amount })
+                .to_string()
+                .into_bytes(),
+            NearToken::from_yoctonear(1),
+            FT_TRANSFER_GAS,
+        )
+    }
+}
+
+impl Contract {
+    fn credit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        self.deposits
+            .entry(account_id.clone())
+            .or_default()
+            .entry(token_id.clone())
+            .or_insert(U128(0))
+            .0 += amount;
+    }
+
+    fn debit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        let deposit = self
+            .deposits
+            .get_mut(account_id)
+            .and_then(|deposits| deposits.get_mut(token_id))
+            .filter(|deposit| deposit.0 >= amount)
+            .unwrap_or_else(|| env::panic_str("E22: not enough tokens in deposit"));
+        deposit.0 -= amount;
+    }
+
+    fn pool_mut(&mut self, pool_id: u64) -> &mut Pool {
+        self.pools
+            .get_mut(pool_id as usize)
+            .unwrap_or_else(|| env::panic_str("Pool not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn swap_action(amount_in: u128, min_amount_out: u128) -> Vec<SwapAction> {
+        vec![SwapAction {
+            pool_id: 0,
+            token_in: accounts(1),
+            token_out: accounts(2),
+            amount_in: Some(U128(amount_in)),
+            min_amount_out: U128(min_amount_out),
+        }]
+    }
+
+    /// A pool selling `accounts(2)` for `accounts(1)` at 1/2 with 700 of `accounts(1)`
+    /// deposited by `accounts(3)`, who is calling next.
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .build());
+        let mut contract = Contract::new();
+        contract.add_pool(accounts(1), accounts(2), U128(1), U128(2));
+        contract.ft_on_transfer(accounts(3), U128(700), String::new());
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        contract
+    }
+
+    #[test]
+    fn swap_at_pool_rate() {
+        let mut contract = setup();
+        assert_eq!(contract.swap(swap_action(700, 350), None), U128(350));
+
+        let deposits = contract.get_deposits(accounts(3));
+        assert_eq!(deposits[&accounts(1)], U128(0));
+        assert_eq!(deposits[&accounts(2)], U128(350));
+    }
+
+    #[test]
+    fn swap_back_at_inverse_rate() {
+        let mut contract = setup();
+        contract.swap(swap_action(700, 350), None);
+        let action = SwapAction {
+            pool_id: 0,
+            token_in: accounts(2),
+            token_out: accounts(1),
+            amount_in: Some(U128(350)),
+            min_amount_out: U128(700),
+        };
+        assert_eq!(contract.swap(vec![action], None), U128(700));
+    }
+
+    #[test]
+    #[should_panic(expected = "E68: slippage error")]
+    fn swap_below_min_amount_out() {
+        let mut contract = setup();
+        contract.swap(swap_action(700, 351), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "E22: not enough tokens in deposit")]
+    fn swap_more_than_deposited() {
+        let mut contract = setup();
+        contract.swap(swap_action(800, 0), None);
+    }
+
+    #[test]
+    fn withdraw_debits_the_deposit() {
+        let mut contract = setup();
+        contract.withdraw(accounts(1), U128(200), None);
+        assert_eq!(contract.get_deposits(accounts(3))[&accounts(1)], U128(500));
+    }
+}
@@ -0,0 +1,129 @@
+// Bare NEP-141 token for sandbox tests. Anyone can mint, and accounts don't need a
+// storage deposit before receiving tokens.
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, ext_contract, near, require, AccountId, Gas, NearToken, PanicOnDefault, PromiseOrValue,
+    PromiseResult,
+};
+use std::collections::HashMap;
+
+const FT_RESOLVE_TRANSFER_GAS: Gas = Gas::from_tgas(10);
+
+#[ext_contract(ext_ft_receiver)]
+pub trait FungibleTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> U128;
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    balances: HashMap<AccountId, u128>,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            balances: HashMap::new(),
+        }
+    }
+
+    pub fn mint(&mut self, account_id: AccountId, amount: U128) {
+        *self.balances.entry(account_id).or_insert(0) += amount.0;
+    }
+
+    #[payable]
+    pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        require!(
+            env::attached_deposit() == NearToken::from_yoctonear(1),
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let _ = memo;
+        self.transfer(&env::predecessor_account_id(), &receiver_id, amount.0);
+    }
+
+    #[payable]
+    pub fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::attached_deposit() == NearToken::from_yoctonear(1),
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let _ = memo;
+        let sender_id = env::predecessor_account_id();
+        self.transfer(&sender_id, &receiver_id, amount.0);
+        // The receiver gets whatever gas is left, like in the standard implementation
+        ext_ft_receiver::ext(receiver_id.clone())
+            .ft_on_transfer(sender_id.clone(), amount, msg)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(FT_RESOLVE_TRANSFER_GAS)
+                    .ft_resolve_transfer(sender_id, receiver_id, amount),
+            )
+            .into()
+    }
+
+    /// Refunds what the receiver didn't use, all of it when `ft_on_transfer` failed.
+    #[private]
+    pub fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let unused = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map_or(amount.0, |unused| unused.0.min(amount.0)),
+            PromiseResult::Failed => amount.0,
+        };
+        let refund = unused.min(self.balance(&receiver_id));
+        if refund > 0 {
+            self.transfer(&receiver_id, &sender_id, refund);
+        }
+        U128(amount.0 - refund)
+    }
+
+    pub fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        U128(self.balance(&account_id))
+    }
+}
+
+impl Contract {
+    fn balance(&self, account_id: &AccountId) -> u128 {
+        self.balances.get(account_id).copied().unwrap_or(0)
+    }
+
+    fn transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: u128) {
+        let balance = self.balance(sender_id);
+        require!(balance >= amount, "The account doesn't have enough balance");
+        self.balances.insert(sender_id.clone(), balance - amount);
+        *self.balances.entry(receiver_id.clone()).or_insert(0) += amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn mint_and_transfer() {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        let mut contract = Contract::new();
+        contract.mint(accounts(1), U128(1_000));
+        contract.ft_transfer(accounts(2), U128(400), None);
+
+        assert_eq!(contract.ft_balance_of(accounts(1)), U128(600));
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(400));
+    }
+}
@@ -0,0 +1,184 @@
+// Minimal stand-in for a Burrow-style lending contract. Tokens are supplied with
+// `ft_transfer_call` and withdrawn with `execute`, and `get_account` reports the
+// supplied balance with the same JSON shape as Burrow, extra decimals included.
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, near, require, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseOrValue,
+};
+use std::collections::HashMap;
+
+const FT_TRANSFER_GAS: Gas = Gas::from_tgas(10);
+
+#[near(serializers = [json])]
+pub struct AssetAmount {
+    pub token_id: AccountId,
+    pub amount: Option<U128>,
+    pub max_amount: Option<U128>,
+}
+
+#[near(serializers = [json])]
+pub enum Action {
+    Withdraw(AssetAmount),
+}
+
+#[near(serializers = [json])]
+pub struct AssetView {
+    pub token_id: AccountId,
+    pub balance: U128,
+    pub shares: U128,
+}
+
+#[near(serializers = [json])]
+pub struct AccountView {
+    pub account_id: AccountId,
+    pub supplied: Vec<AssetView>,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    token_id: AccountId,
+    extra_decimals: u8,
+    // Supplied balances in token decimals, interest included
+    supplied: HashMap<AccountId, u128>,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new(token_id: AccountId, extra_decimals: u8) -> Self {
+        Self {
+            token_id,
+            extra_decimals,
+            supplied: HashMap::new(),
+        }
+    }
+
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) {
+        // Registering is all a deposit does here, `registration_only` makes no difference
+        let _ = registration_only;
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        self.supplied.entry(account_id).or_insert(0);
+    }
+
+    /// Supplies the whole transfer to the sender's account, like Burrow does for an empty `msg`.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::predecessor_account_id() == self.token_id,
+            "Only the supplied token is accepted"
+        );
+        require!(msg.is_empty(), "Only supplies are supported");
+        let balance = self
+            .supplied
+            .get_mut(&sender_id)
+            .unwrap_or_else(|| env::panic_str("Account is not registered"));
+        *balance += amount.0;
+        PromiseOrValue::Value(U128(0))
+    }
+
+    /// Withdraws to the caller. The transfer isn't awaited, as in Burrow.
+    #[payable]
+    pub fn execute(&mut self, actions: Vec<Action>) {
+        require!(
+            env::attached_deposit() == NearToken::from_yoctonear(1),
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let account_id = env::predecessor_account_id();
+        for action in actions {
+            let Action::Withdraw(asset) = action;
+            require!(asset.token_id == self.token_id, "Unknown token");
+            let balance = self.supplied.get(&account_id).copied().unwrap_or(0);
+            let amount = match (asset.amount, asset.max_amount) {
+                (Some(amount), _) => self.from_inner(amount.0),
+                (None, Some(max_amount)) => self.from_inner(max_amount.0).min(balance),
+                (None, None) => balance,
+            };
+            require!(amount <= balance, "Not enough supplied");
+            self.supplied.insert(account_id.clone(), balance - amount);
+
+            Promise::new(self.token_id.clone()).function_call(
+                "ft_transfer".to_string(),
+                json!({ "receiver_id": account_id, "amount": U128(amount) })
+                    .to_string()
+                    .into_bytes(),
+                NearToken::from_yoctonear(1),
+                FT_TRANSFER_GAS,
+            );
+        }
+    }
+
+    /// Adds interest to an account, in token decimals.
+    pub fn accrue_interest(&mut self, account_id: AccountId, amount: U128) {
+        *self.supplied.entry(account_id).or_insert(0) += amount.0;
+    }
+
+    pub fn get_account(&self, account_id: AccountId) -> Option<AccountView> {
+        let balance = *self.supplied.get(&account_id)?;
+        let supplied = if balance > 0 {
+            vec![AssetView {
+                token_id: self.token_id.clone(),
+                balance: U128(self.to_inner(balance)),
+                shares: U128(self.to_inner(balance)),
+            }]
+        } else {
+            Vec::new()
+        };
+        Some(AccountView {
+            account_id,
+            supplied,
+        })
+    }
+}
+
+impl Contract {
+    fn to_inner(&self, amount: u128) -> u128 {
+        amount * 10u128.pow(self.extra_decimals as u32)
+    }
+
+    fn from_inner(&self, amount: u128) -> u128 {
+        amount / 10u128.pow(self.extra_decimals as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn supply_accrue_and_withdraw() {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .build());
+        let mut contract = Contract::new(accounts(1), 12);
+        contract.storage_deposit(Some(accounts(2)), None);
+        contract.ft_on_transfer(accounts(2), U128(1_000), String::new());
+        contract.accrue_interest(accounts(2), U128(50));
+
+        let account = contract.get_account(accounts(2)).unwrap();
+        assert_eq!(account.supplied[0].balance, U128(1_050 * 10u128.pow(12)));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        contract.execute(vec![Action::Withdraw(AssetAmount {
+            token_id: accounts(1),
+            amount: None,
+            max_amount: Some(U128(2_000 * 10u128.pow(12))),
+        })]);
+        assert!(contract.get_account(accounts(2)).unwrap().supplied.is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+// Minimal stand-in for the NEAR priceoracle contract. The JSON returned by
+// `get_price_data` mirrors the real oracle so the token contract can be
+// pointed at it without any changes.
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near, PanicOnDefault};
+
+pub type AssetId = String;
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct AssetOptionalPrice {
+    pub asset_id: AssetId,
+    pub price: Option<Price>,
+}
+
+#[near(serializers = [json])]
+pub struct PriceData {
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    prices: Vec<AssetOptionalPrice>,
+    // `None` reports the current block timestamp, like a freshly updated oracle
+    timestamp: Option<U64>,
+    recency_duration_sec: u32,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new(recency_duration_sec: u32) -> Self {
+        Self {
+            prices: Vec::new(),
+            timestamp: None,
+            recency_duration_sec,
+        }
+    }
+
+    pub fn set_price(&mut self, asset_id: AssetId, multiplier: U128, decimals: u8) {
+        let price = Some(Price {
+            multiplier,
+            decimals,
+        });
+        match self.prices.iter_mut().find(|p| p.asset_id == asset_id) {
+            Some(entry) => entry.price = price,
+            None => self.prices.push(AssetOptionalPrice { asset_id, price }),
+        }
+    }
+
+    // The asset is still listed, but without a price, which is how the real
+    // oracle reports assets it has no recent data for
+    pub fn remove_price(&mut self, asset_id: AssetId) {
+        match self.prices.iter_mut().find(|p| p.asset_id == asset_id) {
+            Some(entry) => entry.price = None,
+            None => self.prices.push(AssetOptionalPrice {
+                asset_id,
+                price: None,
+            }),
+        }
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: Option<U64>) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn set_recency_duration_sec(&mut self, recency_duration_sec: u32) {
+        self.recency_duration_sec = recency_duration_sec;
+    }
+
+    pub fn get_price_data(&self, asset_ids: Option<Vec<AssetId>>) -> PriceData {
+        let prices = match asset_ids {
+            Some(asset_ids) => asset_ids
+                .into_iter()
+                .map(|asset_id| {
+                    let price = self
+                        .prices
+                        .iter()
+                        .find(|p| p.asset_id == asset_id)
+                        .and_then(|p| p.price.clone());
+                    AssetOptionalPrice { asset_id, price }
+                })
+                .collect(),
+            None => self.prices.clone(),
+        };
+
+        PriceData {
+            timestamp: self.timestamp.unwrap_or(U64(env::block_timestamp())),
+            recency_duration_sec: self.recency_duration_sec,
+            prices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_remove_price() {
+        let mut contract = Contract::new(90);
+        contract.set_price("weth.fakes.testnet".to_string(), U128(30_000), 4);
+        contract.remove_price("aurora.fakes.testnet".to_string());
+
+        let data = contract.get_price_data(None);
+        assert_eq!(data.recency_duration_sec, 90);
+        assert_eq!(data.prices.len(), 2);
+        assert_eq!(
+            data.prices[0].price,
+            Some(Price {
+                multiplier: U128(30_000),
+                decimals: 4
+            })
+        );
+        assert_eq!(data.prices[1].price, None);
+
+        contract.remove_price("weth.fakes.testnet".to_string());
+        let data = contract.get_price_data(Some(vec!["weth.fakes.testnet".to_string()]));
+        assert_eq!(data.prices.len(), 1);
+        assert_eq!(data.prices[0].price, None);
+    }
+
+    #[test]
+    fn pinned_timestamp() {
+        let mut contract = Contract::new(90);
+        contract.set_timestamp(Some(U64(42)));
+        assert_eq!(contract.get_price_data(None).timestamp, U64(42));
+    }
+}
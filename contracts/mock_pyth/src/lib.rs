@@ -0,0 +1,94 @@
+// Minimal stand-in for the Pyth NEAR contract. `list_prices` answers with the same
+// JSON as Pyth, so the token contract can use it as a Pyth oracle source unchanged.
+use near_sdk::json_types::{I64, U64};
+use near_sdk::{env, near, PanicOnDefault};
+use std::collections::HashMap;
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Price {
+    pub price: I64,
+    pub conf: U64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+#[near(serializers = [borsh])]
+#[derive(Clone)]
+struct StoredPrice {
+    price: I64,
+    conf: U64,
+    expo: i32,
+    // `None` reports the current block time, like a freshly pushed update
+    publish_time: Option<i64>,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    prices: HashMap<String, StoredPrice>,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            prices: HashMap::new(),
+        }
+    }
+
+    pub fn set_price(
+        &mut self,
+        price_id: String,
+        price: I64,
+        conf: U64,
+        expo: i32,
+        publish_time: Option<i64>,
+    ) {
+        self.prices.insert(
+            price_id,
+            StoredPrice {
+                price,
+                conf,
+                expo,
+                publish_time,
+            },
+        );
+    }
+
+    pub fn remove_price(&mut self, price_id: String) {
+        self.prices.remove(&price_id);
+    }
+
+    pub fn list_prices(&self, price_ids: Vec<String>) -> HashMap<String, Option<Price>> {
+        let now = (env::block_timestamp() / 1_000_000_000) as i64;
+        price_ids
+            .into_iter()
+            .map(|price_id| {
+                let price = self.prices.get(&price_id).map(|p| Price {
+                    price: p.price,
+                    conf: p.conf,
+                    expo: p.expo,
+                    publish_time: p.publish_time.unwrap_or(now),
+                });
+                (price_id, price)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_feeds_have_no_price() {
+        let mut contract = Contract::new();
+        contract.set_price("eth".to_string(), I64(2_500), U64(1), -2, Some(1_000));
+
+        let prices = contract.list_prices(vec!["eth".to_string(), "btc".to_string()]);
+        assert_eq!(prices["eth"].as_ref().unwrap().publish_time, 1_000);
+        assert_eq!(prices["btc"], None);
+    }
+}
@@ -0,0 +1,197 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::env;
+use near_sdk::serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Addresses that are valid hex but where funds can never be recovered from.
+const BURN_ADDRESSES: [[u8; 20]; 2] = [
+    // 0x000000000000000000000000000000000000dEaD
+    [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xde, 0xad,
+    ],
+    // 0xdEAD000000000000000042069420694206942069
+    [
+        0xde, 0xad, 0, 0, 0, 0, 0, 0, 0, 0, 0x42, 0x06, 0x94, 0x20, 0x69, 0x42, 0x06, 0x94,
+        0x20, 0x69,
+    ],
+];
+
+/// A validated EVM address. It is parsed from, and serialized to, its EIP-55
+/// checksummed hex form, so every string key derived from it has the same casing.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash,
+)]
+#[serde(crate = "near_sdk::serde", try_from = "String", into = "String")]
+pub struct EvmAddress([u8; 20]);
+
+impl EvmAddress {
+    pub fn as_bytes(&self) -> [u8; 20] {
+        self.0
+    }
+
+    pub fn to_checksum(&self) -> String {
+        let lower = hex::encode(self.0);
+        let hash = env::keccak256(lower.as_bytes());
+
+        let checksummed: String = lower
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f;
+                if nibble >= 8 {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect();
+        format!("0x{}", checksummed)
+    }
+}
+
+impl FromStr for EvmAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s
+            .strip_prefix("0x")
+            .ok_or_else(|| format!("Address {} must start with 0x", s))?;
+        if digits.len() != 40 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Address {} must be 20 bytes of hex", s));
+        }
+
+        let mut bytes = [0u8; 20];
+        hex::decode_to_slice(digits, &mut bytes)
+            .map_err(|_| format!("Address {} must be 20 bytes of hex", s))?;
+        let address = Self(bytes);
+
+        // All lower or all upper case addresses carry no checksum, anything else must match it
+        let has_lower = digits.chars().any(|c| c.is_ascii_lowercase());
+        let has_upper = digits.chars().any(|c| c.is_ascii_uppercase());
+        if has_lower && has_upper && address.to_checksum() != s {
+            return Err(format!("Invalid EIP-55 checksum for address {}", s));
+        }
+
+        Self::try_from(bytes)
+    }
+}
+
+impl EvmAddress {
+    /// Reads an address stored before addresses were validated. Those were kept as
+    /// given, so the casing is ignored instead of checked, the other rules still apply.
+    pub fn from_legacy(s: &str) -> Result<Self, String> {
+        match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(digits) => format!("0x{}", digits.to_lowercase()).parse(),
+            None => Err(format!("Address {} must start with 0x", s)),
+        }
+    }
+}
+
+impl TryFrom<[u8; 20]> for EvmAddress {
+    type Error = String;
+
+    fn try_from(bytes: [u8; 20]) -> Result<Self, Self::Error> {
+        let address = Self(bytes);
+        if bytes == [0u8; 20] {
+            return Err("The zero address is not allowed".to_string());
+        }
+        if BURN_ADDRESSES.contains(&bytes) {
+            return Err(format!("Burn address {} is not allowed", address));
+        }
+        Ok(address)
+    }
+}
+
+impl TryFrom<String> for EvmAddress {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<EvmAddress> for String {
+    fn from(address: EvmAddress) -> Self {
+        address.to_checksum()
+    }
+}
+
+impl fmt::Display for EvmAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_checksum())
+    }
+}
+
+impl fmt::Debug for EvmAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_checksum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksummed_address() {
+        let address: EvmAddress = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse().unwrap();
+        assert_eq!(
+            address.to_string(),
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+        );
+    }
+
+    #[test]
+    fn test_unchecksummed_address_is_normalized() {
+        let address: EvmAddress = "0xf08a50178dfcde18524640ea6618a1f965821715".parse().unwrap();
+        assert_eq!(
+            address.to_string(),
+            "0xf08A50178dfcDe18524640EA6618a1f965821715"
+        );
+    }
+
+    #[test]
+    fn test_rejected_addresses() {
+        assert_eq!(
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD".parse::<EvmAddress>(),
+            Err("Invalid EIP-55 checksum for address 0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD".to_string())
+        );
+        assert_eq!(
+            "0x1234...".parse::<EvmAddress>(),
+            Err("Address 0x1234... must be 20 bytes of hex".to_string())
+        );
+        assert!("5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+            .parse::<EvmAddress>()
+            .is_err());
+        assert_eq!(
+            "0x0000000000000000000000000000000000000000".parse::<EvmAddress>(),
+            Err("The zero address is not allowed".to_string())
+        );
+        assert!("0x000000000000000000000000000000000000dEaD"
+            .parse::<EvmAddress>()
+            .is_err());
+        assert!("0xdEAD000000000000000042069420694206942069"
+            .parse::<EvmAddress>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_raw_bytes_are_checked() {
+        assert_eq!(
+            EvmAddress::try_from([0u8; 20]),
+            Err("The zero address is not allowed".to_string())
+        );
+        assert!(EvmAddress::try_from(BURN_ADDRESSES[0]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_address_ignores_casing() {
+        let address = EvmAddress::from_legacy("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD").unwrap();
+        assert_eq!(
+            address.to_string(),
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+        );
+        assert!(EvmAddress::from_legacy("0x0000000000000000000000000000000000000000").is_err());
+    }
+}
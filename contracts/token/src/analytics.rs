@@ -0,0 +1,430 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen};
+
+use crate::events::emit_event;
+use crate::price_store::usdc_to_value;
+use crate::redeem::MAX_BPS;
+use crate::{Contract, ContractExt};
+
+/// Fixed point scale of NAV per share and of period returns
+pub const NAV_SCALE: u128 = 1_000_000_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECONDS_PER_YEAR: u128 = 31_536_000;
+/// Period returns kept for `get_fund_performance`
+const MAX_RECENT_SNAPSHOTS: usize = 30;
+
+pub const DEFAULT_SNAPSHOT_INTERVAL_SEC: u64 = 86_400;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NavSnapshot {
+    pub timestamp: u64,
+    /// Value of one share in USDC, scaled by `NAV_SCALE`
+    pub nav_per_share: U128,
+    /// Value of `NAV_SCALE` units of the benchmark asset, when it had a fresh price
+    pub benchmark_level: Option<U128>,
+}
+
+/// Statistics over the fund's NAV snapshots. They are updated one snapshot at a time,
+/// only the last few snapshots are kept.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct FundAnalytics {
+    /// Least time between two snapshots, a price refresh takes one once it has elapsed
+    pub snapshot_interval_sec: u64,
+    /// Annual rate the Sharpe ratio is measured against
+    pub risk_free_rate_bps: u32,
+    /// Asset the fund's return is compared to
+    pub benchmark: Option<String>,
+    pub first: Option<NavSnapshot>,
+    pub recent: Vec<NavSnapshot>,
+    /// First snapshot with a level of the current benchmark, the fund is compared to
+    /// it from there
+    pub benchmark_start: Option<NavSnapshot>,
+    pub peak_nav_per_share: u128,
+    pub max_drawdown_bps: u32,
+    /// Period returns seen so far, with their running mean and sum of squared deviations
+    /// (Welford), scaled by `NAV_SCALE`
+    pub periods: u64,
+    pub mean_return: i128,
+    pub m2: u128,
+}
+
+impl Default for FundAnalytics {
+    fn default() -> Self {
+        Self {
+            snapshot_interval_sec: DEFAULT_SNAPSHOT_INTERVAL_SEC,
+            risk_free_rate_bps: 0,
+            benchmark: None,
+            first: None,
+            recent: Vec::new(),
+            benchmark_start: None,
+            peak_nav_per_share: 0,
+            max_drawdown_bps: 0,
+            periods: 0,
+            mean_return: 0,
+            m2: 0,
+        }
+    }
+}
+
+/// Returns and risk of the fund, all rates in basis points.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundPerformance {
+    pub first_snapshot_at: Option<u64>,
+    pub last_snapshot_at: Option<u64>,
+    /// Scaled by `NAV_SCALE`
+    pub nav_per_share: Option<U128>,
+    /// Return since the first snapshot
+    pub roi_bps: Option<i64>,
+    /// Returns of the last periods, oldest first
+    pub period_returns_bps: Vec<i64>,
+    pub periods: u64,
+    pub annualized_return_bps: Option<i64>,
+    pub annualized_volatility_bps: Option<u64>,
+    pub max_drawdown_bps: u32,
+    pub risk_free_rate_bps: u32,
+    /// Sharpe ratio in ten-thousandths
+    pub sharpe_ratio_bps: Option<i64>,
+    pub benchmark: Option<String>,
+    /// Return of the benchmark since it was first seen with a fresh price
+    pub benchmark_return_bps: Option<i64>,
+    /// Return of the fund over the same snapshots less the benchmark's
+    pub relative_return_bps: Option<i64>,
+}
+
+/// Change from `from` to `to`, scaled by `NAV_SCALE`.
+fn scaled_return(from: u128, to: u128) -> i128 {
+    (to as i128 - from as i128) * NAV_SCALE as i128 / from as i128
+}
+
+fn to_bps(scaled: i128) -> i64 {
+    (scaled * MAX_BPS as i128 / NAV_SCALE as i128) as i64
+}
+
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+impl FundAnalytics {
+    /// Adds a snapshot unless the last one is more recent than the snapshot interval.
+    pub fn record(&mut self, snapshot: NavSnapshot) -> bool {
+        let nav = snapshot.nav_per_share.0;
+        if nav == 0 {
+            return false;
+        }
+        if let Some(last) = self.recent.last() {
+            let interval = self.snapshot_interval_sec.saturating_mul(NANOS_PER_SEC);
+            if snapshot.timestamp < last.timestamp.saturating_add(interval) {
+                return false;
+            }
+            let r = scaled_return(last.nav_per_share.0, nav);
+            self.periods += 1;
+            let delta = r - self.mean_return;
+            self.mean_return += delta / self.periods as i128;
+            self.m2 += (delta * (r - self.mean_return)).max(0) as u128;
+        }
+
+        self.peak_nav_per_share = self.peak_nav_per_share.max(nav);
+        let drawdown = (self.peak_nav_per_share - nav) * MAX_BPS as u128 / self.peak_nav_per_share;
+        self.max_drawdown_bps = self.max_drawdown_bps.max(drawdown as u32);
+        if self.benchmark_start.is_none() && snapshot.benchmark_level.is_some() {
+            self.benchmark_start = Some(snapshot.clone());
+        }
+        if self.first.is_none() {
+            self.first = Some(snapshot.clone());
+        }
+        self.recent.push(snapshot);
+        if self.recent.len() > MAX_RECENT_SNAPSHOTS {
+            self.recent.remove(0);
+        }
+        true
+    }
+
+    /// Compares the fund to `benchmark` from its next snapshot on.
+    pub fn set_benchmark(&mut self, benchmark: Option<String>) {
+        self.benchmark = benchmark;
+        self.benchmark_start = None;
+    }
+
+    /// Periods in a year, from the average time between snapshots.
+    fn periods_per_year(&self) -> Option<u128> {
+        let (first, last) = (self.first.as_ref()?, self.recent.last()?);
+        let elapsed_sec = (last.timestamp - first.timestamp) as u128 / NANOS_PER_SEC as u128;
+        let period_sec = elapsed_sec / (self.periods as u128).max(1);
+        (period_sec > 0).then(|| SECONDS_PER_YEAR / period_sec)
+    }
+
+    pub fn performance(&self) -> FundPerformance {
+        let last = self.recent.last();
+        let roi = self
+            .first
+            .as_ref()
+            .zip(last)
+            .map(|(first, last)| scaled_return(first.nav_per_share.0, last.nav_per_share.0));
+        let periods_per_year = self.periods_per_year().filter(|_| self.periods > 0);
+        let annualized_return = periods_per_year.map(|n| self.mean_return * n as i128);
+        // Sample variance, scaled by NAV_SCALE squared
+        let annualized_volatility = periods_per_year
+            .filter(|_| self.periods > 1)
+            .map(|n| isqrt(self.m2 / (self.periods as u128 - 1) * n));
+        let risk_free = self.risk_free_rate_bps as i128 * NAV_SCALE as i128 / MAX_BPS as i128;
+        let sharpe = annualized_return
+            .zip(annualized_volatility)
+            .filter(|(_, volatility)| *volatility > 0)
+            .map(|(ret, volatility)| (ret - risk_free) * MAX_BPS as i128 / volatility as i128);
+        // NAV and benchmark level where the benchmark started and where it was last seen
+        let levels = |s: &NavSnapshot| Some((s.nav_per_share.0, s.benchmark_level?.0));
+        let benchmark_window = self
+            .benchmark_start
+            .as_ref()
+            .and_then(levels)
+            .zip(self.recent.iter().rev().find_map(levels));
+        let benchmark_return =
+            benchmark_window.map(|((_, from), (_, to))| scaled_return(from, to));
+        let fund_return = benchmark_window.map(|((from, _), (to, _))| scaled_return(from, to));
+
+        FundPerformance {
+            first_snapshot_at: self.first.as_ref().map(|s| s.timestamp),
+            last_snapshot_at: last.map(|s| s.timestamp),
+            nav_per_share: last.map(|s| s.nav_per_share),
+            roi_bps: roi.map(to_bps),
+            period_returns_bps: self
+                .recent
+                .windows(2)
+                .map(|w| to_bps(scaled_return(w[0].nav_per_share.0, w[1].nav_per_share.0)))
+                .collect(),
+            periods: self.periods,
+            annualized_return_bps: annualized_return.map(to_bps),
+            annualized_volatility_bps: annualized_volatility.map(|v| to_bps(v as i128) as u64),
+            max_drawdown_bps: self.max_drawdown_bps,
+            risk_free_rate_bps: self.risk_free_rate_bps,
+            sharpe_ratio_bps: sharpe.map(|s| s as i64),
+            benchmark: self.benchmark.clone(),
+            benchmark_return_bps: benchmark_return.map(to_bps),
+            relative_return_bps: fund_return
+                .zip(benchmark_return)
+                .map(|(fund, benchmark)| to_bps(fund - benchmark)),
+        }
+    }
+}
+
+impl Contract {
+    /// USDC value of the fund, `None` while a held component has no fresh price.
+    /// Components being claimed in kind are left out, like in `get_portfolio_value`.
+    /// USDC of deposits still being swapped counts at face value, its shares are
+    /// already minted.
+    fn fund_value(&self, now: u64) -> Option<u128> {
+        let mut value = usdc_to_value(
+            self.lending.accrued_interest()
+                + self
+                    .swap_venue
+                    .in_flight
+                    .values()
+                    .map(|amount| amount.0)
+                    .sum::<u128>(),
+        );
+        for (asset_address, amount) in self.total_holdings() {
+            if amount == 0 || self.is_claimed_in_kind(&asset_address) {
+                continue;
+            }
+            if !self.price_store.has_fresh_price(&asset_address, now) {
+                return None;
+            }
+            value += self.price_store.value_of(&asset_address, amount, now);
+        }
+        Some(value)
+    }
+
+    /// Takes a NAV snapshot if one is due, called after every price refresh.
+    pub(crate) fn record_nav_snapshot(&mut self) {
+        let now = env::block_timestamp();
+        if self.total_assets.0 == 0 {
+            return;
+        }
+        let Some(value) = self.fund_value(now) else {
+            return;
+        };
+        let benchmark_level = self
+            .analytics
+            .benchmark
+            .as_deref()
+            .filter(|benchmark| self.price_store.has_fresh_price(benchmark, now))
+            .map(|benchmark| U128(self.price_store.value_of(benchmark, NAV_SCALE, now)));
+        let snapshot = NavSnapshot {
+            timestamp: now,
+            nav_per_share: U128(value * NAV_SCALE / self.total_assets.0),
+            benchmark_level,
+        };
+        if self.analytics.record(snapshot.clone()) {
+            emit_event("nav_snapshot", json!(snapshot));
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_fund_performance(&self) -> FundPerformance {
+        self.analytics.performance()
+    }
+
+    pub fn set_snapshot_interval_sec(&mut self, snapshot_interval_sec: u64) {
+        self.assert_owner();
+        assert!(snapshot_interval_sec > 0, "Interval must be positive");
+        self.analytics.snapshot_interval_sec = snapshot_interval_sec;
+    }
+
+    pub fn set_risk_free_rate_bps(&mut self, risk_free_rate_bps: u32) {
+        self.assert_owner();
+        self.analytics.risk_free_rate_bps = risk_free_rate_bps;
+    }
+
+    /// Sets the asset the fund's return is compared to, any asset with a cached price.
+    pub fn set_benchmark(&mut self, benchmark: Option<String>) {
+        self.assert_owner();
+        self.analytics.set_benchmark(benchmark);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssetInfo, PriceFeedInfo};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const DAY: u64 = 86_400 * NANOS_PER_SEC;
+
+    fn snapshot(day: u64, nav: u128, benchmark: Option<u128>) -> NavSnapshot {
+        NavSnapshot {
+            timestamp: day * DAY,
+            nav_per_share: U128(nav * NAV_SCALE / 100),
+            benchmark_level: benchmark.map(|level| U128(level * NAV_SCALE)),
+        }
+    }
+
+    #[test]
+    fn test_returns_and_drawdown() {
+        let mut analytics = FundAnalytics::default();
+        for (day, nav) in [(0, 100), (1, 110), (2, 99), (3, 120)] {
+            assert!(analytics.record(snapshot(day, nav, None)));
+        }
+        // Too early for the next period
+        assert!(!analytics.record(snapshot(3, 200, None)));
+
+        let performance = analytics.performance();
+        assert_eq!(performance.periods, 3);
+        assert_eq!(performance.roi_bps, Some(2_000));
+        assert_eq!(performance.period_returns_bps, vec![1_000, -1_000, 2_121]);
+        assert_eq!(performance.max_drawdown_bps, 1_000);
+        // Daily mean of 7.07% over 365 days
+        assert_eq!(performance.annualized_return_bps, Some(258_080));
+        assert!(performance.annualized_volatility_bps.unwrap() > 0);
+        assert!(performance.sharpe_ratio_bps.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_welford_matches_sample_variance() {
+        let mut analytics = FundAnalytics::default();
+        for (day, nav) in [(0, 100), (1, 110), (2, 121), (3, 133)] {
+            analytics.record(snapshot(day, nav, None));
+        }
+        // Returns of 10%, 10% and ~9.92% barely vary
+        assert!(analytics.performance().annualized_volatility_bps.unwrap() < 100);
+    }
+
+    #[test]
+    fn test_benchmark_relative_return() {
+        let mut analytics = FundAnalytics::default();
+        analytics.set_benchmark(Some("eth".to_string()));
+        analytics.record(snapshot(0, 100, Some(2_000)));
+        analytics.record(snapshot(1, 110, None));
+        analytics.record(snapshot(2, 115, Some(2_200)));
+
+        let performance = analytics.performance();
+        assert_eq!(performance.benchmark_return_bps, Some(1_000));
+        assert_eq!(performance.relative_return_bps, Some(500));
+    }
+
+    #[test]
+    fn test_benchmark_set_later_compares_the_same_window() {
+        let mut analytics = FundAnalytics::default();
+        analytics.record(snapshot(0, 100, None));
+        analytics.set_benchmark(Some("eth".to_string()));
+        analytics.record(snapshot(1, 110, Some(2_000)));
+        analytics.record(snapshot(2, 121, Some(2_200)));
+
+        // The fund made 21% since its first snapshot but 10% since the benchmark's
+        let performance = analytics.performance();
+        assert_eq!(performance.roi_bps, Some(2_100));
+        assert_eq!(performance.benchmark_return_bps, Some(1_000));
+        assert_eq!(performance.relative_return_bps, Some(0));
+    }
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract
+    }
+
+    #[test]
+    fn test_swaps_in_flight_count_in_fund_value() {
+        let mut contract = setup();
+        contract.set_swap_amm(Some(accounts(4)));
+        contract.set_swap_route(ETH.to_string(), 7, None);
+        contract.process_deposit(accounts(1), U128(1_000));
+        // 700 USDC are on their way to the AMM, 300 AURORA are credited
+        assert_eq!(contract.fund_value(0), Some(1_000));
+    }
+
+    #[test]
+    fn test_isqrt() {
+        assert_eq!(isqrt(0), 0);
+        assert_eq!(isqrt(15), 3);
+        assert_eq!(isqrt(16), 4);
+        assert_eq!(isqrt(NAV_SCALE * NAV_SCALE), NAV_SCALE);
+    }
+}
@@ -0,0 +1,647 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Gas, Promise, PromiseResult};
+use omni_transaction::bitcoin::bitcoin_transaction::BitcoinTransaction;
+use omni_transaction::bitcoin::types::{
+    Amount, EcdsaSighashType, Hash, LockTime, OutPoint, ScriptBuf, Sequence, TransactionType,
+    TxIn, TxOut, Txid, Version, Witness,
+};
+use omni_transaction::transaction_builder::{TransactionBuilder, TxBuilder};
+use omni_transaction::types::BITCOIN;
+
+use crate::events::emit_event;
+use crate::kdf::{compress_raw_public_key, compressed_public_key};
+use crate::signer::{mpc, SignRequest, SignResult};
+use crate::{Contract, ContractExt, MPC_CONTRACT_ACCOUNT_ID};
+
+pub const BTC_TREASURY_PATH: &str = "btc-treasury";
+
+const BTC_SIGN_GAS: Gas = Gas::from_tgas(100);
+const BTC_SIGN_CALLBACK_GAS: Gas = Gas::from_tgas(40);
+// Every input needs its own MPC signature, more than two do not fit into one transaction's gas
+const MAX_BTC_INPUTS: usize = 2;
+// Outputs below this are not relayed, smaller change is left to the miner
+const DUST_LIMIT_SATS: u64 = 546;
+const SIGHASH_ALL: u8 = 0x01;
+
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+// Order of the secp256k1 group and half of it, big endian
+const SECP256K1_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+const SECP256K1_HALF_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BitcoinNetwork {
+    Mainnet,
+    Testnet,
+}
+
+impl BitcoinNetwork {
+    fn hrp(&self) -> &'static str {
+        match self {
+            BitcoinNetwork::Mainnet => "bc",
+            BitcoinNetwork::Testnet => "tb",
+        }
+    }
+}
+
+/// An unspent output held by the treasury. `txid` is hex in the usual display order.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BitcoinCustody {
+    pub network: BitcoinNetwork,
+    pub treasury_path: String,
+    /// Compressed secp256k1 key the MPC derives for `treasury_path`
+    pub treasury_public_key: Vec<u8>,
+    pub utxos: Vec<Utxo>,
+    pub signed_txs: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BitcoinTreasury {
+    pub path: String,
+    pub address: String,
+    pub public_key: String,
+}
+
+/// Everything the sign callback needs to rebuild the transaction, or to undo the withdrawal.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BtcWithdrawal {
+    pub account_id: AccountId,
+    pub debited: U128,
+    pub inputs: Vec<Utxo>,
+    /// Script pubkey (hex) and value of every output
+    pub outputs: Vec<(String, U64)>,
+}
+
+impl BitcoinCustody {
+    pub fn treasury_pubkey_hash(&self) -> Vec<u8> {
+        env::ripemd160(&env::sha256(&self.treasury_public_key)).to_vec()
+    }
+
+    pub fn treasury_script_pubkey(&self) -> Vec<u8> {
+        witness_v0_script(&self.treasury_pubkey_hash())
+    }
+
+    pub fn treasury_address(&self) -> String {
+        bech32_encode(self.network.hrp(), 0, &self.treasury_pubkey_hash())
+    }
+
+    /// BIP143 script code for spending a P2WPKH output of the treasury
+    fn treasury_script_code(&self) -> Vec<u8> {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend_from_slice(&self.treasury_pubkey_hash());
+        script.extend_from_slice(&[0x88, 0xac]);
+        script
+    }
+
+    /// Takes the largest outputs first until `target` is covered.
+    fn select_utxos(&mut self, target: u64) -> Vec<Utxo> {
+        self.utxos.sort_by(|a, b| b.value.0.cmp(&a.value.0));
+
+        let mut total = 0u64;
+        let mut count = 0;
+        for utxo in self.utxos.iter().take(MAX_BTC_INPUTS) {
+            if total >= target {
+                break;
+            }
+            total += utxo.value.0;
+            count += 1;
+        }
+        assert!(
+            total >= target,
+            "Treasury UTXOs cannot cover {} sats with at most {} inputs",
+            target,
+            MAX_BTC_INPUTS
+        );
+
+        self.utxos.drain(..count).collect()
+    }
+}
+
+fn witness_v0_script(program: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x00, program.len() as u8];
+    script.extend_from_slice(program);
+    script
+}
+
+fn build_transaction(
+    inputs: &[Utxo],
+    outputs: &[(String, U64)],
+) -> Result<BitcoinTransaction, String> {
+    let inputs = inputs
+        .iter()
+        .map(|utxo| TxIn {
+            previous_output: OutPoint::new(
+                Txid(Hash::from_hex(&utxo.txid).expect("Invalid UTXO txid")),
+                utxo.vout,
+            ),
+            script_sig: ScriptBuf::default(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        })
+        .collect();
+    let outputs = outputs
+        .iter()
+        .map(|(script_pubkey, value)| {
+            let script_pubkey = hex::decode(script_pubkey)
+                .map_err(|_| format!("Invalid output script {}", script_pubkey))?;
+            Ok(TxOut {
+                value: Amount::from_sat(value.0),
+                script_pubkey: ScriptBuf(script_pubkey),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+
+    Ok(TransactionBuilder::new::<BITCOIN>()
+        .version(Version::Two)
+        .inputs(inputs)
+        .outputs(outputs)
+        .lock_time(LockTime::from_height(0).unwrap())
+        .build())
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|b| b & 0x1f));
+    expanded
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+    let max = (1u32 << to) - 1;
+    let max_acc = (1u32 << (from + to - 1)) - 1;
+    let mut converted = Vec::new();
+    for value in data {
+        if (*value as u32) >> from != 0 {
+            return None;
+        }
+        acc = ((acc << from) | *value as u32) & max_acc;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+pub fn bech32_encode(hrp: &str, witness_version: u8, program: &[u8]) -> String {
+    let mut data = vec![witness_version];
+    data.extend(convert_bits(program, 8, 5, true).unwrap());
+
+    let mut values = bech32_hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    values.extend_from_slice(&[0; 6]);
+    let polymod = bech32_polymod(&values) ^ 1;
+    data.extend((0..6).map(|i| ((polymod >> (5 * (5 - i))) & 0x1f) as u8));
+
+    let mut address = format!("{}1", hrp);
+    address.extend(data.iter().map(|d| BECH32_CHARSET[*d as usize] as char));
+    address
+}
+
+/// Script pubkey of a native segwit v0 address (P2WPKH or P2WSH) on `network`.
+pub fn segwit_v0_script_pubkey(address: &str, network: BitcoinNetwork) -> Result<Vec<u8>, String> {
+    let invalid = || format!("Invalid Bitcoin address {}", address);
+    let address = address.to_lowercase();
+    let separator = address.rfind('1').ok_or_else(invalid)?;
+    let (hrp, data) = (&address[..separator], &address[separator + 1..]);
+    if hrp != network.hrp() {
+        return Err(format!("Address {} is not on {:?}", address, network));
+    }
+    if data.len() < 7 {
+        return Err(invalid());
+    }
+
+    let values = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|&x| x == c).map(|p| p as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    let mut checked = bech32_hrp_expand(hrp);
+    checked.extend_from_slice(&values);
+    if bech32_polymod(&checked) != 1 {
+        return Err(format!("Invalid checksum for Bitcoin address {}", address));
+    }
+
+    let (version, program) = (values[0], &values[1..values.len() - 6]);
+    let program = convert_bits(program, 5, 8, false).ok_or_else(invalid)?;
+    if version != 0 || !(program.len() == 20 || program.len() == 32) {
+        return Err(format!("Only native segwit v0 addresses are supported, got {}", address));
+    }
+    Ok(witness_v0_script(&program))
+}
+
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let start = value
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(value.len() - 1);
+    let mut integer = value[start..].to_vec();
+    if integer[0] & 0x80 != 0 {
+        integer.insert(0, 0);
+    }
+    let mut encoded = vec![0x02, integer.len() as u8];
+    encoded.extend(integer);
+    encoded
+}
+
+/// Replaces `s` by `n - s` when it is in the upper half, Bitcoin only relays low-S signatures.
+fn normalize_s(s: [u8; 32]) -> [u8; 32] {
+    if s <= SECP256K1_HALF_ORDER {
+        return s;
+    }
+    let mut normalized = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let mut diff = SECP256K1_ORDER[i] as i16 - s[i] as i16 - borrow;
+        borrow = if diff < 0 { 1 } else { 0 };
+        if diff < 0 {
+            diff += 256;
+        }
+        normalized[i] = diff as u8;
+    }
+    normalized
+}
+
+/// DER encoded signature followed by the sighash type, as it goes into the witness.
+pub fn der_signature(r: [u8; 32], s: [u8; 32]) -> Vec<u8> {
+    let r = der_integer(&r);
+    let s = der_integer(&normalize_s(s));
+    let mut signature = vec![0x30, (r.len() + s.len()) as u8];
+    signature.extend(r);
+    signature.extend(s);
+    signature.push(SIGHASH_ALL);
+    signature
+}
+
+/// BIP143 hash the treasury key signs for input `index`.
+fn segwit_sighash(
+    tx: &BitcoinTransaction,
+    script_code: &ScriptBuf,
+    index: usize,
+    value: u64,
+) -> Vec<u8> {
+    let preimage = tx.build_for_signing_segwit(EcdsaSighashType::All, index, script_code, value);
+    env::sha256(&env::sha256(&preimage))
+}
+
+/// `r` and `s` of an MPC signature over `sighash`, if it was made by `treasury_public_key`.
+fn verified_signature(
+    result: &SignResult,
+    sighash: &[u8],
+    treasury_public_key: &[u8],
+) -> Result<([u8; 32], [u8; 32]), String> {
+    let (r, s, _) = result.parts()?;
+    let signer = compress_raw_public_key(&result.recover_public_key(sighash)?);
+    if signer != treasury_public_key {
+        return Err(format!("signed by {} instead of the treasury", hex::encode(signer)));
+    }
+    Ok((r, s))
+}
+
+impl Contract {
+    /// Sets redeemed BTC aside for `withdraw_btc`. It left the holder's balance with
+    /// the shares, so it no longer counts towards the fund.
+    pub(crate) fn add_btc_claim(&mut self, account_id: &AccountId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let claim = self
+            .btc_claims
+            .entry(account_id.clone())
+            .or_insert(U128(0));
+        claim.0 += amount;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Without `treasury_public_key` the key is derived from the MPC root key,
+    /// see `get_mpc_root_public_key`.
+    pub fn configure_bitcoin(
+        &mut self,
+        network: BitcoinNetwork,
+        treasury_public_key: Option<String>,
+    ) {
+        self.assert_owner();
+        let treasury_public_key = match treasury_public_key {
+            Some(key) => hex::decode(&key).expect("Treasury public key must be hex"),
+            None => compressed_public_key(&self.derive_public_key(BTC_TREASURY_PATH)),
+        };
+        assert!(
+            treasury_public_key.len() == 33 && matches!(treasury_public_key[0], 0x02 | 0x03),
+            "Treasury public key must be a compressed secp256k1 key"
+        );
+
+        let (utxos, signed_txs) = match self.bitcoin.take() {
+            Some(custody) => (custody.utxos, custody.signed_txs),
+            None => (Vec::new(), Vec::new()),
+        };
+        self.bitcoin = Some(BitcoinCustody {
+            network,
+            treasury_path: BTC_TREASURY_PATH.to_string(),
+            treasury_public_key,
+            utxos,
+            signed_txs,
+        });
+    }
+
+    /// Records outputs received by the treasury address, including change of past withdrawals
+    /// once they confirmed.
+    pub fn add_btc_utxos(&mut self, utxos: Vec<Utxo>) {
+        self.assert_owner();
+        let custody = self.bitcoin.as_mut().expect("Bitcoin custody is not configured");
+        for utxo in utxos {
+            assert!(
+                utxo.txid.len() == 64 && hex::decode(&utxo.txid).is_ok(),
+                "Invalid UTXO txid {}",
+                utxo.txid
+            );
+            assert!(
+                !custody
+                    .utxos
+                    .iter()
+                    .any(|u| u.txid == utxo.txid && u.vout == utxo.vout),
+                "UTXO {}:{} is already tracked",
+                utxo.txid,
+                utxo.vout
+            );
+            custody.utxos.push(utxo);
+        }
+    }
+
+    /// Sends `amount` of the caller's redeemed BTC to `destination`, paying `fee` out of
+    /// the claim as well. BTC is claimable once shares were redeemed with `redeem`,
+    /// `claim_withdrawal` or `queue_withdrawal`, which also take care of the lock-up
+    /// penalty and the cooldown.
+    #[payable]
+    pub fn withdraw_btc(&mut self, destination: String, amount: U64, fee: U64) -> Promise {
+        self.assert_not_frozen();
+        let account_id = env::predecessor_account_id();
+        let custody = self
+            .bitcoin
+            .as_mut()
+            .expect("Bitcoin custody is not configured");
+        let destination_script = segwit_v0_script_pubkey(&destination, custody.network)
+            .unwrap_or_else(|err| env::panic_str(&err));
+        assert!(amount.0 >= DUST_LIMIT_SATS, "Withdrawal is below the dust limit");
+
+        let debited = amount.0.checked_add(fee.0).expect("Withdrawal overflow") as u128;
+        let claim = self
+            .btc_claims
+            .get_mut(&account_id)
+            .filter(|claim| claim.0 >= debited)
+            .expect("Not enough redeemed BTC, redeem shares first");
+        claim.0 -= debited;
+        if claim.0 == 0 {
+            self.btc_claims.remove(&account_id);
+        }
+
+        let inputs = custody.select_utxos(amount.0 + fee.0);
+        let total: u64 = inputs.iter().map(|u| u.value.0).sum();
+        let change = total - amount.0 - fee.0;
+
+        let mut outputs = vec![(hex::encode(destination_script), amount)];
+        if change >= DUST_LIMIT_SATS {
+            outputs.push((hex::encode(custody.treasury_script_pubkey()), U64(change)));
+        }
+
+        let tx = build_transaction(&inputs, &outputs).unwrap_or_else(|err| env::panic_str(&err));
+        let script_code = ScriptBuf(custody.treasury_script_code());
+        let sign_requests: Vec<Promise> = inputs
+            .iter()
+            .enumerate()
+            .map(|(index, utxo)| {
+                let sighash = segwit_sighash(&tx, &script_code, index, utxo.value.0);
+
+                mpc::ext(MPC_CONTRACT_ACCOUNT_ID.parse().unwrap())
+                    .with_static_gas(BTC_SIGN_GAS)
+                    .sign(SignRequest {
+                        payload: sighash,
+                        path: custody.treasury_path.clone(),
+                        key_version: 0,
+                    })
+            })
+            .collect();
+
+        sign_requests
+            .into_iter()
+            .reduce(|acc, promise| acc.and(promise))
+            .unwrap()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(BTC_SIGN_CALLBACK_GAS)
+                    .btc_sign_callback(BtcWithdrawal {
+                        account_id,
+                        debited: U128(debited),
+                        inputs,
+                        outputs,
+                    }),
+            )
+    }
+
+    #[private]
+    pub fn btc_sign_callback(&mut self, withdrawal: BtcWithdrawal) -> Option<String> {
+        let custody = self
+            .bitcoin
+            .as_mut()
+            .expect("Bitcoin custody is not configured");
+
+        let script_code = ScriptBuf(custody.treasury_script_code());
+        let signed = build_transaction(&withdrawal.inputs, &withdrawal.outputs).and_then(|tx| {
+            let signatures = withdrawal
+                .inputs
+                .iter()
+                .enumerate()
+                .map(|(index, utxo)| match env::promise_result(index as u64) {
+                    PromiseResult::Successful(data) => {
+                        let result = near_sdk::serde_json::from_slice::<SignResult>(&data)
+                            .map_err(|_| "malformed signing response".to_string())?;
+                        let sighash = segwit_sighash(&tx, &script_code, index, utxo.value.0);
+                        verified_signature(&result, &sighash, &custody.treasury_public_key)
+                    }
+                    PromiseResult::Failed => Err("signing failed".to_string()),
+                })
+                .collect::<Result<Vec<([u8; 32], [u8; 32])>, String>>()?;
+            Ok((tx, signatures))
+        });
+
+        let (mut tx, signatures) = match signed {
+            Ok(signed) => signed,
+            Err(err) => {
+                // Give the inputs and the claim back, nothing usable was signed
+                custody.utxos.extend(withdrawal.inputs);
+                self.add_btc_claim(&withdrawal.account_id, withdrawal.debited.0);
+                emit_event(
+                    "btc_withdrawal_failed",
+                    json!({
+                        "account_id": withdrawal.account_id,
+                        "amount": withdrawal.debited,
+                        "reason": err,
+                    }),
+                );
+                return None;
+            }
+        };
+
+        let mut signed_tx = Vec::new();
+        for (index, (r, s)) in signatures.into_iter().enumerate() {
+            let witness = vec![der_signature(r, s), custody.treasury_public_key.clone()];
+            signed_tx = tx.build_with_witness(index, witness, TransactionType::P2WPKH);
+        }
+
+        custody.signed_txs.push(signed_tx.clone());
+        env::log_str(&format!(
+            "Signed BTC withdrawal for {}, ready to broadcast",
+            withdrawal.account_id
+        ));
+        Some(hex::encode(signed_tx))
+    }
+
+    /// Redeemed BTC `account_id` can withdraw, in satoshis.
+    pub fn get_btc_claim(&self, account_id: AccountId) -> U128 {
+        self.btc_claims.get(&account_id).copied().unwrap_or(U128(0))
+    }
+
+    pub fn get_btc_treasury(&self) -> Option<BitcoinTreasury> {
+        self.bitcoin.as_ref().map(|custody| BitcoinTreasury {
+            path: custody.treasury_path.clone(),
+            address: custody.treasury_address(),
+            public_key: hex::encode(&custody.treasury_public_key),
+        })
+    }
+
+    pub fn get_btc_utxos(&self) -> Vec<Utxo> {
+        self.bitcoin
+            .as_ref()
+            .map(|custody| custody.utxos.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_latest_signed_btc_txs(&self) -> Vec<String> {
+        self.bitcoin
+            .as_ref()
+            .map(|custody| custody.signed_txs.iter().map(hex::encode).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_output_script_is_an_error() {
+        let outputs = vec![("not hex".to_string(), U64(1_000))];
+        assert_eq!(
+            build_transaction(&[], &outputs).err(),
+            Some("Invalid output script not hex".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bech32_round_trip() {
+        let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let script = segwit_v0_script_pubkey(address, BitcoinNetwork::Mainnet).unwrap();
+        assert_eq!(
+            hex::encode(&script),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        assert_eq!(bech32_encode("bc", 0, &script[2..]), address);
+    }
+
+    #[test]
+    fn test_invalid_addresses() {
+        // Wrong network
+        assert!(segwit_v0_script_pubkey(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            BitcoinNetwork::Testnet
+        )
+        .is_err());
+        // Broken checksum
+        assert!(segwit_v0_script_pubkey(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+            BitcoinNetwork::Mainnet
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_der_signature_uses_low_s() {
+        let r = [0x80; 32];
+        let mut high_s = SECP256K1_ORDER;
+        high_s[31] -= 1; // n - 1
+
+        let signature = der_signature(r, high_s);
+        // r gets a zero byte since its top bit is set, s = n - (n - 1) = 1
+        let mut expected = vec![0x30, 0x26, 0x02, 0x21, 0x00];
+        expected.extend_from_slice(&[0x80; 32]);
+        expected.extend_from_slice(&[0x02, 0x01, 0x01, SIGHASH_ALL]);
+        assert_eq!(signature, expected);
+    }
+
+    #[test]
+    fn test_select_utxos() {
+        let utxo = |vout: u32, value: u64| Utxo {
+            txid: "ab".repeat(32),
+            vout,
+            value: U64(value),
+        };
+        let mut custody = BitcoinCustody {
+            network: BitcoinNetwork::Testnet,
+            treasury_path: BTC_TREASURY_PATH.to_string(),
+            treasury_public_key: vec![0x02; 33],
+            utxos: vec![utxo(0, 1_000), utxo(1, 50_000), utxo(2, 20_000)],
+            signed_txs: Vec::new(),
+        };
+
+        let selected = custody.select_utxos(60_000);
+        assert_eq!(selected, vec![utxo(1, 50_000), utxo(2, 20_000)]);
+        assert_eq!(custody.utxos, vec![utxo(0, 1_000)]);
+    }
+}
@@ -0,0 +1,337 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Gas, Promise, PromiseError};
+
+use crate::events::emit_event;
+use crate::models::EVMTransactionWrapper;
+use crate::signer::SignResult;
+use crate::{
+    signed_evm_tx, AssetInfo, Chain, Contract, ContractExt, EvmAddress, NetworkDetails,
+};
+
+const CLAIM_IN_KIND_CALLBACK_GAS: Gas = Gas::from_tgas(10);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum WindDownMode {
+    /// The keeper sells the component into the remaining ones over time
+    Sell,
+    /// Holders keep their balance of it and claim it with `claim_in_kind`, for
+    /// components that are illiquid or delisted
+    InKind,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ComponentRemoval {
+    pub asset_address: String,
+    pub mode: WindDownMode,
+}
+
+/// A component removed from the fund that still has to leave its treasury.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WindDown {
+    pub asset: AssetInfo,
+    pub mode: WindDownMode,
+    pub started_at: u64,
+    /// Left to sell, or to be claimed by holders
+    pub remaining: U128,
+    pub completed_at: Option<u64>,
+}
+
+/// The fund's components after `added` and `removed` are applied to `assets`. Weights
+/// are left as they are, the proposal sets them afterwards.
+pub fn apply_components(
+    assets: &[AssetInfo],
+    added: &[AssetInfo],
+    removed: &[ComponentRemoval],
+) -> Result<Vec<AssetInfo>, String> {
+    let mut next: Vec<AssetInfo> = assets.to_vec();
+    for removal in removed {
+        let index = next
+            .iter()
+            .position(|a| a.key() == removal.asset_address)
+            .ok_or_else(|| format!("asset {} is not registered", removal.asset_address))?;
+        if next[index].chain() == Chain::Bitcoin && removal.mode == WindDownMode::InKind {
+            return Err("Bitcoin can only be sold down".to_string());
+        }
+        next.remove(index);
+    }
+    for asset in added {
+        asset.validate()?;
+        if next.iter().any(|a| a.key() == asset.key()) {
+            return Err(format!("asset {} is already registered", asset.key()));
+        }
+        next.push(asset.clone());
+    }
+    if next.is_empty() {
+        return Err("a fund needs at least one component".to_string());
+    }
+    Ok(next)
+}
+
+impl Contract {
+    /// A component of the fund, or one being wound down.
+    pub(crate) fn asset_info(&self, asset_address: &str) -> Option<AssetInfo> {
+        self.assets
+            .iter()
+            .find(|a| a.key() == asset_address)
+            .or_else(|| {
+                self.wind_downs
+                    .iter()
+                    .find(|w| w.asset.key() == asset_address)
+                    .map(|w| &w.asset)
+            })
+            .cloned()
+    }
+
+    pub(crate) fn is_claimed_in_kind(&self, asset_address: &str) -> bool {
+        self.wind_downs
+            .iter()
+            .any(|w| w.mode == WindDownMode::InKind && w.asset.key() == asset_address)
+    }
+
+    /// Value of `account_id`'s unclaimed balances of components removed in kind, at the
+    /// last price cached for them. Their prices are no longer refreshed once they left
+    /// the fund, so they are valued apart from the position.
+    pub(crate) fn in_kind_value(&self, account_id: &AccountId) -> u128 {
+        self.settled_balances(account_id)
+            .into_iter()
+            .flatten()
+            .filter(|(asset_address, _)| self.is_claimed_in_kind(asset_address))
+            .map(|(asset_address, balance)| self.last_price_value(&asset_address, balance.0))
+            .sum()
+    }
+
+    fn last_price_value(&self, asset_address: &str, amount: u128) -> u128 {
+        self.price_store
+            .prices
+            .get(asset_address)
+            .map_or(0, |price| price.value_of(amount))
+    }
+
+    /// Completes a signed in-kind claim. The shares stay, but the claimed balance left
+    /// the position and is realized at the value it was counted at.
+    pub(crate) fn finish_in_kind_claim(
+        &mut self,
+        account_id: &AccountId,
+        asset_address: &str,
+        amount: u128,
+    ) {
+        self.reduce_wind_down(asset_address, WindDownMode::InKind, amount);
+        let value = self.last_price_value(asset_address, amount);
+        self.record_redemption_flow(account_id, 0, value);
+    }
+
+    /// Starts winding down `asset`. `total` is what the fund's holders held of it.
+    pub(crate) fn start_wind_down(&mut self, asset: AssetInfo, mode: WindDownMode, total: u128) {
+        let now = env::block_timestamp();
+        emit_event(
+            "wind_down_started",
+            json!({
+                "asset_address": asset.key(),
+                "mode": mode,
+                "amount": U128(total),
+            }),
+        );
+        self.wind_downs.retain(|w| w.asset.key() != asset.key());
+        self.wind_downs.push(WindDown {
+            asset,
+            mode,
+            started_at: now,
+            remaining: U128(total),
+            completed_at: (total == 0).then_some(now),
+        });
+    }
+
+    /// Balance of `asset_address` summed over every account, penalties not paid out yet
+    /// included.
+    pub(crate) fn total_balance(&self, asset_address: &str) -> u128 {
+        self.user_balances
+            .values()
+            .filter_map(|balances| balances.get(asset_address))
+            .map(|balance| balance.0)
+            .sum::<u128>()
+            + self.penalty_pool.unpaid.get(asset_address).copied().unwrap_or(0)
+    }
+
+    fn wind_down_mut(&mut self, asset_address: &str, mode: WindDownMode) -> &mut WindDown {
+        self.wind_downs
+            .iter_mut()
+            .find(|w| w.mode == mode && w.asset.key() == asset_address)
+            .unwrap_or_else(|| {
+                env::panic_str(&format!("Asset {} is not being wound down", asset_address))
+            })
+    }
+
+    fn reduce_wind_down(&mut self, asset_address: &str, mode: WindDownMode, amount: u128) {
+        let wind_down = self.wind_down_mut(asset_address, mode);
+        assert!(
+            wind_down.remaining.0 >= amount,
+            "Amount exceeds the remaining {}",
+            wind_down.remaining.0
+        );
+        wind_down.remaining.0 -= amount;
+        if wind_down.remaining.0 == 0 {
+            wind_down.completed_at = Some(env::block_timestamp());
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_wind_downs(&self) -> Vec<WindDown> {
+        self.wind_downs.clone()
+    }
+
+    /// Records that the keeper sold `amount` of a component being sold down.
+    pub fn record_wind_down_sale(&mut self, asset_address: String, amount: U128) {
+        self.assert_owner();
+        self.reduce_wind_down(&asset_address, WindDownMode::Sell, amount.0);
+    }
+
+    /// Withdraws the caller's whole balance of a component removed in kind to `destination`.
+    #[payable]
+    pub fn claim_in_kind(
+        &mut self,
+        asset_address: String,
+        destination: EvmAddress,
+        network_details: NetworkDetails,
+    ) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let asset = self
+            .wind_down_mut(&asset_address, WindDownMode::InKind)
+            .asset
+            .clone();
+        self.settle_penalties(&account_id);
+        let amount = self
+            .user_balances
+            .get_mut(&account_id)
+            .and_then(|balances| balances.remove(&asset_address))
+            .filter(|balance| balance.0 > 0)
+            .expect("Nothing to claim");
+        self.sync_holder(&account_id);
+
+        let (sign_promise, evm_tx, expected_signer) = self.sign_erc20_transfer(
+            asset.contract_address.expect("Asset has no contract address"),
+            destination,
+            amount.0,
+            network_details,
+            &self.custody_path(asset.chain(), &account_id),
+        );
+        sign_promise.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(CLAIM_IN_KIND_CALLBACK_GAS)
+                .claim_in_kind_callback(account_id, asset_address, amount, evm_tx, expected_signer),
+        )
+    }
+
+    #[private]
+    pub fn claim_in_kind_callback(
+        &mut self,
+        account_id: AccountId,
+        asset_address: String,
+        amount: U128,
+        evm_tx: EVMTransactionWrapper,
+        expected_signer: EvmAddress,
+        #[callback_result] result: Result<SignResult, PromiseError>,
+    ) -> Option<Vec<u8>> {
+        match signed_evm_tx(&evm_tx, expected_signer, result) {
+            Ok(signed_tx) => {
+                self.finish_in_kind_claim(&account_id, &asset_address, amount.0);
+                self.latest_signed_txs.push(signed_tx.clone());
+                Some(signed_tx)
+            }
+            Err(reason) => {
+                self.user_balances
+                    .entry(account_id.clone())
+                    .or_default()
+                    .entry(asset_address.clone())
+                    .or_insert(U128(0))
+                    .0 += amount.0;
+                self.sync_holder(&account_id);
+                emit_event(
+                    "in_kind_claim_failed",
+                    json!({
+                        "account_id": account_id,
+                        "asset_address": asset_address,
+                        "amount": amount,
+                        "reason": reason,
+                    }),
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(name: &str, contract_address: Option<&str>, chain: Chain) -> AssetInfo {
+        AssetInfo {
+            name: name.to_string(),
+            contract_address: contract_address.map(|a| a.parse().unwrap()),
+            weight: 5_000,
+            chain: Some(chain),
+        }
+    }
+
+    fn assets() -> Vec<AssetInfo> {
+        vec![
+            asset(
+                "ETH",
+                Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87"),
+                Chain::Ethereum,
+            ),
+            asset("BTC", None, Chain::Bitcoin),
+        ]
+    }
+
+    fn removal(asset_address: &str, mode: WindDownMode) -> ComponentRemoval {
+        ComponentRemoval {
+            asset_address: asset_address.to_string(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn test_apply_components() {
+        let aurora = asset(
+            "AURORA",
+            Some("0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6"),
+            Chain::Aurora,
+        );
+        let next = apply_components(
+            &assets(),
+            &[aurora.clone()],
+            &[removal("BTC", WindDownMode::Sell)],
+        )
+        .unwrap();
+        assert_eq!(next, vec![assets()[0].clone(), aurora]);
+    }
+
+    #[test]
+    fn test_invalid_component_changes() {
+        assert_eq!(
+            apply_components(&assets(), &[], &[removal("BTC", WindDownMode::InKind)]),
+            Err("Bitcoin can only be sold down".to_string())
+        );
+        assert_eq!(
+            apply_components(&assets(), &[assets()[1].clone()], &[]),
+            Err("asset BTC is already registered".to_string())
+        );
+        assert_eq!(
+            apply_components(
+                &assets(),
+                &[asset("AURORA", None, Chain::Aurora)],
+                &[]
+            ),
+            Err("Asset AURORA needs a contract address".to_string())
+        );
+    }
+}
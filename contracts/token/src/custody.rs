@@ -0,0 +1,156 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::events::emit_event;
+use crate::{AssetRebalance, Chain, Contract, ContractExt, TreasuryAddress};
+
+/// MPC path of the address holding `account_id`'s assets on `chain` under segregated custody.
+pub fn user_custody_path(chain: Chain, account_id: &AccountId) -> String {
+    format!("{}/{}", chain.treasury_path(), account_id)
+}
+
+impl Contract {
+    /// Tells the keeper what a deposit buys, with how much USDC, and which custody path to
+    /// deliver it to, the account's own addresses under segregated custody.
+    pub(crate) fn emit_deposit_allocation(
+        &self,
+        account_id: &AccountId,
+        credits: &[(String, u128, u128)],
+    ) {
+        let allocations: Vec<_> = credits
+            .iter()
+            .filter_map(|(asset_address, usdc_amount, amount)| {
+                let asset = self.asset_info(asset_address)?;
+                Some(json!({
+                    "asset_address": asset_address,
+                    "usdc_amount": U128(*usdc_amount),
+                    "amount": U128(*amount),
+                    "custody_path": self.custody_path(asset.chain(), account_id),
+                }))
+            })
+            .collect();
+        emit_event(
+            "deposit_allocated",
+            json!({
+                "account_id": account_id,
+                "allocations": allocations,
+            }),
+        );
+    }
+
+    /// Segregated assets sit at the account's own addresses, the pooled flows that sell
+    /// or net them from the shared treasuries can't be used.
+    pub(crate) fn assert_not_segregated(&self, account_id: &AccountId, action: &str) {
+        assert!(
+            !self.segregated_accounts.contains(account_id),
+            "{} is not available under segregated custody, use redeem",
+            action
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Opts the caller into segregated custody: their underlying assets are held at
+    /// addresses derived from their own account id instead of the shared treasuries.
+    /// Only possible before the first deposit, so no assets are ever left in the pool.
+    /// Bitcoin stays in the pooled treasury, its UTXOs are not tracked per user. What a
+    /// deposit buys is delivered to the paths in its `deposit_allocated` event, and
+    /// withdrawals go through `redeem` only.
+    pub fn enable_segregated_custody(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let has_balance = self
+            .user_balances
+            .get(&account_id)
+            .map_or(false, |balances| balances.values().any(|b| b.0 > 0));
+        assert!(
+            !has_balance && self.get_user_shares(account_id.clone()).0 == 0,
+            "Segregated custody must be enabled before the first deposit"
+        );
+        assert!(
+            self.segregated_accounts.insert(account_id),
+            "Segregated custody is already enabled"
+        );
+    }
+
+    pub fn is_custody_segregated(&self, account_id: AccountId) -> bool {
+        self.segregated_accounts.contains(&account_id)
+    }
+
+    /// Keeps `segregated_shares` in step with `total_assets` when `account_id` mints or
+    /// burns shares.
+    pub(crate) fn track_custody_shares(&mut self, account_id: &AccountId, minted: u128, burned: u128) {
+        if self.segregated_accounts.contains(account_id) {
+            self.segregated_shares = U128(self.segregated_shares.0 + minted - burned);
+        }
+    }
+
+    /// Shares whose assets sit in the shared treasuries.
+    pub(crate) fn pooled_shares(&self) -> u128 {
+        self.total_assets.0 - self.segregated_shares.0
+    }
+
+    /// Tells the keeper how a rebalance changed a segregated holding. The assets sit at
+    /// the account's own addresses, so they are traded there rather than in the
+    /// treasuries the proposal's rebalance covers.
+    pub(crate) fn emit_segregated_rebalance(
+        &self,
+        account_id: &AccountId,
+        proposal_id: u64,
+        rebalance: &[(AssetRebalance, Chain)],
+    ) {
+        let assets: Vec<_> = rebalance
+            .iter()
+            .filter(|(asset, _)| asset.before != asset.after)
+            .map(|(asset, chain)| {
+                json!({
+                    "asset_address": asset.asset_address,
+                    "before": asset.before,
+                    "after": asset.after,
+                    "custody_path": self.custody_path(*chain, account_id),
+                })
+            })
+            .collect();
+        emit_event(
+            "segregated_rebalanced",
+            json!({
+                "proposal_id": proposal_id,
+                "account_id": account_id,
+                "assets": assets,
+            }),
+        );
+    }
+
+    /// Path `account_id`'s assets on `chain` are held and signed for at.
+    pub(crate) fn custody_path(&self, chain: Chain, account_id: &AccountId) -> String {
+        if chain != Chain::Bitcoin && self.segregated_accounts.contains(account_id) {
+            user_custody_path(chain, account_id)
+        } else {
+            chain.treasury_path().to_string()
+        }
+    }
+
+    /// Addresses holding `account_id`'s assets, the shared treasuries unless custody is segregated.
+    pub fn get_custody_addresses(&self, account_id: AccountId) -> Vec<TreasuryAddress> {
+        self.chain_addresses(|chain| self.custody_path(chain, &account_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_custody_path() {
+        let account_id: AccountId = "alice.testnet".parse().unwrap();
+        assert_eq!(
+            user_custody_path(Chain::Ethereum, &account_id),
+            "eth-treasury/alice.testnet"
+        );
+        assert_eq!(
+            user_custody_path(Chain::Aurora, &account_id),
+            "aurora-treasury/alice.testnet"
+        );
+    }
+}
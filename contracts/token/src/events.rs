@@ -0,0 +1,16 @@
+use near_sdk::env;
+use near_sdk::serde_json::{json, Value};
+
+const EVENT_STANDARD: &str = "nexusfi";
+const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// Logs a NEP-297 event, so indexers can pick up failures without parsing log text.
+pub fn emit_event(event: &str, data: Value) {
+    let event = json!({
+        "standard": EVENT_STANDARD,
+        "version": EVENT_STANDARD_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log_str(&format!("EVENT_JSON:{}", event));
+}
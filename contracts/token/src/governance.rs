@@ -0,0 +1,729 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::store::Vector;
+use near_sdk::{env, near_bindgen, AccountId};
+use std::collections::HashMap;
+
+use crate::components::{apply_components, ComponentRemoval, WindDownMode};
+use crate::events::emit_event;
+use crate::redeem::MAX_BPS;
+use crate::{split_by_weights, AssetInfo, Chain, Contract, ContractExt, Prefix, PriceFeedInfo};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+/// Holders rebalanced per call when no limit is given
+const DEFAULT_REBALANCE_LIMIT: u64 = 50;
+
+pub const DEFAULT_TIMELOCK_SEC: u64 = 2 * 24 * 60 * 60;
+/// Shortest timelock allowed, so a proposal can't be pushed through before holders notice
+pub const MIN_TIMELOCK_SEC: u64 = 24 * 60 * 60;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ProposalStatus {
+    /// Waiting for its timelock to pass
+    Pending,
+    Executed,
+    Cancelled,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetWeight {
+    pub asset_address: String,
+    /// In basis points
+    pub weight: u32,
+}
+
+/// How much of an asset the fund held before and after a rebalance. The keeper
+/// trades the difference on the asset's chain.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetRebalance {
+    pub asset_address: String,
+    pub before: U128,
+    pub after: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WeightProposal {
+    pub id: u64,
+    pub proposer: AccountId,
+    pub weights: Vec<AssetWeight>,
+    /// Components joining the fund
+    pub added: Vec<AssetInfo>,
+    /// Components leaving the fund, and how they are wound down
+    pub removed: Vec<ComponentRemoval>,
+    pub proposed_at: u64,
+    pub executable_at: u64,
+    pub status: ProposalStatus,
+    pub executed_at: Option<u64>,
+    /// Filled in when the proposal is executed
+    pub rebalance: Vec<AssetRebalance>,
+}
+
+/// Rebalance of an executed proposal that still has holders to go through.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Rebalancing {
+    pub proposal_id: u64,
+    /// Components whose balances are folded into the others
+    pub sold: Vec<String>,
+    /// Components leaving the fund, their wind-down starts once every holder is done
+    pub removed: Vec<(AssetInfo, WindDownMode)>,
+    /// Prices at execution, so every holder is rebalanced at the same ones
+    pub prices: Vec<PriceFeedInfo>,
+    /// Index in `holders` of the next holder to rebalance
+    pub next_holder: u64,
+    /// Holdings before and after of the pooled holders done so far. Segregated holdings
+    /// are traded at their own addresses, see `emit_segregated_rebalance`.
+    pub rebalance: Vec<AssetRebalance>,
+}
+
+impl Rebalancing {
+    fn price(&self, asset_address: &str) -> &PriceFeedInfo {
+        self.prices
+            .iter()
+            .find(|p| p.asset_address == asset_address)
+            .unwrap_or_else(|| env::panic_str(&format!("No price for asset {}", asset_address)))
+    }
+
+    /// Gives one holder the same value split by `weights` over `keys`, folding in the
+    /// balances of the sold components. Returns the holder's own before and after, in
+    /// the order of `rebalance`.
+    fn rebalance(
+        &self,
+        balances: &mut HashMap<String, U128>,
+        keys: &[String],
+        weights: &[u32],
+    ) -> Vec<AssetRebalance> {
+        let mut holder: Vec<AssetRebalance> = self
+            .rebalance
+            .iter()
+            .map(|r| AssetRebalance {
+                asset_address: r.asset_address.clone(),
+                before: U128(balances.get(&r.asset_address).map_or(0, |b| b.0)),
+                after: U128(0),
+            })
+            .collect();
+        let total_value: u128 = holder
+            .iter()
+            .map(|r| self.price(&r.asset_address).value_of(r.before.0))
+            .sum();
+        for key in &self.sold {
+            balances.remove(key);
+        }
+        for (key, value) in keys.iter().zip(split_by_weights(total_value, weights)) {
+            let amount = if value == 0 { 0 } else { self.price(key).amount_for(value) };
+            holder
+                .iter_mut()
+                .find(|r| &r.asset_address == key)
+                .expect("Every rebalanced asset has an entry")
+                .after = U128(amount);
+            balances.insert(key.clone(), U128(amount));
+        }
+        holder
+    }
+
+    /// Adds a pooled holder's rebalance to the proposal's.
+    fn add(&mut self, holder: &[AssetRebalance]) {
+        for (total, holder) in self.rebalance.iter_mut().zip(holder) {
+            total.before.0 += holder.before.0;
+            total.after.0 += holder.after.0;
+        }
+    }
+
+    /// Chain `asset_address` is held on, for components still in `assets` and removed ones.
+    fn chain(&self, assets: &[AssetInfo], asset_address: &str) -> Chain {
+        assets
+            .iter()
+            .chain(self.removed.iter().map(|(asset, _)| asset))
+            .find(|asset| asset.key() == asset_address)
+            .map(|asset| asset.chain())
+            .expect("Every rebalanced asset is a component")
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Governance {
+    pub timelock_sec: u64,
+    /// Every proposal ever made, its id is its index
+    pub proposals: Vector<WeightProposal>,
+    /// Set from the execution of a proposal until every holder is rebalanced
+    pub rebalancing: Option<Rebalancing>,
+}
+
+impl Default for Governance {
+    fn default() -> Self {
+        Self {
+            timelock_sec: DEFAULT_TIMELOCK_SEC,
+            proposals: Vector::new(Prefix::Proposals),
+            rebalancing: None,
+        }
+    }
+}
+
+impl Governance {
+    /// Only one proposal can be pending at a time, so it is always the newest one.
+    pub fn pending(&self) -> Option<&WeightProposal> {
+        self.proposals
+            .len()
+            .checked_sub(1)
+            .and_then(|last| self.proposals.get(last))
+            .filter(|p| p.status == ProposalStatus::Pending)
+    }
+}
+
+/// Checks that `weights` give every asset of the fund exactly one weight and add up to `MAX_BPS`.
+pub fn validate_weights(assets: &[AssetInfo], weights: &[AssetWeight]) -> Result<(), String> {
+    for (i, weight) in weights.iter().enumerate() {
+        if !assets.iter().any(|a| a.key() == weight.asset_address) {
+            return Err(format!("asset {} is not registered", weight.asset_address));
+        }
+        if weights[..i]
+            .iter()
+            .any(|w| w.asset_address == weight.asset_address)
+        {
+            return Err(format!("asset {} is listed twice", weight.asset_address));
+        }
+    }
+    if let Some(asset) = assets
+        .iter()
+        .find(|a| !weights.iter().any(|w| w.asset_address == a.key()))
+    {
+        return Err(format!("asset {} has no weight", asset.key()));
+    }
+    let total: u32 = weights.iter().map(|w| w.weight).sum();
+    if total != MAX_BPS {
+        return Err(format!("weights add up to {} instead of {}", total, MAX_BPS));
+    }
+    Ok(())
+}
+
+impl Contract {
+    /// Rebalances up to `limit` holders at the prices of the execution. After the last
+    /// one the removed components start winding down and the proposal gets its
+    /// rebalance. Returns how many holders are left.
+    fn rebalance_next(&mut self, limit: u64) -> u64 {
+        let mut rebalancing = self
+            .governance
+            .rebalancing
+            .take()
+            .unwrap_or_else(|| env::panic_str("No rebalance in progress"));
+        let keys: Vec<String> = self.assets.iter().map(|a| a.key()).collect();
+        let weights: Vec<u32> = self.assets.iter().map(|a| a.weight).collect();
+
+        for _ in 0..limit {
+            let account_id = match self.holders.iter().nth(rebalancing.next_holder as usize) {
+                Some(account_id) => account_id.clone(),
+                None => break,
+            };
+            // Holders that stopped holding during the rebalance are dropped here, the
+            // last holder takes their place and comes next
+            if !self.holds_anything(&account_id) {
+                self.holders.remove(&account_id);
+                continue;
+            }
+            rebalancing.next_holder += 1;
+            self.settle_penalties(&account_id);
+            let holder = match self.user_balances.get_mut(&account_id) {
+                Some(balances) => rebalancing.rebalance(balances, &keys, &weights),
+                None => continue,
+            };
+            if self.segregated_accounts.contains(&account_id) {
+                let holder: Vec<(AssetRebalance, Chain)> = holder
+                    .into_iter()
+                    .map(|r| {
+                        let chain = rebalancing.chain(&self.assets, &r.asset_address);
+                        (r, chain)
+                    })
+                    .collect();
+                self.emit_segregated_rebalance(&account_id, rebalancing.proposal_id, &holder);
+            } else {
+                rebalancing.add(&holder);
+            }
+        }
+
+        let remaining = (self.holders.len() as u64).saturating_sub(rebalancing.next_holder);
+        if remaining > 0 {
+            self.governance.rebalancing = Some(rebalancing);
+        } else {
+            self.finish_rebalance(rebalancing);
+        }
+        remaining
+    }
+
+    fn finish_rebalance(&mut self, rebalancing: Rebalancing) {
+        for (asset, mode) in rebalancing.removed {
+            let total = match mode {
+                WindDownMode::Sell => rebalancing
+                    .rebalance
+                    .iter()
+                    .find(|r| r.asset_address == asset.key())
+                    .map_or(0, |r| r.before.0),
+                WindDownMode::InKind => self.total_balance(&asset.key()),
+            };
+            self.start_wind_down(asset, mode, total);
+        }
+
+        let proposal = self.proposal_mut(rebalancing.proposal_id);
+        proposal.rebalance = rebalancing.rebalance;
+        emit_event(
+            "weights_executed",
+            json!({
+                "proposal_id": rebalancing.proposal_id,
+                "rebalance": proposal.rebalance,
+            }),
+        );
+    }
+
+    fn create_proposal(
+        &mut self,
+        weights: Vec<AssetWeight>,
+        added: Vec<AssetInfo>,
+        removed: Vec<ComponentRemoval>,
+    ) -> WeightProposal {
+        self.assert_creator();
+        assert!(
+            self.governance.pending().is_none(),
+            "A weight proposal is already pending"
+        );
+        assert!(
+            self.governance.rebalancing.is_none(),
+            "Holdings are still being rebalanced"
+        );
+        if let Err(err) = apply_components(&self.assets, &added, &removed)
+            .and_then(|next| validate_weights(&next, &weights))
+        {
+            env::panic_str(&format!("Invalid weights: {}", err));
+        }
+
+        let proposed_at = env::block_timestamp();
+        let timelock_sec = self
+            .governance
+            .timelock_sec
+            .max(self.withdrawal_policy.cooldown_sec);
+        let proposal = WeightProposal {
+            id: self.governance.proposals.len().into(),
+            proposer: env::predecessor_account_id(),
+            weights,
+            added,
+            removed,
+            proposed_at,
+            executable_at: proposed_at.saturating_add(timelock_sec.saturating_mul(NANOS_PER_SEC)),
+            status: ProposalStatus::Pending,
+            executed_at: None,
+            rebalance: Vec::new(),
+        };
+        self.governance.proposals.push(proposal.clone());
+        emit_event(
+            "weights_proposed",
+            json!({
+                "proposal_id": proposal.id,
+                "weights": proposal.weights,
+                "added": proposal.added.iter().map(|a| a.key()).collect::<Vec<_>>(),
+                "removed": proposal.removed,
+                "executable_at": proposal.executable_at,
+            }),
+        );
+        proposal
+    }
+
+    fn proposal_mut(&mut self, proposal_id: u64) -> &mut WeightProposal {
+        u32::try_from(proposal_id)
+            .ok()
+            .and_then(|index| self.governance.proposals.get_mut(index))
+            .unwrap_or_else(|| env::panic_str(&format!("Proposal {} not found", proposal_id)))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Proposes new asset weights. They can be executed once the timelock passed, which
+    /// is never shorter than the withdrawal cooldown so holders who disagree can leave.
+    pub fn propose_weights(&mut self, weights: Vec<AssetWeight>) -> WeightProposal {
+        self.create_proposal(weights, Vec::new(), Vec::new())
+    }
+
+    /// Proposes adding and removing components, with the weights of the resulting fund.
+    /// Goes through the same timelock as `propose_weights`.
+    pub fn propose_components(
+        &mut self,
+        added: Vec<AssetInfo>,
+        removed: Vec<ComponentRemoval>,
+        weights: Vec<AssetWeight>,
+    ) -> WeightProposal {
+        self.create_proposal(weights, added, removed)
+    }
+
+    pub fn cancel_weight_proposal(&mut self, proposal_id: u64) {
+        self.assert_creator();
+        let proposal = self.proposal_mut(proposal_id);
+        assert!(
+            proposal.status == ProposalStatus::Pending,
+            "Proposal {} is not pending",
+            proposal_id
+        );
+        proposal.status = ProposalStatus::Cancelled;
+        emit_event("weights_cancelled", json!({ "proposal_id": proposal_id }));
+    }
+
+    /// Applies the components and weights of a proposal whose timelock passed and
+    /// starts rebalancing every holding at the cached prices, which must be fresh.
+    /// Up to `limit` holders are rebalanced right away, `rebalance_holders` does the
+    /// rest. Deposits and withdrawals wait until every holder is done, then removed
+    /// components start winding down. Anyone may execute it.
+    pub fn execute_weight_proposal(&mut self, proposal_id: u64, limit: Option<u64>) -> WeightProposal {
+        self.assert_not_frozen();
+        let proposal = self.proposal_mut(proposal_id).clone();
+        assert!(
+            proposal.status == ProposalStatus::Pending,
+            "Proposal {} is not pending",
+            proposal_id
+        );
+        assert!(
+            env::block_timestamp() >= proposal.executable_at,
+            "Proposal {} can be executed at {}",
+            proposal_id,
+            proposal.executable_at
+        );
+
+        let mut next = apply_components(&self.assets, &proposal.added, &proposal.removed)
+            .unwrap_or_else(|err| env::panic_str(&format!("Invalid weights: {}", err)));
+        for asset in next.iter_mut() {
+            let key = asset.key();
+            if let Some(weight) = proposal.weights.iter().find(|w| w.asset_address == key) {
+                asset.weight = weight.weight;
+            }
+        }
+        let removed: Vec<(AssetInfo, WindDownMode)> = proposal
+            .removed
+            .iter()
+            .filter_map(|removal| {
+                self.assets
+                    .iter()
+                    .find(|a| a.key() == removal.asset_address)
+                    .map(|asset| (asset.clone(), removal.mode))
+            })
+            .collect();
+        let sold: Vec<String> = removed
+            .iter()
+            .filter(|(_, mode)| *mode == WindDownMode::Sell)
+            .map(|(asset, _)| asset.key())
+            .collect();
+
+        self.assets = next;
+        let now = env::block_timestamp();
+        let keys: Vec<String> = self
+            .assets
+            .iter()
+            .map(|a| a.key())
+            .chain(sold.iter().cloned())
+            .collect();
+        self.governance.rebalancing = Some(Rebalancing {
+            proposal_id,
+            sold,
+            removed,
+            prices: keys
+                .iter()
+                .map(|key| self.price_store.fresh_price(key, now).clone())
+                .collect(),
+            next_holder: 0,
+            rebalance: keys
+                .into_iter()
+                .map(|asset_address| AssetRebalance {
+                    asset_address,
+                    before: U128(0),
+                    after: U128(0),
+                })
+                .collect(),
+        });
+
+        let proposal = self.proposal_mut(proposal_id);
+        proposal.status = ProposalStatus::Executed;
+        proposal.executed_at = Some(now);
+        self.rebalance_next(limit.unwrap_or(DEFAULT_REBALANCE_LIMIT));
+        self.proposal_mut(proposal_id).clone()
+    }
+
+    /// Rebalances up to `limit` more holders of the executed proposal and returns how
+    /// many are left. Anyone may call it.
+    pub fn rebalance_holders(&mut self, limit: Option<u64>) -> u64 {
+        self.rebalance_next(limit.unwrap_or(DEFAULT_REBALANCE_LIMIT))
+    }
+
+    /// Holders the executed proposal still has to rebalance, zero when none is in progress.
+    pub fn get_holders_to_rebalance(&self) -> u64 {
+        self.governance
+            .rebalancing
+            .as_ref()
+            .map_or(0, |r| (self.holders.len() as u64).saturating_sub(r.next_holder))
+    }
+
+    /// Timelock of future proposals, the pending one keeps its own.
+    pub fn set_governance_timelock_sec(&mut self, timelock_sec: u64) {
+        self.assert_owner();
+        assert!(
+            timelock_sec >= MIN_TIMELOCK_SEC,
+            "Timelock can't be shorter than {} seconds",
+            MIN_TIMELOCK_SEC
+        );
+        self.governance.timelock_sec = timelock_sec;
+    }
+
+    pub fn get_governance_timelock_sec(&self) -> u64 {
+        self.governance.timelock_sec
+    }
+
+    pub fn get_pending_weight_proposal(&self) -> Option<WeightProposal> {
+        self.governance.pending().cloned()
+    }
+
+    /// Proposals from oldest to newest, pending, executed and cancelled ones alike.
+    pub fn get_weight_proposals(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<WeightProposal> {
+        self.governance
+            .proposals
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PriceFeedInfo;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn assets() -> Vec<AssetInfo> {
+        vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap()),
+                weight: 7_000,
+                chain: Some(Chain::Ethereum),
+            },
+            AssetInfo {
+                name: "BTC".to_string(),
+                contract_address: None,
+                weight: 3_000,
+                chain: Some(Chain::Bitcoin),
+            },
+        ]
+    }
+
+    fn weight(asset_address: &str, weight: u32) -> AssetWeight {
+        AssetWeight {
+            asset_address: asset_address.to_string(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_validate_weights() {
+        let eth = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+        assert_eq!(
+            validate_weights(&assets(), &[weight(eth, 5_000), weight("BTC", 5_000)]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_weights(&assets(), &[weight(eth, 6_000), weight("BTC", 5_000)]),
+            Err("weights add up to 11000 instead of 10000".to_string())
+        );
+        assert_eq!(
+            validate_weights(&assets(), &[weight(eth, 10_000)]),
+            Err("asset BTC has no weight".to_string())
+        );
+        assert_eq!(
+            validate_weights(&assets(), &[weight(eth, 5_000), weight("SOL", 5_000)]),
+            Err("asset SOL is not registered".to_string())
+        );
+        assert_eq!(
+            validate_weights(&assets(), &[weight(eth, 5_000), weight(eth, 5_000)]),
+            Err(format!("asset {} is listed twice", eth))
+        );
+    }
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract.process_deposit(accounts(1), U128(1_000));
+        contract
+    }
+
+    fn execute(contract: &mut Contract, proposal: &WeightProposal) -> WeightProposal {
+        execute_with_limit(contract, proposal, None)
+    }
+
+    /// Moves past the timelock of `proposal` with ETH priced at 2 USD and AURORA at 1.
+    fn execute_with_limit(
+        contract: &mut Contract,
+        proposal: &WeightProposal,
+        limit: Option<u64>,
+    ) -> WeightProposal {
+        let now = proposal.executable_at;
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(now)
+            .build());
+        for (asset_address, price) in [(ETH, 20_000), (AURORA, 10_000)] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(price),
+                    decimals: 22,
+                    last_updated: now,
+                },
+            );
+        }
+        contract.execute_weight_proposal(proposal.id, limit)
+    }
+
+    #[test]
+    fn test_execute_rebalances_holdings() {
+        let mut contract = setup();
+        let proposal = contract.propose_weights(vec![weight(ETH, 5_000), weight(AURORA, 5_000)]);
+        assert_eq!(contract.get_pending_weight_proposal().unwrap().id, proposal.id);
+
+        // 700 ETH at 2 and 300 AURORA at 1 are worth 1700, split 850/850
+        let proposal = execute(&mut contract, &proposal);
+        assert_eq!(proposal.status, ProposalStatus::Executed);
+        assert_eq!(proposal.rebalance[0].before, U128(700 * UNITS));
+        assert_eq!(proposal.rebalance[0].after, U128(425 * UNITS));
+        assert_eq!(proposal.rebalance[1].after, U128(850 * UNITS));
+        assert_eq!(contract.assets[0].weight, 5_000);
+        assert!(contract.get_pending_weight_proposal().is_none());
+    }
+
+    #[test]
+    fn test_removed_component_is_sold_down() {
+        let mut contract = setup();
+        let proposal = contract.propose_components(
+            Vec::new(),
+            vec![ComponentRemoval {
+                asset_address: AURORA.to_string(),
+                mode: WindDownMode::Sell,
+            }],
+            vec![weight(ETH, 10_000)],
+        );
+
+        let proposal = execute(&mut contract, &proposal);
+        assert_eq!(contract.assets.len(), 1);
+        assert_eq!(proposal.rebalance[0].after, U128(850 * UNITS));
+        assert_eq!(proposal.rebalance[1].after, U128(0));
+
+        let balances = contract.user_balances.get(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(850 * UNITS)));
+        assert_eq!(balances.get(AURORA), None);
+        let wind_down = &contract.get_wind_downs()[0];
+        assert_eq!(wind_down.mode, WindDownMode::Sell);
+        assert_eq!(wind_down.remaining, U128(300 * UNITS));
+    }
+
+    #[test]
+    fn test_removed_component_is_kept_in_kind() {
+        let mut contract = setup();
+        let proposal = contract.propose_components(
+            Vec::new(),
+            vec![ComponentRemoval {
+                asset_address: AURORA.to_string(),
+                mode: WindDownMode::InKind,
+            }],
+            vec![weight(ETH, 10_000)],
+        );
+
+        execute(&mut contract, &proposal);
+        let balances = contract.user_balances.get(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(700 * UNITS)));
+        assert_eq!(balances.get(AURORA), Some(&U128(300 * UNITS)));
+        assert_eq!(contract.get_wind_downs()[0].remaining, U128(300 * UNITS));
+        assert!(contract.is_claimed_in_kind(AURORA));
+    }
+
+    #[test]
+    fn test_rebalance_in_batches() {
+        let mut contract = setup();
+        contract.process_deposit(accounts(5), U128(1_000));
+        let proposal = contract.propose_weights(vec![weight(ETH, 5_000), weight(AURORA, 5_000)]);
+
+        let proposal = execute_with_limit(&mut contract, &proposal, Some(1));
+        assert_eq!(proposal.status, ProposalStatus::Executed);
+        assert!(proposal.rebalance.is_empty());
+        assert_eq!(contract.get_holders_to_rebalance(), 1);
+        // accounts(1) goes first, accounts(5) still holds the old split
+        let balances = contract.user_balances.get(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(425 * UNITS)));
+        let balances = contract.user_balances.get(&accounts(5)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(700 * UNITS)));
+
+        assert_eq!(contract.rebalance_holders(None), 0);
+        let proposal = &contract.get_weight_proposals(None, None)[0];
+        assert_eq!(proposal.rebalance[0].before, U128(1_400 * UNITS));
+        assert_eq!(proposal.rebalance[0].after, U128(850 * UNITS));
+        assert!(contract.governance.rebalancing.is_none());
+    }
+
+    #[test]
+    fn test_segregated_holding_is_rebalanced_at_its_own_addresses() {
+        let mut contract = setup();
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(5))
+            .build());
+        contract.enable_segregated_custody();
+        contract.process_deposit(accounts(5), U128(1_000));
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let proposal = contract.propose_weights(vec![weight(ETH, 5_000), weight(AURORA, 5_000)]);
+
+        let proposal = execute(&mut contract, &proposal);
+        // The treasuries only trade the pooled holding of accounts(1)
+        assert_eq!(proposal.rebalance[0].before, U128(700 * UNITS));
+        assert_eq!(proposal.rebalance[0].after, U128(425 * UNITS));
+        let balances = contract.user_balances.get(&accounts(5)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(425 * UNITS)));
+        assert!(near_sdk::test_utils::get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"segregated_rebalanced\"")
+                && log.contains(&format!("/{}", accounts(5)))));
+    }
+}
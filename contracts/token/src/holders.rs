@@ -0,0 +1,184 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+use std::collections::HashMap;
+
+use crate::redeem::AssetAmount;
+use crate::{Contract, ContractExt};
+
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Holder {
+    pub account_id: AccountId,
+    pub shares: U128,
+    pub balances: HashMap<String, U128>,
+}
+
+impl Contract {
+    /// Whether `account_id` holds shares or any asset.
+    pub(crate) fn holds_anything(&self, account_id: &AccountId) -> bool {
+        self.get_user_shares(account_id.clone()).0 > 0
+            || self
+                .user_balances
+                .get(account_id)
+                .map_or(false, |balances| balances.values().any(|balance| balance.0 > 0))
+    }
+
+    /// Keeps `account_id` in `holders` while it holds shares or any asset. Called wherever
+    /// shares or balances of an account change. Removing a holder moves the last one into
+    /// its place, so while a rebalance pages through the set it is left to the rebalance.
+    pub(crate) fn sync_holder(&mut self, account_id: &AccountId) {
+        if self.holds_anything(account_id) {
+            self.holders.insert(account_id.clone());
+        } else if self.governance.rebalancing.is_none() {
+            self.holders.remove(account_id);
+        }
+        if self.get_user_shares(account_id.clone()).0 == 0 {
+            // Nothing accrues without shares, the next deposit is paid from then on
+            self.penalty_pool.paid.remove(account_id);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Holders in the order they first held something. An account that stops holding
+    /// is replaced by the last holder, so pages can shift while the set changes.
+    pub fn get_holders(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<Holder> {
+        self.holders
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .map(|account_id| Holder {
+                account_id: account_id.clone(),
+                shares: self.get_user_shares(account_id.clone()),
+                balances: self.settled_balances(account_id).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    pub fn get_holder_count(&self) -> u64 {
+        self.holders.len() as u64
+    }
+
+    /// What all holders hold of every asset, the fund's components first. Assets taken
+    /// over from USDC redemptions are in `get_pending_sales` instead.
+    pub fn get_total_holdings_by_asset(&self) -> Vec<AssetAmount> {
+        let mut totals: Vec<AssetAmount> = self
+            .total_holdings()
+            .into_iter()
+            .map(|(asset_address, amount)| AssetAmount {
+                asset_address,
+                amount: U128(amount),
+            })
+            .collect();
+        let position = |asset_address: &str| {
+            self.assets
+                .iter()
+                .position(|asset| asset.key() == asset_address)
+                .unwrap_or(self.assets.len())
+        };
+        totals.sort_by(|a, b| {
+            position(&a.asset_address)
+                .cmp(&position(&b.asset_address))
+                .then_with(|| a.asset_address.cmp(&b.asset_address))
+        });
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redeem::{RedeemAmount, MAX_BPS};
+    use crate::{AssetInfo, PriceFeedInfo};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract.process_deposit(accounts(4), U128(1_000));
+        contract.process_deposit(accounts(1), U128(500));
+        contract
+    }
+
+    #[test]
+    fn test_holders_are_paged_in_deposit_order() {
+        let contract = setup();
+        assert_eq!(contract.get_holder_count(), 2);
+
+        let holders = contract.get_holders(None, Some(1));
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].account_id, accounts(4));
+        assert_eq!(holders[0].shares, U128(1_000));
+        assert_eq!(holders[0].balances[ETH], U128(700 * UNITS));
+
+        let holders = contract.get_holders(Some(1), None);
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].account_id, accounts(1));
+    }
+
+    #[test]
+    fn test_redeemed_accounts_are_not_holders() {
+        let mut contract = setup();
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+        contract.debit_redemption(&accounts(1), &preview);
+
+        assert_eq!(contract.get_holder_count(), 1);
+        assert_eq!(contract.get_holders(None, None)[0].account_id, accounts(4));
+    }
+
+    #[test]
+    fn test_total_holdings_by_asset() {
+        let contract = setup();
+        assert_eq!(
+            contract.get_total_holdings_by_asset(),
+            vec![
+                AssetAmount {
+                    asset_address: ETH.to_string(),
+                    amount: U128(1_050 * UNITS),
+                },
+                AssetAmount {
+                    asset_address: AURORA.to_string(),
+                    amount: U128(450 * UNITS),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+// On-chain counterpart of the frontend's `kdf.ts`: derives the keys the MPC
+// signer uses for this contract, so the treasury addresses can be read from
+// the contract instead of being recomputed off-chain.
+use k256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use k256::elliptic_curve::PrimeField;
+use k256::{AffinePoint, EncodedPoint, ProjectivePoint, Scalar};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, CurveType, PublicKey};
+use sha3::{Digest, Sha3_256};
+
+use crate::{Chain, Contract, ContractExt, EvmAddress, MPC_ROOT_PUBLIC_KEY};
+
+const EPSILON_DERIVATION_PREFIX: &str = "near-mpc-recovery v0.1.0 epsilon derivation:";
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DerivedKey {
+    pub path: String,
+    /// Uncompressed secp256k1 point, hex encoded with the `04` prefix
+    pub public_key: String,
+    pub evm_address: EvmAddress,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryAddress {
+    pub chain: Chain,
+    pub path: String,
+    pub address: String,
+}
+
+pub fn derive_epsilon(predecessor_id: &AccountId, path: &str) -> Scalar {
+    let derivation_path = format!("{}{},{}", EPSILON_DERIVATION_PREFIX, predecessor_id, path);
+    let hash: [u8; 32] = Sha3_256::digest(derivation_path.as_bytes()).into();
+    Option::from(Scalar::from_repr(hash.into()))
+        .unwrap_or_else(|| env::panic_str("Derived epsilon is not a valid scalar"))
+}
+
+/// Root key of the MPC signer this contract requests signatures from.
+pub fn default_mpc_root_public_key() -> PublicKey {
+    MPC_ROOT_PUBLIC_KEY.parse().unwrap()
+}
+
+pub fn root_public_key_point(public_key: &PublicKey) -> AffinePoint {
+    assert!(
+        public_key.curve_type() == CurveType::SECP256K1,
+        "MPC root key must be a secp256k1 key"
+    );
+    // Skip the curve type byte, what remains is the untagged uncompressed point
+    let mut uncompressed = vec![0x04];
+    uncompressed.extend_from_slice(&public_key.as_bytes()[1..]);
+
+    EncodedPoint::from_bytes(&uncompressed)
+        .ok()
+        .and_then(|point| Option::from(AffinePoint::from_encoded_point(&point)))
+        .unwrap_or_else(|| env::panic_str("MPC root key is not a valid secp256k1 point"))
+}
+
+pub fn derive_public_key(root: &AffinePoint, predecessor_id: &AccountId, path: &str) -> AffinePoint {
+    let epsilon = derive_epsilon(predecessor_id, path);
+    (ProjectivePoint::GENERATOR * epsilon + ProjectivePoint::from(*root)).to_affine()
+}
+
+pub fn uncompressed_public_key(public_key: &AffinePoint) -> Vec<u8> {
+    public_key.to_encoded_point(false).as_bytes().to_vec()
+}
+
+pub fn compressed_public_key(public_key: &AffinePoint) -> Vec<u8> {
+    public_key.to_encoded_point(true).as_bytes().to_vec()
+}
+
+/// Compressed form of an uncompressed public key given without its `04` tag.
+pub fn compress_raw_public_key(public_key: &[u8; 64]) -> Vec<u8> {
+    let prefix = if public_key[63] & 1 == 0 { 0x02 } else { 0x03 };
+    let mut compressed = vec![prefix];
+    compressed.extend_from_slice(&public_key[..32]);
+    compressed
+}
+
+pub fn evm_address(public_key: &AffinePoint) -> EvmAddress {
+    raw_public_key_to_evm_address(&uncompressed_public_key(public_key)[1..])
+}
+
+/// Address of an uncompressed public key given without its `04` tag, as `env::ecrecover` returns it.
+pub fn raw_public_key_to_evm_address(public_key: &[u8]) -> EvmAddress {
+    let hash = env::keccak256(public_key);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    EvmAddress::try_from(address).unwrap_or_else(|err| env::panic_str(&err))
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Key the MPC signer derives this contract's keys from, `secp256k1:` prefixed base58.
+    /// Funds start with the key of `MPC_CONTRACT_ACCOUNT_ID`, this replaces it if the signer
+    /// rotates its key.
+    pub fn set_mpc_root_public_key(&mut self, public_key: PublicKey) {
+        self.assert_owner();
+        root_public_key_point(&public_key);
+        self.mpc_root_public_key = public_key;
+    }
+
+    pub fn get_mpc_root_public_key(&self) -> PublicKey {
+        self.mpc_root_public_key.clone()
+    }
+
+    pub(crate) fn derive_public_key(&self, path: &str) -> AffinePoint {
+        derive_public_key(
+            &root_public_key_point(&self.mpc_root_public_key),
+            &env::current_account_id(),
+            path,
+        )
+    }
+
+    pub fn derive_treasury_address(&self, path: String) -> DerivedKey {
+        let public_key = self.derive_public_key(&path);
+        DerivedKey {
+            path,
+            public_key: hex::encode(uncompressed_public_key(&public_key)),
+            evm_address: evm_address(&public_key),
+        }
+    }
+
+    /// Every address the fund keeps assets at, one per chain its assets live on.
+    pub fn get_treasury_addresses(&self) -> Vec<TreasuryAddress> {
+        self.chain_addresses(|chain| chain.treasury_path().to_string())
+    }
+
+    /// Address on each of the fund's chains for the MPC path `path_of` gives.
+    pub(crate) fn chain_addresses(&self, path_of: impl Fn(Chain) -> String) -> Vec<TreasuryAddress> {
+        let mut chains: Vec<Chain> = Vec::new();
+        for asset in &self.assets {
+            if !chains.contains(&asset.chain()) {
+                chains.push(asset.chain());
+            }
+        }
+
+        chains
+            .into_iter()
+            .filter_map(|chain| {
+                let path = path_of(chain);
+                let address = match chain {
+                    Chain::Ethereum | Chain::Aurora => {
+                        evm_address(&self.derive_public_key(&path)).to_string()
+                    }
+                    // The address also depends on the network, only known once custody is set up
+                    Chain::Bitcoin => self.bitcoin.as_ref()?.treasury_address(),
+                };
+                Some(TreasuryAddress {
+                    chain,
+                    path,
+                    address,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derive(path: &str) -> AffinePoint {
+        let root = root_public_key_point(&default_mpc_root_public_key());
+        derive_public_key(&root, &"nexusfi.testnet".parse().unwrap(), path)
+    }
+
+    #[test]
+    fn test_derive_evm_treasury() {
+        let public_key = derive("eth-treasury");
+
+        assert_eq!(
+            hex::encode(uncompressed_public_key(&public_key)),
+            "0453f8e6a66c9d87508e4e69d22fc34c635f0aa3d71c36ee172bdae0d22ba6559e\
+             79639759b2674cfee6404abe32332d1bd1926c4a00a89154a05d1381465bbfae"
+        );
+        assert_eq!(
+            evm_address(&public_key).to_string().to_lowercase(),
+            "0xdb1339f3ce9d278860b13b2a4e67b8ce77992ff5"
+        );
+    }
+
+    #[test]
+    fn test_derive_bitcoin_treasury() {
+        assert_eq!(
+            hex::encode(compressed_public_key(&derive("btc-treasury"))),
+            "029bb4ca14afc2029b8c730ba905e4bf1feb45798eb94f08e4e2165d880bcd0ad2"
+        );
+    }
+}
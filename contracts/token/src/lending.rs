@@ -0,0 +1,396 @@
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Gas, NearToken, Promise, PromiseError,
+    PromiseOrValue,
+};
+
+use crate::events::emit_event;
+use crate::redeem::{pro_rata, MAX_BPS};
+use crate::{Contract, ContractExt};
+
+const FT_VIEW_GAS: Gas = Gas::from_tgas(5);
+const FT_TRANSFER_CALL_GAS: Gas = Gas::from_tgas(100);
+const LENDING_EXECUTE_GAS: Gas = Gas::from_tgas(60);
+const LENDING_VIEW_GAS: Gas = Gas::from_tgas(10);
+const LENDING_CALLBACK_GAS: Gas = Gas::from_tgas(10);
+const SUPPLY_CALLBACK_GAS: Gas = Gas::from_tgas(130);
+/// Covers the withdrawal from the protocol and the USDC payout that follows it
+const LIQUIDITY_CALLBACK_GAS: Gas = Gas::from_tgas(130);
+
+/// Where idle USDC is lent out, and what the position is worth.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Lending {
+    /// Burrow-style lending contract, `None` until one is set
+    pub protocol: Option<AccountId>,
+    /// Part of the liquid USDC that may be supplied, the rest stays as a cash buffer
+    pub max_supply_bps: u32,
+    /// Decimals the protocol adds to USDC amounts, Burrow adds 12
+    pub extra_decimals: u8,
+    /// USDC supplied and not withdrawn yet
+    pub supplied: U128,
+    /// Supplied USDC with interest, as last reported by the protocol
+    pub balance: U128,
+    /// Interest withdrawn from the protocol and not paid out to holders yet
+    pub interest_cash: U128,
+    pub updated_at: u64,
+}
+
+impl Lending {
+    /// Interest the holders earned and haven't been paid, withdrawn or not.
+    pub fn accrued_interest(&self) -> u128 {
+        self.balance.0.saturating_sub(self.supplied.0) + self.interest_cash.0
+    }
+
+    /// USDC to supply so the position reaches `max_supply_bps` of the USDC the contract
+    /// holds plus what is already lent out.
+    pub fn supply_amount(&self, idle: u128) -> u128 {
+        let target = pro_rata(
+            idle + self.balance.0,
+            self.max_supply_bps as u128,
+            MAX_BPS as u128,
+        );
+        target.saturating_sub(self.balance.0)
+    }
+
+    pub fn record_supply(&mut self, amount: u128) {
+        self.supplied.0 += amount;
+        self.balance.0 += amount;
+    }
+
+    /// Takes `amount` out of the position, principal first. What goes beyond the
+    /// principal is interest and stays owed to the holders.
+    pub fn record_withdrawal(&mut self, amount: u128) {
+        let principal = amount.min(self.supplied.0);
+        self.supplied.0 -= principal;
+        self.balance.0 = self.balance.0.saturating_sub(amount);
+        self.interest_cash.0 += amount - principal;
+    }
+
+    /// Pays `amount` of interest out of the cash, the rest comes out of the idle USDC
+    /// and is owed back to it by the position.
+    pub fn pay_interest(&mut self, amount: u128) {
+        let from_cash = amount.min(self.interest_cash.0);
+        self.interest_cash.0 -= from_cash;
+        self.supplied.0 += amount - from_cash;
+    }
+
+    /// Undoes `pay_interest` for a payout that failed.
+    pub fn restore_interest(&mut self, amount: u128) {
+        self.interest_cash.0 += amount;
+    }
+
+    fn to_protocol_amount(&self, amount: u128) -> u128 {
+        amount
+            .checked_mul(10u128.pow(self.extra_decimals as u32))
+            .expect("Amount overflow")
+    }
+
+    fn from_protocol_amount(&self, amount: u128) -> u128 {
+        amount / 10u128.pow(self.extra_decimals as u32)
+    }
+}
+
+/// Token amount of a Burrow action, in the protocol's decimals.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LendingAssetAmount {
+    pub token_id: AccountId,
+    pub max_amount: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum LendingAction {
+    Withdraw(LendingAssetAmount),
+}
+
+/// The parts of Burrow's `get_account` view the fund reads.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LendingAccount {
+    pub supplied: Vec<LendingAsset>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LendingAsset {
+    pub token_id: AccountId,
+    pub balance: U128,
+}
+
+#[ext_contract(ext_lending)]
+pub trait LendingProtocol {
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+    fn execute(&mut self, actions: Vec<LendingAction>);
+    fn get_account(&self, account_id: AccountId) -> Option<LendingAccount>;
+}
+
+impl Contract {
+    fn lending_protocol(&self) -> AccountId {
+        self.lending
+            .protocol
+            .clone()
+            .expect("No lending protocol is set")
+    }
+
+    /// Interest owed to `shares` out of `total_shares`.
+    pub(crate) fn interest_share(&self, shares: u128, total_shares: u128) -> u128 {
+        if shares == 0 {
+            return 0;
+        }
+        pro_rata(self.lending.accrued_interest(), shares, total_shares)
+    }
+
+    /// Withdraws `amount` of USDC from the lending protocol back to the contract.
+    fn withdraw_lent_usdc(&self, amount: u128) -> Promise {
+        ext_lending::ext(self.lending_protocol())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(LENDING_EXECUTE_GAS)
+            .execute(vec![LendingAction::Withdraw(LendingAssetAmount {
+                token_id: self.usdc_contract.clone(),
+                max_amount: U128(self.lending.to_protocol_amount(amount)),
+            })])
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(LENDING_CALLBACK_GAS)
+                    .on_lending_withdraw(U128(amount)),
+            )
+    }
+
+    /// Pays out a redemption, first withdrawing from the lending protocol whatever the
+    /// contract's USDC falls short of.
+    pub(crate) fn pay_out_with_liquidity(
+        &self,
+        account_id: AccountId,
+        shares: u128,
+        amount_out: u128,
+    ) -> Promise {
+        if self.lending.protocol.is_none() || self.lending.balance.0 == 0 {
+            return self.send_usdc_payout(account_id, shares, amount_out);
+        }
+        ext_ft_core::ext(self.usdc_contract.clone())
+            .with_static_gas(FT_VIEW_GAS)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(LIQUIDITY_CALLBACK_GAS)
+                    .ensure_usdc_liquidity(account_id, U128(shares), U128(amount_out)),
+            )
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_lending(&self) -> Lending {
+        self.lending.clone()
+    }
+
+    pub fn get_accrued_interest(&self) -> U128 {
+        U128(self.lending.accrued_interest())
+    }
+
+    /// Points idle USDC at a lending protocol and registers the contract with it. The
+    /// attached deposit pays for the registration.
+    #[payable]
+    pub fn set_lending_protocol(&mut self, protocol: AccountId, extra_decimals: u8) -> Promise {
+        self.assert_owner();
+        assert!(
+            self.lending.balance.0 == 0,
+            "Withdraw the current lending position first"
+        );
+        self.lending.protocol = Some(protocol.clone());
+        self.lending.extra_decimals = extra_decimals;
+        ext_lending::ext(protocol)
+            .with_attached_deposit(env::attached_deposit())
+            .storage_deposit(None, None)
+    }
+
+    /// Sets how much of the liquid USDC may be lent out, 0 stops new supplies.
+    pub fn set_max_supply_bps(&mut self, max_supply_bps: u32) {
+        self.assert_owner();
+        assert!(
+            max_supply_bps <= MAX_BPS,
+            "Supply share can't exceed {} basis points",
+            MAX_BPS
+        );
+        self.lending.max_supply_bps = max_supply_bps;
+    }
+
+    /// Supplies idle USDC to the lending protocol, up to `max_supply_bps`.
+    pub fn supply_idle_usdc(&mut self) -> Promise {
+        self.assert_owner();
+        assert!(self.lending.protocol.is_some(), "No lending protocol is set");
+        ext_ft_core::ext(self.usdc_contract.clone())
+            .with_static_gas(FT_VIEW_GAS)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(SUPPLY_CALLBACK_GAS)
+                    .supply_idle_usdc_callback(),
+            )
+    }
+
+    #[private]
+    pub fn supply_idle_usdc_callback(
+        &mut self,
+        #[callback_result] idle: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        let idle = idle.map_or(0, |idle| idle.0);
+        let amount = self.lending.supply_amount(idle).min(idle);
+        if amount == 0 {
+            env::log_str("No idle USDC to supply");
+            return PromiseOrValue::Value(U128(0));
+        }
+        PromiseOrValue::Promise(
+            ext_ft_core::ext(self.usdc_contract.clone())
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .with_static_gas(FT_TRANSFER_CALL_GAS)
+                .ft_transfer_call(self.lending_protocol(), U128(amount), None, String::new())
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(LENDING_CALLBACK_GAS)
+                        .on_lending_supply(U128(amount)),
+                ),
+        )
+    }
+
+    /// Records what the protocol kept of a supply, `ft_transfer_call` refunds the rest.
+    #[private]
+    pub fn on_lending_supply(
+        &mut self,
+        amount: U128,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) -> U128 {
+        let used = used.map_or(0, |used| used.0.min(amount.0));
+        if used == 0 {
+            emit_event("lending_supply_failed", json!({ "amount": amount }));
+            return U128(0);
+        }
+        self.lending.record_supply(used);
+        emit_event("lending_supplied", json!({ "amount": U128(used) }));
+        U128(used)
+    }
+
+    /// Withdraws `amount` from the lending protocol, all of the position when `None`.
+    pub fn withdraw_from_lending(&mut self, amount: Option<U128>) -> Promise {
+        self.assert_owner();
+        let amount = amount.map_or(self.lending.balance.0, |amount| amount.0);
+        assert!(amount > 0, "Nothing to withdraw");
+        self.withdraw_lent_usdc(amount)
+    }
+
+    #[private]
+    pub fn on_lending_withdraw(
+        &mut self,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_err() {
+            emit_event("lending_withdraw_failed", json!({ "amount": amount }));
+            return false;
+        }
+        self.lending.record_withdrawal(amount.0);
+        emit_event("lending_withdrawn", json!({ "amount": amount }));
+        true
+    }
+
+    /// Withdraws what the contract's USDC falls short of `amount_out` before paying
+    /// the redemption out.
+    #[private]
+    pub fn ensure_usdc_liquidity(
+        &mut self,
+        account_id: AccountId,
+        shares: U128,
+        amount_out: U128,
+        #[callback_result] idle: Result<U128, PromiseError>,
+    ) -> Promise {
+        // Without a balance, withdraw as if the contract held nothing
+        let idle = idle.map_or(0, |idle| idle.0);
+        let shortfall = amount_out.0.saturating_sub(idle).min(self.lending.balance.0);
+        let payout = self.send_usdc_payout(account_id, shares.0, amount_out.0);
+        if shortfall == 0 {
+            return payout;
+        }
+        self.withdraw_lent_usdc(shortfall).then(payout)
+    }
+
+    /// Reads the position from the lending protocol, so accrued interest shows in NAV.
+    pub fn refresh_lending_position(&mut self) -> Promise {
+        ext_lending::ext(self.lending_protocol())
+            .with_static_gas(LENDING_VIEW_GAS)
+            .get_account(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(LENDING_CALLBACK_GAS)
+                    .on_lending_position(),
+            )
+    }
+
+    #[private]
+    pub fn on_lending_position(
+        &mut self,
+        #[callback_result] account: Result<Option<LendingAccount>, PromiseError>,
+    ) -> U128 {
+        let Ok(account) = account else {
+            emit_event("lending_refresh_failed", json!({}));
+            return self.lending.balance;
+        };
+        let balance = account
+            .and_then(|account| {
+                account
+                    .supplied
+                    .into_iter()
+                    .find(|asset| asset.token_id == self.usdc_contract)
+            })
+            .map_or(0, |asset| self.lending.from_protocol_amount(asset.balance.0));
+        self.lending.balance = U128(balance);
+        self.lending.updated_at = env::block_timestamp();
+        self.lending.balance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lending(supplied: u128, balance: u128) -> Lending {
+        Lending {
+            max_supply_bps: 8_000,
+            supplied: U128(supplied),
+            balance: U128(balance),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_supply_amount_keeps_a_buffer() {
+        assert_eq!(lending(0, 0).supply_amount(1_000), 800);
+        // 600 of the 1000 liquid USDC are lent out already
+        assert_eq!(lending(600, 600).supply_amount(400), 200);
+        assert_eq!(lending(900, 900).supply_amount(100), 0);
+    }
+
+    #[test]
+    fn test_interest_survives_withdrawal_and_payout() {
+        let mut lending = lending(1_000, 1_050);
+        assert_eq!(lending.accrued_interest(), 50);
+
+        lending.record_withdrawal(1_030);
+        assert_eq!(lending.supplied, U128(0));
+        assert_eq!(lending.interest_cash, U128(30));
+        assert_eq!(lending.accrued_interest(), 50);
+
+        lending.pay_interest(40);
+        assert_eq!(lending.interest_cash, U128(0));
+        assert_eq!(lending.supplied, U128(10));
+        assert_eq!(lending.accrued_interest(), 10);
+
+        lending.restore_interest(40);
+        assert_eq!(lending.accrued_interest(), 50);
+    }
+}
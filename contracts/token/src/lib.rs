@@ -3,62 +3,233 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseError,
-    PromiseOrValue,
+    env, near_bindgen, AccountId, Gas, IntoStorageKey, PanicOnDefault, Promise, PromiseError,
+    PromiseOrValue, PromiseResult, PublicKey,
 };
+use near_sdk::serde_json::json;
+use near_sdk::store::IterableSet;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use crate::signer::mpc;
 
+mod address;
+mod analytics;
+mod bitcoin;
+mod components;
+mod custody;
+mod events;
+mod governance;
+mod holders;
+mod kdf;
+mod lending;
+mod limits;
+mod lockup;
+mod metadata;
+mod migrate;
 mod models;
+mod oracle;
+mod performance;
+mod price_store;
+mod pyth;
+mod queue;
+mod redeem;
 mod signer;
-
+mod swap;
+mod upgrade;
+
+pub use address::EvmAddress;
+pub use analytics::{FundAnalytics, FundPerformance, NavSnapshot};
+pub use bitcoin::{BitcoinCustody, BitcoinNetwork, Utxo};
+use bitcoin::BTC_TREASURY_PATH;
+pub use components::{ComponentRemoval, WindDown, WindDownMode};
+use kdf::{evm_address, raw_public_key_to_evm_address};
+use events::emit_event;
+pub use governance::{AssetRebalance, AssetWeight, Governance, ProposalStatus, WeightProposal};
+pub use holders::Holder;
+pub use kdf::{DerivedKey, TreasuryAddress};
+pub use lending::Lending;
+pub use limits::DepositLimits;
+pub use lockup::{DepositLot, DepositLotView, PenaltyPool, PendingWithdrawal, WithdrawalPolicy};
+pub use metadata::{FundMetadata, FundMetadataArgs, FundMetadataUpdate};
 use models::EVMTransactionWrapper;
+pub use oracle::{OracleConfig, OracleKind, OracleSource};
+pub use performance::{AccountFlows, AccountPerformance};
+pub use price_store::{CircuitBreakerTrip, PriceStore};
+use price_store::usdc_to_value;
+pub use pyth::{PythConfig, PythFeed};
+pub use queue::{EpochStatus, WithdrawalBatch, WithdrawalEpoch, WithdrawalQueue};
+pub use redeem::{AssetAmount, RedeemAmount, RedeemPreview, UsdcRedemption};
+use redeem::MAX_BPS;
 use omni_transaction::evm::evm_transaction::EVMTransaction;
 use omni_transaction::evm::types::Signature as OmniSignature;
-use omni_transaction::evm::utils::parse_eth_address;
 use omni_transaction::transaction_builder::{TransactionBuilder, TxBuilder};
 use omni_transaction::types::EVM;
 use signer::{ SignResult, SignRequest };
+pub use swap::{QueuedSwap, SaleLeg, SwapRoute, SwapVenue};
+use swap::FT_TRANSFER_GAS;
 
 // Constants
 const MPC_CONTRACT_ACCOUNT_ID: &str = "v1.signer-prod.testnet";
+/// Root key of `MPC_CONTRACT_ACCOUNT_ID`, the one the frontend's `kdf.ts` derives from
+const MPC_ROOT_PUBLIC_KEY: &str = "secp256k1:4NfTiv3UsGahebgTaHyD9vF8KYKMBnfd6kh94mK6xv8fGBiJB8TBtFMP5WWXz6B89Ac1fbpzPwAvoyQebemHFwx3";
+const MPC_SIGN_GAS: Gas = Gas::from_tgas(100);
+/// Taken by `withdrawal_callback` for every transfer
+const WITHDRAWAL_CALLBACK_GAS: Gas = Gas::from_tgas(10);
+/// One transfer of a withdrawal, signed and handled in the callback
+const SIGN_LEG_GAS: Gas =
+    Gas::from_gas(MPC_SIGN_GAS.as_gas() + WITHDRAWAL_CALLBACK_GAS.as_gas());
+/// One transfer of a withdrawal held on NEAR, sent and handled in the callback
+const NEAR_LEG_GAS: Gas =
+    Gas::from_gas(FT_TRANSFER_GAS.as_gas() + WITHDRAWAL_CALLBACK_GAS.as_gas());
+/// Left to the call that asks for the signatures
+const SIGN_GAS_RESERVE: Gas = Gas::from_tgas(20);
 const ETH_TREASURY_PATH: &str = "eth-treasury";
 const AURORA_TREASURY_PATH: &str = "aurora-treasury";
 
-pub static TOKEN_ADDRESSES: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
+pub static TOKEN_ADDRESSES: Lazy<HashMap<EvmAddress, &str>> = Lazy::new(|| {
     let mut m = HashMap::new();
     m.insert(
-        "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6",
+        "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".parse().unwrap(),
         "aurora.fakes.testnet",
     );
     m.insert(
-        "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87",
+        "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap(),
         "weth.fakes.testnet",
     );
     m.insert(
-        "0xf08a50178dfcde18524640ea6618a1f965821715",
+        "0xf08a50178dfcde18524640ea6618a1f965821715".parse().unwrap(),
         "usdc.fakes.testnet",
     );
     m
 });
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+/// Prefixes of the collections stored apart from the contract's state. Each keeps the
+/// bytes it was first deployed with, so collections already stored stay readable.
+#[derive(Clone, Copy)]
+pub(crate) enum Prefix {
+    Proposals,
+    Holders,
+    Epochs,
+}
+
+impl IntoStorageKey for Prefix {
+    fn into_storage_key(self) -> Vec<u8> {
+        match self {
+            Prefix::Proposals => b"p".to_vec(),
+            Prefix::Holders => b"h".to_vec(),
+            Prefix::Epochs => b"e".to_vec(),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Chain {
+    Ethereum,
+    Aurora,
+    Bitcoin,
+}
+
+impl Chain {
+    /// MPC derivation path of the treasury holding the fund's assets on this chain
+    pub fn treasury_path(&self) -> &'static str {
+        match self {
+            Chain::Ethereum => ETH_TREASURY_PATH,
+            Chain::Aurora => AURORA_TREASURY_PATH,
+            Chain::Bitcoin => BTC_TREASURY_PATH,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct AssetInfo {
     pub name: String,
-    pub contract_address: String,
-    pub weight: u8,
+    /// ERC20 contract of the asset, `None` for native Bitcoin
+    pub contract_address: Option<EvmAddress>,
+    /// Share of every deposit in basis points, the weights of a fund add up to `MAX_BPS`
+    pub weight: u32,
+    pub chain: Option<Chain>,
+}
+
+impl AssetInfo {
+    /// Key of the asset in balances and price feeds
+    pub fn key(&self) -> String {
+        match self.contract_address {
+            Some(address) => address.to_string(),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Checks the contract address fits the chain.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.chain(), self.contract_address) {
+            (Chain::Bitcoin, Some(_)) => Err(format!(
+                "Bitcoin asset {} has no contract address",
+                self.name
+            )),
+            (Chain::Ethereum | Chain::Aurora, None) => {
+                Err(format!("Asset {} needs a contract address", self.name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn chain(&self) -> Chain {
+        // Funds created before `chain` existed route ETH to Ethereum and everything else to Aurora
+        self.chain.unwrap_or(if self.name == "ETH" {
+            Chain::Ethereum
+        } else {
+            Chain::Aurora
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct WithdrawRequest {
-    pub eth_destination: String,
-    pub aurora_destination: String,
+    pub eth_destination: EvmAddress,
+    pub aurora_destination: EvmAddress,
     pub network_details: NetworkDetails,
 }
 
+/// An in-kind redemption whose transfers are signed over as many calls as they need.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalSigning {
+    pub preview: RedeemPreview,
+    pub eth_destination: EvmAddress,
+    pub aurora_destination: EvmAddress,
+    /// Transfers waiting for `continue_withdrawal`
+    pub to_sign: Vec<AssetAmount>,
+    /// Signatures on their way back from the MPC signer
+    pub signing: u32,
+    /// Transfers that couldn't be signed, given back once the rest have been tried
+    pub failed: Vec<AssetAmount>,
+}
+
+/// How one transfer of a redemption leaves the fund.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum LegTransfer {
+    /// Signed out of a treasury, to be broadcast on its chain
+    Evm {
+        evm_tx: EVMTransactionWrapper,
+        expected_signer: EvmAddress,
+    },
+    /// Sent to the redeemer's NEAR account with `ft_transfer`
+    Near,
+}
+
+/// One transfer of a redemption, handed to `withdrawal_callback`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalLeg {
+    pub asset: AssetAmount,
+    pub transfer: LegTransfer,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct NetworkDetails {
@@ -69,7 +240,7 @@ pub struct NetworkDetails {
     pub gas_limit: u128,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct PriceFeedInfo {
     pub asset_address: String,
@@ -100,16 +271,121 @@ pub struct PriceData {
     pub decimals: u32,
 }
 
-#[near_bindgen]
+/// Key of `asset` in balances and price feeds, an address in any case or an asset name.
+pub(crate) fn asset_key(asset: &str) -> String {
+    match asset.parse::<EvmAddress>() {
+        Ok(address) => address.to_string(),
+        Err(_) => asset.to_string(),
+    }
+}
+
+/// Signed form of the transaction in `evm_tx_wrapper`, if the MPC signature is well formed
+/// and recovers to `expected_signer`.
+pub(crate) fn signed_evm_tx(
+    evm_tx_wrapper: &EVMTransactionWrapper,
+    expected_signer: EvmAddress,
+    result: Result<SignResult, PromiseError>,
+) -> Result<Vec<u8>, String> {
+    let mpc_signature = result.map_err(|_| "signing failed".to_string())?;
+    let (r, s, v) = mpc_signature.parts()?;
+
+    let evm_tx = evm_tx_wrapper.to_evm_transaction();
+    let tx_hash = env::keccak256(&evm_tx.build_for_signing());
+    let signer = raw_public_key_to_evm_address(&mpc_signature.recover_public_key(&tx_hash)?);
+    if signer != expected_signer {
+        return Err(format!(
+            "signed by {} instead of treasury {}",
+            signer, expected_signer
+        ));
+    }
+
+    let signature_omni = OmniSignature {
+        v: v as u64,
+        r: r.to_vec(),
+        s: s.to_vec(),
+    };
+    Ok(evm_tx.build_with_signature(&signature_omni))
+}
+
+/// Splits `amount` over `weights` in basis points. Every part is rounded down and the
+/// dust left over goes to the largest weight, the first one on a tie, so the parts add
+/// up to `amount` and the same inputs always give the same split.
+pub fn split_by_weights(amount: u128, weights: &[u32]) -> Vec<u128> {
+    let mut parts: Vec<u128> = weights
+        .iter()
+        .map(|weight| {
+            amount
+                .checked_mul(*weight as u128)
+                .unwrap_or_else(|| env::panic_str("Amount overflow"))
+                / MAX_BPS as u128
+        })
+        .collect();
+    let dust = amount.saturating_sub(parts.iter().sum());
+    if let Some(largest) = (0..weights.len()).max_by_key(|&i| (weights[i], Reverse(i))) {
+        parts[largest] += dust;
+    }
+    parts
+}
+
+/// NEP-330 metadata reports the crate version, the layout of the state is `migrate::STATE_VERSION`
+#[near_bindgen(contract_metadata(standard(standard = "nep330", version = "1.1.0")))]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
     pub total_assets: U128,
     pub assets: Vec<AssetInfo>,
     pub owner_id: AccountId,
     pub user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    /// Balances carried over from before version 4, which held the USDC every deposit put
+    /// into a component rather than units of it. `convert_usdc_balances` moves them into
+    /// `user_balances` at fresh prices, deposits and withdrawals wait until it is done.
+    pub usdc_balances: HashMap<AccountId, HashMap<String, U128>>,
     pub usdc_contract: AccountId,
-    pub oracle_contract: AccountId,
+    pub oracle_config: OracleConfig,
+    pub price_store: PriceStore,
     pub latest_signed_txs: Vec<Vec<u8>>,
+    pub bitcoin: Option<BitcoinCustody>,
+    /// Redeemed BTC waiting for `withdraw_btc`, see `bitcoin.rs`
+    pub btc_claims: HashMap<AccountId, U128>,
+    pub mpc_root_public_key: PublicKey,
+    /// Accounts whose assets are held at their own derived addresses, see `custody.rs`
+    pub segregated_accounts: HashSet<AccountId>,
+    /// Part of `total_assets` held by `segregated_accounts`
+    pub segregated_shares: U128,
+    /// Shares of every depositor, minted 1:1 with deposited USDC. Their sum is `total_assets`.
+    pub user_shares: HashMap<AccountId, U128>,
+    /// Underlying taken over from USDC redemptions before they sold it, waiting for the keeper
+    pub pending_sales: HashMap<String, U128>,
+    /// USDC redemptions selling their legs on the AMM, see `redeem.rs`
+    pub usdc_redemptions: HashMap<AccountId, UsdcRedemption>,
+    /// USDC of redemptions whose payout failed, waiting for `claim_usdc`
+    pub usdc_claims: HashMap<AccountId, U128>,
+    pub withdrawal_policy: WithdrawalPolicy,
+    /// Deposits backing each holder's shares, oldest first, for the lock-up
+    pub deposit_lots: HashMap<AccountId, Vec<DepositLot>>,
+    /// Early exit penalties owed to the remaining holders, see `lockup.rs`
+    pub penalty_pool: PenaltyPool,
+    pub pending_withdrawals: HashMap<AccountId, PendingWithdrawal>,
+    /// In-kind withdrawals with transfers still to sign
+    pub withdrawal_signings: HashMap<AccountId, WithdrawalSigning>,
+    pub withdrawal_queue: WithdrawalQueue,
+    pub deposit_limits: DepositLimits,
+    pub allowlist: HashSet<AccountId>,
+    pub metadata: FundMetadata,
+    pub governance: Governance,
+    /// Components removed from the fund that are still leaving it, see `components.rs`
+    pub wind_downs: Vec<WindDown>,
+    /// Idle USDC lent out for interest, see `lending.rs`
+    pub lending: Lending,
+    /// Where deposits buy NEP-141 components on NEAR, see `swap.rs`
+    pub swap_venue: SwapVenue,
+    /// USDC each account deposited and got back, see `performance.rs`
+    pub account_flows: HashMap<AccountId, AccountFlows>,
+    /// Statistics over NAV snapshots, see `analytics.rs`
+    pub analytics: FundAnalytics,
+    /// May deploy new code besides the owner, see `upgrade.rs`
+    pub upgrader: Option<AccountId>,
+    /// Accounts holding shares or any asset, see `holders.rs`
+    pub holders: IterableSet<AccountId>,
 }
 
 #[near_bindgen]
@@ -120,22 +396,89 @@ impl Contract {
         assets: Vec<AssetInfo>,
         usdc_contract: AccountId,
         oracle_contract: AccountId,
+        metadata: Option<FundMetadataArgs>,
     ) -> Self {
         assert!(!env::state_exists(), "Contract is already initialized");
-        let total_weight: u8 = assets.iter().map(|a| a.weight).sum();
-        assert_eq!(total_weight, 100, "Total weight of assets must equal 100%");
+        let metadata = FundMetadata::new(metadata.unwrap_or_default(), owner_id.clone());
+        if let Err(err) = metadata.validate() {
+            env::panic_str(&format!("Invalid fund metadata: {}", err));
+        }
+        let total_weight: u32 = assets.iter().map(|a| a.weight).sum();
+        assert_eq!(
+            total_weight, MAX_BPS,
+            "Total weight of assets must equal {} basis points",
+            MAX_BPS
+        );
+        for (i, asset) in assets.iter().enumerate() {
+            if let Err(err) = asset.validate() {
+                env::panic_str(&err);
+            }
+            assert!(
+                !assets[..i].iter().any(|a| a.key() == asset.key()),
+                "Duplicate asset {}",
+                asset.key()
+            );
+        }
 
+        migrate::write_state_version();
         Self {
             total_assets: U128(0),
             assets,
             owner_id,
             user_balances: HashMap::new(),
-            usdc_contract: "3e2210e1184b45b64c8a434c0a7e7b23cc04ea7eb7a6c3c32520d03d4afcb8af".parse::<AccountId>().unwrap(),
-            oracle_contract: "priceoracle.testnet".parse::<AccountId>().unwrap(),
+            usdc_balances: HashMap::new(),
+            usdc_contract,
+            oracle_config: OracleConfig::price_oracle(oracle_contract),
+            price_store: PriceStore::default(),
             latest_signed_txs: Vec::new(),
+            bitcoin: None,
+            btc_claims: HashMap::new(),
+            mpc_root_public_key: kdf::default_mpc_root_public_key(),
+            segregated_accounts: HashSet::new(),
+            segregated_shares: U128(0),
+            user_shares: HashMap::new(),
+            pending_sales: HashMap::new(),
+            usdc_redemptions: HashMap::new(),
+            usdc_claims: HashMap::new(),
+            withdrawal_policy: WithdrawalPolicy::default(),
+            deposit_lots: HashMap::new(),
+            penalty_pool: PenaltyPool::default(),
+            pending_withdrawals: HashMap::new(),
+            withdrawal_signings: HashMap::new(),
+            withdrawal_queue: WithdrawalQueue::default(),
+            deposit_limits: DepositLimits::default(),
+            allowlist: HashSet::new(),
+            metadata,
+            governance: Governance::default(),
+            wind_downs: Vec::new(),
+            lending: Lending::default(),
+            swap_venue: SwapVenue::default(),
+            account_flows: HashMap::new(),
+            analytics: FundAnalytics::default(),
+            upgrader: None,
+            holders: IterableSet::new(Prefix::Holders),
         }
     }
 
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+
+    /// Normalizes an asset identifier to its balance key and checks the asset is part of the fund.
+    pub(crate) fn registered_asset_key(&self, asset: &str) -> String {
+        let key = asset_key(asset);
+        assert!(
+            self.assets.iter().any(|a| a.key() == key),
+            "Asset {} is not registered",
+            asset
+        );
+        key
+    }
+
     pub fn get_assets(&self) -> Vec<AssetInfo> {
         self.assets.clone()
     }
@@ -144,190 +487,283 @@ impl Contract {
         self.total_assets
     }
 
-    pub fn get_user_balance(&self, account_id: &AccountId) -> Option<&HashMap<String, U128>> {
-        self.user_balances.get(account_id)
+    /// Balances of `account_id`, with the early exit penalties it is owed.
+    pub fn get_user_balance(&self, account_id: &AccountId) -> Option<HashMap<String, U128>> {
+        self.settled_balances(account_id)
     }
 
-    // Price Feed Functions
-    pub fn get_current_prices(&self) -> Promise {
-        Promise::new(self.oracle_contract.clone())
-            .function_call(
-                "get_price_data".to_string(),
-                Vec::new(),
-                NearToken::from_near(0),
-                Gas::from_tgas(100),
-            )
-            .then(
-                Self::ext(env::current_account_id())
-                    .with_static_gas(Gas::from_tgas(50))
-                    .get_prices_callback(),
-            )
+    /// What all holders hold of every asset, keyed like balances.
+    pub(crate) fn total_holdings(&self) -> HashMap<String, u128> {
+        let mut totals = self.penalty_pool.unpaid.clone();
+        for balances in self.user_balances.values() {
+            for (asset_address, balance) in balances {
+                *totals.entry(asset_address.clone()).or_insert(0) += balance.0;
+            }
+        }
+        totals
     }
 
-    pub fn get_prices_callback(
-        &self,
-        #[callback_result] call_result: Result<OraclePriceData, PromiseError>,
-    ) -> Vec<PriceFeedInfo> {
-        let price_data = match call_result {
-            Ok(data) => data,
-            Err(_) => env::panic_str("Failed to fetch price data from oracle"),
+    // Withdrawal Functions
+    /// Gives back the transfers of a withdrawal that couldn't be signed and records the
+    /// rest as redeemed.
+    fn finish_withdrawal_signing(&mut self, account_id: &AccountId) {
+        let WithdrawalSigning {
+            preview, failed, ..
+        } = self.withdrawal_signings.remove(account_id).unwrap();
+        let restored = if failed.is_empty() {
+            0
+        } else {
+            self.restore_failed_withdrawals(account_id, &preview, &failed)
         };
+        let sent: Vec<AssetAmount> = preview
+            .assets
+            .iter()
+            .filter(|asset| !failed.contains(asset))
+            .cloned()
+            .collect();
+        let value = self.usdc_value(&sent);
+        self.record_redemption_flow(account_id, preview.shares.0 - restored, value);
+    }
+
+    /// Redeems the caller's whole position, see `redeem` for partial redemptions.
+    #[payable]
+    pub fn withdraw_underlying_assets(&mut self, request: WithdrawRequest) -> Promise {
+        self.redeem(RedeemAmount::BasisPoints(MAX_BPS), request)
+    }
 
-        let mut price_feeds = Vec::new();
+    /// Signs the transfers of an already debited redemption out of `account_id`'s custody,
+    /// or sends them on NEAR for components held there, as many as this call's gas
+    /// covers. The rest wait for `continue_withdrawal`. Whatever can't be sent is given
+    /// back once every transfer has been tried.
+    pub(crate) fn sign_withdrawals(
+        &mut self,
+        account_id: &AccountId,
+        preview: RedeemPreview,
+        request: &WithdrawRequest,
+    ) -> Promise {
+        assert!(
+            !self.withdrawal_signings.contains_key(account_id),
+            "A withdrawal is still being signed, finish it with continue_withdrawal"
+        );
+        let mut to_sign = Vec::new();
+        for asset_amount in preview.assets.iter().filter(|a| a.amount.0 > 0) {
+            if self.near_token(&asset_amount.asset_address).is_some() {
+                to_sign.push(asset_amount.clone());
+                continue;
+            }
+            let asset = match self.asset_info(&asset_amount.asset_address) {
+                Some(asset) => asset,
+                None => continue,
+            };
+            // Bitcoin is withdrawn separately through `withdraw_btc`
+            if asset.chain() == Chain::Bitcoin {
+                self.add_btc_claim(account_id, asset_amount.amount.0);
+                continue;
+            }
+            if asset.contract_address.is_some() {
+                to_sign.push(asset_amount.clone());
+            }
+        }
 
-        let timestamp = price_data.timestamp.parse::<u64>().unwrap_or(0);
-        let current_time = env::block_timestamp();
+        if to_sign.is_empty() {
+            // Nothing to sign, whatever was redeemed is claimable BTC now
+            let value = self.usdc_value(&preview.assets);
+            self.record_redemption_flow(account_id, preview.shares.0, value);
+            return Promise::new(env::current_account_id());
+        }
+        self.withdrawal_signings.insert(
+            account_id.clone(),
+            WithdrawalSigning {
+                preview,
+                eth_destination: request.eth_destination,
+                aurora_destination: request.aurora_destination,
+                to_sign,
+                signing: 0,
+                failed: Vec::new(),
+            },
+        );
+        self.sign_withdrawal_legs(account_id, &request.network_details)
+    }
 
-        if current_time - timestamp > price_data.recency_duration_sec * 1_000_000_000 {
-            env::panic_str("Price data is too old");
+    /// Asks the MPC signer for the next transfers of `account_id`'s withdrawal, or sends
+    /// them with `ft_transfer` for components held on NEAR. Transfers out of the same
+    /// treasury take consecutive nonces from `network_details`.
+    fn sign_withdrawal_legs(
+        &mut self,
+        account_id: &AccountId,
+        network_details: &NetworkDetails,
+    ) -> Promise {
+        let gas_left = env::prepaid_gas()
+            .as_gas()
+            .saturating_sub(env::used_gas().as_gas() + SIGN_GAS_RESERVE.as_gas());
+        let mut gas = 0;
+        let count = self.withdrawal_signings[account_id]
+            .to_sign
+            .iter()
+            .take_while(|asset| {
+                gas += match self.near_token(&asset.asset_address) {
+                    Some(_) => NEAR_LEG_GAS.as_gas(),
+                    None => SIGN_LEG_GAS.as_gas(),
+                };
+                gas <= gas_left
+            })
+            .count();
+        assert!(count > 0, "Not enough gas to sign a transfer");
+        let signing = self.withdrawal_signings.get_mut(account_id).unwrap();
+        let assets: Vec<AssetAmount> = signing.to_sign.drain(..count).collect();
+        let (eth_destination, aurora_destination) =
+            (signing.eth_destination, signing.aurora_destination);
+
+        let mut nonces: HashMap<String, u64> = HashMap::new();
+        let mut legs = Vec::new();
+        let mut promises = Vec::new();
+        let mut unsignable = Vec::new();
+        for asset_amount in assets {
+            if self.near_token(&asset_amount.asset_address).is_some() {
+                promises.push(self.transfer_near_token(&asset_amount, account_id));
+                legs.push(WithdrawalLeg {
+                    asset: asset_amount,
+                    transfer: LegTransfer::Near,
+                });
+                continue;
+            }
+            // A component wound down since the redemption can't be signed for anymore
+            let Some(asset) = self.asset_info(&asset_amount.asset_address) else {
+                unsignable.push(asset_amount);
+                continue;
+            };
+            let destination = match asset.chain() {
+                Chain::Aurora => aurora_destination,
+                _ => eth_destination,
+            };
+            let path = self.custody_path(asset.chain(), account_id);
+            let nonce = nonces.entry(path.clone()).or_insert(network_details.eth_nonce);
+            let details = NetworkDetails {
+                eth_nonce: *nonce,
+                ..network_details.clone()
+            };
+            *nonce += 1;
+
+            let (sign_promise, evm_tx, expected_signer) = self.sign_erc20_transfer(
+                asset.contract_address.unwrap(),
+                destination,
+                asset_amount.amount.0,
+                details,
+                &path,
+            );
+            promises.push(sign_promise);
+            legs.push(WithdrawalLeg {
+                asset: asset_amount,
+                transfer: LegTransfer::Evm {
+                    evm_tx,
+                    expected_signer,
+                },
+            });
         }
 
-        for price in price_data.prices {
-            if let Some(price_info) = price.price {
-                if let Some(&near_address) = TOKEN_ADDRESSES.get(price.asset_id.as_str()) {
-                    price_feeds.push(PriceFeedInfo {
-                        asset_address: near_address.to_string(),
-                        price: U128(price_info.multiplier.parse().unwrap_or(0)),
-                        decimals: price_info.decimals as u8,
-                        last_updated: timestamp,
-                    });
+        let signing = self.withdrawal_signings.get_mut(account_id).unwrap();
+        signing.signing = legs.len() as u32;
+        signing.failed.extend(unsignable);
+        match promises.into_iter().reduce(|acc, promise| acc.and(promise)) {
+            Some(signatures) => signatures.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas::from_gas(
+                        WITHDRAWAL_CALLBACK_GAS.as_gas() * legs.len() as u64,
+                    ))
+                    .withdrawal_callback(account_id.clone(), legs),
+            ),
+            None => {
+                if signing.to_sign.is_empty() {
+                    self.finish_withdrawal_signing(account_id);
                 }
+                Promise::new(env::current_account_id())
             }
         }
-
-        price_feeds
-    }
-
-    pub fn get_asset_price(&self, asset_address: String) -> Promise {
-        self.get_current_prices().then(
-            Self::ext(env::current_account_id())
-                .with_static_gas(Gas::from_tgas(50))
-                .get_single_price_callback(asset_address),
-        )
     }
 
-    pub fn get_single_price_callback(
-        &self,
-        asset_address: String,
-        #[callback_result] price_feeds_result: Result<Vec<PriceFeedInfo>, PromiseError>,
-    ) -> Option<PriceFeedInfo> {
-        let price_feeds = match price_feeds_result {
-            Ok(feeds) => feeds,
-            Err(_) => env::panic_str("Failed to fetch price feeds"),
-        };
-
-        price_feeds
-            .into_iter()
-            .find(|feed| feed.asset_address == asset_address)
+    /// Signs the next transfers of the caller's withdrawal, with `network_details` for
+    /// their chain as of now.
+    pub fn continue_withdrawal(&mut self, network_details: NetworkDetails) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let signing = self
+            .withdrawal_signings
+            .get(&account_id)
+            .expect("No withdrawal is being signed");
+        assert!(
+            signing.signing == 0,
+            "Signatures of the withdrawal are still on their way"
+        );
+        self.sign_withdrawal_legs(&account_id, &network_details)
     }
 
-    pub fn get_portfolio_value(&self, account_id: AccountId) -> Promise {
-        let balances = self
-            .get_user_balance(&account_id)
-            .expect("No balance found for user");
-
-        self.get_current_prices().then(
-            Self::ext(env::current_account_id())
-                .with_static_gas(Gas::from_tgas(50))
-                .calculate_portfolio_value_callback(balances.clone()),
-        )
+    pub fn get_withdrawal_signing(&self, account_id: AccountId) -> Option<WithdrawalSigning> {
+        self.withdrawal_signings.get(&account_id).cloned()
     }
 
-    pub fn calculate_portfolio_value_callback(
-        &self,
-        balances: HashMap<String, U128>,
-        #[callback_result] price_feeds_result: Result<Vec<PriceFeedInfo>, PromiseError>,
-    ) -> U128 {
-        let price_feeds = match price_feeds_result {
-            Ok(feeds) => feeds,
-            Err(_) => env::panic_str("Failed to fetch price feeds"),
-        };
-
-        let mut total_value: u128 = 0;
-
-        for (asset_address, balance) in balances {
-            if let Some(price_feed) = price_feeds
-                .iter()
-                .find(|feed| feed.asset_address == asset_address)
-            {
-                let asset_value = (balance.0 as f64
-                    * (price_feed.price.0 as f64 / 10u64.pow(price_feed.decimals as u32) as f64))
-                    as u128;
-                total_value += asset_value;
+    /// Stores the signed transfers of a redemption. Transfers that weren't signed, whose
+    /// signature doesn't recover to the treasury, or that failed on NEAR, are credited back
+    /// to the account once the last transfer has been tried.
+    #[private]
+    pub fn withdrawal_callback(
+        &mut self,
+        account_id: AccountId,
+        legs: Vec<WithdrawalLeg>,
+    ) -> Vec<Vec<u8>> {
+        let mut signed_txs = Vec::new();
+        let mut failed = Vec::new();
+        for (index, leg) in legs.into_iter().enumerate() {
+            let result = match (env::promise_result(index as u64), &leg.transfer) {
+                (PromiseResult::Successful(_), LegTransfer::Near) => Ok(None),
+                (PromiseResult::Failed, LegTransfer::Near) => Err("transfer failed".to_string()),
+                (PromiseResult::Successful(data), LegTransfer::Evm { evm_tx, expected_signer }) => {
+                    near_sdk::serde_json::from_slice::<SignResult>(&data)
+                        .map_err(|_| "malformed signing response".to_string())
+                        .and_then(|result| signed_evm_tx(evm_tx, *expected_signer, Ok(result)))
+                        .map(Some)
+                }
+                (PromiseResult::Failed, LegTransfer::Evm { .. }) => {
+                    Err("signing failed".to_string())
+                }
+            };
+            match result {
+                Ok(signed_tx) => signed_txs.extend(signed_tx),
+                Err(reason) => {
+                    emit_event(
+                        "withdrawal_failed",
+                        json!({
+                            "account_id": account_id,
+                            "asset_address": leg.asset.asset_address,
+                            "amount": leg.asset.amount,
+                            "reason": reason,
+                        }),
+                    );
+                    failed.push(leg.asset);
+                }
             }
         }
-
-        U128(total_value)
-    }
-
-    // Withdrawal Functions
-    #[payable]
-    pub fn withdraw_underlying_assets(&mut self, request: WithdrawRequest) -> Promise {
-        let sender_id = env::predecessor_account_id();
-
-        // Clone balances to avoid borrowing issues
-        let balances = self
-            .user_balances
-            .get(&sender_id)
-            .expect("No balance found for user")
-            .clone();
-
-        // Collect the required data into a temporary vector
-        let withdrawals: Vec<_> = self
-            .assets
-            .iter()
-            .filter_map(|asset| {
-                balances.get(&asset.contract_address).map(|balance| {
-                    let destination = if asset.name == "ETH" {
-                        request.eth_destination.clone()
-                    } else {
-                        request.aurora_destination.clone()
-                    };
-                    (asset.contract_address.clone(), destination, balance.0)
-                })
-            })
-            .collect();
-
-        // Create promises
-        let promises: Vec<Promise> = withdrawals
-            .into_iter()
-            .map(|(contract_address, destination, amount)| {
-                self.create_and_sign_withdrawal(
-                    &contract_address, // Pass as &str
-                    destination,
-                    amount,
-                    request.network_details.clone(),
-                    if contract_address == ETH_TREASURY_PATH {
-                        ETH_TREASURY_PATH
-                    } else {
-                        AURORA_TREASURY_PATH
-                    },
-                )
-            })
-            .collect();
-
-        // Combine promises
-        promises
-            .into_iter()
-            .reduce(|acc, promise| acc.and(promise))
-            .unwrap_or_else(|| Promise::new(env::current_account_id()))
+        self.latest_signed_txs.extend(signed_txs.iter().cloned());
+
+        let signing = self
+            .withdrawal_signings
+            .get_mut(&account_id)
+            .expect("No withdrawal is being signed");
+        signing.signing = 0;
+        signing.failed.extend(failed);
+        if signing.to_sign.is_empty() {
+            self.finish_withdrawal_signing(&account_id);
+        }
+        signed_txs
     }
 
-    fn construct_erc20_transfer_tx(
+    fn construct_evm_call_tx(
         &self,
-        token_address: String,
-        recipient_address: String,
-        amount: u128,
+        contract_address: EvmAddress,
+        data: Vec<u8>,
         network_details: NetworkDetails,
     ) -> EVMTransaction {
-        let token_address = parse_eth_address(&token_address);
-        let recipient_address = parse_eth_address(&recipient_address);
-
-        let data = self.construct_erc20_transfer_data(recipient_address, amount);
-
         TransactionBuilder::new::<EVM>()
             .nonce(network_details.eth_nonce)
-            .to(token_address)
+            .to(contract_address.as_bytes())
             .value(0)
             .input(data)
             .max_priority_fee_per_gas(network_details.max_priority_fee_per_gas)
@@ -347,47 +783,30 @@ impl Contract {
         data
     }
 
-    pub fn sign_callback(
-        &mut self,
-        evm_tx_wrapper: EVMTransactionWrapper,
-        #[callback_result] result: Result<SignResult, PromiseError>,
-    ) -> Vec<u8> {
-        let mpc_signature = result.unwrap();
-        let big_r = &mpc_signature.big_r.affine_point;
-        let s = &mpc_signature.s.scalar;
-
-        let r = &big_r[2..];
-        let v = mpc_signature.recovery_id;
-        let signature_omni = OmniSignature {
-            v,
-            r: hex::decode(r).unwrap(),
-            s: hex::decode(s).unwrap(),
-        };
-
-        let evm_tx = evm_tx_wrapper.to_evm_transaction();
-        let signed_tx = evm_tx.build_with_signature(&signature_omni);
-
-        self.latest_signed_txs.push(signed_tx.clone());
-        signed_tx
+    /// Asks the MPC signer to sign an ERC20 transfer out of `treasury_path`. Returns the
+    /// signing promise, the transaction and the address the signature has to recover to.
+    pub(crate) fn sign_erc20_transfer(
+        &self,
+        token_address: EvmAddress,
+        recipient: EvmAddress,
+        amount: u128,
+        network_details: NetworkDetails,
+        treasury_path: &str,
+    ) -> (Promise, EVMTransactionWrapper, EvmAddress) {
+        let data = self.construct_erc20_transfer_data(recipient.as_bytes(), amount);
+        self.sign_evm_call(token_address, data, network_details, treasury_path)
     }
 
-    fn create_and_sign_withdrawal(
-        &mut self,
-        token_address: &str,
-        recipient: String,
-        amount: u128,
+    /// Like `sign_erc20_transfer`, for a call of `contract_address` with any `data`.
+    pub(crate) fn sign_evm_call(
+        &self,
+        contract_address: EvmAddress,
+        data: Vec<u8>,
         network_details: NetworkDetails,
         treasury_path: &str,
-    ) -> Promise {
-        // Use the construct_erc20_transfer_tx method if you want to keep it
-        let omni_tx = self.construct_erc20_transfer_tx(
-            token_address.to_string(),
-            recipient,
-            amount,
-            network_details.clone(),
-        );
+    ) -> (Promise, EVMTransactionWrapper, EvmAddress) {
+        let omni_tx = self.construct_evm_call_tx(contract_address, data, network_details);
 
-        // Rest of the implementation remains the same
         let encoded_tx = omni_tx.build_for_signing();
         let tx_hash = env::keccak256(&encoded_tx);
 
@@ -396,15 +815,16 @@ impl Contract {
             path: treasury_path.to_string(),
             key_version: 0,
         };
-
-        mpc::ext(MPC_CONTRACT_ACCOUNT_ID.parse().unwrap())
-            .with_static_gas(Gas::from_tgas(100))
-            .sign(sign_request)
-            .then(
-                Self::ext(env::current_account_id())
-                    .with_static_gas(Gas::from_tgas(10))
-                    .sign_callback(EVMTransactionWrapper::from_evm_transaction(&omni_tx)),
-            )
+        let expected_signer = evm_address(&self.derive_public_key(treasury_path));
+
+        let sign_promise = mpc::ext(MPC_CONTRACT_ACCOUNT_ID.parse().unwrap())
+            .with_static_gas(MPC_SIGN_GAS)
+            .sign(sign_request);
+        (
+            sign_promise,
+            EVMTransactionWrapper::from_evm_transaction(&omni_tx),
+            expected_signer,
+        )
     }
 
     // View functions
@@ -413,26 +833,58 @@ impl Contract {
     }
 
     pub fn get_oracle_contract(&self) -> AccountId {
-        self.oracle_contract.clone()
+        self.oracle_config.sources[0].account_id.clone()
     }
 
+    /// Credits a deposit to `sender_id` in units of every component, bought at the cached
+    /// oracle price. Slices of components with a NEAR swap route are queued, swapped as
+    /// far as the deposit's gas covers `NEAR_SWAP_CHAIN_GAS` per swap, and credited once
+    /// their swap is back. The other slices are left to the keeper.
+    #[private]
     pub fn process_deposit(&mut self, sender_id: AccountId, amount: U128) {
+        self.settle_penalties(&sender_id);
+        let now = env::block_timestamp();
+        let weights: Vec<u32> = self.assets.iter().map(|a| a.weight).collect();
+        let mut credits = Vec::new();
+        let mut swaps = Vec::new();
+        let parts = split_by_weights(amount.0, &weights);
+        for (asset, asset_amount) in self.assets.iter().zip(parts) {
+            let key = asset.key();
+            // Components held on NEAR are credited once a swap bought them
+            if self.near_token(&key).is_some() {
+                swaps.push((key, asset_amount));
+                continue;
+            }
+            let value = usdc_to_value(asset_amount);
+            let expected = self.price_store.amount_for(&key, value, now);
+            credits.push((key, asset_amount, expected));
+        }
+
         let user_balance = self
             .user_balances
             .entry(sender_id.clone())
             .or_insert_with(HashMap::new);
-
-        for asset in &self.assets {
-            let weight_fraction = f64::from(asset.weight) / 100.0;
-            let asset_amount = (amount.0 as f64 * weight_fraction) as u128;
-
+        for (key, _, units) in &credits {
             user_balance
-                .entry(asset.contract_address.clone())
-                .and_modify(|balance| *balance = U128(balance.0 + asset_amount))
-                .or_insert(U128(asset_amount));
+                .entry(key.clone())
+                .and_modify(|balance| *balance = U128(balance.0 + units))
+                .or_insert(U128(*units));
         }
+        self.emit_deposit_allocation(&sender_id, &credits);
 
+        let shares = self.user_shares.entry(sender_id.clone()).or_insert(U128(0));
+        shares.0 += amount.0;
+        self.record_deposit_lot(&sender_id, amount.0, env::block_timestamp());
+        self.record_deposit_flow(&sender_id, amount.0);
+        self.sync_holder(&sender_id);
         self.total_assets = U128(self.total_assets.0 + amount.0);
+        self.track_custody_shares(&sender_id, amount.0, 0);
+        for (key, amount_in) in swaps {
+            self.queue_near_swap(&sender_id, key, amount_in);
+        }
+        // Older slices go first, whatever this deposit's gas doesn't cover waits for
+        // `run_queued_swaps`
+        self.start_queued_swaps();
 
         env::log_str(&format!(
             "Processed deposit for user {} with amount {}",
@@ -455,7 +907,27 @@ impl FungibleTokenReceiver for Contract {
             "Only USDC token is accepted"
         );
 
+        if self.price_store.circuit_breaker.is_some() {
+            env::log_str("Deposits are frozen by the price circuit breaker");
+            return PromiseOrValue::Value(amount);
+        }
+        if self.governance.rebalancing.is_some() {
+            env::log_str("Deposits are paused while holdings are rebalanced");
+            return PromiseOrValue::Value(amount);
+        }
+        if !self.usdc_balances.is_empty() {
+            env::log_str("Deposits are paused while balances are converted to units");
+            return PromiseOrValue::Value(amount);
+        }
+
         if msg.is_empty() {
+            if let Err(reason) = self.check_deposit(&sender_id, amount.0) {
+                env::log_str(&format!(
+                    "Deposit of {} from {} refunded: {}",
+                    amount.0, sender_id, reason
+                ));
+                return PromiseOrValue::Value(amount);
+            }
             self.process_deposit(sender_id, amount);
             PromiseOrValue::Value(U128(0))
         } else {
@@ -485,13 +957,15 @@ mod tests {
         let assets = vec![
             AssetInfo {
                 name: "ETH".to_string(),
-                contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
-                weight: 70,
+                contract_address: Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap()),
+                weight: 7_000,
+                chain: None,
             },
             AssetInfo {
                 name: "AURORA".to_string(),
-                contract_address: "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".to_string(),
-                weight: 30,
+                contract_address: Some("0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".parse().unwrap()),
+                weight: 3_000,
+                chain: None,
             },
         ];
 
@@ -500,6 +974,7 @@ mod tests {
             assets.clone(),
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            None,
         );
 
         assert_eq!(contract.get_number_of_assets(), 2);
@@ -516,17 +991,20 @@ mod tests {
             vec![
                 AssetInfo {
                     name: "ETH".to_string(),
-                    contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
-                    weight: 70,
+                    contract_address: Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap()),
+                    weight: 7_000,
+                    chain: None,
                 },
                 AssetInfo {
                     name: "AURORA".to_string(),
-                    contract_address: "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".to_string(),
-                    weight: 30,
+                    contract_address: Some("0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".parse().unwrap()),
+                    weight: 3_000,
+                    chain: None,
                 },
             ],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            None,
         );
 
         // Test deposit
@@ -536,8 +1014,8 @@ mod tests {
 
         // Test withdrawal request
         let withdraw_request = WithdrawRequest {
-            eth_destination: "0x1234...".to_string(),
-            aurora_destination: "0x5678...".to_string(),
+            eth_destination: "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse().unwrap(),
+            aurora_destination: "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359".parse().unwrap(),
             network_details: NetworkDetails {
                 chain_id: 1,
                 eth_nonce: 0,
@@ -560,11 +1038,13 @@ mod tests {
             accounts(1),
             vec![AssetInfo {
                 name: "ETH".to_string(),
-                contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
-                weight: 70,
+                contract_address: Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap()),
+                weight: 10_000,
+                chain: None,
             }],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            None,
         );
 
         let _prices = contract.get_current_prices();
@@ -582,6 +1062,7 @@ mod tests {
             vec![],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            None,
         );
 
         contract.ft_on_transfer(accounts(3), U128(1000), "".to_string());
@@ -596,14 +1077,227 @@ mod tests {
             accounts(1),
             vec![AssetInfo {
                 name: "ETH".to_string(),
-                contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
-                weight: 70,
+                contract_address: Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap()),
+                weight: 10_000,
+                chain: None,
             }],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            None,
         );
 
         let _portfolio_value = contract.get_portfolio_value(accounts(1));
         // Note: Can't fully test portfolio valuation in unit tests due to cross-contract calls
     }
+
+    fn deposit_at_prices(eth: (u128, u8), aurora: (u128, u8)) -> HashMap<String, U128> {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let eth_address = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+        let aurora_address = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+        let mut contract = Contract::new(
+            accounts(0),
+            vec![
+                AssetInfo {
+                    name: "ETH".to_string(),
+                    contract_address: Some(eth_address.parse().unwrap()),
+                    weight: 7_000,
+                    chain: None,
+                },
+                AssetInfo {
+                    name: "AURORA".to_string(),
+                    contract_address: Some(aurora_address.parse().unwrap()),
+                    weight: 3_000,
+                    chain: None,
+                },
+            ],
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            None,
+        );
+        for (asset_address, (price, decimals)) in [(eth_address, eth), (aurora_address, aurora)] {
+            contract.price_store.update(
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(price),
+                    decimals,
+                    last_updated: 0,
+                },
+                0,
+            );
+        }
+
+        // 1000 USDC
+        contract.process_deposit(accounts(1), U128(1_000_000_000));
+        contract.user_balances[&accounts(1)].clone()
+    }
+
+    #[test]
+    fn test_deposit_at_oracle_prices() {
+        let eth_address = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+        let aurora_address = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+        // ETH at 2500 USD and AURORA at 0.10 USD, both with 18 decimals. The NEAR
+        // priceoracle adds 4 decimals, Pyth reports an exponent of -8.
+        let price_oracle = deposit_at_prices((25_000_000, 22), (1_000, 22));
+        let pyth = deposit_at_prices((250_000_000_000, 26), (10_000_000, 26));
+
+        for balances in [price_oracle, pyth] {
+            // 700 USDC buy 0.28 ETH, 300 USDC buy 3000 AURORA
+            assert_eq!(balances[eth_address], U128(28 * 10u128.pow(16)));
+            assert_eq!(balances[aurora_address], U128(3_000 * 10u128.pow(18)));
+        }
+    }
+
+    const THREE_COMPONENTS: [(&str, &str, u32); 3] = [
+        ("ETH", "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87", 5_000),
+        ("AURORA", "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6", 3_000),
+        ("USDT", "0xdAC17F958D2ee523a2206206994597C13D831ec7", 2_000),
+    ];
+
+    /// A fund of `THREE_COMPONENTS` that `accounts(1)` deposited 1000 USDC in.
+    fn three_component_fund() -> Contract {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(
+            accounts(0),
+            THREE_COMPONENTS
+                .iter()
+                .map(|(name, address, weight)| AssetInfo {
+                    name: name.to_string(),
+                    contract_address: Some(address.parse().unwrap()),
+                    weight: *weight,
+                    chain: None,
+                })
+                .collect(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            None,
+        );
+        // Every component at 1 USD with 18 decimals, as the NEAR priceoracle reports it
+        for (_, address, _) in THREE_COMPONENTS {
+            contract.price_store.update(
+                PriceFeedInfo {
+                    asset_address: address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+                0,
+            );
+        }
+        contract.process_deposit(accounts(1), U128(1_000_000_000));
+        contract
+    }
+
+    fn withdraw_request(network_details: &NetworkDetails) -> WithdrawRequest {
+        WithdrawRequest {
+            eth_destination: "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse().unwrap(),
+            aurora_destination: "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359".parse().unwrap(),
+            network_details: network_details.clone(),
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_signs_over_several_calls() {
+        let mut contract = three_component_fund();
+        testing_env!(get_context(accounts(1)).prepaid_gas(Gas::from_tgas(300)).build());
+        let network_details = NetworkDetails {
+            chain_id: 1,
+            eth_nonce: 0,
+            max_priority_fee_per_gas: 1_000_000_000,
+            max_fee_per_gas: 2_000_000_000,
+            gas_limit: 100_000,
+        };
+        contract.withdraw_underlying_assets(withdraw_request(&network_details));
+
+        // Two signatures fit in the call, the third waits for `continue_withdrawal`
+        let signing = contract.get_withdrawal_signing(accounts(1)).unwrap();
+        assert_eq!(signing.signing, 2);
+        assert_eq!(signing.to_sign.len(), 1);
+        assert_eq!(signing.to_sign[0].asset_address, THREE_COMPONENTS[2].1);
+
+        contract.withdrawal_signings.get_mut(&accounts(1)).unwrap().signing = 0;
+        contract.continue_withdrawal(network_details);
+        let signing = contract.get_withdrawal_signing(accounts(1)).unwrap();
+        assert_eq!(signing.signing, 1);
+        assert!(signing.to_sign.is_empty());
+    }
+
+    #[test]
+    fn test_withdrawal_sends_components_held_on_near() {
+        let mut contract = three_component_fund();
+        // As if a swap had bought the AURORA on NEAR
+        contract.swap_venue.routes.insert(
+            THREE_COMPONENTS[1].1.to_string(),
+            SwapRoute {
+                pool_id: 0,
+                token_id: "aurora.fakes.testnet".parse().unwrap(),
+            },
+        );
+        testing_env!(get_context(accounts(1)).prepaid_gas(Gas::from_tgas(300)).build());
+        contract.withdraw_underlying_assets(withdraw_request(&NetworkDetails {
+            chain_id: 1,
+            eth_nonce: 0,
+            max_priority_fee_per_gas: 1_000_000_000,
+            max_fee_per_gas: 2_000_000_000,
+            gas_limit: 100_000,
+        }));
+
+        // The transfer on NEAR costs less than a signature, so all three fit
+        let signing = contract.get_withdrawal_signing(accounts(1)).unwrap();
+        assert_eq!(signing.signing, 3);
+        assert!(signing.to_sign.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Signatures of the withdrawal are still on their way")]
+    fn test_withdrawal_continues_once_signatures_are_back() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut contract = Contract::new(
+            accounts(0),
+            vec![AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".parse().unwrap()),
+                weight: 10_000,
+                chain: None,
+            }],
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            None,
+        );
+        contract.withdrawal_signings.insert(
+            accounts(0),
+            WithdrawalSigning {
+                preview: RedeemPreview {
+                    shares: U128(0),
+                    remaining_shares: U128(0),
+                    assets: Vec::new(),
+                    penalty_shares: U128(0),
+                    penalty: Vec::new(),
+                    interest: U128(0),
+                },
+                eth_destination: "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse().unwrap(),
+                aurora_destination: "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359".parse().unwrap(),
+                to_sign: Vec::new(),
+                signing: 1,
+                failed: Vec::new(),
+            },
+        );
+        contract.continue_withdrawal(NetworkDetails {
+            chain_id: 1,
+            eth_nonce: 1,
+            max_priority_fee_per_gas: 1_000_000_000,
+            max_fee_per_gas: 2_000_000_000,
+            gas_limit: 100_000,
+        });
+    }
+
+    #[test]
+    fn test_split_by_weights() {
+        assert_eq!(split_by_weights(1_001, &[7_000, 3_000]), vec![701, 300]);
+        // The dust goes to the largest weight
+        assert_eq!(split_by_weights(100, &[3_333, 3_334, 3_333]), vec![33, 34, 33]);
+        // and to the first of tied largest weights
+        assert_eq!(split_by_weights(10, &[3_334, 3_334, 3_332]), vec![4, 3, 3]);
+        assert_eq!(split_by_weights(7, &[50, 9_950]), vec![0, 7]);
+    }
 }
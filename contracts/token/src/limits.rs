@@ -0,0 +1,56 @@
+use near_sdk::{env, near_bindgen, AccountId};
+
+pub use fund_common::limits::DepositLimits;
+
+use crate::{Contract, ContractExt};
+
+impl Contract {
+    pub(crate) fn check_deposit(&self, account_id: &AccountId, amount: u128) -> Result<(), String> {
+        let account_total = self.user_shares.get(account_id).map_or(0, |shares| shares.0);
+        self.deposit_limits.check(
+            self.allowlist.contains(account_id),
+            amount,
+            account_total,
+            self.total_assets.0,
+        )?;
+        // Deposits are credited in units of every component at its cached price
+        let now = env::block_timestamp();
+        match self
+            .assets
+            .iter()
+            .find(|asset| !self.price_store.has_fresh_price(&asset.key(), now))
+        {
+            Some(asset) => Err(format!("no fresh price for asset {}", asset.key())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_deposit_limits(&mut self, limits: DepositLimits) {
+        self.assert_owner();
+        limits.validate().unwrap_or_else(|err| env::panic_str(&err));
+        self.deposit_limits = limits;
+    }
+
+    pub fn get_deposit_limits(&self) -> DepositLimits {
+        self.deposit_limits.clone()
+    }
+
+    pub fn add_to_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        self.allowlist.extend(account_ids);
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in &account_ids {
+            self.allowlist.remove(account_id);
+        }
+    }
+
+    pub fn is_allowlisted(&self, account_id: AccountId) -> bool {
+        self.allowlist.contains(&account_id)
+    }
+}
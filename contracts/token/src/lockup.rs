@@ -0,0 +1,418 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Promise, PromiseOrValue};
+use std::collections::HashMap;
+
+use crate::redeem::{pro_rata, AssetAmount, RedeemAmount, RedeemPreview, MAX_BPS};
+use crate::{Contract, ContractExt, WithdrawRequest};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// Precision of the penalty paid per share
+const PENALTY_SCALE: u128 = 1_000_000_000_000;
+
+/// How long deposits stay locked and how withdrawals are paced. All zero by default,
+/// which lets deposits be redeemed right away.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalPolicy {
+    /// Time every deposit is locked for, counted from the deposit
+    pub lockup_sec: u64,
+    /// When set, withdrawals go through `request_withdrawal` and can be claimed this long after
+    pub cooldown_sec: u64,
+    /// Part of locked shares forfeited to the remaining holders on early exit
+    pub early_exit_penalty_bps: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositLot {
+    pub shares: U128,
+    pub deposited_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositLotView {
+    pub shares: U128,
+    pub deposited_at: u64,
+    pub unlocks_at: u64,
+    pub locked: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingWithdrawal {
+    pub preview: RedeemPreview,
+    /// Deposit lots the shares were taken from, restored if the withdrawal is cancelled
+    pub lots: Vec<DepositLot>,
+    pub requested_at: u64,
+    pub claimable_at: u64,
+}
+
+/// Early exit penalties owed to the holders who stayed. They are added up per share and
+/// only paid into a balance when the holder's shares change, so an early exit costs the
+/// same however many holders there are.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct PenaltyPool {
+    /// Penalty of each asset paid per share so far, scaled by `PENALTY_SCALE`
+    pub per_share: HashMap<String, u128>,
+    /// `per_share` when each holder was last paid
+    pub paid: HashMap<AccountId, HashMap<String, u128>>,
+    /// Penalties not paid into any balance yet, rounding dust included
+    pub unpaid: HashMap<String, u128>,
+}
+
+/// Shares of `lots` still locked at `now`.
+pub fn locked_shares(lots: &[DepositLot], lockup_ns: u64, now: u64) -> u128 {
+    lots.iter()
+        .filter(|lot| lot.deposited_at.saturating_add(lockup_ns) > now)
+        .map(|lot| lot.shares.0)
+        .sum()
+}
+
+/// Removes `shares` from `lots`, oldest first, so unlocked shares are always used up
+/// before locked ones. Returns the removed parts of the lots.
+pub fn take_lots(lots: &mut Vec<DepositLot>, shares: u128) -> Vec<DepositLot> {
+    let mut taken = Vec::new();
+    let mut remaining = shares;
+    for lot in lots.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+        let part = remaining.min(lot.shares.0);
+        lot.shares.0 -= part;
+        remaining -= part;
+        taken.push(DepositLot {
+            shares: U128(part),
+            deposited_at: lot.deposited_at,
+        });
+    }
+    lots.retain(|lot| lot.shares.0 > 0);
+    taken
+}
+
+/// Puts lots removed by `take_lots` back, less `forfeited` shares. The penalty is only
+/// charged on locked shares, so it comes off the newest lots first.
+pub fn restore_lots(lots: &mut Vec<DepositLot>, mut taken: Vec<DepositLot>, forfeited: u128) {
+    let mut remaining = forfeited;
+    for lot in taken.iter_mut().rev() {
+        let part = remaining.min(lot.shares.0);
+        lot.shares.0 -= part;
+        remaining -= part;
+    }
+    lots.extend(taken.into_iter().filter(|lot| lot.shares.0 > 0));
+    lots.sort_by_key(|lot| lot.deposited_at);
+}
+
+impl Contract {
+    fn lockup_ns(&self) -> u64 {
+        self.withdrawal_policy.lockup_sec.saturating_mul(NANOS_PER_SEC)
+    }
+
+    pub(crate) fn record_deposit_lot(&mut self, account_id: &AccountId, shares: u128, deposited_at: u64) {
+        self.deposit_lots
+            .entry(account_id.clone())
+            .or_default()
+            .push(DepositLot {
+                shares: U128(shares),
+                deposited_at,
+            });
+    }
+
+    /// Shares forfeited when `account_id` redeems `shares` now. Lots deposited before
+    /// pending weights were proposed can leave for free, so holders who disagree with
+    /// them aren't penalized. Locked shares can only leave when the penalty takes at
+    /// least one of them to another holder, otherwise the lock-up would be optional, so
+    /// segregated shares stay locked.
+    pub(crate) fn early_exit_penalty_shares(&self, account_id: &AccountId, shares: u128) -> u128 {
+        let lots = match self.deposit_lots.get(account_id) {
+            Some(lots) => lots,
+            None => return 0,
+        };
+        let lockup_ns = self.lockup_ns();
+        let now = env::block_timestamp();
+        let proposed_at = self.governance.pending().map(|proposal| proposal.proposed_at);
+        let is_free = |lot: &DepositLot| {
+            lot.deposited_at.saturating_add(lockup_ns) <= now
+                || proposed_at.is_some_and(|proposed_at| lot.deposited_at < proposed_at)
+        };
+        // Lots are taken oldest first, and free lots are always the oldest ones
+        let free: u128 = lots.iter().filter(|lot| is_free(lot)).map(|lot| lot.shares.0).sum();
+        let locked_taken = shares.saturating_sub(free);
+        if locked_taken == 0 {
+            return 0;
+        }
+
+        let bps = self.withdrawal_policy.early_exit_penalty_bps as u128;
+        if bps == 0 || self.penalty_receiver_shares(account_id) == 0 {
+            let unlocks_at = lots
+                .iter()
+                .filter(|lot| !is_free(lot))
+                .map(|lot| lot.deposited_at.saturating_add(lockup_ns))
+                .max()
+                .unwrap_or(now);
+            env::panic_str(&format!("Shares are locked until {}", unlocks_at));
+        }
+        // Rounded up so even the smallest early exit forfeits something
+        (locked_taken * bps).div_ceil(MAX_BPS as u128)
+    }
+
+    pub(crate) fn consume_deposit_lots(
+        &mut self,
+        account_id: &AccountId,
+        shares: u128,
+    ) -> Vec<DepositLot> {
+        self.deposit_lots
+            .get_mut(account_id)
+            .map(|lots| take_lots(lots, shares))
+            .unwrap_or_default()
+    }
+
+    /// Shares the penalty of `account_id` is shared out over. The forfeited assets stay
+    /// in the shared treasuries, so only other pooled holders can be credited with them.
+    fn penalty_receiver_shares(&self, account_id: &AccountId) -> u128 {
+        if self.segregated_accounts.contains(account_id) {
+            return 0;
+        }
+        self.pooled_shares() - self.get_user_shares(account_id.clone()).0
+    }
+
+    /// Hands the forfeited `penalty` to every other pooled holder in proportion to their
+    /// shares. Must run after the shares of `account_id` were burned.
+    pub(crate) fn distribute_penalty(&mut self, account_id: &AccountId, penalty: &[AssetAmount]) {
+        let other_shares = self.penalty_receiver_shares(account_id);
+        if other_shares == 0 {
+            return;
+        }
+        let pool = &mut self.penalty_pool;
+        let paid = pool.paid.entry(account_id.clone()).or_default();
+        for asset in penalty.iter().filter(|asset| asset.amount.0 > 0) {
+            let per_share = pool.per_share.entry(asset.asset_address.clone()).or_insert(0);
+            *per_share += asset
+                .amount
+                .0
+                .checked_mul(PENALTY_SCALE)
+                .expect("Penalty overflow")
+                / other_shares;
+            // The shares `account_id` keeps get nothing of its own penalty
+            paid.insert(asset.asset_address.clone(), *per_share);
+            *pool.unpaid.entry(asset.asset_address.clone()).or_insert(0) += asset.amount.0;
+        }
+    }
+
+    /// Penalties `account_id` is owed since it was last paid.
+    fn owed_penalties(&self, account_id: &AccountId) -> Vec<(String, u128)> {
+        if self.segregated_accounts.contains(account_id) {
+            return Vec::new();
+        }
+        let shares = self.get_user_shares(account_id.clone()).0;
+        let paid = self.penalty_pool.paid.get(account_id);
+        self.penalty_pool
+            .per_share
+            .iter()
+            .map(|(asset_address, per_share)| {
+                let last = paid
+                    .and_then(|paid| paid.get(asset_address))
+                    .copied()
+                    .unwrap_or(0);
+                let owed = shares
+                    .checked_mul(per_share - last)
+                    .expect("Penalty overflow")
+                    / PENALTY_SCALE;
+                (asset_address.clone(), owed)
+            })
+            .filter(|(_, owed)| *owed > 0)
+            .collect()
+    }
+
+    /// Pays `account_id` the penalties it is owed. Called before its shares change, and
+    /// before its balances are moved as a whole.
+    pub(crate) fn settle_penalties(&mut self, account_id: &AccountId) {
+        if self.penalty_pool.per_share.is_empty() {
+            return;
+        }
+        let owed = self.owed_penalties(account_id);
+        self.penalty_pool
+            .paid
+            .insert(account_id.clone(), self.penalty_pool.per_share.clone());
+        let balances = self.user_balances.entry(account_id.clone()).or_default();
+        for (asset_address, amount) in owed {
+            balances.entry(asset_address.clone()).or_insert(U128(0)).0 += amount;
+            let unpaid = self.penalty_pool.unpaid.entry(asset_address).or_insert(0);
+            *unpaid = unpaid.saturating_sub(amount);
+        }
+    }
+
+    /// Balances of `account_id` with the penalties it is owed.
+    pub(crate) fn settled_balances(&self, account_id: &AccountId) -> Option<HashMap<String, U128>> {
+        let mut balances = self.user_balances.get(account_id).cloned();
+        for (asset_address, amount) in self.owed_penalties(account_id) {
+            balances
+                .get_or_insert_with(HashMap::new)
+                .entry(asset_address)
+                .or_insert(U128(0))
+                .0 += amount;
+        }
+        balances
+    }
+
+    pub(crate) fn assert_no_cooldown(&self) {
+        assert!(
+            self.withdrawal_policy.cooldown_sec == 0,
+            "Withdrawals must be requested with request_withdrawal first"
+        );
+    }
+
+    fn take_pending_withdrawal(&mut self, account_id: &AccountId) -> RedeemPreview {
+        let pending = self
+            .pending_withdrawals
+            .remove(account_id)
+            .expect("No pending withdrawal");
+        assert!(
+            env::block_timestamp() >= pending.claimable_at,
+            "Withdrawal can be claimed at {}",
+            pending.claimable_at
+        );
+        pending.preview
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_withdrawal_policy(&mut self, policy: WithdrawalPolicy) {
+        self.assert_owner();
+        assert!(
+            policy.early_exit_penalty_bps <= MAX_BPS,
+            "Penalty can't exceed {} basis points",
+            MAX_BPS
+        );
+        self.withdrawal_policy = policy;
+    }
+
+    pub fn get_withdrawal_policy(&self) -> WithdrawalPolicy {
+        self.withdrawal_policy.clone()
+    }
+
+    /// Every deposit of `account_id` still backing shares, with the time it unlocks.
+    pub fn get_deposit_lots(&self, account_id: AccountId) -> Vec<DepositLotView> {
+        let lockup_ns = self.lockup_ns();
+        let now = env::block_timestamp();
+        self.deposit_lots
+            .get(&account_id)
+            .map(|lots| {
+                lots.iter()
+                    .map(|lot| {
+                        let unlocks_at = lot.deposited_at.saturating_add(lockup_ns);
+                        DepositLotView {
+                            shares: lot.shares,
+                            deposited_at: lot.deposited_at,
+                            unlocks_at,
+                            locked: unlocks_at > now,
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Starts the cooldown of a withdrawal. The shares are burned, and any early exit
+    /// penalty is paid, right away so the amounts can't change until the claim.
+    pub fn request_withdrawal(&mut self, amount: RedeemAmount) -> PendingWithdrawal {
+        self.assert_not_frozen();
+        let account_id = env::predecessor_account_id();
+        assert!(
+            !self.pending_withdrawals.contains_key(&account_id),
+            "A withdrawal is already pending"
+        );
+
+        let preview = self.preview_redeem(account_id.clone(), amount);
+        let lots = self.debit_redemption(&account_id, &preview);
+
+        let requested_at = env::block_timestamp();
+        let pending = PendingWithdrawal {
+            preview,
+            lots,
+            requested_at,
+            claimable_at: requested_at
+                .saturating_add(self.withdrawal_policy.cooldown_sec.saturating_mul(NANOS_PER_SEC)),
+        };
+        self.pending_withdrawals
+            .insert(account_id, pending.clone());
+        pending
+    }
+
+    /// Gives the shares of the pending withdrawal back in their original deposit lots,
+    /// so they unlock when they would have. A paid penalty is not refunded.
+    pub fn cancel_withdrawal(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let pending = self
+            .pending_withdrawals
+            .remove(&account_id)
+            .expect("No pending withdrawal");
+        let restored = self.credit_redemption(&account_id, &pending.preview, pending.lots);
+        self.record_redemption_flow(&account_id, pending.preview.shares.0 - restored, 0);
+    }
+
+    /// Withdraws the pending assets in kind, without the preview's share of the lending interest.
+    #[payable]
+    pub fn claim_withdrawal(&mut self, request: WithdrawRequest) -> Promise {
+        self.assert_not_frozen();
+        let account_id = env::predecessor_account_id();
+        let preview = self.take_pending_withdrawal(&account_id);
+        self.sign_withdrawals(&account_id, preview, &request)
+    }
+
+    /// Pays the pending assets out in USDC like `redeem_at_oracle_price`.
+    #[payable]
+    pub fn claim_withdrawal_at_oracle_price(
+        &mut self,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<bool> {
+        self.assert_not_frozen();
+        let account_id = env::predecessor_account_id();
+        let preview = self.take_pending_withdrawal(&account_id);
+        self.pay_out_usdc(account_id, preview, min_amount_out)
+    }
+
+    pub fn get_pending_withdrawal(&self, account_id: AccountId) -> Option<PendingWithdrawal> {
+        self.pending_withdrawals.get(&account_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lot(shares: u128, deposited_at: u64) -> DepositLot {
+        DepositLot {
+            shares: U128(shares),
+            deposited_at,
+        }
+    }
+
+    #[test]
+    fn test_locked_shares() {
+        let lots = vec![lot(100, 0), lot(50, 10)];
+        assert_eq!(locked_shares(&lots, 20, 15), 150);
+        assert_eq!(locked_shares(&lots, 20, 25), 50);
+        assert_eq!(locked_shares(&lots, 20, 30), 0);
+    }
+
+    #[test]
+    fn test_take_unlocked_lots_first() {
+        let mut lots = vec![lot(100, 0), lot(50, 10)];
+        assert_eq!(take_lots(&mut lots, 120), vec![lot(100, 0), lot(20, 10)]);
+        assert_eq!(lots, vec![lot(30, 10)]);
+    }
+
+    #[test]
+    fn test_restore_lots_less_penalty() {
+        let mut lots = vec![lot(30, 10), lot(40, 20)];
+        let taken = take_lots(&mut lots, 50);
+        assert_eq!(lots, vec![lot(20, 20)]);
+
+        restore_lots(&mut lots, taken, 5);
+        assert_eq!(lots, vec![lot(30, 10), lot(20, 20), lot(15, 20)]);
+    }
+}
@@ -0,0 +1,30 @@
+use near_sdk::{env, near_bindgen};
+
+pub use fund_common::metadata::*;
+
+use crate::{Contract, ContractExt};
+
+impl Contract {
+    pub(crate) fn assert_creator(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.metadata.creator,
+            "Only the creator can call this method"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_metadata(&self) -> FundMetadata {
+        self.metadata.clone()
+    }
+
+    pub fn update_metadata(&mut self, update: FundMetadataUpdate) -> FundMetadata {
+        self.assert_creator();
+        if let Err(err) = self.metadata.apply(update) {
+            env::panic_str(&format!("Invalid fund metadata: {}", err));
+        }
+        self.metadata.clone()
+    }
+}
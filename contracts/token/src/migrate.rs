@@ -0,0 +1,1222 @@
+//! State migrations of the token contract.
+//!
+//! The state is stored as a plain `Contract` under `STATE`, so `#[near_bindgen]` reads
+//! it without an enum tag in front. Which layout it is in is stored apart, as one byte
+//! under `STATE_VERSION`, written by `new` and by every `migrate`. States from before
+//! that key existed are told apart by which layout they parse as, Borsh only accepts a
+//! layout that takes up the stored bytes exactly.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::store::{IterableSet, LookupMap};
+use near_sdk::{env, near_bindgen, AccountId, PublicKey};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::components::{ComponentRemoval, WindDown, WindDownMode};
+use crate::governance::{
+    AssetRebalance, AssetWeight, Governance, ProposalStatus, WeightProposal, DEFAULT_TIMELOCK_SEC,
+};
+use crate::kdf::default_mpc_root_public_key;
+use crate::price_store::usdc_to_value;
+use crate::queue::{BatchStatus, BatchTransfer, QueuedWithdrawal};
+use crate::redeem::AssetAmount;
+use crate::{
+    AccountFlows, AssetInfo, BitcoinCustody, Chain, Contract, ContractExt, DepositLimits,
+    DepositLot, EpochStatus, EvmAddress, FundAnalytics, FundMetadata, FundMetadataArgs, Lending,
+    NavSnapshot, OracleConfig, PenaltyPool, PendingWithdrawal, Prefix, PriceStore, RedeemPreview,
+    SwapRoute, SwapVenue, WithdrawalBatch, WithdrawalEpoch, WithdrawalPolicy, WithdrawalQueue,
+};
+
+/// Version of the layout `Contract` is stored in. Bump it with every layout change and
+/// add the previous layout to `VersionedContract`.
+pub const STATE_VERSION: u8 = 4;
+const STATE_KEY: &[u8] = b"STATE";
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+/// Accounts `convert_usdc_balances` converts when no limit is given
+const DEFAULT_CONVERT_LIMIT: u64 = 50;
+
+/// Percent weights were stored as `u8`, basis points are a hundred times finer.
+const BPS_PER_PERCENT: u32 = 100;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct AssetInfoV0 {
+    name: String,
+    contract_address: String,
+    weight: u8,
+}
+
+/// Layout of the contract as first deployed.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ContractV0 {
+    total_assets: U128,
+    assets: Vec<AssetInfoV0>,
+    owner_id: AccountId,
+    user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    usdc_contract: AccountId,
+    oracle_contract: AccountId,
+    latest_signed_txs: Vec<Vec<u8>>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldAssetInfo {
+    name: String,
+    contract_address: Option<EvmAddress>,
+    weight: u8,
+    chain: Option<Chain>,
+}
+
+impl From<OldAssetInfo> for AssetInfo {
+    fn from(old: OldAssetInfo) -> Self {
+        Self {
+            name: old.name,
+            contract_address: old.contract_address,
+            weight: old.weight as u32 * BPS_PER_PERCENT,
+            chain: old.chain,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldAssetWeight {
+    asset_address: String,
+    weight: u8,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldWeightProposal {
+    id: u64,
+    proposer: AccountId,
+    weights: Vec<OldAssetWeight>,
+    added: Vec<OldAssetInfo>,
+    removed: Vec<ComponentRemoval>,
+    proposed_at: u64,
+    executable_at: u64,
+    status: ProposalStatus,
+    executed_at: Option<u64>,
+    rebalance: Vec<AssetRebalance>,
+}
+
+impl From<OldWeightProposal> for WeightProposal {
+    fn from(old: OldWeightProposal) -> Self {
+        Self {
+            id: old.id,
+            proposer: old.proposer,
+            weights: old
+                .weights
+                .into_iter()
+                .map(|w| AssetWeight {
+                    asset_address: w.asset_address,
+                    weight: w.weight as u32 * BPS_PER_PERCENT,
+                })
+                .collect(),
+            added: old.added.into_iter().map(AssetInfo::from).collect(),
+            removed: old.removed,
+            proposed_at: old.proposed_at,
+            executable_at: old.executable_at,
+            status: old.status,
+            executed_at: old.executed_at,
+            rebalance: old.rebalance,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldGovernance {
+    timelock_sec: u64,
+    proposals: Vec<OldWeightProposal>,
+}
+
+/// Governance while proposals were kept in the contract's own state and executing one
+/// rebalanced every holder at once.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct GovernanceV3 {
+    timelock_sec: u64,
+    proposals: Vec<WeightProposal>,
+}
+
+impl From<GovernanceV3> for Governance {
+    fn from(old: GovernanceV3) -> Self {
+        let mut governance = Self {
+            timelock_sec: old.timelock_sec,
+            ..Self::default()
+        };
+        governance.proposals.extend(old.proposals);
+        governance
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldWindDown {
+    asset: OldAssetInfo,
+    mode: WindDownMode,
+    started_at: u64,
+    remaining: U128,
+    completed_at: Option<u64>,
+}
+
+/// Redemption preview before it carried the lending interest.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct RedeemPreviewV3 {
+    shares: U128,
+    remaining_shares: U128,
+    assets: Vec<AssetAmount>,
+    penalty_shares: U128,
+    penalty: Vec<AssetAmount>,
+}
+
+impl From<RedeemPreviewV3> for RedeemPreview {
+    fn from(old: RedeemPreviewV3) -> Self {
+        Self {
+            shares: old.shares,
+            remaining_shares: old.remaining_shares,
+            assets: old.assets,
+            penalty_shares: old.penalty_shares,
+            penalty: old.penalty,
+            interest: U128(0),
+        }
+    }
+}
+
+/// Pending withdrawal before it kept the deposit lots its shares were taken from.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct PendingWithdrawalV3 {
+    preview: RedeemPreviewV3,
+    requested_at: u64,
+    claimable_at: u64,
+}
+
+impl From<PendingWithdrawalV3> for PendingWithdrawal {
+    fn from(old: PendingWithdrawalV3) -> Self {
+        Self {
+            preview: old.preview.into(),
+            // Without them a cancelled withdrawal gives its shares back unlocked
+            lots: Vec::new(),
+            requested_at: old.requested_at,
+            claimable_at: old.claimable_at,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct QueuedWithdrawalV3 {
+    account_id: AccountId,
+    eth_destination: EvmAddress,
+    aurora_destination: EvmAddress,
+    preview: RedeemPreviewV3,
+    claimed: bool,
+}
+
+impl From<QueuedWithdrawalV3> for QueuedWithdrawal {
+    fn from(old: QueuedWithdrawalV3) -> Self {
+        Self {
+            account_id: old.account_id,
+            eth_destination: old.eth_destination,
+            aurora_destination: old.aurora_destination,
+            preview: old.preview.into(),
+            claimed: old.claimed,
+        }
+    }
+}
+
+/// Withdrawal batch while every destination got a batch of its own.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct WithdrawalBatchV3 {
+    asset_address: String,
+    destination: EvmAddress,
+    amount: U128,
+    status: BatchStatus,
+    signed_tx: Option<String>,
+}
+
+impl From<WithdrawalBatchV3> for WithdrawalBatch {
+    fn from(old: WithdrawalBatchV3) -> Self {
+        Self {
+            asset_address: old.asset_address,
+            transfers: vec![BatchTransfer {
+                destination: old.destination,
+                amount: old.amount,
+            }],
+            amount: old.amount,
+            status: old.status,
+            signed_txs: old.signed_tx.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct WithdrawalEpochV3 {
+    id: u64,
+    status: EpochStatus,
+    opened_at: u64,
+    closed_at: Option<u64>,
+    requests: Vec<QueuedWithdrawalV3>,
+    batches: Vec<WithdrawalBatchV3>,
+}
+
+impl From<WithdrawalEpochV3> for WithdrawalEpoch {
+    fn from(old: WithdrawalEpochV3) -> Self {
+        Self {
+            id: old.id,
+            status: old.status,
+            opened_at: old.opened_at,
+            closed_at: old.closed_at,
+            requests: old.requests.into_iter().map(QueuedWithdrawal::from).collect(),
+            batches: old.batches.into_iter().map(WithdrawalBatch::from).collect(),
+        }
+    }
+}
+
+/// Withdrawal queue before batches were paid through dispersers.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct WithdrawalQueueV3 {
+    epoch_duration_sec: u64,
+    current_epoch: u64,
+    epochs: HashMap<u64, WithdrawalEpochV3>,
+}
+
+impl Default for WithdrawalQueueV3 {
+    fn default() -> Self {
+        let queue = WithdrawalQueue::default();
+        Self {
+            epoch_duration_sec: queue.epoch_duration_sec,
+            current_epoch: queue.current_epoch,
+            epochs: HashMap::new(),
+        }
+    }
+}
+
+impl From<WithdrawalQueueV3> for WithdrawalQueue {
+    /// Epochs move out of the root state into their own storage entries.
+    fn from(old: WithdrawalQueueV3) -> Self {
+        let mut epochs = LookupMap::new(Prefix::Epochs);
+        for (id, epoch) in old.epochs {
+            epochs.insert(id, epoch.into());
+        }
+        Self {
+            epoch_duration_sec: old.epoch_duration_sec,
+            current_epoch: old.current_epoch,
+            epochs,
+            dispersers: Vec::new(),
+        }
+    }
+}
+
+/// Swap venue while a swap was measured on the contract's balance, one per component.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct SwapVenueV3 {
+    amm: Option<AccountId>,
+    max_slippage_bps: u32,
+    routes: HashMap<String, SwapRoute>,
+    in_flight: HashSet<String>,
+}
+
+impl Default for SwapVenueV3 {
+    fn default() -> Self {
+        let venue = SwapVenue::default();
+        Self {
+            amm: venue.amm,
+            max_slippage_bps: venue.max_slippage_bps,
+            routes: venue.routes,
+            in_flight: HashSet::new(),
+        }
+    }
+}
+
+impl From<SwapVenueV3> for SwapVenue {
+    /// Version 3 didn't keep what a swap in flight had spent, nor can its callbacks be
+    /// taken over. The upgrade has to wait until they are back. It also credited slices
+    /// whose swap failed at the oracle price for the keeper to buy, while a route now means
+    /// the component is held on NEAR only, so the keeper moves those onto NEAR first.
+    fn from(old: SwapVenueV3) -> Self {
+        if !old.in_flight.is_empty() {
+            env::panic_str(&format!(
+                "Swaps of {:?} are still in flight, upgrade once they are back",
+                old.in_flight
+            ));
+        }
+        Self {
+            amm: old.amm,
+            max_slippage_bps: old.max_slippage_bps,
+            routes: old.routes,
+            in_flight: HashMap::new(),
+            queued: VecDeque::new(),
+            with_amm: HashMap::new(),
+        }
+    }
+}
+
+/// Analytics while only the benchmark level was kept where the benchmark started.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct FundAnalyticsV3 {
+    snapshot_interval_sec: u64,
+    risk_free_rate_bps: u32,
+    benchmark: Option<String>,
+    first: Option<NavSnapshot>,
+    recent: Vec<NavSnapshot>,
+    benchmark_start: Option<U128>,
+    peak_nav_per_share: u128,
+    max_drawdown_bps: u32,
+    periods: u64,
+    mean_return: i128,
+    m2: u128,
+}
+
+impl Default for FundAnalyticsV3 {
+    fn default() -> Self {
+        let analytics = FundAnalytics::default();
+        Self {
+            snapshot_interval_sec: analytics.snapshot_interval_sec,
+            risk_free_rate_bps: analytics.risk_free_rate_bps,
+            benchmark: None,
+            first: None,
+            recent: Vec::new(),
+            benchmark_start: None,
+            peak_nav_per_share: 0,
+            max_drawdown_bps: 0,
+            periods: 0,
+            mean_return: 0,
+            m2: 0,
+        }
+    }
+}
+
+impl From<FundAnalyticsV3> for FundAnalytics {
+    /// The start is the first kept snapshot with the stored level. Once it has rolled out
+    /// of the kept ones the comparison starts over at the next snapshot.
+    fn from(old: FundAnalyticsV3) -> Self {
+        let benchmark_start = old.benchmark_start.and_then(|level| {
+            old.first
+                .iter()
+                .chain(&old.recent)
+                .find(|snapshot| snapshot.benchmark_level == Some(level))
+                .cloned()
+        });
+        Self {
+            snapshot_interval_sec: old.snapshot_interval_sec,
+            risk_free_rate_bps: old.risk_free_rate_bps,
+            benchmark: old.benchmark,
+            first: old.first,
+            recent: old.recent,
+            benchmark_start,
+            peak_nav_per_share: old.peak_nav_per_share,
+            max_drawdown_bps: old.max_drawdown_bps,
+            periods: old.periods,
+            mean_return: old.mean_return,
+            m2: old.m2,
+        }
+    }
+}
+
+/// Layout of the contract while weights were whole percents.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ContractV1 {
+    total_assets: U128,
+    assets: Vec<OldAssetInfo>,
+    owner_id: AccountId,
+    user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    usdc_contract: AccountId,
+    oracle_config: OracleConfig,
+    price_store: PriceStore,
+    latest_signed_txs: Vec<Vec<u8>>,
+    bitcoin: Option<BitcoinCustody>,
+    mpc_root_public_key: Option<PublicKey>,
+    segregated_accounts: HashSet<AccountId>,
+    user_shares: HashMap<AccountId, U128>,
+    pending_sales: HashMap<String, U128>,
+    withdrawal_policy: WithdrawalPolicy,
+    deposit_lots: HashMap<AccountId, Vec<DepositLot>>,
+    pending_withdrawals: HashMap<AccountId, PendingWithdrawalV3>,
+    withdrawal_queue: WithdrawalQueueV3,
+    deposit_limits: DepositLimits,
+    allowlist: HashSet<AccountId>,
+    metadata: FundMetadata,
+    governance: OldGovernance,
+    wind_downs: Vec<OldWindDown>,
+}
+
+/// Layout of the contract before the upgrader role.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ContractV2 {
+    total_assets: U128,
+    assets: Vec<AssetInfo>,
+    owner_id: AccountId,
+    user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    usdc_contract: AccountId,
+    oracle_config: OracleConfig,
+    price_store: PriceStore,
+    latest_signed_txs: Vec<Vec<u8>>,
+    bitcoin: Option<BitcoinCustody>,
+    mpc_root_public_key: Option<PublicKey>,
+    segregated_accounts: HashSet<AccountId>,
+    user_shares: HashMap<AccountId, U128>,
+    pending_sales: HashMap<String, U128>,
+    withdrawal_policy: WithdrawalPolicy,
+    deposit_lots: HashMap<AccountId, Vec<DepositLot>>,
+    pending_withdrawals: HashMap<AccountId, PendingWithdrawalV3>,
+    withdrawal_queue: WithdrawalQueueV3,
+    deposit_limits: DepositLimits,
+    allowlist: HashSet<AccountId>,
+    metadata: FundMetadata,
+    governance: GovernanceV3,
+    wind_downs: Vec<WindDown>,
+    lending: Lending,
+    swap_venue: SwapVenueV3,
+    account_flows: HashMap<AccountId, AccountFlows>,
+    analytics: FundAnalyticsV3,
+}
+
+/// Layout of the contract before BTC claims, batched withdrawals through dispersers,
+/// bounded rebalances, AMM swaps through deposits and the stored holder set.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ContractV3 {
+    total_assets: U128,
+    assets: Vec<AssetInfo>,
+    owner_id: AccountId,
+    user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    usdc_contract: AccountId,
+    oracle_config: OracleConfig,
+    price_store: PriceStore,
+    latest_signed_txs: Vec<Vec<u8>>,
+    bitcoin: Option<BitcoinCustody>,
+    mpc_root_public_key: Option<PublicKey>,
+    segregated_accounts: HashSet<AccountId>,
+    user_shares: HashMap<AccountId, U128>,
+    pending_sales: HashMap<String, U128>,
+    withdrawal_policy: WithdrawalPolicy,
+    deposit_lots: HashMap<AccountId, Vec<DepositLot>>,
+    pending_withdrawals: HashMap<AccountId, PendingWithdrawalV3>,
+    withdrawal_queue: WithdrawalQueueV3,
+    deposit_limits: DepositLimits,
+    allowlist: HashSet<AccountId>,
+    metadata: FundMetadata,
+    governance: GovernanceV3,
+    wind_downs: Vec<WindDown>,
+    lending: Lending,
+    swap_venue: SwapVenueV3,
+    account_flows: HashMap<AccountId, AccountFlows>,
+    analytics: FundAnalyticsV3,
+    upgrader: Option<AccountId>,
+}
+
+impl From<ContractV0> for ContractV1 {
+    /// Balances of the first deployment are the USDC of every deposit split by weight,
+    /// shares are backfilled from them as one unlocked lot per account. Their sum, what
+    /// was deposited less rounding, becomes `total_assets`.
+    fn from(old: ContractV0) -> Self {
+        let mut user_balances = old.user_balances;
+        let assets: Vec<OldAssetInfo> = old
+            .assets
+            .into_iter()
+            .map(|asset| {
+                let address = EvmAddress::from_legacy(&asset.contract_address)
+                    .unwrap_or_else(|err| env::panic_str(&err));
+                // Balances were keyed by the address as given, they are keyed like the asset now
+                for balances in user_balances.values_mut() {
+                    if let Some(balance) = balances.remove(&asset.contract_address) {
+                        balances.insert(address.to_string(), balance);
+                    }
+                }
+                OldAssetInfo {
+                    name: asset.name,
+                    contract_address: Some(address),
+                    weight: asset.weight,
+                    chain: None,
+                }
+            })
+            .collect();
+
+        let user_shares: HashMap<AccountId, U128> = user_balances
+            .iter()
+            .map(|(account_id, balances)| {
+                (account_id.clone(), U128(balances.values().map(|b| b.0).sum()))
+            })
+            .collect();
+        let deposit_lots = user_shares
+            .iter()
+            .map(|(account_id, shares)| {
+                let lot = DepositLot {
+                    shares: *shares,
+                    deposited_at: 0,
+                };
+                (account_id.clone(), vec![lot])
+            })
+            .collect();
+
+        Self {
+            total_assets: U128(user_shares.values().map(|shares| shares.0).sum()),
+            assets,
+            metadata: FundMetadata::new(FundMetadataArgs::default(), old.owner_id.clone()),
+            owner_id: old.owner_id,
+            user_balances,
+            usdc_contract: old.usdc_contract,
+            oracle_config: OracleConfig::price_oracle(old.oracle_contract),
+            price_store: PriceStore::default(),
+            latest_signed_txs: old.latest_signed_txs,
+            bitcoin: None,
+            mpc_root_public_key: None,
+            segregated_accounts: HashSet::new(),
+            user_shares,
+            pending_sales: HashMap::new(),
+            withdrawal_policy: WithdrawalPolicy::default(),
+            deposit_lots,
+            pending_withdrawals: HashMap::new(),
+            withdrawal_queue: WithdrawalQueueV3::default(),
+            deposit_limits: DepositLimits::default(),
+            allowlist: HashSet::new(),
+            governance: OldGovernance {
+                timelock_sec: DEFAULT_TIMELOCK_SEC,
+                proposals: Vec::new(),
+            },
+            wind_downs: Vec::new(),
+        }
+    }
+}
+
+impl From<ContractV1> for ContractV2 {
+    fn from(old: ContractV1) -> Self {
+        Self {
+            total_assets: old.total_assets,
+            assets: old.assets.into_iter().map(AssetInfo::from).collect(),
+            owner_id: old.owner_id,
+            user_balances: old.user_balances,
+            usdc_contract: old.usdc_contract,
+            oracle_config: old.oracle_config,
+            price_store: old.price_store,
+            latest_signed_txs: old.latest_signed_txs,
+            bitcoin: old.bitcoin,
+            mpc_root_public_key: old.mpc_root_public_key,
+            segregated_accounts: old.segregated_accounts,
+            user_shares: old.user_shares,
+            pending_sales: old.pending_sales,
+            withdrawal_policy: old.withdrawal_policy,
+            deposit_lots: old.deposit_lots,
+            pending_withdrawals: old.pending_withdrawals,
+            withdrawal_queue: old.withdrawal_queue,
+            deposit_limits: old.deposit_limits,
+            allowlist: old.allowlist,
+            metadata: old.metadata,
+            governance: GovernanceV3 {
+                timelock_sec: old.governance.timelock_sec,
+                proposals: old
+                    .governance
+                    .proposals
+                    .into_iter()
+                    .map(WeightProposal::from)
+                    .collect(),
+            },
+            wind_downs: old
+                .wind_downs
+                .into_iter()
+                .map(|w| WindDown {
+                    asset: w.asset.into(),
+                    mode: w.mode,
+                    started_at: w.started_at,
+                    remaining: w.remaining,
+                    completed_at: w.completed_at,
+                })
+                .collect(),
+            lending: Lending::default(),
+            swap_venue: SwapVenueV3::default(),
+            account_flows: HashMap::new(),
+            analytics: FundAnalyticsV3::default(),
+        }
+    }
+}
+
+impl From<ContractV2> for ContractV3 {
+    fn from(old: ContractV2) -> Self {
+        Self {
+            total_assets: old.total_assets,
+            assets: old.assets,
+            owner_id: old.owner_id,
+            user_balances: old.user_balances,
+            usdc_contract: old.usdc_contract,
+            oracle_config: old.oracle_config,
+            price_store: old.price_store,
+            latest_signed_txs: old.latest_signed_txs,
+            bitcoin: old.bitcoin,
+            mpc_root_public_key: old.mpc_root_public_key,
+            segregated_accounts: old.segregated_accounts,
+            user_shares: old.user_shares,
+            pending_sales: old.pending_sales,
+            withdrawal_policy: old.withdrawal_policy,
+            deposit_lots: old.deposit_lots,
+            pending_withdrawals: old.pending_withdrawals,
+            withdrawal_queue: old.withdrawal_queue,
+            deposit_limits: old.deposit_limits,
+            allowlist: old.allowlist,
+            metadata: old.metadata,
+            governance: old.governance,
+            wind_downs: old.wind_downs,
+            lending: old.lending,
+            swap_venue: old.swap_venue,
+            account_flows: old.account_flows,
+            analytics: old.analytics,
+            upgrader: None,
+        }
+    }
+}
+
+impl From<ContractV3> for Contract {
+    /// Up to version 3 balances held the USDC every deposit put into a component, they
+    /// hold units of it now. They are set aside in `usdc_balances` until
+    /// `convert_usdc_balances` runs at fresh prices, a first deployment has no prices at
+    /// all. Deposits and withdrawals wait for it, so nobody trades against a balance in
+    /// the wrong unit. It also adds every account to `holders`, a page at a time.
+    fn from(old: ContractV3) -> Self {
+        let segregated_shares = old
+            .segregated_accounts
+            .iter()
+            .filter_map(|account_id| old.user_shares.get(account_id))
+            .map(|shares| shares.0)
+            .sum();
+        let mut usdc_balances = old.user_balances;
+        for account_id in old.user_shares.keys() {
+            usdc_balances.entry(account_id.clone()).or_default();
+        }
+        let mut contract = Self {
+            total_assets: old.total_assets,
+            assets: old.assets,
+            owner_id: old.owner_id,
+            user_balances: HashMap::new(),
+            usdc_balances,
+            usdc_contract: old.usdc_contract,
+            oracle_config: old.oracle_config,
+            price_store: old.price_store,
+            latest_signed_txs: old.latest_signed_txs,
+            bitcoin: old.bitcoin,
+            btc_claims: HashMap::new(),
+            // Funds that never set the key sign with the default signer's
+            mpc_root_public_key: old
+                .mpc_root_public_key
+                .unwrap_or_else(default_mpc_root_public_key),
+            segregated_accounts: old.segregated_accounts,
+            segregated_shares: U128(segregated_shares),
+            user_shares: old.user_shares,
+            pending_sales: old.pending_sales,
+            usdc_redemptions: HashMap::new(),
+            usdc_claims: HashMap::new(),
+            withdrawal_policy: old.withdrawal_policy,
+            deposit_lots: old.deposit_lots,
+            penalty_pool: PenaltyPool::default(),
+            pending_withdrawals: old
+                .pending_withdrawals
+                .into_iter()
+                .map(|(account_id, pending)| (account_id, pending.into()))
+                .collect(),
+            withdrawal_signings: HashMap::new(),
+            withdrawal_queue: old.withdrawal_queue.into(),
+            deposit_limits: old.deposit_limits,
+            allowlist: old.allowlist,
+            metadata: old.metadata,
+            governance: old.governance.into(),
+            wind_downs: old.wind_downs,
+            lending: old.lending,
+            swap_venue: old.swap_venue.into(),
+            account_flows: old.account_flows,
+            analytics: old.analytics.into(),
+            upgrader: old.upgrader,
+            holders: IterableSet::new(Prefix::Holders),
+        };
+        contract.fill_pending_interest();
+        contract.convert_debited_amounts();
+        contract
+    }
+}
+
+impl Contract {
+    /// Units of `asset_address` that `usdc` of a version 3 amount buys at the last cached
+    /// price, however old.
+    fn cached_units(&self, asset_address: &str, usdc: u128) -> u128 {
+        self.price_store
+            .prices
+            .get(asset_address)
+            .unwrap_or_else(|| {
+                env::panic_str(&format!(
+                    "No price is cached for asset {}, its amounts can't be converted to units",
+                    asset_address
+                ))
+            })
+            .amount_for(usdc_to_value(usdc))
+    }
+
+    /// Version 3 kept what withdrawals and wind-downs still owe in USDC like the
+    /// balances. They are paid out before long, so they are converted at the last cached
+    /// price instead of waiting for `convert_usdc_balances`. Epochs that were closed keep
+    /// their batches as netted and signed, only the current one can still be open.
+    fn convert_debited_amounts(&mut self) {
+        let current_epoch = self.withdrawal_queue.current_epoch;
+        let mut pending_withdrawals = std::mem::take(&mut self.pending_withdrawals);
+        let mut open_epoch = self.withdrawal_queue.epochs.remove(&current_epoch);
+        let mut wind_downs = std::mem::take(&mut self.wind_downs);
+        let previews = pending_withdrawals
+            .values_mut()
+            .map(|pending| &mut pending.preview)
+            .chain(
+                open_epoch
+                    .iter_mut()
+                    .filter(|epoch| epoch.status == EpochStatus::Open)
+                    .flat_map(|epoch| epoch.requests.iter_mut())
+                    .map(|request| &mut request.preview),
+            );
+        for preview in previews {
+            for asset in &mut preview.assets {
+                asset.amount = U128(self.cached_units(&asset.asset_address, asset.amount.0));
+            }
+        }
+        for wind_down in wind_downs.iter_mut().filter(|w| w.remaining.0 > 0) {
+            wind_down.remaining =
+                U128(self.cached_units(&wind_down.asset.key(), wind_down.remaining.0));
+        }
+        self.pending_withdrawals = pending_withdrawals;
+        if let Some(epoch) = open_epoch {
+            self.withdrawal_queue.epochs.insert(current_epoch, epoch);
+        }
+        self.wind_downs = wind_downs;
+    }
+
+    /// Units of `asset_address` that a version 3 balance of `usdc` buys, at a fresh price
+    /// for a component and at the last cached one for an asset wound down in kind.
+    fn units_of_usdc_balance(&self, asset_address: &str, usdc: u128, now: u64) -> u128 {
+        if self.assets.iter().any(|asset| asset.key() == asset_address) {
+            self.price_store.amount_for(asset_address, usdc_to_value(usdc), now)
+        } else {
+            self.cached_units(asset_address, usdc)
+        }
+    }
+
+    /// Version 3 worked a pending withdrawal's interest out when it was claimed, from
+    /// shares already burned. It is worked out the same way for the preview now.
+    fn fill_pending_interest(&mut self) {
+        let interests: Vec<(AccountId, u128)> = self
+            .pending_withdrawals
+            .iter()
+            .map(|(account_id, pending)| {
+                let preview = &pending.preview;
+                let interest = self.interest_share(
+                    preview.shares.0 - preview.penalty_shares.0,
+                    self.total_assets.0 + preview.shares.0,
+                );
+                (account_id.clone(), interest)
+            })
+            .collect();
+        for (account_id, interest) in interests {
+            if let Some(pending) = self.pending_withdrawals.get_mut(&account_id) {
+                pending.preview.interest = U128(interest);
+            }
+        }
+    }
+}
+
+/// Every layout the contract's state has been stored in, oldest first.
+enum VersionedContract {
+    V0(ContractV0),
+    V1(ContractV1),
+    V2(ContractV2),
+    V3(ContractV3),
+    V4(Contract),
+}
+
+impl VersionedContract {
+    /// Reads the stored state in the layout it was written in. States from before the
+    /// version was stored are told apart by which layout they parse as.
+    fn read() -> Self {
+        let state = env::storage_read(STATE_KEY).expect("Contract is not initialized");
+        match stored_state_version() {
+            Some(4) => Contract::try_from_slice(&state).map(Self::V4),
+            Some(3) => ContractV3::try_from_slice(&state).map(Self::V3),
+            Some(version) => env::panic_str(&format!("Unknown state version {}", version)),
+            None => ContractV2::try_from_slice(&state)
+                .map(Self::V2)
+                .or_else(|_| ContractV1::try_from_slice(&state).map(Self::V1))
+                .or_else(|_| ContractV0::try_from_slice(&state).map(Self::V0)),
+        }
+        .unwrap_or_else(|_| env::panic_str("State doesn't match its layout"))
+    }
+
+    fn version(&self) -> u8 {
+        match self {
+            Self::V0(_) => 0,
+            Self::V1(_) => 1,
+            Self::V2(_) => 2,
+            Self::V3(_) => 3,
+            Self::V4(_) => 4,
+        }
+    }
+
+    /// Runs every migration from the stored layout up to the current one.
+    fn migrate(self) -> Contract {
+        match self {
+            Self::V0(old) => Self::V1(old.into()).migrate(),
+            Self::V1(old) => Self::V2(old.into()).migrate(),
+            Self::V2(old) => Self::V3(old.into()).migrate(),
+            Self::V3(old) => old.into(),
+            Self::V4(contract) => contract,
+        }
+    }
+}
+
+fn stored_state_version() -> Option<u8> {
+    env::storage_read(STATE_VERSION_KEY).and_then(|version| version.first().copied())
+}
+
+/// Records that the state is stored in the current layout.
+pub(crate) fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &[STATE_VERSION]);
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Brings the state of any earlier version to the current layout, a no-op when it is
+    /// current already. `upgrade` calls it right after deploying new code, a deployment
+    /// from before `upgrade` deploys the new code with its own key and calls it in the
+    /// same transaction.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = VersionedContract::read();
+        env::log_str(&format!(
+            "Migrating state from version {} to {}",
+            state.version(),
+            STATE_VERSION
+        ));
+        let contract = state.migrate();
+        write_state_version();
+        contract
+    }
+
+    pub fn get_state_version(&self) -> u8 {
+        stored_state_version().unwrap_or(STATE_VERSION)
+    }
+
+    /// Converts the USDC balances of up to `limit` accounts carried over by `migrate` into
+    /// units at fresh prices, and adds the accounts to `holders`. Anyone can call it once
+    /// prices are refreshed, it returns how many accounts are left.
+    pub fn convert_usdc_balances(&mut self, limit: Option<u64>) -> u64 {
+        let now = env::block_timestamp();
+        let accounts: Vec<AccountId> = self
+            .usdc_balances
+            .keys()
+            .take(limit.unwrap_or(DEFAULT_CONVERT_LIMIT) as usize)
+            .cloned()
+            .collect();
+        for account_id in accounts {
+            let usdc_balances = self.usdc_balances.remove(&account_id).unwrap();
+            let units: HashMap<String, U128> = usdc_balances
+                .into_iter()
+                .map(|(asset_address, usdc)| {
+                    let units = self.units_of_usdc_balance(&asset_address, usdc.0, now);
+                    (asset_address, U128(units))
+                })
+                .collect();
+            if !units.is_empty() {
+                self.user_balances.insert(account_id.clone(), units);
+            }
+            self.sync_holder(&account_id);
+        }
+        self.usdc_balances.len() as u64
+    }
+
+    pub fn get_usdc_balance_count(&self) -> u64 {
+        self.usdc_balances.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redeem::MAX_BPS;
+    use crate::{PriceFeedInfo, RedeemAmount};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+    const DESTINATION: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract.process_deposit(accounts(1), U128(1_000));
+        contract
+    }
+
+    /// The first deployment after a deposit of 1000 USDC by `accounts(1)`, with the
+    /// addresses stored in lower case.
+    fn v0_state() -> ContractV0 {
+        let asset = |name: &str, address: &str, weight| AssetInfoV0 {
+            name: name.to_string(),
+            contract_address: address.to_lowercase(),
+            weight,
+        };
+        let balances = HashMap::from([
+            (ETH.to_lowercase(), U128(700)),
+            (AURORA.to_lowercase(), U128(300)),
+        ]);
+        ContractV0 {
+            total_assets: U128(1_000),
+            assets: vec![asset("ETH", ETH, 70), asset("AURORA", AURORA, 30)],
+            owner_id: accounts(0),
+            user_balances: HashMap::from([(accounts(1), balances)]),
+            usdc_contract: accounts(2),
+            oracle_contract: accounts(3),
+            latest_signed_txs: Vec::new(),
+        }
+    }
+
+    fn v3_state() -> ContractV3 {
+        ContractV2::from(ContractV1::from(v0_state())).into()
+    }
+
+    fn preview_v3() -> RedeemPreviewV3 {
+        RedeemPreviewV3 {
+            shares: U128(500),
+            remaining_shares: U128(500),
+            assets: vec![AssetAmount {
+                asset_address: ETH.to_string(),
+                amount: U128(350),
+            }],
+            penalty_shares: U128(0),
+            penalty: Vec::new(),
+        }
+    }
+
+    /// Caches `asset_address` at `usd` USD with 18 decimals, as the NEAR priceoracle
+    /// reports it, fresh at the test's time.
+    fn cache_price(price_store: &mut PriceStore, asset_address: &str, usd: u128) {
+        price_store.prices.insert(
+            asset_address.to_string(),
+            PriceFeedInfo {
+                asset_address: asset_address.to_string(),
+                price: U128(usd * 10_000),
+                decimals: 22,
+                last_updated: 0,
+            },
+        );
+    }
+
+    fn write_state(state: &impl BorshSerialize, version: Option<u8>) {
+        env::storage_write(STATE_KEY, &borsh::to_vec(state).unwrap());
+        match version {
+            Some(version) => env::storage_write(STATE_VERSION_KEY, &[version]),
+            None => env::storage_remove(STATE_VERSION_KEY),
+        };
+    }
+
+    #[test]
+    fn test_percent_weights_become_basis_points() {
+        let asset: AssetInfo = OldAssetInfo {
+            name: "ETH".to_string(),
+            contract_address: None,
+            weight: 70,
+            chain: Some(Chain::Ethereum),
+        }
+        .into();
+        assert_eq!(asset.weight, 7_000);
+    }
+
+    #[test]
+    fn test_migrate_from_first_deployment() {
+        write_state(&v0_state(), None);
+
+        let migrated = Contract::migrate();
+        let weights: Vec<(String, u32)> = migrated
+            .get_assets()
+            .iter()
+            .map(|asset| (asset.key(), asset.weight))
+            .collect();
+        assert_eq!(weights, vec![(ETH.to_string(), 7_000), (AURORA.to_string(), 3_000)]);
+        // The USDC put into each component waits for prices to become units
+        assert_eq!(migrated.get_user_balance(&accounts(1)), None);
+        assert_eq!(migrated.usdc_balances[&accounts(1)][ETH], U128(700));
+        assert_eq!(migrated.usdc_balances[&accounts(1)][AURORA], U128(300));
+        assert_eq!(migrated.get_user_shares(accounts(1)), U128(1_000));
+        assert_eq!(migrated.total_assets, U128(1_000));
+        assert_eq!(
+            migrated.deposit_lots[&accounts(1)],
+            vec![DepositLot {
+                shares: U128(1_000),
+                deposited_at: 0,
+            }]
+        );
+        assert_eq!(migrated.get_oracle_contract(), accounts(3));
+        // Holders are added as their balances are converted
+        assert_eq!(migrated.get_holder_count(), 0);
+        assert_eq!(stored_state_version(), Some(STATE_VERSION));
+    }
+
+    #[test]
+    fn test_usdc_balances_become_units_at_fresh_prices() {
+        write_state(&v0_state(), None);
+        let mut migrated = Contract::migrate();
+        cache_price(&mut migrated.price_store, ETH, 2);
+        cache_price(&mut migrated.price_store, AURORA, 1);
+
+        assert_eq!(migrated.convert_usdc_balances(None), 0);
+        let balances = migrated.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances[ETH], U128(350 * UNITS));
+        assert_eq!(balances[AURORA], U128(300 * UNITS));
+        assert!(migrated.usdc_balances.is_empty());
+        assert_eq!(migrated.get_holder_count(), 1);
+    }
+
+    #[test]
+    fn test_accounts_with_only_shares_become_holders() {
+        let mut state = v3_state();
+        state.user_shares.insert(accounts(4), U128(10));
+        write_state(&state, Some(3));
+        let mut migrated = Contract::migrate();
+        cache_price(&mut migrated.price_store, ETH, 2);
+        cache_price(&mut migrated.price_store, AURORA, 1);
+
+        assert_eq!(migrated.convert_usdc_balances(Some(1)), 1);
+        assert_eq!(migrated.get_holder_count(), 1);
+        assert_eq!(migrated.convert_usdc_balances(None), 0);
+        assert_eq!(migrated.get_holder_count(), 2);
+        assert_eq!(migrated.get_user_balance(&accounts(4)), None);
+        assert!(migrated.holders.contains(&accounts(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Balances are being converted to units")]
+    fn test_withdrawals_wait_for_the_conversion() {
+        write_state(&v0_state(), None);
+        let mut migrated = Contract::migrate();
+        migrated.queue_withdrawal(
+            RedeemAmount::BasisPoints(MAX_BPS),
+            DESTINATION.parse().unwrap(),
+            DESTINATION.parse().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_migrate_from_unversioned_layout() {
+        write_state(&ContractV2::from(ContractV1::from(v0_state())), None);
+
+        let migrated = Contract::migrate();
+        assert_eq!(migrated.get_user_shares(accounts(1)), U128(1_000));
+        assert_eq!(migrated.upgrader, None);
+        assert_eq!(stored_state_version(), Some(STATE_VERSION));
+    }
+
+    #[test]
+    fn test_migrate_from_version_3() {
+        let mut state = v3_state();
+        cache_price(&mut state.price_store, ETH, 2);
+        state.pending_withdrawals.insert(
+            accounts(1),
+            PendingWithdrawalV3 {
+                preview: preview_v3(),
+                requested_at: 1,
+                claimable_at: 2,
+            },
+        );
+        state.withdrawal_queue.epochs.insert(
+            0,
+            WithdrawalEpochV3 {
+                id: 0,
+                status: EpochStatus::Settled,
+                opened_at: 0,
+                closed_at: Some(1),
+                requests: vec![QueuedWithdrawalV3 {
+                    account_id: accounts(4),
+                    eth_destination: DESTINATION.parse().unwrap(),
+                    aurora_destination: DESTINATION.parse().unwrap(),
+                    preview: preview_v3(),
+                    claimed: false,
+                }],
+                batches: vec![WithdrawalBatchV3 {
+                    asset_address: ETH.to_string(),
+                    destination: DESTINATION.parse().unwrap(),
+                    amount: U128(350),
+                    status: BatchStatus::Signed,
+                    signed_tx: Some("02f8".to_string()),
+                }],
+            },
+        );
+        let snapshot = |timestamp, level| NavSnapshot {
+            timestamp,
+            nav_per_share: U128(1_000),
+            benchmark_level: level,
+        };
+        state.analytics.first = Some(snapshot(1, None));
+        state.analytics.recent = vec![snapshot(2, Some(U128(50))), snapshot(3, Some(U128(60)))];
+        state.analytics.benchmark_start = Some(U128(50));
+        write_state(&state, Some(3));
+
+        let migrated = Contract::migrate();
+        let pending = migrated.get_pending_withdrawal(accounts(1)).unwrap();
+        assert_eq!(pending.preview.shares, U128(500));
+        assert_eq!(pending.preview.interest, U128(0));
+        // 350 USDC of ETH at 2 USD
+        assert_eq!(pending.preview.assets[0].amount, U128(175 * UNITS));
+        assert!(pending.lots.is_empty());
+
+        let epoch = migrated.withdrawal_queue.epochs.get(&0).unwrap();
+        assert_eq!(epoch.requests[0].preview.assets, preview_v3().assets);
+        assert_eq!(
+            epoch.batches[0].transfers,
+            vec![BatchTransfer {
+                destination: DESTINATION.parse().unwrap(),
+                amount: U128(350),
+            }]
+        );
+        assert_eq!(epoch.batches[0].signed_txs, vec!["02f8".to_string()]);
+        assert!(migrated.withdrawal_queue.dispersers.is_empty());
+
+        assert_eq!(migrated.analytics.benchmark_start, Some(snapshot(2, Some(U128(50)))));
+        assert!(migrated.btc_claims.is_empty());
+        assert!(migrated.governance.rebalancing.is_none());
+        assert_eq!(migrated.get_usdc_balance_count(), 1);
+        assert_eq!(stored_state_version(), Some(STATE_VERSION));
+    }
+
+    #[test]
+    #[should_panic(expected = "still in flight")]
+    fn test_swaps_in_flight_hold_the_migration_back() {
+        let mut state = v3_state();
+        state.swap_venue.in_flight.insert(ETH.to_string());
+        write_state(&state, Some(3));
+
+        Contract::migrate();
+    }
+
+    #[test]
+    fn test_migrate_current_state_is_a_no_op() {
+        let contract = setup();
+        write_state(&contract, Some(STATE_VERSION));
+
+        let migrated = Contract::migrate();
+        assert_eq!(migrated.get_user_shares(accounts(1)), U128(1_000));
+        assert_eq!(migrated.get_state_version(), STATE_VERSION);
+    }
+}
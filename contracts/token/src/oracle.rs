@@ -0,0 +1,462 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Gas, NearToken, Promise, PromiseResult};
+use std::collections::HashMap;
+
+use crate::pyth::{parse_pyth_prices, PythConfig, PythPrice};
+use crate::{Contract, ContractExt, OraclePriceData, PriceFeedInfo, TOKEN_ADDRESSES};
+
+const ORACLE_CALL_GAS: Gas = Gas::from_tgas(30);
+const AGGREGATE_CALLBACK_GAS: Gas = Gas::from_tgas(50);
+/// Largest scale a price can be brought to without overflowing a u128
+pub(crate) const MAX_PRICE_DECIMALS: u8 = 38;
+const BPS_DENOMINATOR: u128 = 10_000;
+
+pub const DEFAULT_MIN_ORACLE_SOURCES: u8 = 1;
+pub const DEFAULT_MAX_PRICE_DEVIATION_BPS: u32 = 500; // 5%
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OracleKind {
+    /// NEAR priceoracle, answers `get_price_data` with `OraclePriceData` timestamped
+    /// in nanoseconds
+    PriceOracle,
+    /// Pyth NEAR contract, answers `list_prices` for the feeds in `PythConfig` with
+    /// publish times in seconds
+    Pyth,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleSource {
+    pub account_id: AccountId,
+    pub kind: OracleKind,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleConfig {
+    pub sources: Vec<OracleSource>,
+    /// Sources that must answer, and agree, before a price is accepted
+    pub min_sources: u8,
+    /// Maximum distance of a single source from the median, in basis points
+    pub max_deviation_bps: u32,
+    pub pyth: PythConfig,
+}
+
+impl OracleConfig {
+    /// Reads prices from the NEAR priceoracle at `account_id` alone.
+    pub fn price_oracle(account_id: AccountId) -> Self {
+        Self {
+            sources: vec![OracleSource {
+                account_id,
+                kind: OracleKind::PriceOracle,
+            }],
+            min_sources: DEFAULT_MIN_ORACLE_SOURCES,
+            max_deviation_bps: DEFAULT_MAX_PRICE_DEVIATION_BPS,
+            pyth: PythConfig::default(),
+        }
+    }
+}
+
+impl OracleSource {
+    fn query(&self, config: &OracleConfig) -> Promise {
+        let (method, args) = match self.kind {
+            OracleKind::PriceOracle => ("get_price_data", b"{}".to_vec()),
+            OracleKind::Pyth => ("list_prices", config.pyth.list_prices_args()),
+        };
+        Promise::new(self.account_id.clone()).function_call(
+            method.to_string(),
+            args,
+            NearToken::from_near(0),
+            ORACLE_CALL_GAS,
+        )
+    }
+
+    fn parse(&self, data: &[u8], config: &OracleConfig) -> Result<Vec<PriceFeedInfo>, String> {
+        match self.kind {
+            OracleKind::PriceOracle => {
+                let price_data: OraclePriceData = near_sdk::serde_json::from_slice(data)
+                    .map_err(|_| "Invalid price data".to_string())?;
+                parse_price_oracle_data(price_data)
+            }
+            OracleKind::Pyth => {
+                let prices: HashMap<String, Option<PythPrice>> =
+                    near_sdk::serde_json::from_slice(data)
+                        .map_err(|_| "Invalid Pyth price data".to_string())?;
+                Ok(parse_pyth_prices(
+                    prices,
+                    &config.pyth,
+                    env::block_timestamp() / 1_000_000_000,
+                ))
+            }
+        }
+    }
+}
+
+fn parse_price_oracle_data(price_data: OraclePriceData) -> Result<Vec<PriceFeedInfo>, String> {
+    let timestamp = price_data
+        .timestamp
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid price timestamp {}", price_data.timestamp))?;
+    let current_time = env::block_timestamp();
+
+    if current_time.saturating_sub(timestamp) > price_data.recency_duration_sec * 1_000_000_000 {
+        return Err("Price data is too old".to_string());
+    }
+
+    let mut price_feeds = Vec::new();
+    for price in price_data.prices {
+        if let Some(price_info) = price.price {
+            // The oracle reports NEAR token accounts, balances are keyed by EVM address
+            if let Some((evm_address, _)) = TOKEN_ADDRESSES
+                .iter()
+                .find(|(_, near_address)| **near_address == price.asset_id)
+            {
+                // A garbled price drops the whole answer rather than reading as a price of 0
+                let multiplier = price_info.multiplier.parse::<u128>().map_err(|_| {
+                    format!(
+                        "Invalid price {} for asset {}",
+                        price_info.multiplier, price.asset_id
+                    )
+                })?;
+                let decimals = u8::try_from(price_info.decimals).map_err(|_| {
+                    format!(
+                        "Invalid decimals {} for asset {}",
+                        price_info.decimals, price.asset_id
+                    )
+                })?;
+                price_feeds.push(PriceFeedInfo {
+                    asset_address: evm_address.to_string(),
+                    price: U128(multiplier),
+                    decimals,
+                    last_updated: timestamp,
+                });
+            }
+        }
+    }
+
+    Ok(price_feeds)
+}
+
+/// Prices agreed on by the oracle sources, and the assets none of them priced
+#[derive(Debug, Default)]
+pub struct AggregatedPrices {
+    pub prices: Vec<PriceFeedInfo>,
+    pub missing: Vec<String>,
+}
+
+/// Combines the answers of all sources into one price for each of `asset_keys`.
+/// Every source price is scaled to the highest precision reported for the asset,
+/// prices further than `max_deviation_bps` from the median are dropped, and the
+/// median of what is left is used. An asset no source priced is listed in
+/// `missing`, one priced by fewer than `min_sources` agreeing sources fails the
+/// whole call. Prices of other assets are ignored.
+pub fn aggregate_prices(
+    asset_keys: &[String],
+    answers: &[Vec<PriceFeedInfo>],
+    min_sources: u8,
+    max_deviation_bps: u32,
+) -> Result<AggregatedPrices, String> {
+    let mut aggregated = AggregatedPrices::default();
+    for asset_address in asset_keys {
+        let asset_address = asset_address.as_str();
+        let feeds: Vec<&PriceFeedInfo> = answers
+            .iter()
+            .filter_map(|answer| answer.iter().find(|f| f.asset_address == asset_address))
+            // 10^39 no longer fits in a u128, such feeds can't be brought to a common scale
+            .filter(|f| f.decimals <= MAX_PRICE_DECIMALS)
+            .collect();
+        if feeds.is_empty() {
+            aggregated.missing.push(asset_address.to_string());
+            continue;
+        }
+
+        let decimals = feeds.iter().map(|f| f.decimals).max().unwrap_or(0);
+        let mut prices: Vec<u128> = feeds
+            .iter()
+            .map(|f| {
+                f.price
+                    .0
+                    .checked_mul(10u128.pow((decimals - f.decimals) as u32))
+                    .ok_or_else(|| format!("Price overflow for asset {}", asset_address))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let reference = median(&mut prices);
+        prices.retain(|&price| deviation_bps(price, reference) <= max_deviation_bps as u128);
+
+        if prices.len() < min_sources as usize {
+            return Err(format!(
+                "Not enough agreeing oracle prices for asset {}: {} of {} required",
+                asset_address,
+                prices.len(),
+                min_sources
+            ));
+        }
+
+        aggregated.prices.push(PriceFeedInfo {
+            asset_address: asset_address.to_string(),
+            price: U128(median(&mut prices)),
+            decimals,
+            last_updated: feeds.iter().map(|f| f.last_updated).min().unwrap_or(0),
+        });
+    }
+
+    Ok(aggregated)
+}
+
+fn median(prices: &mut [u128]) -> u128 {
+    if prices.is_empty() {
+        return 0;
+    }
+    prices.sort_unstable();
+    let mid = prices.len() / 2;
+    if prices.len() % 2 == 0 {
+        prices[mid - 1] / 2 + prices[mid] / 2 + (prices[mid - 1] % 2 + prices[mid] % 2) / 2
+    } else {
+        prices[mid]
+    }
+}
+
+fn deviation_bps(price: u128, reference: u128) -> u128 {
+    if reference == 0 {
+        return if price == 0 { 0 } else { u128::MAX };
+    }
+    price.abs_diff(reference).saturating_mul(BPS_DENOMINATOR) / reference
+}
+
+impl Contract {
+    /// Assets a price refresh must cover: the components, the ones a pending proposal
+    /// adds, the benchmark and every asset with a cached price, such as one being sold.
+    fn priced_asset_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.assets.iter().map(|asset| asset.key()).collect();
+        let added = self
+            .governance
+            .pending()
+            .into_iter()
+            .flat_map(|proposal| proposal.added.iter().map(|asset| asset.key()));
+        let others = added
+            .chain(self.analytics.benchmark.iter().cloned())
+            .chain(self.price_store.prices.keys().cloned());
+        for key in others {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Price Feed Functions
+    pub fn get_current_prices(&self) -> Promise {
+        let sources = &self.oracle_config.sources;
+        assert!(!sources.is_empty(), "No oracle sources configured");
+
+        sources
+            .iter()
+            .map(|source| source.query(&self.oracle_config))
+            .reduce(|acc, promise| acc.and(promise))
+            .unwrap()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(AGGREGATE_CALLBACK_GAS)
+                    .aggregate_prices_callback(sources.clone()),
+            )
+    }
+
+    #[private]
+    pub fn aggregate_prices_callback(&self, sources: Vec<OracleSource>) -> Vec<PriceFeedInfo> {
+        let config = &self.oracle_config;
+        let mut answers = Vec::new();
+        let mut errors = Vec::new();
+
+        // Results arrive in the order the sources were queried in
+        for (index, source) in sources.iter().enumerate() {
+            let parsed = match env::promise_result(index as u64) {
+                PromiseResult::Successful(data) => source.parse(&data, config),
+                PromiseResult::Failed => Err("Call failed".to_string()),
+            };
+            match parsed {
+                Ok(feeds) => answers.push(feeds),
+                Err(err) => errors.push(format!("{}: {}", source.account_id, err)),
+            }
+        }
+
+        if answers.len() < config.min_sources as usize {
+            env::panic_str(&format!(
+                "Not enough oracle sources answered: {} of {} required ({})",
+                answers.len(),
+                config.min_sources,
+                errors.join(", ")
+            ));
+        }
+        for error in errors {
+            env::log_str(&format!("Ignoring oracle source {}", error));
+        }
+
+        let aggregated = aggregate_prices(
+            &self.priced_asset_keys(),
+            &answers,
+            config.min_sources,
+            config.max_deviation_bps,
+        )
+                .unwrap_or_else(|err| env::panic_str(&err));
+        for asset_address in aggregated.missing {
+            env::log_str(&format!("No oracle price for asset {}", asset_address));
+        }
+        aggregated.prices
+    }
+
+    pub fn add_oracle_source(&mut self, account_id: AccountId, kind: OracleKind) {
+        self.assert_owner();
+        assert!(
+            !self
+                .oracle_config
+                .sources
+                .iter()
+                .any(|s| s.account_id == account_id),
+            "Oracle source already registered"
+        );
+        self.oracle_config
+            .sources
+            .push(OracleSource { account_id, kind });
+    }
+
+    pub fn remove_oracle_source(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let sources = &mut self.oracle_config.sources;
+        let len = sources.len();
+        sources.retain(|s| s.account_id != account_id);
+        assert_ne!(sources.len(), len, "Oracle source not found");
+        assert!(
+            sources.len() >= self.oracle_config.min_sources as usize,
+            "Fewer sources than required would remain"
+        );
+    }
+
+    pub fn set_oracle_thresholds(&mut self, min_sources: u8, max_deviation_bps: u32) {
+        self.assert_owner();
+        assert!(min_sources > 0, "At least one source must be required");
+        assert!(
+            min_sources as usize <= self.oracle_config.sources.len(),
+            "More sources required than configured"
+        );
+        assert!(
+            max_deviation_bps as u128 <= BPS_DENOMINATOR,
+            "Deviation threshold cannot exceed 100%"
+        );
+        self.oracle_config.min_sources = min_sources;
+        self.oracle_config.max_deviation_bps = max_deviation_bps;
+    }
+
+    pub fn get_oracle_config(&self) -> OracleConfig {
+        self.oracle_config.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssetPrice, PriceData};
+
+    fn feed(asset_address: &str, price: u128, decimals: u8) -> PriceFeedInfo {
+        PriceFeedInfo {
+            asset_address: asset_address.to_string(),
+            price: U128(price),
+            decimals,
+            last_updated: 0,
+        }
+    }
+
+    fn keys(asset_addresses: &[&str]) -> Vec<String> {
+        asset_addresses.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_median_of_agreeing_sources() {
+        let answers = vec![
+            vec![feed("eth", 2_000, 0)],
+            vec![feed("eth", 20_100, 1)],
+            vec![feed("eth", 2_010, 0)],
+        ];
+
+        let prices = aggregate_prices(&keys(&["eth"]), &answers, 2, 500).unwrap().prices;
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices[0].price, U128(20_100));
+        assert_eq!(prices[0].decimals, 1);
+    }
+
+    #[test]
+    fn test_outlier_is_dropped() {
+        let answers = vec![
+            vec![feed("eth", 2_000, 0)],
+            vec![feed("eth", 2_010, 0)],
+            vec![feed("eth", 3_000, 0)],
+        ];
+
+        let prices = aggregate_prices(&keys(&["eth"]), &answers, 2, 500).unwrap().prices;
+        assert_eq!(prices[0].price, U128(2_005));
+    }
+
+    #[test]
+    fn test_feed_with_too_many_decimals_is_ignored() {
+        let answers = vec![
+            vec![feed("eth", 2_000, 0)],
+            vec![feed("eth", 2_010, 0)],
+            vec![feed("eth", 2_000, 40)],
+        ];
+
+        let prices = aggregate_prices(&keys(&["eth"]), &answers, 2, 500).unwrap().prices;
+        assert_eq!(prices[0].price, U128(2_005));
+        assert_eq!(prices[0].decimals, 0);
+    }
+
+    #[test]
+    fn test_too_few_agreeing_sources() {
+        let answers = vec![vec![feed("eth", 2_000, 0)], vec![feed("eth", 3_000, 0)]];
+
+        let err = aggregate_prices(&keys(&["eth"]), &answers, 2, 500).unwrap_err();
+        assert!(err.starts_with("Not enough agreeing oracle prices for asset eth"));
+    }
+
+    #[test]
+    fn test_unpriced_asset_is_reported_missing() {
+        let answers = vec![vec![feed("eth", 2_000, 0), feed("doge", 1, 0)]];
+
+        let aggregated = aggregate_prices(&keys(&["eth", "btc"]), &answers, 1, 500).unwrap();
+        assert_eq!(aggregated.prices.len(), 1);
+        assert_eq!(aggregated.prices[0].asset_address, "eth");
+        assert_eq!(aggregated.missing, vec!["btc".to_string()]);
+    }
+
+    #[test]
+    fn test_malformed_price_drops_the_answer() {
+        let price_data = OraclePriceData {
+            timestamp: "0".to_string(),
+            recency_duration_sec: 90,
+            prices: vec![
+                AssetPrice {
+                    asset_id: "weth.fakes.testnet".to_string(),
+                    price: Some(PriceData {
+                        multiplier: "25000000".to_string(),
+                        decimals: 22,
+                    }),
+                },
+                AssetPrice {
+                    asset_id: "aurora.fakes.testnet".to_string(),
+                    price: Some(PriceData {
+                        multiplier: "1.5e6".to_string(),
+                        decimals: 22,
+                    }),
+                },
+            ],
+        };
+
+        let err = parse_price_oracle_data(price_data).unwrap_err();
+        assert_eq!(err, "Invalid price 1.5e6 for asset aurora.fakes.testnet");
+    }
+}
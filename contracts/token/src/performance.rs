@@ -0,0 +1,183 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{I128, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+
+use crate::{Contract, ContractExt};
+
+/// USDC that went in and out of one account. Shares are minted 1:1 with deposited USDC,
+/// so a share's cost is one unit of USDC and the cost basis of a position is its shares.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountFlows {
+    pub deposited: U128,
+    /// Cost of the shares burned by completed redemptions, early exit penalties included
+    pub redeemed_cost: U128,
+    /// What those redemptions paid, at the cached prices when they were paid out
+    pub redeemed_value: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountPerformance {
+    pub account_id: AccountId,
+    pub deposited: U128,
+    pub redeemed_value: U128,
+    /// Cost of the shares the account holds
+    pub cost_basis: U128,
+    /// Value of the position at the cached prices, lending interest and unclaimed
+    /// components removed in kind included
+    pub current_value: U128,
+    pub realized_pnl: I128,
+    pub unrealized_pnl: I128,
+}
+
+fn pnl(value: u128, cost: u128) -> I128 {
+    I128(value as i128 - cost as i128)
+}
+
+impl Contract {
+    pub(crate) fn record_deposit_flow(&mut self, account_id: &AccountId, amount: u128) {
+        self.account_flows
+            .entry(account_id.clone())
+            .or_default()
+            .deposited
+            .0 += amount;
+    }
+
+    /// Realizes a redemption of shares costing `cost` that paid out `value`. Shares only
+    /// count once the payout went through, the ones given back never left the position.
+    pub(crate) fn record_redemption_flow(&mut self, account_id: &AccountId, cost: u128, value: u128) {
+        if cost == 0 && value == 0 {
+            return;
+        }
+        let flows = self.account_flows.entry(account_id.clone()).or_default();
+        flows.redeemed_cost.0 += cost;
+        flows.redeemed_value.0 += value;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// What `account_id` put in and made. Redemptions still waiting for their cooldown,
+    /// epoch or payout are in neither the position nor the realized PnL.
+    pub fn get_account_performance(&self, account_id: AccountId) -> AccountPerformance {
+        let flows = self.account_flows.get(&account_id).cloned().unwrap_or_default();
+        let cost_basis = self.get_user_shares(account_id.clone()).0;
+        let current_value = self.portfolio_value(&account_id) + self.in_kind_value(&account_id);
+
+        AccountPerformance {
+            account_id,
+            deposited: flows.deposited,
+            redeemed_value: flows.redeemed_value,
+            cost_basis: U128(cost_basis),
+            current_value: U128(current_value),
+            realized_pnl: pnl(flows.redeemed_value.0, flows.redeemed_cost.0),
+            unrealized_pnl: pnl(current_value, cost_basis),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::WindDownMode;
+    use crate::redeem::{RedeemAmount, MAX_BPS};
+    use crate::{AssetInfo, PriceFeedInfo};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract.process_deposit(accounts(1), U128(1_000));
+        // ETH doubles after the deposit
+        contract.price_store.prices.get_mut(ETH).unwrap().price = U128(20_000);
+        contract
+    }
+
+    #[test]
+    fn test_unrealized_pnl_at_cached_prices() {
+        let contract = setup();
+        let performance = contract.get_account_performance(accounts(1));
+        // 700 ETH at 2 and 300 AURORA at 1
+        assert_eq!(performance.deposited, U128(1_000));
+        assert_eq!(performance.cost_basis, U128(1_000));
+        assert_eq!(performance.current_value, U128(1_700));
+        assert_eq!(performance.unrealized_pnl, I128(700));
+        assert_eq!(performance.realized_pnl, I128(0));
+    }
+
+    #[test]
+    fn test_redemption_realizes_pnl() {
+        let mut contract = setup();
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS / 2));
+        contract.debit_redemption(&accounts(1), &preview);
+        contract.record_redemption_flow(&accounts(1), preview.shares.0, 850);
+
+        let performance = contract.get_account_performance(accounts(1));
+        assert_eq!(performance.cost_basis, U128(500));
+        assert_eq!(performance.redeemed_value, U128(850));
+        assert_eq!(performance.realized_pnl, I128(350));
+        assert_eq!(performance.unrealized_pnl, I128(350));
+    }
+
+    #[test]
+    fn test_in_kind_claim_is_realized() {
+        let mut contract = setup();
+        // AURORA leaves the fund in kind, the holder keeps its 300 until claiming them
+        let aurora = contract.assets.remove(1);
+        contract.start_wind_down(aurora, WindDownMode::InKind, 300 * UNITS);
+        let performance = contract.get_account_performance(accounts(1));
+        assert_eq!(performance.current_value, U128(1_700));
+
+        contract.user_balances.get_mut(&accounts(1)).unwrap().remove(AURORA);
+        contract.finish_in_kind_claim(&accounts(1), AURORA, 300 * UNITS);
+        let performance = contract.get_account_performance(accounts(1));
+        assert_eq!(performance.current_value, U128(1_400));
+        assert_eq!(performance.redeemed_value, U128(300));
+        assert_eq!(performance.realized_pnl, I128(300));
+        assert_eq!(performance.unrealized_pnl, I128(400));
+    }
+
+    #[test]
+    fn test_unknown_account() {
+        let contract = setup();
+        let performance = contract.get_account_performance(accounts(4));
+        assert_eq!(performance.current_value, U128(0));
+        assert_eq!(performance.unrealized_pnl, I128(0));
+    }
+}
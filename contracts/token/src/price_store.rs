@@ -0,0 +1,445 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Gas, Promise, PromiseError};
+use std::collections::HashMap;
+
+use crate::events::emit_event;
+use crate::{asset_key, Contract, ContractExt, PriceFeedInfo};
+
+const STORE_PRICES_CALLBACK_GAS: Gas = Gas::from_tgas(30);
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Decimals of the USDC the fund takes deposits in and pays out
+pub const USDC_DECIMALS: u8 = 6;
+/// Values are USD in the decimals of USDC. Oracle prices are USD per smallest unit of
+/// the asset with their own decimals, `PriceFeedInfo::value_of` rescales them.
+pub const VALUE_DECIMALS: u8 = USDC_DECIMALS;
+
+pub const DEFAULT_MAX_STALENESS_SEC: u64 = 600;
+pub const DEFAULT_MAX_PRICE_CHANGE_BPS: u32 = 2_000; // 20%
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CircuitBreakerTrip {
+    pub asset_address: String,
+    pub previous_price: PriceFeedInfo,
+    pub new_price: PriceFeedInfo,
+    pub change_bps: U128,
+    pub tripped_at: u64,
+}
+
+/// Last accepted price of every asset, kept so valuations don't need a
+/// round trip to the oracles.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceStore {
+    pub prices: HashMap<String, PriceFeedInfo>,
+    pub default_max_staleness_sec: u64,
+    /// Per asset overrides of `default_max_staleness_sec`
+    pub max_staleness_sec: HashMap<String, u64>,
+    /// Largest move between two accepted prices before deposits and withdrawals freeze
+    pub max_price_change_bps: u32,
+    pub circuit_breaker: Option<CircuitBreakerTrip>,
+}
+
+impl Default for PriceStore {
+    fn default() -> Self {
+        Self {
+            prices: HashMap::new(),
+            default_max_staleness_sec: DEFAULT_MAX_STALENESS_SEC,
+            max_staleness_sec: HashMap::new(),
+            max_price_change_bps: DEFAULT_MAX_PRICE_CHANGE_BPS,
+            circuit_breaker: None,
+        }
+    }
+}
+
+impl PriceStore {
+    pub fn staleness_limit_sec(&self, asset_address: &str) -> u64 {
+        self.max_staleness_sec
+            .get(asset_address)
+            .copied()
+            .unwrap_or(self.default_max_staleness_sec)
+    }
+
+    /// Stores `feed` and reports a trip if it moved too far from the previous price.
+    /// The new price is kept either way, the breaker only stops funds from moving
+    /// until the owner has looked at it.
+    pub fn update(&mut self, feed: PriceFeedInfo, now: u64) -> Option<CircuitBreakerTrip> {
+        let trip = self.prices.get(&feed.asset_address).and_then(|previous| {
+            let change_bps = price_change_bps(previous, &feed);
+            (change_bps > self.max_price_change_bps as u128).then(|| CircuitBreakerTrip {
+                asset_address: feed.asset_address.clone(),
+                previous_price: previous.clone(),
+                new_price: feed.clone(),
+                change_bps: U128(change_bps),
+                tripped_at: now,
+            })
+        });
+
+        self.prices.insert(feed.asset_address.clone(), feed);
+        if trip.is_some() && self.circuit_breaker.is_none() {
+            self.circuit_breaker = trip.clone();
+        }
+        trip
+    }
+
+    /// Whether `feed` was published after the cached price of its asset. An older or
+    /// replayed answer must not overwrite a newer price or move the circuit breaker.
+    pub fn is_newer(&self, feed: &PriceFeedInfo) -> bool {
+        self.prices
+            .get(&feed.asset_address)
+            .map_or(true, |previous| feed.last_updated > previous.last_updated)
+    }
+
+    /// Whether `asset_address` has a non-zero price recent enough to trade on.
+    pub fn has_fresh_price(&self, asset_address: &str, now: u64) -> bool {
+        self.prices.get(asset_address).is_some_and(|price| {
+            price.price.0 > 0
+                && now.saturating_sub(price.last_updated)
+                    <= self.staleness_limit_sec(asset_address) * NANOS_PER_SEC
+        })
+    }
+
+    /// Price of `asset_address`, panicking when there is none or it is older than allowed.
+    pub fn fresh_price(&self, asset_address: &str, now: u64) -> &PriceFeedInfo {
+        let price = self
+            .prices
+            .get(asset_address)
+            .unwrap_or_else(|| {
+                env::panic_str(&format!("No price available for asset {}", asset_address))
+            });
+        let max_age = self.staleness_limit_sec(asset_address) * NANOS_PER_SEC;
+        if now.saturating_sub(price.last_updated) > max_age {
+            env::panic_str(&format!("Cached price for asset {} is stale", asset_address));
+        }
+        price
+    }
+
+    /// Value of `amount` units of `asset_address` at the cached price.
+    pub fn value_of(&self, asset_address: &str, amount: u128, now: u64) -> u128 {
+        if amount == 0 {
+            return 0;
+        }
+        self.fresh_price(asset_address, now).value_of(amount)
+    }
+
+    /// Units of `asset_address` worth `value` at the cached price, the inverse of `value_of`.
+    pub fn amount_for(&self, asset_address: &str, value: u128, now: u64) -> u128 {
+        if value == 0 {
+            return 0;
+        }
+        self.fresh_price(asset_address, now).amount_for(value)
+    }
+}
+
+/// Value of `amount` of USDC, which counts at par.
+pub fn usdc_to_value(amount: u128) -> u128 {
+    amount
+}
+
+/// USDC worth `value`, the inverse of `usdc_to_value`.
+pub fn value_to_usdc(value: u128) -> u128 {
+    value
+}
+
+impl PriceFeedInfo {
+    /// Value of `amount` units of the asset at this price, in `VALUE_DECIMALS`.
+    pub fn value_of(&self, amount: u128) -> u128 {
+        let value = amount
+            .checked_mul(self.price.0)
+            .expect("Portfolio value overflow");
+        if self.decimals >= VALUE_DECIMALS {
+            value / 10u128.pow((self.decimals - VALUE_DECIMALS) as u32)
+        } else {
+            value
+                .checked_mul(10u128.pow((VALUE_DECIMALS - self.decimals) as u32))
+                .expect("Portfolio value overflow")
+        }
+    }
+
+    /// Units of the asset worth `value` at this price, the inverse of `value_of`.
+    pub fn amount_for(&self, value: u128) -> u128 {
+        assert!(self.price.0 > 0, "Price of asset {} is zero", self.asset_address);
+        if self.decimals >= VALUE_DECIMALS {
+            value
+                .checked_mul(10u128.pow((self.decimals - VALUE_DECIMALS) as u32))
+                .expect("Portfolio value overflow")
+                / self.price.0
+        } else {
+            value
+                / self
+                    .price
+                    .0
+                    .checked_mul(10u128.pow((VALUE_DECIMALS - self.decimals) as u32))
+                    .expect("Portfolio value overflow")
+        }
+    }
+}
+
+/// Relative move between two prices in basis points, comparing them at the same precision.
+fn price_change_bps(previous: &PriceFeedInfo, next: &PriceFeedInfo) -> u128 {
+    let decimals = previous.decimals.max(next.decimals);
+    let scale = |feed: &PriceFeedInfo| {
+        feed.price
+            .0
+            .saturating_mul(10u128.pow((decimals - feed.decimals) as u32))
+    };
+    let (previous, next) = (scale(previous), scale(next));
+    if previous == 0 {
+        return if next == 0 { 0 } else { u128::MAX };
+    }
+    previous.abs_diff(next).saturating_mul(10_000) / previous
+}
+
+#[near_bindgen]
+impl Contract {
+    pub(crate) fn assert_not_frozen(&self) {
+        if let Some(trip) = &self.price_store.circuit_breaker {
+            env::panic_str(&format!(
+                "Circuit breaker tripped by asset {}, deposits and withdrawals are frozen",
+                trip.asset_address
+            ));
+        }
+        if self.governance.rebalancing.is_some() {
+            env::panic_str("Holdings are being rebalanced, call rebalance_holders to finish");
+        }
+        if !self.usdc_balances.is_empty() {
+            env::panic_str(
+                "Balances are being converted to units, call convert_usdc_balances to finish",
+            );
+        }
+    }
+
+    pub(crate) fn asset_value(&self, asset_address: &str, amount: u128) -> u128 {
+        self.price_store
+            .value_of(asset_address, amount, env::block_timestamp())
+    }
+
+    /// Value of `account_id`'s position at the cached prices, lending interest included.
+    pub(crate) fn portfolio_value(&self, account_id: &AccountId) -> u128 {
+        let interest = usdc_to_value(
+            self.interest_share(self.get_user_shares(account_id.clone()).0, self.total_assets.0),
+        );
+        self.settled_balances(account_id)
+            .into_iter()
+            .flatten()
+            .filter(|(asset_address, _)| !self.is_claimed_in_kind(asset_address))
+            .map(|(asset_address, balance)| self.asset_value(&asset_address, balance.0))
+            .sum::<u128>()
+            + interest
+    }
+
+    /// Fetches fresh prices from the oracles and stores them. Meant to be called
+    /// by a keeper, but anyone may pay for the refresh.
+    pub fn refresh_prices(&mut self) -> Promise {
+        self.get_current_prices().then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(STORE_PRICES_CALLBACK_GAS)
+                .store_prices_callback(),
+        )
+    }
+
+    #[private]
+    pub fn store_prices_callback(
+        &mut self,
+        #[callback_result] price_feeds_result: Result<Vec<PriceFeedInfo>, PromiseError>,
+    ) -> Vec<PriceFeedInfo> {
+        // Nothing changes hands here, a failed refresh keeps the cached prices
+        let price_feeds = match price_feeds_result {
+            Ok(feeds) => feeds,
+            Err(_) => {
+                emit_event("price_refresh_failed", json!({}));
+                return Vec::new();
+            }
+        };
+
+        let now = env::block_timestamp();
+        let price_feeds: Vec<PriceFeedInfo> = price_feeds
+            .into_iter()
+            .filter(|feed| {
+                let newer = self.price_store.is_newer(feed);
+                if !newer {
+                    env::log_str(&format!(
+                        "Ignoring price of {}: not newer than the cached one",
+                        feed.asset_address
+                    ));
+                }
+                newer
+            })
+            .collect();
+        for feed in &price_feeds {
+            if let Some(trip) = self.price_store.update(feed.clone(), now) {
+                env::log_str(&format!(
+                    "Circuit breaker tripped: price of {} moved {} bps",
+                    trip.asset_address, trip.change_bps.0
+                ));
+            }
+        }
+        self.record_nav_snapshot();
+
+        price_feeds
+    }
+
+    pub fn get_asset_price(&self, asset_address: String) -> Option<PriceFeedInfo> {
+        self.price_store.prices.get(&asset_key(&asset_address)).cloned()
+    }
+
+    pub fn get_cached_prices(&self) -> Vec<PriceFeedInfo> {
+        self.price_store.prices.values().cloned().collect()
+    }
+
+    /// Value of the account's holdings of the fund's components, plus its share of the
+    /// interest earned on lent out USDC. Balances of removed components waiting to be
+    /// claimed in kind are left out, they may have no price.
+    pub fn get_portfolio_value(&self, account_id: AccountId) -> U128 {
+        assert!(
+            self.user_balances.contains_key(&account_id),
+            "No balance found for user"
+        );
+        U128(self.portfolio_value(&account_id))
+    }
+
+    pub fn get_circuit_breaker(&self) -> Option<CircuitBreakerTrip> {
+        self.price_store.circuit_breaker.clone()
+    }
+
+    pub fn reset_circuit_breaker(&mut self) {
+        self.assert_owner();
+        assert!(
+            self.price_store.circuit_breaker.take().is_some(),
+            "Circuit breaker is not tripped"
+        );
+    }
+
+    pub fn set_max_price_change_bps(&mut self, max_price_change_bps: u32) {
+        self.assert_owner();
+        assert!(max_price_change_bps > 0, "Threshold must be positive");
+        self.price_store.max_price_change_bps = max_price_change_bps;
+    }
+
+    /// Sets the staleness limit of one asset, or the default when `asset_address` is `None`.
+    pub fn set_max_staleness_sec(
+        &mut self,
+        asset_address: Option<String>,
+        max_staleness_sec: u64,
+    ) {
+        self.assert_owner();
+        match asset_address {
+            Some(asset_address) => {
+                let key = self.registered_asset_key(&asset_address);
+                self.price_store
+                    .max_staleness_sec
+                    .insert(key, max_staleness_sec);
+            }
+            None => self.price_store.default_max_staleness_sec = max_staleness_sec,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn feed(price: u128, decimals: u8, last_updated: u64) -> PriceFeedInfo {
+        PriceFeedInfo {
+            asset_address: "eth".to_string(),
+            price: U128(price),
+            decimals,
+            last_updated,
+        }
+    }
+
+    #[test]
+    fn test_small_move_keeps_breaker_open() {
+        let mut store = PriceStore::default();
+        assert!(store.update(feed(2_000, 0, 0), 0).is_none());
+        assert!(store.update(feed(21_000, 1, 0), 0).is_none());
+        assert!(store.circuit_breaker.is_none());
+        assert_eq!(store.prices["eth"].price, U128(21_000));
+    }
+
+    #[test]
+    fn test_large_move_trips_breaker() {
+        let mut store = PriceStore::default();
+        store.update(feed(2_000, 0, 0), 0);
+
+        let trip = store.update(feed(1_000, 0, 0), 7).unwrap();
+        assert_eq!(trip.change_bps, U128(5_000));
+        assert_eq!(store.circuit_breaker, Some(trip));
+    }
+
+    #[test]
+    fn test_only_newer_prices_replace_the_cache() {
+        let mut store = PriceStore::default();
+        assert!(store.is_newer(&feed(2_000, 0, 5)));
+        store.update(feed(2_000, 0, 5), 0);
+
+        assert!(!store.is_newer(&feed(1_000, 0, 5)));
+        assert!(!store.is_newer(&feed(1_000, 0, 4)));
+        assert!(store.is_newer(&feed(1_000, 0, 6)));
+    }
+
+    #[test]
+    fn test_value_of_fresh_price() {
+        let mut store = PriceStore::default();
+        // ETH at 2500 USD from the NEAR priceoracle, 18 token decimals plus 4
+        store.update(feed(25_000_000, 22, NANOS_PER_SEC), 0);
+        let wei = 28 * 10u128.pow(16);
+        assert_eq!(store.value_of("eth", wei, 10 * NANOS_PER_SEC), 700_000_000);
+        assert_eq!(store.amount_for("eth", 700_000_000, 10 * NANOS_PER_SEC), wei);
+    }
+
+    #[test]
+    fn test_value_of_pyth_price() {
+        // ETH at 2500 USD from Pyth, an exponent of -8 plus 18 token decimals
+        let price = feed(250_000_000_000, 26, 0);
+        let wei = 28 * 10u128.pow(16);
+        assert_eq!(price.value_of(wei), 700_000_000);
+        assert_eq!(price.amount_for(700_000_000), wei);
+    }
+
+    #[test]
+    fn test_value_of_price_with_few_decimals() {
+        // 2 USD per unit of a token without decimals
+        let price = feed(2, 0, 0);
+        assert_eq!(price.value_of(700), 1_400_000_000);
+        assert_eq!(price.amount_for(1_400_000_000), 700);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cached price for asset eth is stale")]
+    fn test_stale_price_is_rejected() {
+        let mut store = PriceStore::default();
+        store.max_staleness_sec.insert("eth".to_string(), 60);
+        store.update(feed(2_000, 0, 0), 0);
+        store.value_of("eth", 700, 61 * NANOS_PER_SEC);
+    }
+
+    #[test]
+    fn test_asset_price_by_address_in_any_case() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut contract = Contract::new(
+            accounts(1),
+            Vec::new(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            None,
+        );
+        let address = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+        contract.price_store.update(
+            PriceFeedInfo {
+                asset_address: address.to_string(),
+                ..feed(25_000_000, 22, 0)
+            },
+            0,
+        );
+
+        let price = contract.get_asset_price(address.to_lowercase()).unwrap();
+        assert_eq!(price.asset_address, address);
+    }
+}
@@ -0,0 +1,278 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{I64, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen};
+use std::collections::HashMap;
+
+use crate::oracle::MAX_PRICE_DECIMALS;
+use crate::{Contract, ContractExt, PriceFeedInfo};
+
+pub const DEFAULT_PYTH_MAX_CONFIDENCE_BPS: u32 = 200; // 2%
+pub const DEFAULT_PYTH_MAX_AGE_SEC: u64 = 120;
+
+/// Price as returned by the Pyth NEAR contract: `price * 10^expo` USD per whole token,
+/// `conf` uses the same exponent and `publish_time` is in seconds.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PythPrice {
+    pub price: I64,
+    pub conf: U64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PythFeed {
+    /// Hex encoded feed id, lowercase and without the `0x` prefix
+    pub price_id: String,
+    /// Balance key of the asset, see `AssetInfo::key`
+    pub asset_address: String,
+    /// Decimals of the token, so prices end up per smallest unit like the NEAR priceoracle
+    pub token_decimals: u8,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PythConfig {
+    pub feeds: Vec<PythFeed>,
+    /// Widest accepted confidence interval relative to the price, in basis points
+    pub max_confidence_bps: u32,
+    pub max_age_sec: u64,
+}
+
+impl Default for PythConfig {
+    fn default() -> Self {
+        Self {
+            feeds: Vec::new(),
+            max_confidence_bps: DEFAULT_PYTH_MAX_CONFIDENCE_BPS,
+            max_age_sec: DEFAULT_PYTH_MAX_AGE_SEC,
+        }
+    }
+}
+
+impl PythConfig {
+    pub fn list_prices_args(&self) -> Vec<u8> {
+        let price_ids: Vec<&str> = self.feeds.iter().map(|f| f.price_id.as_str()).collect();
+        near_sdk::serde_json::json!({ "price_ids": price_ids })
+            .to_string()
+            .into_bytes()
+    }
+}
+
+pub fn normalize_price_id(price_id: &str) -> String {
+    let price_id = price_id.strip_prefix("0x").unwrap_or(price_id).to_lowercase();
+    assert!(
+        price_id.len() == 64 && price_id.chars().all(|c| c.is_ascii_hexdigit()),
+        "Pyth price id must be 32 bytes of hex"
+    );
+    price_id
+}
+
+/// Turns a `list_prices` answer into the same `PriceFeedInfo` the NEAR priceoracle produces.
+/// Feeds that are missing, not positive, too old or too uncertain are left out.
+pub fn parse_pyth_prices(
+    prices: HashMap<String, Option<PythPrice>>,
+    config: &PythConfig,
+    now_sec: u64,
+) -> Vec<PriceFeedInfo> {
+    let mut price_feeds = Vec::new();
+
+    for feed in &config.feeds {
+        let price = match prices.get(&feed.price_id) {
+            Some(Some(price)) => price,
+            _ => continue,
+        };
+
+        match to_price_feed(feed, price, config, now_sec) {
+            Ok(price_feed) => price_feeds.push(price_feed),
+            Err(err) => env::log_str(&format!(
+                "Rejecting Pyth price for {}: {}",
+                feed.asset_address, err
+            )),
+        }
+    }
+
+    price_feeds
+}
+
+fn to_price_feed(
+    feed: &PythFeed,
+    price: &PythPrice,
+    config: &PythConfig,
+    now_sec: u64,
+) -> Result<PriceFeedInfo, String> {
+    if price.price.0 <= 0 {
+        return Err("price is not positive".to_string());
+    }
+    let publish_time = u64::try_from(price.publish_time).map_err(|_| "invalid publish time")?;
+    if now_sec.saturating_sub(publish_time) > config.max_age_sec {
+        return Err("price is too old".to_string());
+    }
+
+    let value = price.price.0 as u128;
+    let confidence_bps = (price.conf.0 as u128).saturating_mul(10_000) / value;
+    if confidence_bps > config.max_confidence_bps as u128 {
+        return Err(format!("confidence interval of {} bps is too wide", confidence_bps));
+    }
+
+    // USD per smallest unit = price * 10^expo / 10^token_decimals
+    let (multiplier, decimals) = if price.expo >= 0 {
+        let scaled = value
+            .checked_mul(10u128.pow(price.expo as u32))
+            .ok_or("price overflow")?;
+        (scaled, feed.token_decimals as u32)
+    } else {
+        (value, price.expo.unsigned_abs() + feed.token_decimals as u32)
+    };
+    let decimals = u8::try_from(decimals)
+        .ok()
+        .filter(|d| *d <= MAX_PRICE_DECIMALS)
+        .ok_or("too many decimals")?;
+
+    Ok(PriceFeedInfo {
+        asset_address: feed.asset_address.clone(),
+        price: U128(multiplier),
+        decimals,
+        last_updated: publish_time
+            .checked_mul(1_000_000_000)
+            .ok_or("invalid publish time")?,
+    })
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_pyth_feed(
+        &mut self,
+        asset_address: String,
+        price_id: String,
+        token_decimals: u8,
+    ) {
+        self.assert_owner();
+        let asset_address = self.registered_asset_key(&asset_address);
+
+        let price_id = normalize_price_id(&price_id);
+        let feeds = &mut self.oracle_config.pyth.feeds;
+        feeds.retain(|f| f.price_id != price_id && f.asset_address != asset_address);
+        feeds.push(PythFeed {
+            price_id,
+            asset_address,
+            token_decimals,
+        });
+    }
+
+    pub fn remove_pyth_feed(&mut self, price_id: String) {
+        self.assert_owner();
+        let price_id = normalize_price_id(&price_id);
+        let feeds = &mut self.oracle_config.pyth.feeds;
+        let len = feeds.len();
+        feeds.retain(|f| f.price_id != price_id);
+        assert_ne!(feeds.len(), len, "Pyth feed not found");
+    }
+
+    pub fn set_pyth_limits(&mut self, max_confidence_bps: u32, max_age_sec: u64) {
+        self.assert_owner();
+        assert!(max_confidence_bps > 0, "Confidence limit must be positive");
+        self.oracle_config.pyth.max_confidence_bps = max_confidence_bps;
+        self.oracle_config.pyth.max_age_sec = max_age_sec;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETH_FEED: &str = "ff61491a931112ddf1bd8147cd1b641375f79f5825126d665480874634fd0ace";
+
+    fn config() -> PythConfig {
+        PythConfig {
+            feeds: vec![PythFeed {
+                price_id: ETH_FEED.to_string(),
+                asset_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
+                token_decimals: 18,
+            }],
+            ..PythConfig::default()
+        }
+    }
+
+    fn price(price: i64, conf: u64, publish_time: i64) -> HashMap<String, Option<PythPrice>> {
+        price_with_expo(price, conf, -8, publish_time)
+    }
+
+    fn price_with_expo(
+        price: i64,
+        conf: u64,
+        expo: i32,
+        publish_time: i64,
+    ) -> HashMap<String, Option<PythPrice>> {
+        let mut prices = HashMap::new();
+        prices.insert(
+            ETH_FEED.to_string(),
+            Some(PythPrice {
+                price: I64(price),
+                conf: U64(conf),
+                expo,
+                publish_time,
+            }),
+        );
+        prices
+    }
+
+    #[test]
+    fn test_parse_price() {
+        // 2500.00000000 USD per ETH
+        let feeds = parse_pyth_prices(price(250_000_000_000, 100_000_000, 1_000), &config(), 1_010);
+
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].price, U128(250_000_000_000));
+        assert_eq!(feeds[0].decimals, 26);
+        assert_eq!(feeds[0].last_updated, 1_000_000_000_000);
+    }
+
+    #[test]
+    fn test_wide_confidence_is_rejected() {
+        // 5% confidence interval
+        let feeds = parse_pyth_prices(
+            price(250_000_000_000, 12_500_000_000, 1_000),
+            &config(),
+            1_010,
+        );
+        assert!(feeds.is_empty());
+    }
+
+    #[test]
+    fn test_stale_price_is_rejected() {
+        let feeds = parse_pyth_prices(price(250_000_000_000, 100_000_000, 1_000), &config(), 2_000);
+        assert!(feeds.is_empty());
+    }
+
+    #[test]
+    fn test_positive_exponent_scales_the_price() {
+        let feeds = parse_pyth_prices(price_with_expo(25, 0, 2, 1_000), &config(), 1_010);
+
+        assert_eq!(feeds[0].price, U128(2_500));
+        assert_eq!(feeds[0].decimals, 18);
+    }
+
+    #[test]
+    fn test_too_many_decimals_are_rejected() {
+        // 18 token decimals and a -21 exponent need a scale of 10^39
+        let feeds = parse_pyth_prices(price_with_expo(25, 0, -21, 1_000), &config(), 1_010);
+        assert!(feeds.is_empty());
+    }
+
+    #[test]
+    fn test_overflowing_publish_time_is_rejected() {
+        let feeds = parse_pyth_prices(
+            price(250_000_000_000, 100_000_000, i64::MAX),
+            &config(),
+            1_010,
+        );
+        assert!(feeds.is_empty());
+    }
+
+    #[test]
+    fn test_normalize_price_id() {
+        assert_eq!(normalize_price_id(&format!("0x{}", ETH_FEED.to_uppercase())), ETH_FEED);
+    }
+}
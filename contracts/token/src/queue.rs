@@ -0,0 +1,846 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::store::LookupMap;
+use near_sdk::{env, near_bindgen, AccountId, Gas, Promise, PromiseError, PromiseResult};
+
+use crate::events::emit_event;
+use crate::models::EVMTransactionWrapper;
+use crate::redeem::{AssetAmount, RedeemAmount, RedeemPreview};
+use crate::signer::SignResult;
+use crate::{signed_evm_tx, Chain, Contract, ContractExt, EvmAddress, NetworkDetails, Prefix};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SIGN_BATCH_CALLBACK_GAS: Gas = Gas::from_tgas(10);
+const NEAR_LEGS_CALLBACK_GAS: Gas = Gas::from_tgas(15);
+
+pub const DEFAULT_EPOCH_DURATION_SEC: u64 = 24 * 60 * 60;
+/// How long after its epoch closed anyone may cancel a batch that failed to sign
+pub const BATCH_RESTORE_TIMEOUT_SEC: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum EpochStatus {
+    /// Taking withdrawal requests
+    Open,
+    /// Closed and netted, batches are being signed
+    Settling,
+    /// Every batch is signed, requests can be claimed
+    Settled,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BatchStatus {
+    Pending,
+    Signing,
+    Signed,
+    Failed,
+    /// Given up on, its requests got their part back
+    Cancelled,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedWithdrawal {
+    pub account_id: AccountId,
+    pub eth_destination: EvmAddress,
+    pub aurora_destination: EvmAddress,
+    pub preview: RedeemPreview,
+    pub claimed: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchTransfer {
+    pub destination: EvmAddress,
+    pub amount: U128,
+}
+
+/// Pays every request of the epoch for one asset out of its treasury. A single destination
+/// gets a plain transfer, several are paid in one call of the chain's disperser contract
+/// after approving it for the total.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalBatch {
+    pub asset_address: String,
+    /// What every destination gets, netted over the epoch's requests
+    pub transfers: Vec<BatchTransfer>,
+    pub amount: U128,
+    pub status: BatchStatus,
+    /// Hex encoded signed transactions, to broadcast in this order
+    pub signed_txs: Vec<String>,
+}
+
+impl WithdrawalBatch {
+    /// Transactions the batch needs signed: a transfer, or an approval and the disperse call.
+    pub fn tx_count(&self) -> usize {
+        if self.transfers.len() == 1 {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn pays(&self, destination: EvmAddress) -> bool {
+        self.transfers.iter().any(|t| t.destination == destination)
+    }
+}
+
+/// Disperse contract `set_disperser` registers for a chain, called with
+/// `disperseToken(address token, address[] recipients, uint256[] values)`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Disperser {
+    pub chain: Chain,
+    pub address: EvmAddress,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalEpoch {
+    pub id: u64,
+    pub status: EpochStatus,
+    pub opened_at: u64,
+    pub closed_at: Option<u64>,
+    pub requests: Vec<QueuedWithdrawal>,
+    pub batches: Vec<WithdrawalBatch>,
+}
+
+impl WithdrawalEpoch {
+    /// Every batch is signed or was cancelled, so requests can be claimed.
+    fn batches_done(&self) -> bool {
+        self.batches
+            .iter()
+            .all(|b| matches!(b.status, BatchStatus::Signed | BatchStatus::Cancelled))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EpochView {
+    pub id: u64,
+    pub status: EpochStatus,
+    pub opened_at: u64,
+    pub closes_at: u64,
+    pub closed_at: Option<u64>,
+    pub request_count: u64,
+    pub batch_count: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct WithdrawalQueue {
+    pub epoch_duration_sec: u64,
+    pub current_epoch: u64,
+    /// Epochs with requests that are not all claimed yet, each in its own storage entry
+    pub epochs: LookupMap<u64, WithdrawalEpoch>,
+    pub dispersers: Vec<Disperser>,
+}
+
+impl Default for WithdrawalQueue {
+    fn default() -> Self {
+        Self {
+            epoch_duration_sec: DEFAULT_EPOCH_DURATION_SEC,
+            current_epoch: 0,
+            epochs: LookupMap::new(Prefix::Epochs),
+            dispersers: Vec::new(),
+        }
+    }
+}
+
+/// Nets `transfers` of (asset, destination, amount) into one batch per asset, paying each
+/// destination once. Assets and destinations keep the order they first appear in.
+pub fn net_batches(transfers: Vec<(String, EvmAddress, u128)>) -> Vec<WithdrawalBatch> {
+    let mut batches: Vec<WithdrawalBatch> = Vec::new();
+    for (asset_address, destination, amount) in transfers.into_iter().filter(|t| t.2 > 0) {
+        let batch = match batches.iter().position(|b| b.asset_address == asset_address) {
+            Some(index) => &mut batches[index],
+            None => {
+                batches.push(WithdrawalBatch {
+                    asset_address,
+                    transfers: Vec::new(),
+                    amount: U128(0),
+                    status: BatchStatus::Pending,
+                    signed_txs: Vec::new(),
+                });
+                batches.last_mut().unwrap()
+            }
+        };
+        batch.amount.0 += amount;
+        match batch.transfers.iter_mut().find(|t| t.destination == destination) {
+            Some(transfer) => transfer.amount.0 += amount,
+            None => batch.transfers.push(BatchTransfer {
+                destination,
+                amount: U128(amount),
+            }),
+        }
+    }
+    batches
+}
+
+fn abi_word(bytes: &[u8]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[32 - bytes.len()..].copy_from_slice(bytes);
+    word
+}
+
+/// Call data of ERC20 `approve(spender, amount)`.
+pub fn erc20_approve_data(spender: EvmAddress, amount: u128) -> Vec<u8> {
+    let mut data = vec![0x09, 0x5e, 0xa7, 0xb3];
+    data.extend_from_slice(&abi_word(&spender.as_bytes()));
+    data.extend_from_slice(&abi_word(&amount.to_be_bytes()));
+    data
+}
+
+/// Call data of `disperseToken(token, recipients, values)`, the two arrays encoded after
+/// the head as the ABI places dynamic arguments.
+pub fn disperse_token_data(token: EvmAddress, transfers: &[BatchTransfer]) -> Vec<u8> {
+    let count = transfers.len() as u128;
+    let mut data = vec![0xc7, 0x3a, 0x2d, 0x60];
+    data.extend_from_slice(&abi_word(&token.as_bytes()));
+    // Offsets of the arrays, counted from the start of the arguments
+    data.extend_from_slice(&abi_word(&96u128.to_be_bytes()));
+    data.extend_from_slice(&abi_word(&(96 + 32 * (count + 1)).to_be_bytes()));
+    data.extend_from_slice(&abi_word(&count.to_be_bytes()));
+    for transfer in transfers {
+        data.extend_from_slice(&abi_word(&transfer.destination.as_bytes()));
+    }
+    data.extend_from_slice(&abi_word(&count.to_be_bytes()));
+    for transfer in transfers {
+        data.extend_from_slice(&abi_word(&transfer.amount.0.to_be_bytes()));
+    }
+    data
+}
+
+fn page<T: Clone>(items: &[T], from_index: Option<u64>, limit: Option<u64>) -> Vec<T> {
+    items
+        .iter()
+        .skip(from_index.unwrap_or(0) as usize)
+        .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+        .cloned()
+        .collect()
+}
+
+impl Contract {
+    fn epoch(&self, epoch_id: u64) -> &WithdrawalEpoch {
+        self.withdrawal_queue
+            .epochs
+            .get(&epoch_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Epoch {} not found", epoch_id)))
+    }
+
+    fn epoch_mut(&mut self, epoch_id: u64) -> &mut WithdrawalEpoch {
+        self.withdrawal_queue
+            .epochs
+            .get_mut(&epoch_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Epoch {} not found", epoch_id)))
+    }
+
+    /// Where a batch pays `asset_address` of `request`. Bitcoin and components held on
+    /// NEAR aren't batched.
+    fn destination_for(&self, request: &QueuedWithdrawal, asset_address: &str) -> Option<EvmAddress> {
+        if self.near_token(asset_address).is_some() {
+            return None;
+        }
+        let asset = self.asset_info(asset_address)?;
+        match asset.chain() {
+            Chain::Ethereum => Some(request.eth_destination),
+            Chain::Aurora => Some(request.aurora_destination),
+            Chain::Bitcoin => None,
+        }
+    }
+
+    /// Gives back the `failed` parts of a queued request and takes them out of it, so
+    /// claiming it doesn't count them as redeemed.
+    fn restore_queued_request(&mut self, epoch_id: u64, request_index: usize, failed: &[AssetAmount]) {
+        let request = self.epoch(epoch_id).requests[request_index].clone();
+        let restored =
+            self.restore_failed_withdrawals(&request.account_id, &request.preview, failed);
+        let preview = &mut self.epoch_mut(epoch_id).requests[request_index].preview;
+        preview.shares.0 -= restored;
+        preview.assets.retain(|asset| !failed.contains(asset));
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Burns the shares right away and queues the withdrawal in the current epoch,
+    /// returning its id. Segregated custody can't be netted and is redeemed directly.
+    /// Queued withdrawals are paid in kind, without a share of the lending interest.
+    pub fn queue_withdrawal(
+        &mut self,
+        amount: RedeemAmount,
+        eth_destination: EvmAddress,
+        aurora_destination: EvmAddress,
+    ) -> u64 {
+        self.assert_not_frozen();
+        self.assert_no_cooldown();
+        let account_id = env::predecessor_account_id();
+        self.assert_not_segregated(&account_id, "Queueing a withdrawal");
+
+        let preview = self.preview_redeem(account_id.clone(), amount);
+        self.debit_redemption(&account_id, &preview);
+        // Bitcoin isn't batched, it is withdrawn through `withdraw_btc`
+        for asset in &preview.assets {
+            if self.asset_info(&asset.asset_address).map(|a| a.chain()) == Some(Chain::Bitcoin) {
+                self.add_btc_claim(&account_id, asset.amount.0);
+            }
+        }
+        // Neither are components held on NEAR, they are sent right away
+        let near_legs: Vec<AssetAmount> = preview
+            .assets
+            .iter()
+            .filter(|asset| asset.amount.0 > 0 && self.near_token(&asset.asset_address).is_some())
+            .cloned()
+            .collect();
+
+        let epoch_id = self.withdrawal_queue.current_epoch;
+        let epoch = self
+            .withdrawal_queue
+            .epochs
+            .entry(epoch_id)
+            .or_insert_with(|| WithdrawalEpoch {
+                id: epoch_id,
+                status: EpochStatus::Open,
+                opened_at: env::block_timestamp(),
+                closed_at: None,
+                requests: Vec::new(),
+                batches: Vec::new(),
+            });
+        epoch.requests.push(QueuedWithdrawal {
+            account_id: account_id.clone(),
+            eth_destination,
+            aurora_destination,
+            preview,
+            claimed: false,
+        });
+        let request_index = epoch.requests.len() as u64 - 1;
+
+        if let Some(transfers) = near_legs
+            .iter()
+            .map(|asset| self.transfer_near_token(asset, &account_id))
+            .reduce(|acc, transfer| acc.and(transfer))
+        {
+            transfers.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(NEAR_LEGS_CALLBACK_GAS)
+                    .queued_near_legs_callback(epoch_id, request_index, near_legs),
+            );
+        }
+        epoch_id
+    }
+
+    /// Gives back the parts of a queued withdrawal held on NEAR whose transfer failed, and
+    /// takes them out of the request so claiming it doesn't count them as redeemed.
+    #[private]
+    pub fn queued_near_legs_callback(
+        &mut self,
+        epoch_id: u64,
+        request_index: u64,
+        legs: Vec<AssetAmount>,
+    ) {
+        let failed: Vec<AssetAmount> = legs
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                matches!(env::promise_result(*index as u64), PromiseResult::Failed)
+            })
+            .map(|(_, leg)| leg)
+            .collect();
+        if failed.is_empty() {
+            return;
+        }
+        let account_id = self.epoch(epoch_id).requests[request_index as usize]
+            .account_id
+            .clone();
+        for leg in &failed {
+            emit_event(
+                "withdrawal_failed",
+                json!({
+                    "account_id": account_id,
+                    "asset_address": leg.asset_address,
+                    "amount": leg.amount,
+                    "reason": "transfer failed",
+                }),
+            );
+        }
+        self.restore_queued_request(epoch_id, request_index as usize, &failed);
+    }
+
+    /// Closes the current epoch once its duration passed and nets its requests into batches.
+    /// An epoch nobody queued a withdrawal in has nothing to close, it stays current.
+    pub fn close_withdrawal_epoch(&mut self) -> Option<EpochView> {
+        self.assert_owner();
+        let epoch_id = self.withdrawal_queue.current_epoch;
+        let opened_at = match self.withdrawal_queue.epochs.get(&epoch_id) {
+            Some(epoch) => epoch.opened_at,
+            None => return None,
+        };
+        let closes_at = opened_at.saturating_add(
+            self.withdrawal_queue
+                .epoch_duration_sec
+                .saturating_mul(NANOS_PER_SEC),
+        );
+        assert!(
+            env::block_timestamp() >= closes_at,
+            "Epoch {} closes at {}",
+            epoch_id,
+            closes_at
+        );
+
+        let epoch = self.epoch(epoch_id);
+        let transfers = epoch
+            .requests
+            .iter()
+            .flat_map(|request| {
+                request.preview.assets.iter().filter_map(move |asset| {
+                    self.destination_for(request, &asset.asset_address)
+                        .map(|destination| (asset.asset_address.clone(), destination, asset.amount.0))
+                })
+            })
+            .collect();
+        let batches = net_batches(transfers);
+
+        let epoch = self.epoch_mut(epoch_id);
+        epoch.status = if batches.is_empty() {
+            EpochStatus::Settled
+        } else {
+            EpochStatus::Settling
+        };
+        epoch.closed_at = Some(env::block_timestamp());
+        epoch.batches = batches;
+        self.withdrawal_queue.current_epoch += 1;
+
+        self.get_withdrawal_epoch(epoch_id)
+    }
+
+    /// Signs the next transaction of one batch of a closed epoch, also used to retry one
+    /// that failed. `network_details` carries the next nonce of the asset's treasury.
+    pub fn sign_withdrawal_batch(
+        &mut self,
+        epoch_id: u64,
+        batch_index: u64,
+        network_details: NetworkDetails,
+    ) -> Promise {
+        self.assert_owner();
+        let batch = self
+            .epoch(epoch_id)
+            .batches
+            .get(batch_index as usize)
+            .cloned()
+            .expect("Batch not found");
+        assert!(
+            matches!(batch.status, BatchStatus::Pending | BatchStatus::Failed),
+            "Batch is {:?}",
+            batch.status
+        );
+        // Components removed since the epoch closed are still found through their wind-down
+        let asset = self
+            .asset_info(&batch.asset_address)
+            .unwrap_or_else(|| env::panic_str(&format!("Unknown asset {}", batch.asset_address)));
+        let token = asset.contract_address.expect("Asset has no contract address");
+
+        let (contract_address, data) = if batch.tx_count() == 1 {
+            let transfer = &batch.transfers[0];
+            let data = self
+                .construct_erc20_transfer_data(transfer.destination.as_bytes(), transfer.amount.0);
+            (token, data)
+        } else {
+            let disperser = self
+                .withdrawal_queue
+                .dispersers
+                .iter()
+                .find(|d| d.chain == asset.chain())
+                .map(|d| d.address)
+                .unwrap_or_else(|| {
+                    env::panic_str(&format!("No disperser set for {:?}", asset.chain()))
+                });
+            if batch.signed_txs.is_empty() {
+                (token, erc20_approve_data(disperser, batch.amount.0))
+            } else {
+                (disperser, disperse_token_data(token, &batch.transfers))
+            }
+        };
+        let (sign_promise, evm_tx_wrapper, expected_signer) = self.sign_evm_call(
+            contract_address,
+            data,
+            network_details,
+            asset.chain().treasury_path(),
+        );
+        self.epoch_mut(epoch_id).batches[batch_index as usize].status = BatchStatus::Signing;
+
+        sign_promise.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(SIGN_BATCH_CALLBACK_GAS)
+                .sign_batch_callback(epoch_id, batch_index, evm_tx_wrapper, expected_signer),
+        )
+    }
+
+    #[private]
+    pub fn sign_batch_callback(
+        &mut self,
+        epoch_id: u64,
+        batch_index: u64,
+        evm_tx_wrapper: EVMTransactionWrapper,
+        expected_signer: EvmAddress,
+        #[callback_result] result: Result<SignResult, PromiseError>,
+    ) -> bool {
+        let signed = signed_evm_tx(&evm_tx_wrapper, expected_signer, result);
+        let epoch = self.epoch_mut(epoch_id);
+        let batch = &mut epoch.batches[batch_index as usize];
+
+        match signed {
+            Ok(signed_tx) => {
+                batch.signed_txs.push(hex::encode(signed_tx));
+                batch.status = if batch.signed_txs.len() == batch.tx_count() {
+                    BatchStatus::Signed
+                } else {
+                    BatchStatus::Pending
+                };
+                if epoch.batches_done() {
+                    epoch.status = EpochStatus::Settled;
+                }
+                true
+            }
+            Err(err) => {
+                // The batch stays owed to its requests and is signed again by the keeper
+                batch.status = BatchStatus::Failed;
+                emit_event(
+                    "withdrawal_batch_failed",
+                    json!({
+                        "epoch_id": epoch_id,
+                        "batch_index": batch_index,
+                        "reason": err,
+                    }),
+                );
+                false
+            }
+        }
+    }
+
+    /// Gives up on a batch that failed to sign and gives every request of the epoch its
+    /// part back, with shares in proportion like a failed redemption. The owner can do it
+    /// any time, anyone once `BATCH_RESTORE_TIMEOUT_SEC` passed since the epoch closed.
+    pub fn cancel_withdrawal_batch(&mut self, epoch_id: u64, batch_index: u64) {
+        let epoch = self.epoch(epoch_id);
+        let batch = epoch
+            .batches
+            .get(batch_index as usize)
+            .expect("Batch not found");
+        assert_eq!(
+            batch.status,
+            BatchStatus::Failed,
+            "Only a batch that failed to sign can be cancelled"
+        );
+        if env::predecessor_account_id() != self.owner_id {
+            let cancellable_at = epoch
+                .closed_at
+                .unwrap_or(0)
+                .saturating_add(BATCH_RESTORE_TIMEOUT_SEC.saturating_mul(NANOS_PER_SEC));
+            assert!(
+                env::block_timestamp() >= cancellable_at,
+                "Batch can be cancelled by anyone at {}",
+                cancellable_at
+            );
+        }
+        // Netting put every request's part of the asset into its one batch
+        let asset_address = batch.asset_address.clone();
+        let legs: Vec<(usize, AssetAmount)> = epoch
+            .requests
+            .iter()
+            .enumerate()
+            .filter_map(|(index, request)| {
+                request
+                    .preview
+                    .assets
+                    .iter()
+                    .find(|asset| asset.asset_address == asset_address && asset.amount.0 > 0)
+                    .map(|asset| (index, asset.clone()))
+            })
+            .collect();
+
+        self.epoch_mut(epoch_id).batches[batch_index as usize].status = BatchStatus::Cancelled;
+        for (request_index, leg) in legs {
+            self.restore_queued_request(epoch_id, request_index, &[leg]);
+        }
+        let epoch = self.epoch_mut(epoch_id);
+        if epoch.batches_done() {
+            epoch.status = EpochStatus::Settled;
+        }
+        emit_event(
+            "withdrawal_batch_cancelled",
+            json!({
+                "epoch_id": epoch_id,
+                "batch_index": batch_index,
+                "asset_address": asset_address,
+            }),
+        );
+    }
+
+    /// Marks the caller's requests in a settled epoch as claimed and returns the signed
+    /// transactions paying them. The epoch is dropped once every request is claimed.
+    pub fn claim_queued_withdrawal(&mut self, epoch_id: u64) -> Vec<String> {
+        let account_id = env::predecessor_account_id();
+        let epoch = self.epoch(epoch_id);
+        assert_eq!(epoch.status, EpochStatus::Settled, "Epoch {} is not settled", epoch_id);
+
+        let mut signed_txs = Vec::new();
+        for request in epoch
+            .requests
+            .iter()
+            .filter(|r| r.account_id == account_id && !r.claimed)
+        {
+            for asset in request.preview.assets.iter().filter(|a| a.amount.0 > 0) {
+                let destination = match self.destination_for(request, &asset.asset_address) {
+                    Some(destination) => destination,
+                    None => continue,
+                };
+                let batch = epoch
+                    .batches
+                    .iter()
+                    .find(|b| b.asset_address == asset.asset_address && b.pays(destination));
+                for signed_tx in batch.into_iter().flat_map(|b| &b.signed_txs) {
+                    if !signed_txs.contains(signed_tx) {
+                        signed_txs.push(signed_tx.clone());
+                    }
+                }
+            }
+        }
+
+        let claimed: Vec<RedeemPreview> = epoch
+            .requests
+            .iter()
+            .filter(|r| r.account_id == account_id && !r.claimed)
+            .map(|r| r.preview.clone())
+            .collect();
+        assert!(!claimed.is_empty(), "Nothing to claim in epoch {}", epoch_id);
+        for preview in &claimed {
+            let value = self.usdc_value(&preview.assets);
+            self.record_redemption_flow(&account_id, preview.shares.0, value);
+        }
+
+        let epoch = self.epoch_mut(epoch_id);
+        for request in epoch.requests.iter_mut().filter(|r| r.account_id == account_id) {
+            request.claimed = true;
+        }
+        if epoch.requests.iter().all(|r| r.claimed) {
+            self.withdrawal_queue.epochs.remove(&epoch_id);
+        }
+        signed_txs
+    }
+
+    /// Contract paying batches with several destinations on `chain`, `None` removes it.
+    pub fn set_disperser(&mut self, chain: Chain, address: Option<EvmAddress>) {
+        self.assert_owner();
+        let dispersers = &mut self.withdrawal_queue.dispersers;
+        dispersers.retain(|d| d.chain != chain);
+        if let Some(address) = address {
+            dispersers.push(Disperser { chain, address });
+        }
+    }
+
+    pub fn get_dispersers(&self) -> Vec<Disperser> {
+        self.withdrawal_queue.dispersers.clone()
+    }
+
+    pub fn set_epoch_duration_sec(&mut self, epoch_duration_sec: u64) {
+        self.assert_owner();
+        self.withdrawal_queue.epoch_duration_sec = epoch_duration_sec;
+    }
+
+    pub fn get_current_withdrawal_epoch(&self) -> u64 {
+        self.withdrawal_queue.current_epoch
+    }
+
+    pub fn get_withdrawal_epoch(&self, epoch_id: u64) -> Option<EpochView> {
+        self.withdrawal_queue.epochs.get(&epoch_id).map(|epoch| EpochView {
+            id: epoch.id,
+            status: epoch.status,
+            opened_at: epoch.opened_at,
+            closes_at: epoch.opened_at.saturating_add(
+                self.withdrawal_queue
+                    .epoch_duration_sec
+                    .saturating_mul(NANOS_PER_SEC),
+            ),
+            closed_at: epoch.closed_at,
+            request_count: epoch.requests.len() as u64,
+            batch_count: epoch.batches.len() as u64,
+        })
+    }
+
+    pub fn get_epoch_requests(
+        &self,
+        epoch_id: u64,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<QueuedWithdrawal> {
+        page(&self.epoch(epoch_id).requests, from_index, limit)
+    }
+
+    pub fn get_epoch_batches(
+        &self,
+        epoch_id: u64,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<WithdrawalBatch> {
+        page(&self.epoch(epoch_id).batches, from_index, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redeem::MAX_BPS;
+    use crate::{AssetInfo, PriceFeedInfo};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+    const DESTINATION: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract
+    }
+
+    /// `accounts(1)` queued all of a 1001 deposit, and the epoch closed with its ETH
+    /// batch failed to sign.
+    fn failed_batch() -> Contract {
+        let mut contract = setup();
+        contract.process_deposit(accounts(1), U128(1_001));
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .build());
+        let destination: EvmAddress = DESTINATION.parse().unwrap();
+        contract.queue_withdrawal(RedeemAmount::BasisPoints(MAX_BPS), destination, destination);
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(DEFAULT_EPOCH_DURATION_SEC * NANOS_PER_SEC)
+            .build());
+        contract.close_withdrawal_epoch();
+        contract.epoch_mut(0).batches[0].status = BatchStatus::Failed;
+        contract
+    }
+
+    #[test]
+    fn test_cancel_failed_batch_restores_its_part() {
+        let mut contract = failed_batch();
+        contract.cancel_withdrawal_batch(0, 0);
+
+        let balances = contract.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(701 * UNITS)));
+        // ETH is 70% of the fund
+        assert_eq!(contract.get_user_shares(accounts(1)), U128(700));
+        let epoch = contract.epoch(0);
+        assert_eq!(epoch.batches[0].status, BatchStatus::Cancelled);
+        assert_eq!(epoch.status, EpochStatus::Settling);
+        let request = &epoch.requests[0];
+        assert_eq!(request.preview.shares, U128(301));
+        assert_eq!(request.preview.assets.len(), 1);
+        assert_eq!(request.preview.assets[0].asset_address, AURORA);
+    }
+
+    #[test]
+    #[should_panic(expected = "Batch can be cancelled by anyone at")]
+    fn test_others_wait_to_cancel_a_failed_batch() {
+        let mut contract = failed_batch();
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.cancel_withdrawal_batch(0, 0);
+    }
+
+    #[test]
+    fn test_net_batches() {
+        let alice: EvmAddress = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse().unwrap();
+        let bob: EvmAddress = "0xf08A50178dfcDe18524640EA6618a1f965821715".parse().unwrap();
+        let batches = net_batches(vec![
+            ("eth".to_string(), alice, 10),
+            ("aurora".to_string(), alice, 5),
+            ("eth".to_string(), bob, 7),
+            ("eth".to_string(), alice, 3),
+            ("aurora".to_string(), bob, 0),
+        ]);
+
+        let netted: Vec<(&str, u128, Vec<(EvmAddress, u128)>)> = batches
+            .iter()
+            .map(|b| {
+                let transfers = b.transfers.iter().map(|t| (t.destination, t.amount.0)).collect();
+                (b.asset_address.as_str(), b.amount.0, transfers)
+            })
+            .collect();
+        assert_eq!(
+            netted,
+            vec![
+                ("eth", 20, vec![(alice, 13), (bob, 7)]),
+                ("aurora", 5, vec![(alice, 5)]),
+            ]
+        );
+        assert_eq!(batches[0].tx_count(), 2);
+        assert_eq!(batches[1].tx_count(), 1);
+    }
+
+    #[test]
+    fn test_disperse_token_data() {
+        let token: EvmAddress = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse().unwrap();
+        let bob: EvmAddress = "0xf08A50178dfcDe18524640EA6618a1f965821715".parse().unwrap();
+        let data = disperse_token_data(
+            token,
+            &[BatchTransfer {
+                destination: bob,
+                amount: U128(7),
+            }],
+        );
+
+        assert_eq!(data.len(), 4 + 32 * 7);
+        assert_eq!(data[..4], [0xc7, 0x3a, 0x2d, 0x60]);
+        assert_eq!(data[4 + 12..4 + 32], token.as_bytes());
+        // recipients start after the three head words, values after their length and entry
+        assert_eq!(data[4 + 32 + 31], 96);
+        assert_eq!(data[4 + 64 + 31], 160);
+        assert_eq!(data[4 + 96 + 31], 1);
+        assert_eq!(data[4 + 128 + 12..4 + 160], bob.as_bytes());
+        assert_eq!(data[4 + 192 + 31], 7);
+    }
+
+    #[test]
+    fn test_page() {
+        let items: Vec<u64> = (0..10).collect();
+        assert_eq!(page(&items, Some(8), None), vec![8, 9]);
+        assert_eq!(page(&items, None, Some(3)), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,938 @@
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, near_bindgen, AccountId, Gas, NearToken, Promise, PromiseOrValue, PromiseResult,
+};
+
+use crate::events::emit_event;
+use crate::lockup::{restore_lots, DepositLot};
+use crate::price_store::value_to_usdc;
+use crate::swap::{NearSale, SaleLeg, NEAR_SALE_CHAIN_GAS};
+use crate::{Contract, ContractExt, WithdrawRequest};
+
+pub const MAX_BPS: u32 = 10_000;
+
+const FT_TRANSFER_GAS: Gas = Gas::from_tgas(30);
+const REDEEM_CALLBACK_GAS: Gas = Gas::from_tgas(20);
+/// Covers topping the USDC up from the lending protocol and the payout
+const FINISH_USDC_REDEMPTION_GAS: Gas = Gas::from_tgas(160);
+/// Left to the call that starts a redemption's sales
+const SELL_GAS_RESERVE: Gas = Gas::from_tgas(20);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum RedeemAmount {
+    Shares(U128),
+    /// Part of the position in basis points, `10000` redeems all of it
+    BasisPoints(u32),
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetAmount {
+    pub asset_address: String,
+    pub amount: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedeemPreview {
+    pub shares: U128,
+    pub remaining_shares: U128,
+    /// Amounts paid out, after the early exit penalty
+    pub assets: Vec<AssetAmount>,
+    /// Shares forfeited for redeeming before the lock-up ended, see `lockup.rs`
+    pub penalty_shares: U128,
+    /// Amounts handed to the remaining holders
+    pub penalty: Vec<AssetAmount>,
+    /// Share of the lending interest, in USDC. Only `redeem_at_oracle_price` and
+    /// `claim_withdrawal_at_oracle_price` pay it. Withdrawals in kind leave it to the
+    /// remaining holders.
+    pub interest: U128,
+}
+
+/// A USDC redemption whose legs are being sold on the AMM, see `redeem_at_oracle_price`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UsdcRedemption {
+    pub preview: RedeemPreview,
+    /// Share of the lending interest, paid on top of the sales
+    pub interest: U128,
+    /// Legs waiting for `continue_usdc_redemption`
+    pub to_sell: Vec<SaleLeg>,
+    /// Sales on their way through the AMM
+    pub selling: u32,
+    /// USDC the sold legs brought
+    pub proceeds: U128,
+    /// Legs the AMM didn't take or that would have sold below their minimum
+    pub unsold: Vec<AssetAmount>,
+}
+
+/// Part of `balance` that `shares` out of `total_shares` is entitled to, rounded down so
+/// the remaining balance never runs short. Redeeming every share returns the whole balance.
+pub fn pro_rata(balance: u128, shares: u128, total_shares: u128) -> u128 {
+    if shares == total_shares {
+        return balance;
+    }
+    balance
+        .checked_mul(shares)
+        .expect("Redemption amount overflow")
+        / total_shares
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_user_shares(&self, account_id: AccountId) -> U128 {
+        self.user_shares.get(&account_id).copied().unwrap_or(U128(0))
+    }
+
+    /// Exact per-asset amounts `redeem` would send for `amount`. The Bitcoin part is not
+    /// sent by `redeem`, it becomes claimable through `withdraw_btc` instead. `interest`
+    /// is only paid when redeeming at the oracle price.
+    pub fn preview_redeem(&self, account_id: AccountId, amount: RedeemAmount) -> RedeemPreview {
+        let user_shares = self.get_user_shares(account_id.clone()).0;
+        assert!(user_shares > 0, "No shares to redeem");
+
+        let shares = match amount {
+            RedeemAmount::Shares(shares) => shares.0,
+            RedeemAmount::BasisPoints(bps) => {
+                assert!(
+                    bps > 0 && bps <= MAX_BPS,
+                    "Basis points must be between 1 and {}",
+                    MAX_BPS
+                );
+                pro_rata(user_shares, bps as u128, MAX_BPS as u128)
+            }
+        };
+        assert!(shares > 0, "Nothing to redeem");
+        assert!(shares <= user_shares, "Not enough shares");
+
+        let penalty_shares = self.early_exit_penalty_shares(&account_id, shares);
+        let balances = self.settled_balances(&account_id);
+        let (assets, penalty) = self
+            .assets
+            .iter()
+            .map(|asset| {
+                let balance = balances
+                    .as_ref()
+                    .and_then(|balances| balances.get(&asset.key()))
+                    .map_or(0, |balance| balance.0);
+                let gross = pro_rata(balance, shares, user_shares);
+                let net = pro_rata(balance, shares - penalty_shares, user_shares);
+                (
+                    AssetAmount {
+                        asset_address: asset.key(),
+                        amount: U128(net),
+                    },
+                    AssetAmount {
+                        asset_address: asset.key(),
+                        amount: U128(gross - net),
+                    },
+                )
+            })
+            .unzip();
+
+        RedeemPreview {
+            shares: U128(shares),
+            remaining_shares: U128(user_shares - shares),
+            assets,
+            penalty_shares: U128(penalty_shares),
+            penalty,
+            interest: U128(self.interest_share(shares - penalty_shares, self.total_assets.0)),
+        }
+    }
+
+    /// Burns `amount` of the caller's shares and withdraws their pro-rata slice of every
+    /// underlying asset to the destinations in `request`. The share of the lending
+    /// interest in the preview is not paid, it stays with the remaining holders.
+    #[payable]
+    pub fn redeem(&mut self, amount: RedeemAmount, request: WithdrawRequest) -> Promise {
+        self.assert_not_frozen();
+        self.assert_no_cooldown();
+        let account_id = env::predecessor_account_id();
+        let preview = self.preview_redeem(account_id.clone(), amount);
+        self.debit_redemption(&account_id, &preview);
+
+        env::log_str(&format!(
+            "Redeemed {} shares of user {}",
+            preview.shares.0, account_id
+        ));
+        self.sign_withdrawals(&account_id, preview, &request)
+    }
+
+    /// USDC `redeem_at_oracle_price` would pay for `amount` at the cached prices. The
+    /// sales on the AMM decide what it actually pays.
+    pub fn preview_redeem_at_oracle_price(
+        &self,
+        account_id: AccountId,
+        amount: RedeemAmount,
+    ) -> U128 {
+        let preview = self.preview_redeem(account_id, amount);
+        U128(self.usdc_value(&preview.assets) + preview.interest.0)
+    }
+
+    /// Burns `amount` of the caller's shares and pays their pro-rata slice out in USDC on
+    /// NEAR. Every asset of the slice is sold on the AMM, so it has to be one the fund
+    /// holds on NEAR, see `swap.rs`. The legs that don't fit in this call's gas wait for
+    /// `continue_usdc_redemption`, and the payout follows the last sale.
+    ///
+    /// `min_amount_out` is split over the legs by their value at the cached prices, and
+    /// no leg sells for less than its part or more than `max_slippage_bps` below its
+    /// value. A leg that can't is given back in kind with its shares.
+    #[payable]
+    pub fn redeem_at_oracle_price(
+        &mut self,
+        amount: RedeemAmount,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<bool> {
+        self.assert_not_frozen();
+        self.assert_no_cooldown();
+        let account_id = env::predecessor_account_id();
+        let preview = self.preview_redeem(account_id.clone(), amount);
+        self.debit_redemption(&account_id, &preview);
+        self.pay_out_usdc(account_id, preview, min_amount_out)
+    }
+
+    /// Sells the legs of `account_id`'s USDC redemption that are left, or pays it out
+    /// once they are all sold. Anyone may call it.
+    pub fn continue_usdc_redemption(&mut self, account_id: AccountId) -> PromiseOrValue<bool> {
+        let redemption = self
+            .usdc_redemptions
+            .get(&account_id)
+            .expect("No USDC redemption to continue");
+        assert!(
+            redemption.selling == 0,
+            "Sales of the redemption are still on their way"
+        );
+        self.sell_usdc_redemption(&account_id)
+    }
+
+    pub fn get_usdc_redemption(&self, account_id: AccountId) -> Option<UsdcRedemption> {
+        self.usdc_redemptions.get(&account_id).cloned()
+    }
+
+    /// Pays out the redemption after the sales of its last legs.
+    #[private]
+    pub fn finish_usdc_redemption(&mut self, account_id: AccountId) -> PromiseOrValue<bool> {
+        match self.usdc_redemptions.get(&account_id) {
+            Some(redemption) if redemption.selling == 0 && redemption.to_sell.is_empty() => {
+                self.pay_out_sold_redemption(&account_id)
+            }
+            _ => PromiseOrValue::Value(false),
+        }
+    }
+
+    #[private]
+    pub fn usdc_payout_callback(
+        &mut self,
+        account_id: AccountId,
+        shares: U128,
+        amount_out: U128,
+    ) -> bool {
+        if matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            env::log_str(&format!(
+                "Paid {} USDC to user {} for {} shares",
+                amount_out.0, account_id, shares.0
+            ));
+            self.record_redemption_flow(&account_id, shares.0, amount_out.0);
+            return true;
+        }
+
+        // The underlying is sold already, so the USDC is kept for `claim_usdc`
+        self.usdc_claims
+            .entry(account_id.clone())
+            .or_insert(U128(0))
+            .0 += amount_out.0;
+        self.record_redemption_flow(&account_id, shares.0, 0);
+        emit_event(
+            "usdc_redemption_failed",
+            json!({
+                "account_id": account_id,
+                "shares": shares,
+                "amount": amount_out,
+            }),
+        );
+        false
+    }
+
+    /// Retries sending the USDC a redemption failed to pay the caller. It may have been
+    /// lent out since, so it is withdrawn first if need be.
+    pub fn claim_usdc(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let amount = self
+            .usdc_claims
+            .remove(&account_id)
+            .expect("No USDC to claim");
+        self.pay_out_with_liquidity(account_id, 0, amount.0)
+    }
+
+    pub fn get_usdc_claim(&self, account_id: AccountId) -> U128 {
+        self.usdc_claims.get(&account_id).copied().unwrap_or(U128(0))
+    }
+
+    /// Records that the keeper sold `amount` of a pending asset back into USDC, see
+    /// `get_pending_sales`.
+    pub fn settle_pending_sale(&mut self, asset_address: String, amount: U128) {
+        self.assert_owner();
+        // Sales of a component that was removed since are still settled
+        let key = match self.asset_info(&asset_address) {
+            Some(asset) => asset.key(),
+            None => self.registered_asset_key(&asset_address),
+        };
+        let pending = self
+            .pending_sales
+            .get_mut(&key)
+            .filter(|pending| pending.0 >= amount.0)
+            .expect("Amount exceeds the pending sale");
+        pending.0 -= amount.0;
+    }
+
+    /// Underlying the fund took over from USDC redemptions made before they sold on the
+    /// AMM, still to be sold by the keeper.
+    pub fn get_pending_sales(&self) -> Vec<AssetAmount> {
+        self.pending_sales
+            .iter()
+            .filter(|(_, amount)| amount.0 > 0)
+            .map(|(asset_address, amount)| AssetAmount {
+                asset_address: asset_address.clone(),
+                amount: *amount,
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Sells an already debited redemption into USDC and pays it out, see
+    /// `redeem_at_oracle_price`.
+    pub(crate) fn pay_out_usdc(
+        &mut self,
+        account_id: AccountId,
+        preview: RedeemPreview,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<bool> {
+        self.assert_not_segregated(&account_id, "Redeeming to USDC");
+        assert!(
+            !self.usdc_redemptions.contains_key(&account_id),
+            "A USDC redemption is still selling"
+        );
+        // The shares are burned already, count them back in to find their interest
+        let interest = self.interest_share(
+            preview.shares.0 - preview.penalty_shares.0,
+            self.total_assets.0 + preview.shares.0,
+        );
+        let value = self.usdc_value(&preview.assets);
+        assert!(
+            value + interest >= min_amount_out.0,
+            "Redemption pays {} USDC at the oracle price, below the minimum of {}",
+            value + interest,
+            min_amount_out.0
+        );
+        assert!(value + interest > 0, "Nothing to redeem");
+        assert!(self.swap_venue.amm.is_some(), "No AMM is set");
+        for asset in preview.assets.iter().filter(|asset| asset.amount.0 > 0) {
+            assert!(
+                self.swap_venue.routes.contains_key(&asset.asset_address),
+                "Asset {} is not held on NEAR, redeem it in kind",
+                asset.asset_address
+            );
+        }
+
+        let legs_min = min_amount_out.0.saturating_sub(interest);
+        let to_sell = preview
+            .assets
+            .iter()
+            .filter(|asset| asset.amount.0 > 0)
+            .map(|asset| {
+                let leg_value = self.usdc_value(std::slice::from_ref(asset));
+                let floor = pro_rata(
+                    leg_value,
+                    (MAX_BPS - self.swap_venue.max_slippage_bps) as u128,
+                    MAX_BPS as u128,
+                );
+                let part = legs_min
+                    .checked_mul(leg_value)
+                    .expect("Redemption amount overflow")
+                    .div_ceil(value.max(1));
+                SaleLeg {
+                    asset: asset.clone(),
+                    min_amount_out: U128(floor.max(part)),
+                }
+            })
+            .collect();
+
+        self.lending.pay_interest(interest);
+        self.usdc_redemptions.insert(
+            account_id.clone(),
+            UsdcRedemption {
+                preview,
+                interest: U128(interest),
+                to_sell,
+                selling: 0,
+                proceeds: U128(0),
+                unsold: Vec::new(),
+            },
+        );
+        self.sell_usdc_redemption(&account_id)
+    }
+
+    /// Starts the sales of as many legs as the gas left allows, followed by the payout
+    /// when that covers the last leg and there is gas for the payout too.
+    fn sell_usdc_redemption(&mut self, account_id: &AccountId) -> PromiseOrValue<bool> {
+        let gas_left = env::prepaid_gas()
+            .as_gas()
+            .saturating_sub(env::used_gas().as_gas() + SELL_GAS_RESERVE.as_gas());
+        let redemption = self.usdc_redemptions.get_mut(account_id).unwrap();
+        let remaining = redemption.to_sell.len() as u64;
+        if remaining == 0 {
+            return self.pay_out_sold_redemption(account_id);
+        }
+        let sales = (gas_left / NEAR_SALE_CHAIN_GAS.as_gas()).min(remaining);
+        assert!(sales > 0, "Not enough gas to sell a leg of the redemption");
+        let pays_out = gas_left
+            >= NEAR_SALE_CHAIN_GAS.as_gas() * remaining + FINISH_USDC_REDEMPTION_GAS.as_gas();
+
+        let legs: Vec<SaleLeg> = redemption.to_sell.drain(..sales as usize).collect();
+        redemption.selling += sales as u32;
+        let sales = legs
+            .into_iter()
+            .map(|leg| self.start_near_sale(account_id, leg))
+            .reduce(Promise::and)
+            .unwrap();
+        if !pays_out {
+            return PromiseOrValue::Promise(sales);
+        }
+        PromiseOrValue::Promise(
+            sales.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(FINISH_USDC_REDEMPTION_GAS)
+                    .finish_usdc_redemption(account_id.clone()),
+            ),
+        )
+    }
+
+    /// Hands the USDC a leg's sale brought, or the leg itself if it didn't sell, to the
+    /// redemption.
+    pub(crate) fn finish_near_sale(&mut self, sale: &NearSale, amount_out: Option<u128>) {
+        let redemption = self
+            .usdc_redemptions
+            .get_mut(&sale.account_id)
+            .expect("No USDC redemption for the sale");
+        redemption.selling -= 1;
+        match amount_out {
+            Some(amount_out) => {
+                redemption.proceeds.0 += amount_out;
+                emit_event(
+                    "near_sale_executed",
+                    json!({
+                        "account_id": sale.account_id,
+                        "asset_address": sale.leg.asset.asset_address,
+                        "amount_in": sale.leg.asset.amount,
+                        "amount_out": U128(amount_out),
+                    }),
+                );
+            }
+            None => {
+                redemption.unsold.push(sale.leg.asset.clone());
+                emit_event(
+                    "near_sale_failed",
+                    json!({
+                        "account_id": sale.account_id,
+                        "asset_address": sale.leg.asset.asset_address,
+                        "amount_in": sale.leg.asset.amount,
+                        "min_amount_out": sale.leg.min_amount_out,
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Gives the unsold legs back and pays the proceeds of the rest out with their
+    /// share of the interest.
+    fn pay_out_sold_redemption(&mut self, account_id: &AccountId) -> PromiseOrValue<bool> {
+        let UsdcRedemption {
+            preview,
+            interest,
+            proceeds,
+            unsold,
+            ..
+        } = self.usdc_redemptions.remove(account_id).unwrap();
+        let restored = if unsold.is_empty() {
+            0
+        } else {
+            self.restore_failed_withdrawals(account_id, &preview, &unsold)
+        };
+        let shares = preview.shares.0 - restored;
+        let net_shares = preview.shares.0 - preview.penalty_shares.0;
+        let paid_interest = pro_rata(interest.0, net_shares.saturating_sub(restored), net_shares);
+        self.lending.restore_interest(interest.0 - paid_interest);
+
+        let amount_out = proceeds.0 + paid_interest;
+        if amount_out == 0 {
+            self.record_redemption_flow(account_id, shares, 0);
+            return PromiseOrValue::Value(false);
+        }
+        PromiseOrValue::Promise(self.pay_out_with_liquidity(account_id.clone(), shares, amount_out))
+    }
+
+    pub(crate) fn send_usdc_payout(
+        &self,
+        account_id: AccountId,
+        shares: u128,
+        amount_out: u128,
+    ) -> Promise {
+        ext_ft_core::ext(self.usdc_contract.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_transfer(account_id.clone(), U128(amount_out), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(REDEEM_CALLBACK_GAS)
+                    .usdc_payout_callback(account_id, U128(shares), U128(amount_out)),
+            )
+    }
+
+    /// USDC worth `assets` at the cached prices.
+    pub(crate) fn usdc_value(&self, assets: &[AssetAmount]) -> u128 {
+        value_to_usdc(
+            assets
+                .iter()
+                .map(|asset| self.asset_value(&asset.asset_address, asset.amount.0))
+                .sum(),
+        )
+    }
+
+    /// Burns the shares of `preview` and takes its assets out of the balance, returning
+    /// the deposit lots the shares came from.
+    pub(crate) fn debit_redemption(
+        &mut self,
+        account_id: &AccountId,
+        preview: &RedeemPreview,
+    ) -> Vec<DepositLot> {
+        self.settle_penalties(account_id);
+        let balances = self
+            .user_balances
+            .get_mut(account_id)
+            .expect("No balance found for user");
+        for (asset, penalty) in preview.assets.iter().zip(&preview.penalty) {
+            if let Some(balance) = balances.get_mut(&asset.asset_address) {
+                balance.0 -= asset.amount.0 + penalty.amount.0;
+            }
+        }
+        self.user_shares
+            .insert(account_id.clone(), preview.remaining_shares);
+        self.total_assets = U128(self.total_assets.0 - preview.shares.0);
+        self.track_custody_shares(account_id, 0, preview.shares.0);
+        let lots = self.consume_deposit_lots(account_id, preview.shares.0);
+        self.distribute_penalty(account_id, &preview.penalty);
+        self.sync_holder(account_id);
+        lots
+    }
+
+    /// Gives back the `failed` transfers of a redemption. When nothing was sent the whole
+    /// redemption is undone, otherwise the failed assets come back with shares in
+    /// proportion to their weight in the fund. Returns the shares given back.
+    pub(crate) fn restore_failed_withdrawals(
+        &mut self,
+        account_id: &AccountId,
+        preview: &RedeemPreview,
+        failed: &[AssetAmount],
+    ) -> u128 {
+        let sent_any = preview
+            .assets
+            .iter()
+            .any(|asset| asset.amount.0 > 0 && !failed.contains(asset));
+        if !sent_any {
+            return self.credit_redemption(account_id, preview, Vec::new());
+        }
+
+        let weight_of = |asset_address: &str| {
+            self.assets
+                .iter()
+                .find(|a| a.key() == asset_address)
+                .map_or(0, |a| a.weight as u128)
+        };
+        let total_weight: u128 = preview
+            .assets
+            .iter()
+            .filter(|asset| asset.amount.0 > 0)
+            .map(|asset| weight_of(&asset.asset_address))
+            .sum();
+        let failed_weight: u128 = failed.iter().map(|asset| weight_of(&asset.asset_address)).sum();
+        let shares = pro_rata(
+            preview.shares.0 - preview.penalty_shares.0,
+            failed_weight,
+            total_weight.max(1),
+        );
+
+        self.settle_penalties(account_id);
+        let balances = self.user_balances.entry(account_id.clone()).or_default();
+        for asset in failed {
+            balances
+                .entry(asset.asset_address.clone())
+                .or_insert(U128(0))
+                .0 += asset.amount.0;
+        }
+        self.user_shares
+            .entry(account_id.clone())
+            .or_insert(U128(0))
+            .0 += shares;
+        self.total_assets = U128(self.total_assets.0 + shares);
+        self.track_custody_shares(account_id, shares, 0);
+        self.record_deposit_lot(account_id, shares, 0);
+        self.sync_holder(account_id);
+        shares
+    }
+
+    /// Undoes `debit_redemption` except for the penalty, which already went to the other
+    /// holders. With the `lots` the shares were taken from they come back as they were,
+    /// without them they come back unlocked. Returns the shares given back.
+    pub(crate) fn credit_redemption(
+        &mut self,
+        account_id: &AccountId,
+        preview: &RedeemPreview,
+        lots: Vec<DepositLot>,
+    ) -> u128 {
+        let shares = preview.shares.0 - preview.penalty_shares.0;
+        self.settle_penalties(account_id);
+        let balances = self.user_balances.entry(account_id.clone()).or_default();
+        for asset in &preview.assets {
+            balances
+                .entry(asset.asset_address.clone())
+                .or_insert(U128(0))
+                .0 += asset.amount.0;
+        }
+        self.user_shares
+            .entry(account_id.clone())
+            .or_insert(U128(0))
+            .0 += shares;
+        self.total_assets = U128(self.total_assets.0 + shares);
+        self.track_custody_shares(account_id, shares, 0);
+        if lots.is_empty() {
+            self.record_deposit_lot(account_id, shares, 0);
+        } else {
+            let account_lots = self.deposit_lots.entry(account_id.clone()).or_default();
+            restore_lots(account_lots, lots, preview.penalty_shares.0);
+        }
+        self.sync_holder(account_id);
+        shares
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssetInfo, AssetWeight, PriceFeedInfo, SwapRoute, WithdrawalPolicy};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // Both at 1 USD with 18 decimals, as the NEAR priceoracle reports them
+        for asset_address in [ETH, AURORA] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(10_000),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract.process_deposit(accounts(1), U128(1_001));
+        contract
+    }
+
+    /// Holds `asset_address` on NEAR, bought from `pool_id`.
+    fn route(contract: &mut Contract, asset_address: &str, pool_id: u64) {
+        contract.swap_venue.routes.insert(
+            asset_address.to_string(),
+            SwapRoute {
+                pool_id,
+                token_id: accounts(5),
+            },
+        );
+    }
+
+    /// `setup` with both components held on NEAR, redeemed at the oracle price by
+    /// `accounts(1)` with `prepaid_tgas` of gas.
+    fn redeem_at_oracle_price(prepaid_tgas: u64, min_amount_out: u128) -> Contract {
+        let mut contract = setup();
+        contract.set_swap_amm(Some(accounts(4)));
+        // As if swaps had bought what the deposit credited
+        route(&mut contract, ETH, 7);
+        route(&mut contract, AURORA, 8);
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .prepaid_gas(Gas::from_tgas(prepaid_tgas))
+            .build());
+        contract.redeem_at_oracle_price(RedeemAmount::BasisPoints(MAX_BPS), U128(min_amount_out));
+        contract
+    }
+
+    fn near_sale(leg: &SaleLeg) -> NearSale {
+        NearSale {
+            account_id: accounts(1),
+            leg: leg.clone(),
+            amm: accounts(4),
+            route: SwapRoute {
+                pool_id: 7,
+                token_id: accounts(5),
+            },
+        }
+    }
+
+    #[test]
+    fn test_pro_rata_rounds_down() {
+        assert_eq!(pro_rata(700, 1, 3), 233);
+        assert_eq!(pro_rata(700, 3, 3), 700);
+        assert_eq!(pro_rata(0, 1, 3), 0);
+    }
+
+    #[test]
+    fn test_preview_redeem_shares() {
+        let contract = setup();
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::Shares(U128(500)));
+
+        assert_eq!(preview.shares, U128(500));
+        assert_eq!(preview.remaining_shares, U128(501));
+        // 701 of ETH and 300 of AURORA were allocated to the 1001 shares
+        assert_eq!(preview.assets[0].amount, U128(350_149_850_149_850));
+        assert_eq!(preview.assets[1].amount, U128(149_850_149_850_149));
+    }
+
+    #[test]
+    fn test_preview_redeem_everything() {
+        let contract = setup();
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+
+        assert_eq!(preview.shares, U128(1_001));
+        assert_eq!(preview.remaining_shares, U128(0));
+        assert_eq!(preview.assets[0].amount, U128(701 * UNITS));
+        assert_eq!(preview.assets[1].amount, U128(300 * UNITS));
+    }
+
+    #[test]
+    fn test_early_exit_penalty() {
+        let mut contract = setup();
+        contract.withdrawal_policy = WithdrawalPolicy {
+            lockup_sec: 100,
+            cooldown_sec: 0,
+            early_exit_penalty_bps: 1_000,
+        };
+        contract.process_deposit(accounts(4), U128(1_001));
+
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+        // 10% of 1001 rounded up
+        assert_eq!(preview.penalty_shares, U128(101));
+        // The assets of 900 of the 1001 shares leave, rounded down
+        assert_eq!(preview.assets[0].amount, U128(630_269_730_269_730));
+        assert_eq!(preview.penalty[0].amount, U128(70_730_269_730_270));
+        assert_eq!(preview.assets[1].amount, U128(269_730_269_730_269));
+        assert_eq!(preview.penalty[1].amount, U128(30_269_730_269_731));
+
+        contract.debit_redemption(&accounts(1), &preview);
+        let balances = contract.get_user_balance(&accounts(4)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(701 * UNITS + 70_730_269_730_269)));
+        assert_eq!(balances.get(AURORA), Some(&U128(300 * UNITS + 30_269_730_269_730)));
+        // Rounding dust stays unpaid but still counts as held
+        contract.settle_penalties(&accounts(4));
+        assert_eq!(contract.penalty_pool.unpaid.get(ETH), Some(&1));
+        assert_eq!(contract.total_balance(ETH), 701 * UNITS + 70_730_269_730_270);
+    }
+
+    #[test]
+    fn test_penalty_goes_to_holders_at_the_exit() {
+        let mut contract = setup();
+        contract.withdrawal_policy = WithdrawalPolicy {
+            lockup_sec: 100,
+            cooldown_sec: 0,
+            early_exit_penalty_bps: 1_000,
+        };
+        contract.process_deposit(accounts(4), U128(1_001));
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+        contract.debit_redemption(&accounts(1), &preview);
+
+        // Joining after the exit earns nothing of its penalty
+        contract.process_deposit(accounts(5), U128(1_001));
+        let balances = contract.get_user_balance(&accounts(5)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(701 * UNITS)));
+
+        // A deposit pays what the holder was owed into its balance first
+        contract.process_deposit(accounts(4), U128(1_001));
+        let balances = contract.user_balances.get(&accounts(4)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(2 * 701 * UNITS + 70_730_269_730_269)));
+        assert_eq!(contract.get_user_balance(&accounts(4)).as_ref(), Some(balances));
+    }
+
+    #[test]
+    fn test_lots_from_before_a_proposal_leave_for_free() {
+        let mut contract = setup();
+        contract.withdrawal_policy = WithdrawalPolicy {
+            lockup_sec: 100,
+            cooldown_sec: 0,
+            early_exit_penalty_bps: 1_000,
+        };
+        contract.process_deposit(accounts(4), U128(1_001));
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(10)
+            .build());
+        contract.propose_weights(vec![
+            AssetWeight {
+                asset_address: ETH.to_string(),
+                weight: 5_000,
+            },
+            AssetWeight {
+                asset_address: AURORA.to_string(),
+                weight: 5_000,
+            },
+        ]);
+        // Deposited once the new weights were known, so still penalized
+        contract.process_deposit(accounts(1), U128(1_001));
+
+        let preview = contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+        assert_eq!(preview.penalty_shares, U128(101));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shares are locked until 100000000000")]
+    fn test_locked_shares_without_penalty() {
+        let mut contract = setup();
+        contract.withdrawal_policy = WithdrawalPolicy {
+            lockup_sec: 100,
+            cooldown_sec: 0,
+            early_exit_penalty_bps: 0,
+        };
+        contract.process_deposit(accounts(4), U128(1_001));
+        contract.preview_redeem(accounts(1), RedeemAmount::Shares(U128(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shares are locked until 100000000000")]
+    fn test_penalty_stays_with_pooled_holders() {
+        let mut contract = setup();
+        contract.withdrawal_policy = WithdrawalPolicy {
+            lockup_sec: 100,
+            cooldown_sec: 0,
+            early_exit_penalty_bps: 1_000,
+        };
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.enable_segregated_custody();
+        contract.process_deposit(accounts(4), U128(1_001));
+        // Nobody else holds in the treasuries to take the forfeited assets
+        contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shares are locked until 100000000000")]
+    fn test_locked_shares_of_the_only_holder() {
+        let mut contract = setup();
+        contract.withdrawal_policy = WithdrawalPolicy {
+            lockup_sec: 100,
+            cooldown_sec: 0,
+            early_exit_penalty_bps: 1_000,
+        };
+        contract.preview_redeem(accounts(1), RedeemAmount::BasisPoints(MAX_BPS));
+    }
+
+    #[test]
+    fn test_usdc_redemption_sells_the_legs_the_gas_allows() {
+        let contract = redeem_at_oracle_price(300, 0);
+        let redemption = contract.get_usdc_redemption(accounts(1)).unwrap();
+        // The payout waits for `continue_usdc_redemption`
+        assert!(redemption.to_sell.is_empty());
+        assert_eq!(redemption.selling, 2);
+        assert_eq!(contract.get_user_shares(accounts(1)), U128(0));
+    }
+
+    #[test]
+    fn test_leg_minimum_covers_its_part_and_the_slippage() {
+        // Only the ETH sale fits, AURORA must bring its part of the 1001 USDC
+        let contract = redeem_at_oracle_price(150, 1_001);
+        let redemption = contract.get_usdc_redemption(accounts(1)).unwrap();
+        assert_eq!(redemption.selling, 1);
+        assert_eq!(redemption.to_sell[0].min_amount_out, U128(300));
+
+        // Without a minimum it still can't sell more than 1% below its value
+        let contract = redeem_at_oracle_price(150, 0);
+        let redemption = contract.get_usdc_redemption(accounts(1)).unwrap();
+        assert_eq!(redemption.to_sell[0].min_amount_out, U128(297));
+    }
+
+    #[test]
+    fn test_unsold_leg_comes_back_with_its_shares() {
+        let mut contract = redeem_at_oracle_price(300, 0);
+        let eth = SaleLeg {
+            asset: AssetAmount {
+                asset_address: ETH.to_string(),
+                amount: U128(701 * UNITS),
+            },
+            min_amount_out: U128(693),
+        };
+        let aurora = SaleLeg {
+            asset: AssetAmount {
+                asset_address: AURORA.to_string(),
+                amount: U128(300 * UNITS),
+            },
+            min_amount_out: U128(297),
+        };
+        contract.finish_near_sale(&near_sale(&eth), Some(700));
+        contract.finish_near_sale(&near_sale(&aurora), None);
+        assert_eq!(contract.get_usdc_redemption(accounts(1)).unwrap().proceeds, U128(700));
+
+        contract.pay_out_sold_redemption(&accounts(1));
+        assert!(contract.get_usdc_redemption(accounts(1)).is_none());
+        let balances = contract.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(0)));
+        assert_eq!(balances.get(AURORA), Some(&U128(300 * UNITS)));
+        // AURORA is 30% of the fund
+        assert_eq!(contract.get_user_shares(accounts(1)), U128(300));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not held on NEAR, redeem it in kind")]
+    fn test_usdc_redemption_needs_assets_held_on_near() {
+        let mut contract = setup();
+        contract.set_swap_amm(Some(accounts(4)));
+        route(&mut contract, ETH, 7);
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.redeem_at_oracle_price(RedeemAmount::BasisPoints(MAX_BPS), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough shares")]
+    fn test_redeem_more_than_owned() {
+        let contract = setup();
+        contract.preview_redeem(accounts(1), RedeemAmount::Shares(U128(1_002)));
+    }
+}
@@ -1,4 +1,4 @@
-use near_sdk::{ext_contract, serde::{Deserialize, Serialize}};
+use near_sdk::{env, ext_contract, serde::{Deserialize, Serialize}};
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -16,6 +16,39 @@ pub struct SignResult {
     pub recovery_id: u64,
 }
 
+impl SignResult {
+    /// `r`, `s` and the recovery id of the signature, rejecting malformed answers.
+    pub fn parts(&self) -> Result<([u8; 32], [u8; 32], u8), String> {
+        // `big_r` is a compressed point, its x coordinate is `r`
+        let r = self
+            .big_r
+            .affine_point
+            .get(2..)
+            .and_then(|x| hex::decode(x).ok())
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| format!("Malformed big_r {}", self.big_r.affine_point))?;
+        let s = hex::decode(&self.s.scalar)
+            .ok()
+            .and_then(|s| s.try_into().ok())
+            .ok_or_else(|| format!("Malformed s {}", self.s.scalar))?;
+        if self.recovery_id > 1 {
+            return Err(format!("Invalid recovery id {}", self.recovery_id));
+        }
+        Ok((r, s, self.recovery_id as u8))
+    }
+
+    /// Uncompressed public key, without the `04` tag, that produced this signature over `hash`.
+    pub fn recover_public_key(&self, hash: &[u8]) -> Result<[u8; 64], String> {
+        let (r, s, v) = self.parts()?;
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&r);
+        signature[32..].copy_from_slice(&s);
+        // High-S signatures are rejected, neither Ethereum nor Bitcoin relays them
+        env::ecrecover(hash, &signature, v, true)
+            .ok_or_else(|| "Signature does not recover to a public key".to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct AffinePoint {
@@ -31,4 +64,57 @@ pub struct Scalar {
 #[ext_contract(mpc)]
 pub trait MPC {
     fn sign(&self, request: SignRequest) -> near_sdk::PromiseOrValue<SignResult>;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kdf::{compress_raw_public_key, raw_public_key_to_evm_address};
+
+    const HASH: &str = "11b34d4135af3809d40cef12120c4d51a5abc523ba2adc3fb1bb11848da5a636";
+
+    fn sign_result(big_r: &str, recovery_id: u64) -> SignResult {
+        SignResult {
+            big_r: AffinePoint {
+                affine_point: big_r.to_string(),
+            },
+            s: Scalar {
+                scalar: "48792926a07156ec97dd47e0476fc9f92daa76cde7c3967f011c329929f00724"
+                    .to_string(),
+            },
+            recovery_id,
+        }
+    }
+
+    #[test]
+    fn test_recover_public_key() {
+        let result = sign_result(
+            "03c4c0eb015df1a2f77919554ff5408c8f613fbb308f3a2bf2eebea75347943617",
+            1,
+        );
+        let public_key = result.recover_public_key(&hex::decode(HASH).unwrap()).unwrap();
+        assert_eq!(
+            hex::encode(compress_raw_public_key(&public_key)),
+            "0368034f029e554cae0c725a6703baac20c0acd6fb5b3bd1774835d4a69732d55f"
+        );
+        assert_eq!(
+            raw_public_key_to_evm_address(&public_key).to_string().to_lowercase(),
+            "0x9dc08d99c5d8c132b23ac46f0a9b6abefd148044"
+        );
+    }
+
+    #[test]
+    fn test_malformed_signatures_are_rejected() {
+        assert_eq!(
+            sign_result("03c4c0", 1).parts(),
+            Err("Malformed big_r 03c4c0".to_string())
+        );
+        assert_eq!(
+            sign_result(
+                "03c4c0eb015df1a2f77919554ff5408c8f613fbb308f3a2bf2eebea75347943617",
+                2
+            )
+            .parts(),
+            Err("Invalid recovery id 2".to_string())
+        );
+    }
+}
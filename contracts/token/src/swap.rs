@@ -0,0 +1,748 @@
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Gas, NearToken, Promise, PromiseError,
+    PromiseOrValue,
+};
+use std::collections::{HashMap, VecDeque};
+
+use crate::events::emit_event;
+use crate::price_store::usdc_to_value;
+use crate::redeem::{pro_rata, AssetAmount, MAX_BPS};
+use crate::{Contract, ContractExt, TOKEN_ADDRESSES};
+
+const FT_TRANSFER_CALL_GAS: Gas = Gas::from_tgas(35);
+pub const FT_TRANSFER_GAS: Gas = Gas::from_tgas(10);
+const AMM_SWAP_GAS: Gas = Gas::from_tgas(10);
+const AMM_WITHDRAW_GAS: Gas = Gas::from_tgas(30);
+/// Covers `on_near_swap` and the withdrawal from the AMM it starts
+const ON_NEAR_SWAP_GAS: Gas = Gas::from_tgas(50);
+/// Covers the swap and `on_near_swap`
+const NEAR_SWAP_GAS: Gas = Gas::from_tgas(65);
+/// Gas a swap takes from the deposit that starts it
+pub const NEAR_SWAP_CHAIN_GAS: Gas =
+    Gas::from_gas(FT_TRANSFER_CALL_GAS.as_gas() + NEAR_SWAP_GAS.as_gas());
+/// Left to the call starting swaps after the swaps it starts
+const SWAP_GAS_RESERVE: Gas = Gas::from_tgas(20);
+const ON_AMM_WITHDRAW_GAS: Gas = Gas::from_tgas(10);
+/// Covers `on_near_sale` and the withdrawal from the AMM it starts
+const ON_NEAR_SALE_GAS: Gas = Gas::from_tgas(55);
+/// Covers the swap and `on_near_sale`
+const NEAR_SALE_GAS: Gas = Gas::from_tgas(75);
+/// Gas the sale of one redemption leg takes
+pub const NEAR_SALE_CHAIN_GAS: Gas =
+    Gas::from_gas(FT_TRANSFER_CALL_GAS.as_gas() + NEAR_SALE_GAS.as_gas());
+
+pub const DEFAULT_MAX_SLIPPAGE_BPS: u32 = 100; // 1%
+
+/// Ref Finance pool a component is bought from with USDC.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapRoute {
+    pub pool_id: u64,
+    /// NEP-141 contract of the component
+    pub token_id: AccountId,
+}
+
+/// Buys NEP-141 components on NEAR as deposits come in, instead of leaving them to the
+/// keeper and chain signatures. A component with a route is held by this contract on
+/// NEAR and leaves it with `ft_transfer`, one without is held by the treasuries.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapVenue {
+    /// Ref Finance-style AMM, `None` leaves every component to the keeper
+    pub amm: Option<AccountId>,
+    /// Most a swap may come out below the cached oracle price
+    pub max_slippage_bps: u32,
+    /// Route of every component bought on NEAR, keyed like balances
+    pub routes: HashMap<String, SwapRoute>,
+    /// USDC of deposits queued or on its way to buy each component. It isn't credited to
+    /// anyone yet.
+    pub in_flight: HashMap<String, U128>,
+    /// Deposit slices waiting for a swap, oldest first
+    pub queued: VecDeque<QueuedSwap>,
+    /// Tokens left with an AMM because withdrawing them failed, by AMM and token
+    /// contract. `withdraw_from_amm` takes them out again.
+    pub with_amm: HashMap<AccountId, HashMap<AccountId, U128>>,
+}
+
+impl Default for SwapVenue {
+    fn default() -> Self {
+        Self {
+            amm: None,
+            max_slippage_bps: DEFAULT_MAX_SLIPPAGE_BPS,
+            routes: HashMap::new(),
+            in_flight: HashMap::new(),
+            queued: VecDeque::new(),
+            with_amm: HashMap::new(),
+        }
+    }
+}
+
+/// A deposit slice waiting to buy its component, because the deposit had no gas left
+/// for its swap or the swap failed. `run_queued_swaps` starts it at the price of the time.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedSwap {
+    pub account_id: AccountId,
+    pub asset_address: String,
+    pub amount_in: U128,
+}
+
+/// A deposit slice on its way through the AMM, handed along the swap's callbacks.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NearSwap {
+    pub account_id: AccountId,
+    pub asset_address: String,
+    pub amm: AccountId,
+    pub route: SwapRoute,
+    pub amount_in: U128,
+    pub min_amount_out: U128,
+}
+
+/// A redemption leg and the least USDC selling it must bring.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleLeg {
+    pub asset: AssetAmount,
+    pub min_amount_out: U128,
+}
+
+/// A redemption leg on its way through the AMM into USDC, handed along the sale's callbacks.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NearSale {
+    pub account_id: AccountId,
+    pub leg: SaleLeg,
+    pub amm: AccountId,
+    pub route: SwapRoute,
+}
+
+/// One hop of a swap on Ref Finance.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AmmSwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: Option<U128>,
+    pub min_amount_out: U128,
+}
+
+#[ext_contract(ext_amm)]
+pub trait Amm {
+    fn swap(&mut self, actions: Vec<AmmSwapAction>, referral_id: Option<AccountId>) -> U128;
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>);
+}
+
+/// Withdraws `amount` of `token_id` the contract holds with `amm` back to the contract.
+fn withdraw_from(amm: AccountId, token_id: AccountId, amount: U128) -> Promise {
+    ext_amm::ext(amm)
+        .with_attached_deposit(NearToken::from_yoctonear(1))
+        .with_static_gas(AMM_WITHDRAW_GAS)
+        .withdraw(token_id, amount, None)
+}
+
+impl Contract {
+    /// NEP-141 contract of `asset_address` if the fund holds the component on NEAR.
+    pub(crate) fn near_token(&self, asset_address: &str) -> Option<AccountId> {
+        self.swap_venue
+            .routes
+            .get(asset_address)
+            .map(|route| route.token_id.clone())
+    }
+
+    /// Sends `asset` of a component held on NEAR to `receiver_id`.
+    pub(crate) fn transfer_near_token(
+        &self,
+        asset: &AssetAmount,
+        receiver_id: &AccountId,
+    ) -> Promise {
+        let token_id = self.near_token(&asset.asset_address).unwrap_or_else(|| {
+            env::panic_str(&format!("Asset {} is not held on NEAR", asset.asset_address))
+        });
+        ext_ft_core::ext(token_id)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_transfer(receiver_id.clone(), asset.amount, None)
+    }
+
+    /// Panics if anyone holds `asset_address` or a withdrawal still has to send it, which
+    /// would leave it in the custody it is moving away from.
+    fn assert_not_held(&self, asset_address: &str) {
+        let unsent = self
+            .withdrawal_signings
+            .values()
+            .flat_map(|signing| &signing.to_sign)
+            .any(|asset| asset.asset_address == asset_address);
+        assert!(
+            self.total_balance(asset_address) == 0 && !unsent,
+            "Asset {} is still held, its custody can't move",
+            asset_address
+        );
+    }
+
+    /// Least a swap expected to buy `expected` at the oracle price must buy.
+    pub(crate) fn near_swap_min_out(&self, expected: u128) -> u128 {
+        pro_rata(
+            expected,
+            (MAX_BPS - self.swap_venue.max_slippage_bps) as u128,
+            MAX_BPS as u128,
+        )
+    }
+
+    /// Queues `amount_in` of `account_id`'s deposit to buy `asset_address` on NEAR. It
+    /// counts as in flight until a swap credits what it bought.
+    pub(crate) fn queue_near_swap(
+        &mut self,
+        account_id: &AccountId,
+        asset_address: String,
+        amount_in: u128,
+    ) {
+        if amount_in == 0 {
+            return;
+        }
+        self.swap_venue
+            .in_flight
+            .entry(asset_address.clone())
+            .or_insert(U128(0))
+            .0 += amount_in;
+        self.swap_venue.queued.push_back(QueuedSwap {
+            account_id: account_id.clone(),
+            asset_address,
+            amount_in: U128(amount_in),
+        });
+    }
+
+    /// Starts as many queued swaps as this call's gas covers, oldest first, at the oracle
+    /// price of now. Returns how many started.
+    pub(crate) fn start_queued_swaps(&mut self) -> u32 {
+        let gas_left = env::prepaid_gas()
+            .as_gas()
+            .saturating_sub(env::used_gas().as_gas() + SWAP_GAS_RESERVE.as_gas());
+        let count = ((gas_left / NEAR_SWAP_CHAIN_GAS.as_gas()) as usize)
+            .min(self.swap_venue.queued.len());
+        if count == 0 {
+            return 0;
+        }
+        let amm = self.swap_venue.amm.clone().expect("No AMM is set");
+        let now = env::block_timestamp();
+        for queued in self.swap_venue.queued.drain(..count).collect::<Vec<_>>() {
+            let value = usdc_to_value(queued.amount_in.0);
+            let expected = self.price_store.amount_for(&queued.asset_address, value, now);
+            let swap = NearSwap {
+                account_id: queued.account_id,
+                route: self.swap_venue.routes[&queued.asset_address].clone(),
+                asset_address: queued.asset_address,
+                amm: amm.clone(),
+                amount_in: queued.amount_in,
+                min_amount_out: U128(self.near_swap_min_out(expected)),
+            };
+            self.start_near_swap(swap);
+        }
+        count as u32
+    }
+
+    /// Swaps a deposit slice into its component and credits what it bought. Like on Ref
+    /// Finance, the USDC is deposited with the AMM, swapped there and what the swap
+    /// returns is withdrawn.
+    fn start_near_swap(&self, swap: NearSwap) -> Promise {
+        ext_ft_core::ext(self.usdc_contract.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(FT_TRANSFER_CALL_GAS)
+            .ft_transfer_call(swap.amm.clone(), swap.amount_in, None, String::new())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(NEAR_SWAP_GAS)
+                    .near_swap_callback(swap),
+            )
+    }
+    /// Sells `leg` of a USDC redemption of `account_id` on the AMM the way
+    /// `start_near_swap` buys: deposit, swap, withdraw. `on_near_sale` hands the result
+    /// to the redemption.
+    pub(crate) fn start_near_sale(&self, account_id: &AccountId, leg: SaleLeg) -> Promise {
+        let route = self
+            .swap_venue
+            .routes
+            .get(&leg.asset.asset_address)
+            .cloned()
+            .unwrap_or_else(|| {
+                env::panic_str(&format!(
+                    "Asset {} is not held on NEAR, redeem it in kind",
+                    leg.asset.asset_address
+                ))
+            });
+        let sale = NearSale {
+            account_id: account_id.clone(),
+            amm: self.swap_venue.amm.clone().expect("No AMM is set"),
+            route,
+            leg,
+        };
+        ext_ft_core::ext(sale.route.token_id.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(FT_TRANSFER_CALL_GAS)
+            .ft_transfer_call(sale.amm.clone(), sale.leg.asset.amount, None, String::new())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(NEAR_SALE_GAS)
+                    .near_sale_callback(sale),
+            )
+    }
+
+    /// Withdraws `amount` of `token_id` from `amm`, keeping it in `with_amm` if that fails.
+    fn withdraw_tracked(&self, amm: AccountId, token_id: AccountId, amount: U128) -> Promise {
+        withdraw_from(amm.clone(), token_id.clone(), amount).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(ON_AMM_WITHDRAW_GAS)
+                .on_amm_withdraw(amm, token_id, amount),
+        )
+    }
+
+    /// Credits the account with what a swap bought. A failed swap leaves the USDC slice
+    /// with the contract, or with the AMM until `withdraw_from_amm`, and queues it again.
+    /// Crediting it at the oracle price instead would hand the holder a component the
+    /// contract doesn't hold.
+    fn finish_near_swap(&mut self, swap: &NearSwap, received: Option<u128>) {
+        let NearSwap {
+            account_id,
+            asset_address,
+            amount_in,
+            ..
+        } = swap;
+        let Some(received) = received else {
+            emit_event(
+                "near_swap_failed",
+                json!({
+                    "account_id": account_id,
+                    "asset_address": asset_address,
+                    "amount_in": amount_in,
+                }),
+            );
+            self.swap_venue.queued.push_back(QueuedSwap {
+                account_id: account_id.clone(),
+                asset_address: asset_address.clone(),
+                amount_in: *amount_in,
+            });
+            return;
+        };
+        if let Some(in_flight) = self.swap_venue.in_flight.get_mut(asset_address) {
+            in_flight.0 = in_flight.0.saturating_sub(amount_in.0);
+            if in_flight.0 == 0 {
+                self.swap_venue.in_flight.remove(asset_address);
+            }
+        }
+        emit_event(
+            "near_swap_executed",
+            json!({
+                "account_id": account_id,
+                "asset_address": asset_address,
+                "amount_in": amount_in,
+                "amount_out": U128(received),
+            }),
+        );
+        self.user_balances
+            .entry(account_id.clone())
+            .or_default()
+            .entry(asset_address.clone())
+            .or_insert(U128(0))
+            .0 += received;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_swap_venue(&self) -> SwapVenue {
+        self.swap_venue.clone()
+    }
+
+    /// Sets the AMM components are bought from, `None` stops buying on NEAR once no
+    /// component has a route. The contract must hold a storage deposit with the AMM, like
+    /// any account trading on Ref Finance.
+    pub fn set_swap_amm(&mut self, amm: Option<AccountId>) {
+        self.assert_owner();
+        assert!(
+            amm.is_some() || self.swap_venue.routes.is_empty(),
+            "Components are still bought on NEAR, remove their routes first"
+        );
+        self.swap_venue.amm = amm;
+    }
+
+    pub fn set_max_slippage_bps(&mut self, max_slippage_bps: u32) {
+        self.assert_owner();
+        assert!(
+            max_slippage_bps < MAX_BPS,
+            "Slippage must be below {} basis points",
+            MAX_BPS
+        );
+        self.swap_venue.max_slippage_bps = max_slippage_bps;
+    }
+
+    /// Buys `asset_address` from `pool_id` on deposits. `token_id` defaults to the NEAR
+    /// token `TOKEN_ADDRESSES` lists for the component. A route moves the component's
+    /// custody to NEAR, so it can only be added while nobody holds the component.
+    pub fn set_swap_route(
+        &mut self,
+        asset_address: String,
+        pool_id: u64,
+        token_id: Option<AccountId>,
+    ) {
+        self.assert_owner();
+        let key = self.registered_asset_key(&asset_address);
+        let token_id = token_id.unwrap_or_else(|| {
+            self.asset_info(&key)
+                .and_then(|asset| asset.contract_address)
+                .and_then(|address| TOKEN_ADDRESSES.get(&address))
+                .and_then(|token_id| token_id.parse().ok())
+                .unwrap_or_else(|| {
+                    env::panic_str(&format!("No NEAR token is known for asset {}", asset_address))
+                })
+        });
+        assert!(self.swap_venue.amm.is_some(), "No AMM is set");
+        if let Some(route) = self.swap_venue.routes.get(&key) {
+            assert_eq!(
+                route.token_id, token_id,
+                "Asset {} is held as {} on NEAR",
+                key, route.token_id
+            );
+        } else {
+            self.assert_not_held(&key);
+        }
+        self.swap_venue
+            .routes
+            .insert(key, SwapRoute { pool_id, token_id });
+    }
+
+    /// Leaves `asset_address` to the keeper again, once nobody holds it on NEAR.
+    pub fn remove_swap_route(&mut self, asset_address: String) {
+        self.assert_owner();
+        let key = self.registered_asset_key(&asset_address);
+        self.assert_not_held(&key);
+        assert!(
+            !self.swap_venue.in_flight.contains_key(&key),
+            "Deposits are still buying asset {}",
+            key
+        );
+        self.swap_venue.routes.remove(&key);
+    }
+
+    /// Starts queued swaps while the gas lasts, anyone can call it. Returns how many started.
+    pub fn run_queued_swaps(&mut self) -> u32 {
+        self.start_queued_swaps()
+    }
+
+    /// Keeps tokens a withdrawal failed to take out of the AMM in `with_amm`.
+    #[private]
+    pub fn on_amm_withdraw(
+        &mut self,
+        amm: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+        self.swap_venue
+            .with_amm
+            .entry(amm.clone())
+            .or_default()
+            .entry(token_id.clone())
+            .or_insert(U128(0))
+            .0 += amount.0;
+        emit_event(
+            "amm_withdraw_failed",
+            json!({ "amm": amm, "token_id": token_id, "amount": amount }),
+        );
+        false
+    }
+
+    /// Withdraws what `with_amm` keeps of `token_id` with `amm` again, anyone can call it.
+    pub fn withdraw_from_amm(&mut self, amm: AccountId, token_id: AccountId) -> Promise {
+        let tokens = self
+            .swap_venue
+            .with_amm
+            .get_mut(&amm)
+            .expect("Nothing is left with this AMM");
+        let amount = tokens.remove(&token_id).expect("Nothing is left of this token");
+        if tokens.is_empty() {
+            self.swap_venue.with_amm.remove(&amm);
+        }
+        self.withdraw_tracked(amm, token_id, amount)
+    }
+
+    /// Swaps the USDC the AMM was sent, unless it refunded the transfer.
+    #[private]
+    pub fn near_swap_callback(
+        &mut self,
+        swap: NearSwap,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        if used.map_or(true, |used| used != swap.amount_in) {
+            // Ref takes the whole deposit or refunds it, so the USDC is back
+            self.finish_near_swap(&swap, None);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let action = AmmSwapAction {
+            pool_id: swap.route.pool_id,
+            token_in: self.usdc_contract.clone(),
+            token_out: swap.route.token_id.clone(),
+            amount_in: Some(swap.amount_in),
+            min_amount_out: swap.min_amount_out,
+        };
+        PromiseOrValue::Promise(
+            ext_amm::ext(swap.amm.clone())
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .with_static_gas(AMM_SWAP_GAS)
+                .swap(vec![action], None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(ON_NEAR_SWAP_GAS)
+                        .on_near_swap(swap),
+                ),
+        )
+    }
+
+    /// Credits the amount the swap returned and withdraws it from the AMM. A failed swap
+    /// withdraws the USDC instead.
+    #[private]
+    pub fn on_near_swap(
+        &mut self,
+        swap: NearSwap,
+        #[callback_result] amount_out: Result<U128, PromiseError>,
+    ) -> U128 {
+        match amount_out {
+            Ok(amount_out) => {
+                self.finish_near_swap(&swap, Some(amount_out.0));
+                if amount_out.0 > 0 {
+                    self.withdraw_tracked(swap.amm, swap.route.token_id, amount_out);
+                }
+                amount_out
+            }
+            Err(_) => {
+                self.finish_near_swap(&swap, None);
+                self.withdraw_tracked(swap.amm, self.usdc_contract.clone(), swap.amount_in);
+                U128(0)
+            }
+        }
+    }
+
+    /// Swaps the leg the AMM was sent into USDC, unless it refunded the transfer.
+    #[private]
+    pub fn near_sale_callback(
+        &mut self,
+        sale: NearSale,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        if used.map_or(true, |used| used != sale.leg.asset.amount) {
+            self.finish_near_sale(&sale, None);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let action = AmmSwapAction {
+            pool_id: sale.route.pool_id,
+            token_in: sale.route.token_id.clone(),
+            token_out: self.usdc_contract.clone(),
+            amount_in: Some(sale.leg.asset.amount),
+            min_amount_out: sale.leg.min_amount_out,
+        };
+        PromiseOrValue::Promise(
+            ext_amm::ext(sale.amm.clone())
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .with_static_gas(AMM_SWAP_GAS)
+                .swap(vec![action], None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(ON_NEAR_SALE_GAS)
+                        .on_near_sale(sale),
+                ),
+        )
+    }
+
+    /// Hands the USDC the sale brought to the redemption and withdraws it from the AMM.
+    /// A failed sale withdraws the leg instead, and it goes back to the redeemer. The
+    /// withdrawal is returned so the payout that follows waits for it.
+    #[private]
+    pub fn on_near_sale(
+        &mut self,
+        sale: NearSale,
+        #[callback_result] amount_out: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        let (token_id, amount) = match amount_out {
+            Ok(amount_out) => {
+                self.finish_near_sale(&sale, Some(amount_out.0));
+                (self.usdc_contract.clone(), amount_out)
+            }
+            Err(_) => {
+                self.finish_near_sale(&sale, None);
+                (sale.route.token_id, sale.leg.asset.amount)
+            }
+        };
+        if amount.0 == 0 {
+            return PromiseOrValue::Value(true);
+        }
+        PromiseOrValue::Promise(self.withdraw_tracked(sale.amm, token_id, amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssetInfo, PriceFeedInfo};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const ETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+    const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+    /// Smallest units of an 18 decimal token worth a smallest unit of USDC at 1 USD
+    const UNITS: u128 = 1_000_000_000_000;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let assets = vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                contract_address: Some(ETH.parse().unwrap()),
+                weight: 7_000,
+                chain: None,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                contract_address: Some(AURORA.parse().unwrap()),
+                weight: 3_000,
+                chain: None,
+            },
+        ];
+        let mut contract = Contract::new(accounts(0), assets, accounts(2), accounts(3), None);
+        // ETH at 2 USD and AURORA at 1 USD with 18 decimals, as the NEAR priceoracle
+        // reports them
+        for (asset_address, price) in [(ETH, 20_000), (AURORA, 10_000)] {
+            contract.price_store.prices.insert(
+                asset_address.to_string(),
+                PriceFeedInfo {
+                    asset_address: asset_address.to_string(),
+                    price: U128(price),
+                    decimals: 22,
+                    last_updated: 0,
+                },
+            );
+        }
+        contract.set_swap_amm(Some(accounts(4)));
+        contract.set_swap_route(ETH.to_string(), 7, None);
+        contract
+    }
+
+    fn near_swap(amount_in: u128) -> NearSwap {
+        NearSwap {
+            account_id: accounts(1),
+            asset_address: ETH.to_string(),
+            amm: accounts(4),
+            route: SwapRoute {
+                pool_id: 7,
+                token_id: "weth.fakes.testnet".parse().unwrap(),
+            },
+            amount_in: U128(amount_in),
+            min_amount_out: U128(0),
+        }
+    }
+
+    fn queued_swap(amount_in: u128) -> QueuedSwap {
+        QueuedSwap {
+            account_id: accounts(1),
+            asset_address: ETH.to_string(),
+            amount_in: U128(amount_in),
+        }
+    }
+
+    #[test]
+    fn test_route_defaults_to_listed_token() {
+        let contract = setup();
+        assert_eq!(
+            contract.swap_venue.routes[ETH],
+            SwapRoute {
+                pool_id: 7,
+                token_id: "weth.fakes.testnet".parse().unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_min_out_follows_oracle_price() {
+        let contract = setup();
+        // 700 USDC buy 350 ETH at 2, less 1% slippage
+        assert_eq!(contract.near_swap_min_out(350 * UNITS), 346_500_000_000_000);
+    }
+
+    #[test]
+    fn test_deposit_credits_what_the_swap_bought() {
+        let mut contract = setup();
+        contract.process_deposit(accounts(1), U128(1_000));
+        let balances = contract.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), None);
+        assert_eq!(balances.get(AURORA), Some(&U128(300 * UNITS)));
+        assert_eq!(contract.swap_venue.in_flight[ETH], U128(700));
+        assert!(contract.swap_venue.queued.is_empty());
+
+        contract.finish_near_swap(&near_swap(700), Some(348 * UNITS));
+        let balances = contract.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), Some(&U128(348 * UNITS)));
+        assert!(contract.swap_venue.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_swaps_of_one_component_overlap() {
+        let mut contract = setup();
+        contract.process_deposit(accounts(1), U128(1_000));
+        contract.process_deposit(accounts(5), U128(1_000));
+        assert_eq!(contract.swap_venue.in_flight[ETH], U128(1_400));
+
+        contract.finish_near_swap(&near_swap(700), Some(348 * UNITS));
+        assert_eq!(contract.swap_venue.in_flight[ETH], U128(700));
+    }
+
+    #[test]
+    fn test_slice_without_gas_for_a_swap_waits_in_the_queue() {
+        let mut contract = setup();
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .prepaid_gas(Gas::from_tgas(100))
+            .build());
+        contract.process_deposit(accounts(1), U128(1_000));
+        let balances = contract.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), None);
+        assert_eq!(contract.swap_venue.queued, vec![queued_swap(700)]);
+        assert_eq!(contract.swap_venue.in_flight[ETH], U128(700));
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(5))
+            .build());
+        assert_eq!(contract.run_queued_swaps(), 1);
+        assert!(contract.swap_venue.queued.is_empty());
+        assert_eq!(contract.swap_venue.in_flight[ETH], U128(700));
+    }
+
+    #[test]
+    fn test_failed_swap_is_queued_again() {
+        let mut contract = setup();
+        contract.process_deposit(accounts(1), U128(1_000));
+        contract.finish_near_swap(&near_swap(700), None);
+        let balances = contract.get_user_balance(&accounts(1)).unwrap();
+        assert_eq!(balances.get(ETH), None);
+        assert_eq!(contract.swap_venue.queued, vec![queued_swap(700)]);
+        assert_eq!(contract.swap_venue.in_flight[ETH], U128(700));
+    }
+
+    #[test]
+    #[should_panic(expected = "its custody can't move")]
+    fn test_route_of_a_held_component_is_refused() {
+        let mut contract = setup();
+        contract.process_deposit(accounts(1), U128(1_000));
+        contract.set_swap_route(AURORA.to_string(), 8, Some(accounts(5)));
+    }
+}
@@ -0,0 +1,47 @@
+use near_sdk::{env, near_bindgen, AccountId, Gas, NearToken, Promise};
+
+use crate::{Contract, ContractExt};
+
+/// Kept back from the migration for the rest of the `upgrade` call
+const UPGRADE_RESERVED_GAS: Gas = Gas::from_tgas(10);
+
+impl Contract {
+    fn assert_upgrader(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || Some(&caller) == self.upgrader.as_ref(),
+            "Only the owner or the upgrader can upgrade the contract"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Deploys the wasm passed as the call's raw input and calls `migrate` on it. Both run
+    /// in one receipt, so a failed migration leaves the old code and state in place.
+    pub fn upgrade(&self) -> Promise {
+        self.assert_upgrader();
+        let code = env::input().expect("No code to deploy");
+        let migrate_gas = env::prepaid_gas()
+            .saturating_sub(env::used_gas())
+            .saturating_sub(UPGRADE_RESERVED_GAS);
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                Vec::new(),
+                NearToken::from_yoctonear(0),
+                migrate_gas,
+            )
+    }
+
+    /// Lets `upgrader` deploy new code besides the owner, `None` leaves it to the owner.
+    pub fn set_upgrader(&mut self, upgrader: Option<AccountId>) {
+        self.assert_owner();
+        self.upgrader = upgrader;
+    }
+
+    pub fn get_upgrader(&self) -> Option<AccountId> {
+        self.upgrader.clone()
+    }
+}
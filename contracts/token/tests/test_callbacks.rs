@@ -0,0 +1,221 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::{AccountId, NearToken};
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+/// NEAR token ids the mock oracle prices the assets under, see `TOKEN_ADDRESSES`
+const WETH_FT: &str = "weth.fakes.testnet";
+const AURORA_FT: &str = "aurora.fakes.testnet";
+/// One USDC in its smallest unit
+const USDC: u128 = 1_000_000;
+/// The NEAR priceoracle reports USD per smallest unit with 4 decimals more than the
+/// token has, 22 for WETH and AURORA
+const PRICE_DECIMALS: u8 = 22;
+
+struct Setup {
+    token: Contract,
+    oracle: Contract,
+    usdc: Account,
+    user: Account,
+}
+
+async fn setup() -> Result<Setup, Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+    let oracle = deploy_oracle(&sandbox).await?;
+    // A plain account stands in for USDC, so every ft_transfer to it fails
+    let usdc = sandbox.dev_create_account().await?;
+    let user = sandbox.dev_create_account().await?;
+    let token = deploy_token(&sandbox, &user, usdc.id(), oracle.id()).await?;
+
+    Ok(Setup {
+        token,
+        oracle,
+        usdc,
+        user,
+    })
+}
+
+/// Deploys the mock oracle, accepting prices up to 90 seconds old.
+async fn deploy_oracle(sandbox: &Worker<Sandbox>) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("../mock_oracle").await?;
+    let oracle = sandbox.dev_deploy(&wasm).await?;
+    oracle
+        .call("new")
+        .args_json(json!({ "recency_duration_sec": 90 }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(oracle)
+}
+
+async fn set_price(
+    oracle: &Contract,
+    asset_id: &str,
+    multiplier: u128,
+    decimals: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    oracle
+        .call("set_price")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "multiplier": multiplier.to_string(),
+            "decimals": decimals,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Deploys this crate's contract as a fund of 70% ETH and 30% AURORA owned by `owner`.
+async fn deploy_token(
+    sandbox: &Worker<Sandbox>,
+    owner: &Account,
+    usdc: &AccountId,
+    oracle: &AccountId,
+) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("./").await?;
+    let token = sandbox.dev_deploy(&wasm).await?;
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH, "weight": 7_000 },
+                { "name": "AURORA", "contract_address": AURORA, "weight": 3_000 },
+            ],
+            "usdc_contract": usdc,
+            "oracle_contract": oracle,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(token)
+}
+
+/// Deposits `amount` for `sender` the way the USDC contract does after `ft_transfer_call`.
+async fn deposit(
+    usdc: &Account,
+    token: &Contract,
+    sender: &Account,
+    amount: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let refunded: String = usdc
+        .call(token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": sender.id(),
+            "amount": amount.to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, "0", "the deposit was refunded");
+    Ok(())
+}
+
+async fn refresh_prices(
+    caller: &Account,
+    token: &Contract,
+) -> Result<(), Box<dyn std::error::Error>> {
+    caller
+        .call(token.id(), "refresh_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn user_shares(
+    token: &Contract,
+    account: &Account,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(token
+        .view("get_user_shares")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?)
+}
+
+async fn user_balance(
+    token: &Contract,
+    account: &Account,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    Ok(token
+        .view("get_user_balance")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?)
+}
+
+#[tokio::test]
+async fn test_usdc_redemption_needs_assets_on_near() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&setup.oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    refresh_prices(&setup.user, &setup.token).await?;
+    deposit(&setup.usdc, &setup.token, &setup.user, 1_000 * USDC).await?;
+    let balance = user_balance(&setup.token, &setup.user).await?;
+
+    // Nothing is bought on NEAR, so there is nothing to sell there either
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "redeem_at_oracle_price")
+        .args_json(json!({ "amount": { "basis_points": 10_000 }, "min_amount_out": "0" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_failure());
+
+    assert_eq!(user_shares(&setup.token, &setup.user).await?, (1_000 * USDC).to_string());
+    assert_eq!(user_balance(&setup.token, &setup.user).await?, balance);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_signing_restores_withdrawal() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&setup.oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    refresh_prices(&setup.user, &setup.token).await?;
+    deposit(&setup.usdc, &setup.token, &setup.user, 1_000 * USDC).await?;
+    let balance = user_balance(&setup.token, &setup.user).await?;
+
+    // The MPC contract doesn't exist in the sandbox, so every signature request fails
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "redeem")
+        .args_json(json!({
+            "amount": { "basis_points": 10_000 },
+            "request": {
+                "eth_destination": "0x1111111111111111111111111111111111111111",
+                "aurora_destination": "0x2222222222222222222222222222222222222222",
+                "network_details": {
+                    "chain_id": 11155111,
+                    "eth_nonce": 0,
+                    "max_priority_fee_per_gas": 1_000_000_000u128,
+                    "max_fee_per_gas": 20_000_000_000u128,
+                    "gas_limit": 100_000u128,
+                },
+            },
+        }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome
+        .logs()
+        .iter()
+        .any(|log| log.contains("\"event\":\"withdrawal_failed\"")));
+
+    assert_eq!(user_shares(&setup.token, &setup.user).await?, (1_000 * USDC).to_string());
+    assert_eq!(user_balance(&setup.token, &setup.user).await?, balance);
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::{AccountId, NearToken};
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+/// NEAR token ids the mock oracle prices the assets under, see `TOKEN_ADDRESSES`
+const WETH_FT: &str = "weth.fakes.testnet";
+const AURORA_FT: &str = "aurora.fakes.testnet";
+/// One USDC in its smallest unit
+const USDC: u128 = 1_000_000;
+/// The NEAR priceoracle reports USD per smallest unit with 4 decimals more than the
+/// token has, 22 for WETH and AURORA
+const PRICE_DECIMALS: u8 = 22;
+
+struct Setup {
+    token: Contract,
+    lending: Contract,
+    usdc: Account,
+    user: Account,
+}
+
+async fn setup() -> Result<Setup, Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+
+    let lending_wasm = near_workspaces::compile_project("../mock_lending").await?;
+    let lending = sandbox.dev_deploy(&lending_wasm).await?;
+    let oracle = deploy_oracle(&sandbox).await?;
+    // A plain account stands in for USDC, so every ft_transfer to it fails
+    let usdc = sandbox.dev_create_account().await?;
+    let user = sandbox.dev_create_account().await?;
+
+    set_price(&oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    lending
+        .call("new")
+        .args_json(json!({ "token_id": usdc.id(), "extra_decimals": 12 }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let token = deploy_token(&sandbox, &user, usdc.id(), oracle.id()).await?;
+    user.call(token.id(), "set_lending_protocol")
+        .args_json(json!({ "protocol": lending.id(), "extra_decimals": 12 }))
+        .deposit(NearToken::from_millinear(100))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    refresh_prices(&user, &token).await?;
+    deposit(&usdc, &token, &user, 1_000 * USDC).await?;
+
+    Ok(Setup {
+        token,
+        lending,
+        usdc,
+        user,
+    })
+}
+
+/// Deploys the mock oracle, accepting prices up to 90 seconds old.
+async fn deploy_oracle(sandbox: &Worker<Sandbox>) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("../mock_oracle").await?;
+    let oracle = sandbox.dev_deploy(&wasm).await?;
+    oracle
+        .call("new")
+        .args_json(json!({ "recency_duration_sec": 90 }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(oracle)
+}
+
+async fn set_price(
+    oracle: &Contract,
+    asset_id: &str,
+    multiplier: u128,
+    decimals: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    oracle
+        .call("set_price")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "multiplier": multiplier.to_string(),
+            "decimals": decimals,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Deploys this crate's contract as a fund of 70% ETH and 30% AURORA owned by `owner`.
+async fn deploy_token(
+    sandbox: &Worker<Sandbox>,
+    owner: &Account,
+    usdc: &AccountId,
+    oracle: &AccountId,
+) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("./").await?;
+    let token = sandbox.dev_deploy(&wasm).await?;
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH, "weight": 7_000 },
+                { "name": "AURORA", "contract_address": AURORA, "weight": 3_000 },
+            ],
+            "usdc_contract": usdc,
+            "oracle_contract": oracle,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(token)
+}
+
+/// Deposits `amount` for `sender` the way the USDC contract does after `ft_transfer_call`.
+async fn deposit(
+    usdc: &Account,
+    token: &Contract,
+    sender: &Account,
+    amount: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let refunded: String = usdc
+        .call(token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": sender.id(),
+            "amount": amount.to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, "0", "the deposit was refunded");
+    Ok(())
+}
+
+async fn refresh_prices(
+    caller: &Account,
+    token: &Contract,
+) -> Result<(), Box<dyn std::error::Error>> {
+    caller
+        .call(token.id(), "refresh_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Credits `amount` of interest to the fund's position and reads it back.
+async fn accrue_interest(setup: &Setup, amount: u128) -> Result<(), Box<dyn std::error::Error>> {
+    setup
+        .usdc
+        .call(setup.lending.id(), "accrue_interest")
+        .args_json(json!({ "account_id": setup.token.id(), "amount": amount.to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    setup
+        .user
+        .call(setup.token.id(), "refresh_lending_position")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn portfolio_value(setup: &Setup) -> Result<u128, Box<dyn std::error::Error>> {
+    let value: String = setup
+        .token
+        .view("get_portfolio_value")
+        .args_json(json!({ "account_id": setup.user.id() }))
+        .await?
+        .json()?;
+    Ok(value.parse()?)
+}
+
+#[tokio::test]
+async fn test_accrued_interest_counts_in_nav() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    let before = portfolio_value(&setup).await?;
+
+    accrue_interest(&setup, 50).await?;
+
+    let lending: Value = setup.token.view("get_lending").args_json(json!({})).await?.json()?;
+    assert_eq!(lending["balance"], "50");
+    let interest: String = setup
+        .token
+        .view("get_accrued_interest")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(interest, "50");
+    // The only holder is owed all of it
+    assert_eq!(portfolio_value(&setup).await?, before + 50);
+    let preview: Value = setup
+        .token
+        .view("preview_redeem")
+        .args_json(json!({
+            "account_id": setup.user.id(),
+            "amount": { "basis_points": 10_000 },
+        }))
+        .await?
+        .json()?;
+    assert_eq!(preview["interest"], "50");
+
+    Ok(())
+}
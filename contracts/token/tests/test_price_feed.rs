@@ -0,0 +1,406 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::AccountId;
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+/// NEAR token ids the mock oracle prices the assets under, see `TOKEN_ADDRESSES`
+const WETH_FT: &str = "weth.fakes.testnet";
+const AURORA_FT: &str = "aurora.fakes.testnet";
+/// One USDC in its smallest unit
+const USDC: u128 = 1_000_000;
+/// The NEAR priceoracle reports USD per smallest unit with 4 decimals more than the
+/// token has, 22 for WETH and AURORA
+const PRICE_DECIMALS: u8 = 22;
+
+struct Setup {
+    sandbox: Worker<Sandbox>,
+    token: Contract,
+    oracle: Contract,
+    usdc: Account,
+    user: Account,
+}
+
+async fn setup() -> Result<Setup, Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+    let oracle = deploy_oracle(&sandbox).await?;
+    let usdc = sandbox.dev_create_account().await?;
+    let user = sandbox.dev_create_account().await?;
+    let token = deploy_token(&sandbox, &user, usdc.id(), oracle.id()).await?;
+
+    Ok(Setup {
+        sandbox,
+        token,
+        oracle,
+        usdc,
+        user,
+    })
+}
+
+/// Deploys the mock oracle, accepting prices up to 90 seconds old.
+async fn deploy_oracle(sandbox: &Worker<Sandbox>) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("../mock_oracle").await?;
+    let oracle = sandbox.dev_deploy(&wasm).await?;
+    oracle
+        .call("new")
+        .args_json(json!({ "recency_duration_sec": 90 }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(oracle)
+}
+
+async fn set_price(
+    oracle: &Contract,
+    asset_id: &str,
+    multiplier: u128,
+    decimals: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    oracle
+        .call("set_price")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "multiplier": multiplier.to_string(),
+            "decimals": decimals,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Deploys this crate's contract as a fund of 70% ETH and 30% AURORA owned by `owner`.
+async fn deploy_token(
+    sandbox: &Worker<Sandbox>,
+    owner: &Account,
+    usdc: &AccountId,
+    oracle: &AccountId,
+) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("./").await?;
+    let token = sandbox.dev_deploy(&wasm).await?;
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH, "weight": 7_000 },
+                { "name": "AURORA", "contract_address": AURORA, "weight": 3_000 },
+            ],
+            "usdc_contract": usdc,
+            "oracle_contract": oracle,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(token)
+}
+
+/// Deposits `amount` for `sender` the way the USDC contract does after `ft_transfer_call`.
+async fn deposit(
+    usdc: &Account,
+    token: &Contract,
+    sender: &Account,
+    amount: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let refunded: String = usdc
+        .call(token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": sender.id(),
+            "amount": amount.to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, "0", "the deposit was refunded");
+    Ok(())
+}
+
+async fn refresh_prices(
+    caller: &Account,
+    token: &Contract,
+) -> Result<(), Box<dyn std::error::Error>> {
+    caller
+        .call(token.id(), "refresh_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn add_oracle(setup: &Setup) -> Result<Contract, Box<dyn std::error::Error>> {
+    let oracle = deploy_oracle(&setup.sandbox).await?;
+    setup
+        .user
+        .call(setup.token.id(), "add_oracle_source")
+        .args_json(json!({ "account_id": oracle.id(), "kind": "PriceOracle" }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(oracle)
+}
+
+async fn set_thresholds(
+    setup: &Setup,
+    min_sources: u8,
+    max_deviation_bps: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    setup
+        .user
+        .call(setup.token.id(), "set_oracle_thresholds")
+        .args_json(json!({
+            "min_sources": min_sources,
+            "max_deviation_bps": max_deviation_bps,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn current_prices(setup: &Setup) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "get_current_prices")
+        .max_gas()
+        .transact()
+        .await?;
+    Ok(outcome.into_result()?.json()?)
+}
+
+async fn portfolio_value(setup: &Setup) -> Result<u128, Box<dyn std::error::Error>> {
+    refresh_prices(&setup.user, &setup.token).await?;
+    let value: String = setup
+        .token
+        .view("get_portfolio_value")
+        .args_json(json!({ "account_id": setup.user.id() }))
+        .await?
+        .json()?;
+    Ok(value.parse()?)
+}
+
+#[tokio::test]
+async fn test_fresh_prices_are_mapped_to_assets() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&setup.oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+
+    let prices = current_prices(&setup).await?;
+    assert_eq!(prices.len(), 2);
+
+    let weth = prices
+        .iter()
+        .find(|p| p["asset_address"] == WETH)
+        .expect("WETH price missing");
+    assert_eq!(weth["price"], "20000000");
+    assert_eq!(weth["decimals"], 22);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_prices_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+
+    setup
+        .oracle
+        .call("set_timestamp")
+        .args_json(json!({ "timestamp": "1" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "get_current_prices")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_failure());
+    assert!(format!("{:?}", outcome.failures()).contains("Price data is too old"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_missing_price_refunds_deposits() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    setup
+        .oracle
+        .call("remove_price")
+        .args_json(json!({ "asset_id": AURORA_FT }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let prices = current_prices(&setup).await?;
+    assert_eq!(prices.len(), 1);
+    assert_eq!(prices[0]["asset_address"], WETH);
+
+    // Without a price the AURORA slice can't be credited, the deposit is refunded
+    refresh_prices(&setup.user, &setup.token).await?;
+    let refunded: String = setup
+        .usdc
+        .call(setup.token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": setup.user.id(),
+            "amount": (1_000 * USDC).to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, (1_000 * USDC).to_string());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_portfolio_valuation() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&setup.oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    refresh_prices(&setup.user, &setup.token).await?;
+
+    // 700 USDC buy 0.35 WETH at 2000 and 300 buy 600 AURORA at 0.5
+    deposit(&setup.usdc, &setup.token, &setup.user, 1_000 * USDC).await?;
+    set_price(&setup.oracle, WETH_FT, 22_000_000, PRICE_DECIMALS).await?;
+    // 0.35 * 2200 + 600 * 0.5
+    assert_eq!(portfolio_value(&setup).await?, 1_070 * USDC);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_high_precision_decimals() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    // 10^21 does not fit into u64, the same prices expressed with 36 decimals
+    set_price(&setup.oracle, WETH_FT, 2 * 10u128.pow(21), 36).await?;
+    set_price(&setup.oracle, AURORA_FT, 5 * 10u128.pow(17), 36).await?;
+    refresh_prices(&setup.user, &setup.token).await?;
+
+    deposit(&setup.usdc, &setup.token, &setup.user, 1_000 * USDC).await?;
+    set_price(&setup.oracle, WETH_FT, 22 * 10u128.pow(20), 36).await?;
+    assert_eq!(portfolio_value(&setup).await?, 1_070 * USDC);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_median_across_sources() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    let second = add_oracle(&setup).await?;
+    let third = add_oracle(&setup).await?;
+    set_thresholds(&setup, 2, 500).await?;
+
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&second, WETH_FT, 201_000_000, PRICE_DECIMALS + 1).await?;
+    // Far off the median, dropped
+    set_price(&third, WETH_FT, 30_000_000, PRICE_DECIMALS).await?;
+
+    let prices = current_prices(&setup).await?;
+    assert_eq!(prices.len(), 1);
+    assert_eq!(prices[0]["price"], "200500000");
+    assert_eq!(prices[0]["decimals"], 23);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deviating_sources_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    let second = add_oracle(&setup).await?;
+    set_thresholds(&setup, 2, 500).await?;
+
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&second, WETH_FT, 30_000_000, PRICE_DECIMALS).await?;
+
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "get_current_prices")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_failure());
+    assert!(format!("{:?}", outcome.failures()).contains("Not enough agreeing oracle prices"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_too_few_sources_answer() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    let second = add_oracle(&setup).await?;
+    set_thresholds(&setup, 2, 500).await?;
+
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&second, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    second
+        .call("set_timestamp")
+        .args_json(json!({ "timestamp": "1" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "get_current_prices")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_failure());
+    let failures = format!("{:?}", outcome.failures());
+    assert!(failures.contains("Not enough oracle sources answered: 1 of 2 required"));
+    assert!(failures.contains("Price data is too old"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_freezes_deposits() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_price(&setup.oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&setup.oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    refresh_prices(&setup.user, &setup.token).await?;
+
+    // WETH halves between two refreshes
+    set_price(&setup.oracle, WETH_FT, 10_000_000, PRICE_DECIMALS).await?;
+    refresh_prices(&setup.user, &setup.token).await?;
+
+    let trip: Value = setup.token.view("get_circuit_breaker").await?.json()?;
+    assert_eq!(trip["asset_address"], WETH);
+    assert_eq!(trip["change_bps"], "5000");
+
+    // The deposit is handed back to the token contract for a refund
+    let refunded: String = setup
+        .usdc
+        .call(setup.token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": setup.user.id(),
+            "amount": (1_000 * USDC).to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, (1_000 * USDC).to_string());
+
+    setup
+        .user
+        .call(setup.token.id(), "reset_circuit_breaker")
+        .transact()
+        .await?
+        .into_result()?;
+    deposit(&setup.usdc, &setup.token, &setup.user, 1_000 * USDC).await?;
+    // 0.7 WETH at 1000 and 600 AURORA at 0.5
+    assert_eq!(portfolio_value(&setup).await?, 1_000 * USDC);
+
+    Ok(())
+}
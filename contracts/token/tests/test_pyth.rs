@@ -0,0 +1,183 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::AccountId;
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+const ETH_FEED: &str = "ff61491a931112ddf1bd8147cd1b641375f79f5825126d665480874634fd0ace";
+
+struct Setup {
+    token: Contract,
+    pyth: Contract,
+    user: Account,
+}
+
+/// Token whose only oracle source is the mock Pyth contract, with a feed for WETH
+async fn setup() -> Result<Setup, Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+
+    let pyth_wasm = near_workspaces::compile_project("../mock_pyth").await?;
+
+    let pyth = sandbox.dev_deploy(&pyth_wasm).await?;
+    // Plain accounts stand in for USDC and the initial oracle, neither is called here
+    let usdc = sandbox.dev_create_account().await?;
+    let oracle = sandbox.dev_create_account().await?;
+    let user = sandbox.dev_create_account().await?;
+
+    pyth.call("new").transact().await?.into_result()?;
+
+    let token = deploy_token(&sandbox, &user, usdc.id(), oracle.id()).await?;
+    user.call(token.id(), "add_oracle_source")
+        .args_json(json!({ "account_id": pyth.id(), "kind": "Pyth" }))
+        .transact()
+        .await?
+        .into_result()?;
+    user.call(token.id(), "remove_oracle_source")
+        .args_json(json!({ "account_id": oracle.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    user.call(token.id(), "set_pyth_feed")
+        .args_json(json!({
+            "asset_address": WETH,
+            "price_id": format!("0x{}", ETH_FEED),
+            "token_decimals": 18,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    Ok(Setup { token, pyth, user })
+}
+
+/// Deploys this crate's contract as a fund of 70% ETH and 30% AURORA owned by `owner`.
+async fn deploy_token(
+    sandbox: &Worker<Sandbox>,
+    owner: &Account,
+    usdc: &AccountId,
+    oracle: &AccountId,
+) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("./").await?;
+    let token = sandbox.dev_deploy(&wasm).await?;
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH, "weight": 7_000 },
+                { "name": "AURORA", "contract_address": AURORA, "weight": 3_000 },
+            ],
+            "usdc_contract": usdc,
+            "oracle_contract": oracle,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(token)
+}
+
+async fn set_eth_price(
+    setup: &Setup,
+    price: i64,
+    conf: u64,
+    expo: i32,
+    publish_time: Option<i64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    setup
+        .pyth
+        .call("set_price")
+        .args_json(json!({
+            "price_id": ETH_FEED,
+            "price": price.to_string(),
+            "conf": conf.to_string(),
+            "expo": expo,
+            "publish_time": publish_time,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Prices the token accepted, and the logs explaining the ones it rejected
+async fn current_prices(
+    setup: &Setup,
+) -> Result<(Vec<Value>, Vec<String>), Box<dyn std::error::Error>> {
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "get_current_prices")
+        .max_gas()
+        .transact()
+        .await?;
+    let logs = outcome.logs().iter().map(|log| log.to_string()).collect();
+    Ok((outcome.into_result()?.json()?, logs))
+}
+
+#[tokio::test]
+async fn test_pyth_price_is_scaled_per_smallest_unit() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    // 2500.00000000 USD per ETH, +/- 1 USD
+    set_eth_price(&setup, 250_000_000_000, 100_000_000, -8, None).await?;
+
+    let (prices, _) = current_prices(&setup).await?;
+    assert_eq!(prices.len(), 1);
+    assert_eq!(prices[0]["asset_address"], WETH);
+    assert_eq!(prices[0]["price"], "250000000000");
+    // 8 decimals of the exponent and 18 of WETH
+    assert_eq!(prices[0]["decimals"], 26);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_positive_exponent_is_applied() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_eth_price(&setup, 25, 0, 2, None).await?;
+
+    let (prices, _) = current_prices(&setup).await?;
+    assert_eq!(prices[0]["price"], "2500");
+    assert_eq!(prices[0]["decimals"], 18);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wide_confidence_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    // +/- 125 USD is a 5% interval, above the 2% default
+    set_eth_price(&setup, 250_000_000_000, 12_500_000_000, -8, None).await?;
+
+    let (prices, logs) = current_prices(&setup).await?;
+    assert!(prices.is_empty());
+    assert!(logs
+        .iter()
+        .any(|log| log.contains("confidence interval of 500 bps is too wide")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_old_price_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    set_eth_price(&setup, 250_000_000_000, 100_000_000, -8, Some(1)).await?;
+
+    let (prices, logs) = current_prices(&setup).await?;
+    assert!(prices.is_empty());
+    assert!(logs.iter().any(|log| log.contains("price is too old")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_too_many_decimals_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    // 18 token decimals and 21 of the exponent don't fit a u128 scale
+    set_eth_price(&setup, 25, 0, -21, None).await?;
+
+    let (prices, logs) = current_prices(&setup).await?;
+    assert!(prices.is_empty());
+    assert!(logs.iter().any(|log| log.contains("too many decimals")));
+
+    Ok(())
+}
@@ -0,0 +1,541 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::{AccountId, NearToken};
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+/// NEAR token ids the mock oracle prices the assets under, see `TOKEN_ADDRESSES`
+const WETH_FT: &str = "weth.fakes.testnet";
+const AURORA_FT: &str = "aurora.fakes.testnet";
+/// One USDC in its smallest unit
+const USDC: u128 = 1_000_000;
+/// The NEAR priceoracle reports USD per smallest unit with 4 decimals more than the
+/// token has, 22 for WETH and AURORA
+const PRICE_DECIMALS: u8 = 22;
+
+struct Setup {
+    sandbox: Worker<Sandbox>,
+    token: Contract,
+    usdc: Contract,
+    weth: Contract,
+    aurora: Contract,
+    amm: Contract,
+    user: Account,
+}
+
+async fn setup() -> Result<Setup, Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+
+    let ft_wasm = near_workspaces::compile_project("../mock_ft").await?;
+    let amm_wasm = near_workspaces::compile_project("../mock_amm").await?;
+
+    let oracle = deploy_oracle(&sandbox).await?;
+    let usdc = sandbox.dev_deploy(&ft_wasm).await?;
+    let weth = sandbox.dev_deploy(&ft_wasm).await?;
+    let aurora = sandbox.dev_deploy(&ft_wasm).await?;
+    let amm = sandbox.dev_deploy(&amm_wasm).await?;
+    let user = sandbox.dev_create_account().await?;
+
+    for ft in [&usdc, &weth, &aurora] {
+        ft.call("new").args_json(json!({})).transact().await?.into_result()?;
+    }
+    amm.call("new").args_json(json!({})).transact().await?.into_result()?;
+    // 1 USDC buys 0.000495 ETH, a little under the oracle price of 2000 USDC per ETH
+    amm.call("add_pool")
+        .args_json(json!({
+            "token_in": usdc.id(),
+            "token_out": weth.id(),
+            "numerator": "495000000",
+            "denominator": "1",
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    // 1 USDC buys 1 AURORA, AURORA is only bought on NEAR once a test routes it
+    amm.call("add_pool")
+        .args_json(json!({
+            "token_in": usdc.id(),
+            "token_out": aurora.id(),
+            "numerator": "1000000000000",
+            "denominator": "1",
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    weth.call("mint")
+        .args_json(json!({ "account_id": amm.id(), "amount": "1000000000000000000" }))
+        .transact()
+        .await?
+        .into_result()?;
+    aurora
+        .call("mint")
+        .args_json(json!({ "account_id": amm.id(), "amount": "1000000000000000000000" }))
+        .transact()
+        .await?
+        .into_result()?;
+    usdc.call("mint")
+        .args_json(json!({ "account_id": user.id(), "amount": (1_000 * USDC).to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    set_price(&oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&oracle, AURORA_FT, 10_000, PRICE_DECIMALS).await?;
+
+    let token = deploy_token(&sandbox, &user, usdc.id(), oracle.id()).await?;
+    user.call(token.id(), "set_swap_amm")
+        .args_json(json!({ "amm": amm.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    user.call(token.id(), "set_swap_route")
+        .args_json(json!({ "asset_address": WETH, "pool_id": 0, "token_id": weth.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    refresh_prices(&user, &token).await?;
+
+    Ok(Setup {
+        sandbox,
+        token,
+        usdc,
+        weth,
+        aurora,
+        amm,
+        user,
+    })
+}
+
+/// Deploys the mock oracle, accepting prices up to 90 seconds old.
+async fn deploy_oracle(sandbox: &Worker<Sandbox>) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("../mock_oracle").await?;
+    let oracle = sandbox.dev_deploy(&wasm).await?;
+    oracle
+        .call("new")
+        .args_json(json!({ "recency_duration_sec": 90 }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(oracle)
+}
+
+async fn set_price(
+    oracle: &Contract,
+    asset_id: &str,
+    multiplier: u128,
+    decimals: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    oracle
+        .call("set_price")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "multiplier": multiplier.to_string(),
+            "decimals": decimals,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Deploys this crate's contract as a fund of 70% ETH and 30% AURORA owned by `owner`.
+async fn deploy_token(
+    sandbox: &Worker<Sandbox>,
+    owner: &Account,
+    usdc: &AccountId,
+    oracle: &AccountId,
+) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("./").await?;
+    let token = sandbox.dev_deploy(&wasm).await?;
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH, "weight": 7_000 },
+                { "name": "AURORA", "contract_address": AURORA, "weight": 3_000 },
+            ],
+            "usdc_contract": usdc,
+            "oracle_contract": oracle,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(token)
+}
+
+/// Deposits `amount` for `sender` the way the USDC contract does after `ft_transfer_call`.
+async fn deposit(
+    usdc: &Account,
+    token: &Contract,
+    sender: &Account,
+    amount: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let refunded: String = usdc
+        .call(token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": sender.id(),
+            "amount": amount.to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, "0", "the deposit was refunded");
+    Ok(())
+}
+
+async fn refresh_prices(
+    caller: &Account,
+    token: &Contract,
+) -> Result<(), Box<dyn std::error::Error>> {
+    caller
+        .call(token.id(), "refresh_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn user_shares(
+    token: &Contract,
+    account: &Account,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(token
+        .view("get_user_shares")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?)
+}
+
+async fn user_balance(
+    token: &Contract,
+    account: &Account,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    Ok(token
+        .view("get_user_balance")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?)
+}
+
+/// Buys AURORA on NEAR too, so the fund holds every component on NEAR.
+async fn route_aurora(setup: &Setup) -> Result<(), Box<dyn std::error::Error>> {
+    setup
+        .user
+        .call(setup.token.id(), "set_swap_route")
+        .args_json(json!({ "asset_address": AURORA, "pool_id": 1, "token_id": setup.aurora.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Sets how many units of ETH the AMM gives for one unit of USDC.
+async fn set_weth_rate(setup: &Setup, numerator: &str) -> Result<(), Box<dyn std::error::Error>> {
+    setup
+        .amm
+        .call("set_rate")
+        .args_json(json!({ "pool_id": 0, "numerator": numerator, "denominator": "1" }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn redeem_at_oracle_price(
+    setup: &Setup,
+    min_amount_out: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    setup
+        .user
+        .call(setup.token.id(), "redeem_at_oracle_price")
+        .args_json(json!({
+            "amount": { "basis_points": 10_000 },
+            "min_amount_out": min_amount_out.to_string(),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Calls `continue_usdc_redemption` until the redemption is paid out.
+async fn finish_redemption(setup: &Setup) -> Result<(), Box<dyn std::error::Error>> {
+    for _ in 0..3 {
+        let redemption: Value = setup
+            .token
+            .view("get_usdc_redemption")
+            .args_json(json!({ "account_id": setup.user.id() }))
+            .await?
+            .json()?;
+        if redemption.is_null() {
+            return Ok(());
+        }
+        setup
+            .user
+            .call(setup.token.id(), "continue_usdc_redemption")
+            .args_json(json!({ "account_id": setup.user.id() }))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+    }
+    Err("the redemption was not paid out".into())
+}
+
+async fn transfer_deposit(setup: &Setup) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let outcome = setup
+        .user
+        .call(setup.usdc.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": setup.token.id(),
+            "amount": (1_000 * USDC).to_string(),
+            "msg": "",
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+    Ok(outcome.logs().into_iter().map(str::to_string).collect())
+}
+
+async fn ft_balance(
+    ft: &Contract,
+    account_id: &AccountId,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(ft
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": account_id }))
+        .await?
+        .json()?)
+}
+
+async fn amm_deposits(setup: &Setup) -> Result<Value, Box<dyn std::error::Error>> {
+    Ok(setup
+        .amm
+        .view("get_deposits")
+        .args_json(json!({ "account_id": setup.token.id() }))
+        .await?
+        .json()?)
+}
+
+#[tokio::test]
+async fn test_deposit_buys_component_on_near() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+
+    let logs = transfer_deposit(&setup).await?;
+    assert!(logs.iter().any(|log| log.contains("\"event\":\"near_swap_executed\"")));
+
+    // 700 USDC went to the AMM for 0.3465 ETH, 300 AURORA at 1 USDC are left to the keeper
+    let balance = user_balance(&setup.token, &setup.user).await?;
+    assert_eq!(balance[WETH], "346500000000000000");
+    assert_eq!(balance[AURORA], "300000000000000000000");
+    assert_eq!(ft_balance(&setup.weth, setup.token.id()).await?, "346500000000000000");
+    assert_eq!(ft_balance(&setup.usdc, setup.token.id()).await?, "300000000");
+    // Nothing is left with the AMM or in flight
+    let deposits = amm_deposits(&setup).await?;
+    assert_eq!(deposits[setup.usdc.id().as_str()], "0");
+    assert_eq!(deposits[setup.weth.id().as_str()], "0");
+    let venue: Value = setup.token.view("get_swap_venue").args_json(json!({})).await?.json()?;
+    assert_eq!(venue["in_flight"], json!({}));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_below_oracle_price_is_queued_again() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    // 0.0875 ETH for 700 USDC is far below the 0.3465 the oracle price allows
+    set_weth_rate(&setup, "125000000").await?;
+
+    let logs = transfer_deposit(&setup).await?;
+    assert!(logs.iter().any(|log| log.contains("\"event\":\"near_swap_failed\"")));
+
+    // Nothing is credited for the slice, it waits for another swap
+    let balance = user_balance(&setup.token, &setup.user).await?;
+    assert_eq!(balance[WETH], Value::Null);
+    let venue: Value = setup.token.view("get_swap_venue").args_json(json!({})).await?.json()?;
+    assert_eq!(venue["queued"].as_array().unwrap().len(), 1);
+    assert_eq!(venue["in_flight"][WETH], "700000000");
+    // The USDC was withdrawn from the AMM again
+    assert_eq!(ft_balance(&setup.usdc, setup.token.id()).await?, "1000000000");
+    assert_eq!(amm_deposits(&setup).await?[setup.usdc.id().as_str()], "0");
+
+    // Once the pool is back in line anyone can run the swap
+    set_weth_rate(&setup, "495000000").await?;
+    setup
+        .user
+        .call(setup.token.id(), "run_queued_swaps")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let balance = user_balance(&setup.token, &setup.user).await?;
+    assert_eq!(balance[WETH], "346500000000000000");
+    assert_eq!(ft_balance(&setup.weth, setup.token.id()).await?, "346500000000000000");
+    let venue: Value = setup.token.view("get_swap_venue").args_json(json!({})).await?.json()?;
+    assert_eq!(venue["queued"], json!([]));
+    assert_eq!(venue["in_flight"], json!({}));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_redeem_sends_components_held_on_near() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    route_aurora(&setup).await?;
+    transfer_deposit(&setup).await?;
+
+    // Nothing is signed, both components leave with `ft_transfer`
+    setup
+        .user
+        .call(setup.token.id(), "redeem")
+        .args_json(json!({
+            "amount": { "basis_points": 10_000 },
+            "request": {
+                "eth_destination": "0x1111111111111111111111111111111111111111",
+                "aurora_destination": "0x2222222222222222222222222222222222222222",
+                "network_details": {
+                    "chain_id": 11155111,
+                    "eth_nonce": 0,
+                    "max_priority_fee_per_gas": 1_000_000_000u128,
+                    "max_fee_per_gas": 20_000_000_000u128,
+                    "gas_limit": 100_000u128,
+                },
+            },
+        }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    assert_eq!(ft_balance(&setup.weth, setup.user.id()).await?, "346500000000000000");
+    assert_eq!(ft_balance(&setup.aurora, setup.user.id()).await?, "300000000000000000000");
+    assert_eq!(user_shares(&setup.token, &setup.user).await?, "0");
+    let signing: Value = setup
+        .token
+        .view("get_withdrawal_signing")
+        .args_json(json!({ "account_id": setup.user.id() }))
+        .await?
+        .json()?;
+    assert!(signing.is_null());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_redeem_at_oracle_price_sells_on_near() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    route_aurora(&setup).await?;
+    transfer_deposit(&setup).await?;
+
+    // 0.3465 ETH and 300 AURORA are worth 993 USDC at the oracle prices
+    redeem_at_oracle_price(&setup, 990 * USDC).await?;
+    finish_redemption(&setup).await?;
+
+    // The AMM buys them back for what they cost
+    assert_eq!(ft_balance(&setup.usdc, setup.user.id()).await?, (1_000 * USDC).to_string());
+    assert_eq!(user_shares(&setup.token, &setup.user).await?, "0");
+    assert_eq!(ft_balance(&setup.weth, setup.token.id()).await?, "0");
+    assert_eq!(ft_balance(&setup.aurora, setup.token.id()).await?, "0");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_leg_below_its_minimum_comes_back() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    route_aurora(&setup).await?;
+    transfer_deposit(&setup).await?;
+    // Selling 300 AURORA now brings 150 USDC, below the 297 the slippage allows
+    setup
+        .amm
+        .call("set_rate")
+        .args_json(json!({ "pool_id": 1, "numerator": "2000000000000", "denominator": "1" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    redeem_at_oracle_price(&setup, 0).await?;
+    finish_redemption(&setup).await?;
+
+    assert_eq!(ft_balance(&setup.usdc, setup.user.id()).await?, (700 * USDC).to_string());
+    let balance = user_balance(&setup.token, &setup.user).await?;
+    assert_eq!(balance[AURORA], "300000000000000000000");
+    assert_eq!(balance[WETH], "0");
+    // AURORA is 30% of the fund
+    assert_eq!(user_shares(&setup.token, &setup.user).await?, (300 * USDC).to_string());
+    assert_eq!(ft_balance(&setup.aurora, setup.token.id()).await?, "300000000000000000000");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_usdc_redemption_tops_up_from_lending() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+    route_aurora(&setup).await?;
+    transfer_deposit(&setup).await?;
+
+    let lending_wasm = near_workspaces::compile_project("../mock_lending").await?;
+    let lending = setup.sandbox.dev_deploy(&lending_wasm).await?;
+    lending
+        .call("new")
+        .args_json(json!({ "token_id": setup.usdc.id(), "extra_decimals": 12 }))
+        .transact()
+        .await?
+        .into_result()?;
+    setup
+        .user
+        .call(setup.token.id(), "set_lending_protocol")
+        .args_json(json!({ "protocol": lending.id(), "extra_decimals": 12 }))
+        .deposit(NearToken::from_millinear(100))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    // 50 USDC of interest, held by the protocol
+    setup
+        .usdc
+        .call("mint")
+        .args_json(json!({ "account_id": lending.id(), "amount": (50 * USDC).to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    setup
+        .user
+        .call(lending.id(), "accrue_interest")
+        .args_json(json!({ "account_id": setup.token.id(), "amount": (50 * USDC).to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    setup
+        .user
+        .call(setup.token.id(), "refresh_lending_position")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    redeem_at_oracle_price(&setup, 0).await?;
+    finish_redemption(&setup).await?;
+
+    // The sales brought 1000 USDC, the interest was withdrawn for the payout
+    assert_eq!(ft_balance(&setup.usdc, setup.user.id()).await?, (1_050 * USDC).to_string());
+    let interest: String = setup
+        .token
+        .view("get_accrued_interest")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(interest, "0");
+
+    Ok(())
+}
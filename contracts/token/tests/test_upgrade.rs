@@ -0,0 +1,307 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::operations::Function;
+use near_workspaces::types::{AccountId, NearGas};
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+const AURORA: &str = "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6";
+/// NEAR token ids the mock oracle prices the assets under, see `TOKEN_ADDRESSES`
+const WETH_FT: &str = "weth.fakes.testnet";
+const AURORA_FT: &str = "aurora.fakes.testnet";
+/// One USDC in its smallest unit
+const USDC: u128 = 1_000_000;
+/// The NEAR priceoracle reports USD per smallest unit with 4 decimals more than the
+/// token has, 22 for WETH and AURORA
+const PRICE_DECIMALS: u8 = 22;
+
+struct Setup {
+    token: Contract,
+    user: Account,
+    stranger: Account,
+    wasm: Vec<u8>,
+}
+
+async fn setup() -> Result<Setup, Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+
+    let wasm = near_workspaces::compile_project("./").await?;
+    let token = sandbox.dev_deploy(&wasm).await?;
+    let oracle = deploy_oracle(&sandbox).await?;
+    // A plain account stands in for USDC, it is never called here
+    let usdc = sandbox.dev_create_account().await?;
+    let user = sandbox.dev_create_account().await?;
+    let stranger = sandbox.dev_create_account().await?;
+
+    init_token(&token, &user, usdc.id(), oracle.id()).await?;
+    set_price(&oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    refresh_prices(&user, &token).await?;
+    deposit(&usdc, &token, &user, 1_000 * USDC).await?;
+
+    Ok(Setup {
+        token,
+        user,
+        stranger,
+        wasm,
+    })
+}
+
+/// Deploys the mock oracle, accepting prices up to 90 seconds old.
+async fn deploy_oracle(sandbox: &Worker<Sandbox>) -> Result<Contract, Box<dyn std::error::Error>> {
+    let wasm = near_workspaces::compile_project("../mock_oracle").await?;
+    let oracle = sandbox.dev_deploy(&wasm).await?;
+    oracle
+        .call("new")
+        .args_json(json!({ "recency_duration_sec": 90 }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(oracle)
+}
+
+async fn set_price(
+    oracle: &Contract,
+    asset_id: &str,
+    multiplier: u128,
+    decimals: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    oracle
+        .call("set_price")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "multiplier": multiplier.to_string(),
+            "decimals": decimals,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Initializes `token` as a fund of 70% ETH and 30% AURORA owned by `owner`.
+async fn init_token(
+    token: &Contract,
+    owner: &Account,
+    usdc: &AccountId,
+    oracle: &AccountId,
+) -> Result<(), Box<dyn std::error::Error>> {
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH, "weight": 7_000 },
+                { "name": "AURORA", "contract_address": AURORA, "weight": 3_000 },
+            ],
+            "usdc_contract": usdc,
+            "oracle_contract": oracle,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Deposits `amount` for `sender` the way the USDC contract does after `ft_transfer_call`.
+async fn deposit(
+    usdc: &Account,
+    token: &Contract,
+    sender: &Account,
+    amount: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let refunded: String = usdc
+        .call(token.id(), "ft_on_transfer")
+        .args_json(json!({
+            "sender_id": sender.id(),
+            "amount": amount.to_string(),
+            "msg": "",
+        }))
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(refunded, "0", "the deposit was refunded");
+    Ok(())
+}
+
+async fn refresh_prices(
+    caller: &Account,
+    token: &Contract,
+) -> Result<(), Box<dyn std::error::Error>> {
+    caller
+        .call(token.id(), "refresh_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn user_shares(
+    token: &Contract,
+    account: &Account,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(token
+        .view("get_user_shares")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?)
+}
+
+async fn user_balance(
+    token: &Contract,
+    account: &Account,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    Ok(token
+        .view("get_user_balance")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?)
+}
+
+#[tokio::test]
+async fn test_upgrade_keeps_state() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+
+    let outcome = setup
+        .user
+        .call(setup.token.id(), "upgrade")
+        .args(setup.wasm.clone())
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+    assert!(outcome
+        .logs()
+        .iter()
+        .any(|log| log.contains("Migrating state from version 4 to 4")));
+
+    assert_eq!(user_shares(&setup.token, &setup.user).await?, (1_000 * USDC).to_string());
+    let version: u8 = setup
+        .token
+        .view("get_state_version")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(version, 4);
+    let metadata: Value = setup
+        .token
+        .view("contract_source_metadata")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(metadata["version"], env!("CARGO_PKG_VERSION"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_only_owner_or_upgrader_can_upgrade() -> Result<(), Box<dyn std::error::Error>> {
+    let setup = setup().await?;
+
+    let outcome = setup
+        .stranger
+        .call(setup.token.id(), "upgrade")
+        .args(setup.wasm.clone())
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_failure());
+
+    setup
+        .user
+        .call(setup.token.id(), "set_upgrader")
+        .args_json(json!({ "upgrader": setup.stranger.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    let outcome = setup
+        .stranger
+        .call(setup.token.id(), "upgrade")
+        .args(setup.wasm.clone())
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upgrade_from_first_deployment() -> Result<(), Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+    let old_wasm = near_workspaces::compile_project("../token_v0").await?;
+    let token = sandbox.dev_deploy(&old_wasm).await?;
+    let usdc = sandbox.dev_create_account().await?;
+    let oracle = deploy_oracle(&sandbox).await?;
+    let user = sandbox.dev_create_account().await?;
+
+    token
+        .call("new")
+        .args_json(json!({
+            "owner_id": user.id(),
+            "assets": [
+                { "name": "ETH", "contract_address": WETH.to_lowercase(), "weight": 70 },
+                { "name": "AURORA", "contract_address": AURORA.to_lowercase(), "weight": 30 },
+            ],
+            "usdc_contract": usdc.id(),
+            "oracle_contract": oracle.id(),
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    user.call(token.id(), "process_deposit")
+        .args_json(json!({ "sender_id": user.id(), "amount": "1000" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    // The first deployment has no `upgrade`, its account deploys the new code itself
+    let wasm = near_workspaces::compile_project("./").await?;
+    let outcome = token
+        .as_account()
+        .batch(token.id())
+        .deploy(&wasm)
+        .call(Function::new("migrate").gas(NearGas::from_tgas(200)))
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+    assert!(outcome
+        .logs()
+        .iter()
+        .any(|log| log.contains("Migrating state from version 0 to 4")));
+
+    assert_eq!(user_shares(&token, &user).await?, "1000");
+    // The first deployment kept the USDC put into each component, it becomes units once
+    // there are prices
+    assert_eq!(user_balance(&token, &user).await?, Value::Null);
+    set_price(&oracle, WETH_FT, 20_000_000, PRICE_DECIMALS).await?;
+    set_price(&oracle, AURORA_FT, 5_000, PRICE_DECIMALS).await?;
+    refresh_prices(&user, &token).await?;
+    let left: u64 = user
+        .call(token.id(), "convert_usdc_balances")
+        .args_json(json!({}))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert_eq!(left, 0);
+
+    // 0.0007 USDC buy 0.00000035 ETH at 2000 USDC, 0.0003 USDC buy 0.0006 AURORA at 0.5
+    let balance = user_balance(&token, &user).await?;
+    assert_eq!(balance[WETH], "350000000000");
+    assert_eq!(balance[AURORA], "600000000000000");
+    let assets: Value = token.view("get_assets").args_json(json!({})).await?.json()?;
+    assert_eq!(assets[0]["contract_address"], WETH);
+    assert_eq!(assets[0]["weight"], 7_000);
+    let oracle_contract: String = token
+        .view("get_oracle_contract")
+        .args_json(json!({}))
+        .await?
+        .json()?;
+    assert_eq!(oracle_contract, oracle.id().as_str());
+
+    Ok(())
+}
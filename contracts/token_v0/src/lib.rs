@@ -0,0 +1,91 @@
+// The token contract as first deployed, cut down to its state and deposits. The layout
+// of `Contract` must not change, the token's `migrate` reads it as `ContractV0`.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault};
+use std::collections::HashMap;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetInfo {
+    pub name: String,
+    pub contract_address: String,
+    pub weight: u8,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    pub total_assets: U128,
+    pub assets: Vec<AssetInfo>,
+    pub owner_id: AccountId,
+    pub user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    pub usdc_contract: AccountId,
+    pub oracle_contract: AccountId,
+    pub latest_signed_txs: Vec<Vec<u8>>,
+}
+
+#[near_bindgen]
+impl Contract {
+    // The first deployment stored hardcoded testnet accounts instead of `usdc_contract`
+    // and `oracle_contract`. The ones given are stored here, so tests can check where
+    // they end up after the upgrade.
+    #[init]
+    pub fn new(
+        owner_id: AccountId,
+        assets: Vec<AssetInfo>,
+        usdc_contract: AccountId,
+        oracle_contract: AccountId,
+    ) -> Self {
+        assert!(!env::state_exists(), "Contract is already initialized");
+        let total_weight: u8 = assets.iter().map(|a| a.weight).sum();
+        assert_eq!(total_weight, 100, "Total weight of assets must equal 100%");
+
+        Self {
+            total_assets: U128(0),
+            assets,
+            owner_id,
+            user_balances: HashMap::new(),
+            usdc_contract,
+            oracle_contract,
+            latest_signed_txs: Vec::new(),
+        }
+    }
+
+    pub fn get_assets(&self) -> Vec<AssetInfo> {
+        self.assets.clone()
+    }
+
+    pub fn get_total_assets(&self) -> U128 {
+        self.total_assets
+    }
+
+    pub fn get_user_balance(&self, account_id: &AccountId) -> Option<&HashMap<String, U128>> {
+        self.user_balances.get(account_id)
+    }
+
+    pub fn process_deposit(&mut self, sender_id: AccountId, amount: U128) {
+        let user_balance = self
+            .user_balances
+            .entry(sender_id.clone())
+            .or_insert_with(HashMap::new);
+
+        for asset in &self.assets {
+            let weight_fraction = f64::from(asset.weight) / 100.0;
+            let asset_amount = (amount.0 as f64 * weight_fraction) as u128;
+
+            user_balance
+                .entry(asset.contract_address.clone())
+                .and_modify(|balance| *balance = U128(balance.0 + asset_amount))
+                .or_insert(U128(asset_amount));
+        }
+
+        self.total_assets = U128(self.total_assets.0 + amount.0);
+
+        env::log_str(&format!(
+            "Processed deposit for user {} with amount {}",
+            sender_id, amount.0
+        ));
+    }
+}
@@ -1,17 +1,33 @@
 // Find all our documentation at https://docs.near.org
 use near_sdk::json_types::U128;
-use near_sdk::store::LookupMap;
-use near_sdk::{env, near, require, AccountId, Gas, NearToken, PanicOnDefault, BorshStorageKey};
+use near_sdk::store::{IterableSet, LookupMap, LookupSet};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, near, require, AccountId, BorshStorageKey, Gas, NearToken, PanicOnDefault, Promise,
+    PromiseError,
+};
 
 pub mod ext;
+pub mod migrate;
 pub use crate::ext::*;
+pub use fund_common::limits::DepositLimits;
 
 pub type TokenId = String;
 
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+
 #[near]
 #[derive(BorshStorageKey)]
 pub enum Prefix {
     LookupMap,
+    Allowlist,
+    Holders,
+}
+
+#[near(serializers = [json])]
+pub struct Holder {
+    pub account_id: AccountId,
+    pub balance: U128,
 }
 
 #[near(contract_state, serializers = [borsh])]
@@ -21,6 +37,10 @@ pub struct Contract {
     usdc_balance: U128,
     owner: AccountId,
     ft_contract: AccountId,
+    limits: DepositLimits,
+    allowlist: LookupSet<AccountId>,
+    /// Accounts with a non-zero balance, `address_balance` can't be iterated
+    holders: IterableSet<AccountId>,
 }
 
 #[near]
@@ -33,9 +53,19 @@ impl Contract {
             usdc_balance: near_sdk::json_types::U128(0),
             owner,
             ft_contract,
+            limits: DepositLimits::default(),
+            allowlist: LookupSet::new(Prefix::Allowlist),
+            holders: IterableSet::new(Prefix::Holders),
         }
     }
 
+    fn assert_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.owner,
+            "Only the owner can call this method"
+        );
+    }
+
     // Users bid by transferring FT tokens
     pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> U128 {
         let ft = env::predecessor_account_id();
@@ -44,8 +74,22 @@ impl Contract {
 
         let current_balance = self.address_balance.get(&sender_id).unwrap_or(&U128(0));
 
+        if let Err(reason) = self.limits.check(
+            self.allowlist.contains(&sender_id),
+            amount.0,
+            current_balance.0,
+            self.usdc_balance.0,
+        ) {
+            env::log_str(&format!(
+                "Deposit of {} USDC from {} refunded: {}",
+                amount.0, sender_id, reason
+            ));
+            return amount;
+        }
+
         self.address_balance
             .insert(sender_id.clone(), U128(current_balance.0 + amount.0));
+        self.holders.insert(sender_id.clone());
         self.usdc_balance = U128(self.usdc_balance.0 + amount.0);
 
         env::log_str(&format!(
@@ -59,7 +103,7 @@ impl Contract {
     }
 
     #[payable]
-    pub fn withdraw(&mut self, amount: U128) {
+    pub fn withdraw(&mut self, amount: U128) -> Promise {
         let account_id = env::predecessor_account_id();
         let current_balance = self.address_balance.get(&account_id).unwrap_or(&U128(0));
 
@@ -69,30 +113,127 @@ impl Contract {
         );
 
         // Update user's balance
+        let remaining = current_balance.0 - amount.0;
         self.address_balance
-            .insert(account_id.clone(), U128(current_balance.0 - amount.0));
+            .insert(account_id.clone(), U128(remaining));
+        if remaining == 0 {
+            self.holders.remove(&account_id);
+        }
 
         // Update total contract balance
         self.usdc_balance = U128(self.usdc_balance.0 - amount.0);
 
-        // Transfer tokens to user
-        ft_contract::ext(self.ft_contract.clone())
-            .with_attached_deposit(NearToken::from_yoctonear(1))
-            .with_static_gas(Gas::from_tgas(30))
-            .ft_transfer(account_id.clone(), amount);
-
         env::log_str(&format!(
             "Withdrawn {} USDC for {}. New balance: {}",
             amount.0,
             account_id,
             self.address_balance.get(&account_id).unwrap().0
         ));
+
+        // Transfer tokens to user, the callback gives the balance back if it fails
+        ft_contract::ext(self.ft_contract.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(Gas::from_tgas(30))
+            .ft_transfer(account_id.clone(), amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas::from_tgas(10))
+                    .withdraw_callback(account_id, amount),
+            )
+    }
+
+    #[private]
+    pub fn withdraw_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+
+        let current_balance = self.address_balance.get(&account_id).unwrap_or(&U128(0));
+        self.address_balance
+            .insert(account_id.clone(), U128(current_balance.0 + amount.0));
+        self.holders.insert(account_id.clone());
+        self.usdc_balance = U128(self.usdc_balance.0 + amount.0);
+
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            json!({
+                "standard": "nexusfi",
+                "version": "1.0.0",
+                "event": "withdraw_failed",
+                "data": [{ "account_id": account_id, "amount": amount }],
+            })
+        ));
+        false
+    }
+
+    pub fn set_deposit_limits(&mut self, limits: DepositLimits) {
+        self.assert_owner();
+        if let Err(err) = limits.validate() {
+            env::panic_str(&err);
+        }
+        self.limits = limits;
+    }
+
+    pub fn get_deposit_limits(&self) -> DepositLimits {
+        self.limits.clone()
+    }
+
+    pub fn add_to_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids {
+            self.allowlist.insert(account_id);
+        }
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in &account_ids {
+            self.allowlist.remove(account_id);
+        }
+    }
+
+    pub fn is_allowlisted(&self, account_id: AccountId) -> bool {
+        self.allowlist.contains(&account_id)
     }
 
     pub fn get_user_balance(&self, account_id: AccountId) -> U128 {
         *self.address_balance.get(&account_id).unwrap_or(&U128(0))
     }
 
+    pub fn get_holders(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<Holder> {
+        self.holders
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .map(|account_id| Holder {
+                account_id: account_id.clone(),
+                balance: self.get_user_balance(account_id.clone()),
+            })
+            .collect()
+    }
+
+    /// Adds the accounts of `account_ids` that hold a balance to the holder set, for
+    /// balances made before the set existed. Returns the number of holders. The
+    /// accounts come from the contract's deposit history, in batches that fit a call.
+    pub fn backfill_holders(&mut self, account_ids: Vec<AccountId>) -> u64 {
+        self.assert_owner();
+        for account_id in account_ids {
+            if self.get_user_balance(account_id.clone()).0 > 0 {
+                self.holders.insert(account_id);
+            }
+        }
+        self.holders.len() as u64
+    }
+
+    pub fn get_holder_count(&self) -> u64 {
+        self.holders.len() as u64
+    }
+
     pub fn get_usdc_balance(&self) -> U128 {
         self.usdc_balance.clone()
     }
@@ -109,6 +250,8 @@ impl Contract {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
 
     #[test]
     fn init_contract() {
@@ -119,4 +262,20 @@ mod tests {
         let owner: AccountId = "rockingg.testnet".parse().unwrap();
         let contract = Contract::init(owner.clone(), ft_contract.clone());
     }
+
+    #[test]
+    fn backfill_skips_empty_balances() {
+        let owner: AccountId = "rockingg.testnet".parse().unwrap();
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .build());
+        let mut contract = Contract::init(owner, "usdc.testnet".parse().unwrap());
+        let (alice, bob): (AccountId, AccountId) =
+            ("alice.testnet".parse().unwrap(), "bob.testnet".parse().unwrap());
+        contract.address_balance.insert(alice.clone(), U128(100));
+        contract.address_balance.insert(bob.clone(), U128(0));
+
+        assert_eq!(contract.backfill_holders(vec![alice.clone(), bob]), 1);
+        assert_eq!(contract.get_holders(None, None)[0].account_id, alice);
+    }
 }
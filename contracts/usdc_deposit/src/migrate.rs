@@ -0,0 +1,35 @@
+use near_sdk::json_types::U128;
+use near_sdk::store::{IterableSet, LookupMap, LookupSet};
+use near_sdk::{env, near, AccountId};
+
+use crate::{Contract, ContractExt, DepositLimits, Prefix};
+
+/// State as deployed before deposit limits, the allowlist and the holder set.
+#[near(serializers = [borsh])]
+pub struct ContractV0 {
+    address_balance: LookupMap<AccountId, U128>,
+    usdc_balance: U128,
+    owner: AccountId,
+    ft_contract: AccountId,
+}
+
+#[near]
+impl Contract {
+    /// Brings a contract deployed before deposit limits to the current layout. Limits
+    /// start disabled and the allowlist empty, which accepts deposits as before. The
+    /// holder set starts empty too, see `backfill_holders`.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: ContractV0 = env::state_read().expect("No state to migrate");
+        Self {
+            address_balance: old.address_balance,
+            usdc_balance: old.usdc_balance,
+            owner: old.owner,
+            ft_contract: old.ft_contract,
+            limits: DepositLimits::default(),
+            allowlist: LookupSet::new(Prefix::Allowlist),
+            holders: IterableSet::new(Prefix::Holders),
+        }
+    }
+}
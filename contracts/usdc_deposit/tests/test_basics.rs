@@ -12,19 +12,85 @@ async fn test_basics_on(contract_wasm: &[u8]) -> Result<(), Box<dyn std::error::
     let sandbox = near_workspaces::sandbox().await?;
     let contract = sandbox.dev_deploy(contract_wasm).await?;
 
+    // A plain account stands in for the USDC contract, so every ft_transfer to it fails
+    let usdc = sandbox.dev_create_account().await?;
     let user_account = sandbox.dev_create_account().await?;
 
-    let outcome = user_account
-        .call(contract.id(), "set_greeting")
-        .args_json(json!({"greeting": "Hello World!"}))
+    let outcome = contract
+        .call("init")
+        .args_json(json!({"owner": contract.id(), "ft_contract": usdc.id()}))
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+
+    let outcome = usdc
+        .call(contract.id(), "ft_on_transfer")
+        .args_json(json!({"sender_id": user_account.id(), "amount": "1000", "msg": ""}))
         .transact()
         .await?;
     assert!(outcome.is_success());
 
-    let user_message_outcome = contract.view("get_greeting").args_json(json!({})).await?;
-    assert_eq!(user_message_outcome.json::<String>()?, "Hello World!");
+    let balance = contract
+        .view("get_user_balance")
+        .args_json(json!({"account_id": user_account.id()}))
+        .await?;
+    assert_eq!(balance.json::<String>()?, "1000");
+
+    let holders = contract
+        .view("get_holders")
+        .args_json(json!({"from_index": 0, "limit": 10}))
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(
+        holders,
+        json!([{"account_id": user_account.id(), "balance": "1000"}])
+    );
+    let count = contract.view("get_holder_count").args_json(json!({})).await?;
+    assert_eq!(count.json::<u64>()?, 1);
 
     Ok(())
 }
 
+#[tokio::test]
+async fn test_failed_withdraw_restores_balance() -> Result<(), Box<dyn std::error::Error>> {
+    let contract_wasm = near_workspaces::compile_project("./").await?;
+    let sandbox = near_workspaces::sandbox().await?;
+    let contract = sandbox.dev_deploy(&contract_wasm).await?;
+    let usdc = sandbox.dev_create_account().await?;
+    let user_account = sandbox.dev_create_account().await?;
 
+    contract
+        .call("init")
+        .args_json(json!({"owner": contract.id(), "ft_contract": usdc.id()}))
+        .transact()
+        .await?
+        .into_result()?;
+    usdc.call(contract.id(), "ft_on_transfer")
+        .args_json(json!({"sender_id": user_account.id(), "amount": "1000", "msg": ""}))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let outcome = user_account
+        .call(contract.id(), "withdraw")
+        .args_json(json!({"amount": "400"}))
+        .deposit(near_workspaces::types::NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+    assert!(outcome
+        .logs()
+        .iter()
+        .any(|log| log.contains("\"event\":\"withdraw_failed\"")));
+
+    let balance = contract
+        .view("get_user_balance")
+        .args_json(json!({"account_id": user_account.id()}))
+        .await?;
+    assert_eq!(balance.json::<String>()?, "1000");
+    let total = contract.view("get_usdc_balance").args_json(json!({})).await?;
+    assert_eq!(total.json::<String>()?, "1000");
+
+    Ok(())
+}
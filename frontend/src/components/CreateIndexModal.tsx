@@ -64,6 +64,18 @@ const ICON_MAP: Record<string, string> = {
   OP: "/icons/optimism.png",
 };
 
+const MAX_BPS = 10000;
+
+// The contract takes weights in basis points that add up to exactly MAX_BPS,
+// whatever rounding leaves over goes to the largest weight
+const toBasisPoints = (percentages: number[]): number[] => {
+  const weights = percentages.map((percentage) => Math.round(percentage * 100));
+  const remainder = MAX_BPS - weights.reduce((sum, weight) => sum + weight, 0);
+  const largest = weights.indexOf(Math.max(...weights));
+  weights[largest] += remainder;
+  return weights;
+};
+
 export const CreateIndexModal: React.FC<CreateIndexModalProps> = ({
   open,
   onOpenChange,
@@ -205,14 +217,15 @@ export const CreateIndexModal: React.FC<CreateIndexModalProps> = ({
     setError(null);
 
     try {
+      const weights = toBasisPoints(tokens.map((token) => token.percentange));
       const fundMetadata = {
         name: metadata.name,
         symbol: metadata.symbol,
         description: metadata.description || null,
-        assets: tokens.map((token) => ({
+        assets: tokens.map((token, index) => ({
           name: token.symbol,
           contract_address: Symbols[token.symbol as keyof typeof Symbols],
-          weight: token.percentange,
+          weight: weights[index],
         })),
       };
 
@@ -20,6 +20,7 @@ export enum Symbols {
 export interface AssetInfo {
   name: string;
   contract_address: string;
+  /** Basis points, the weights of a fund add up to 10000 */
   weight: number;
 }
 